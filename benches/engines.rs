@@ -1,5 +1,7 @@
-use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
-use kvs::{KvStore, KvsEngine, SledEngine};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use kvs::{Durability, KvStore, KvStoreOptions, KvsEngine};
+#[cfg(feature = "sled")]
+use kvs::{SledDurability, SledEngine, SledOptions};
 use rand::{
     distributions::{Alphanumeric, DistString},
     thread_rng, Rng,
@@ -21,7 +23,7 @@ fn write(c: &mut Criterion) {
         ));
     }
 
-    let kvs = KvStore::open(&dir).unwrap();
+    let kvs = KvStore::open(dir).unwrap();
     let mut kvs_group = c.benchmark_group("kvs write 100 values");
     for (id, (k, v)) in kv.iter().enumerate() {
         kvs_group.bench_with_input(BenchmarkId::from_parameter(id), &(k, v), |b, (k, v)| {
@@ -32,16 +34,19 @@ fn write(c: &mut Criterion) {
     }
     kvs_group.finish();
 
-    let sled = SledEngine::open(&dir).unwrap();
-    let mut sled_group = c.benchmark_group("sled write 100 values");
-    for (id, (k, v)) in kv.iter().enumerate() {
-        sled_group.bench_with_input(BenchmarkId::from_parameter(id), &(k, v), |b, (k, v)| {
-            b.iter(|| {
-                sled.set(k.to_string(), v.to_string()).unwrap();
-            })
-        });
+    #[cfg(feature = "sled")]
+    {
+        let sled = SledEngine::open(dir).unwrap();
+        let mut sled_group = c.benchmark_group("sled write 100 values");
+        for (id, (k, v)) in kv.iter().enumerate() {
+            sled_group.bench_with_input(BenchmarkId::from_parameter(id), &(k, v), |b, (k, v)| {
+                b.iter(|| {
+                    sled.set(k.to_string(), v.to_string()).unwrap();
+                })
+            });
+        }
+        sled_group.finish();
     }
-    sled_group.finish();
 }
 
 fn read(c: &mut Criterion) {
@@ -49,13 +54,14 @@ fn read(c: &mut Criterion) {
     let dir = TempDir::new().unwrap();
     let dir = dir.path();
 
-    let kvs = KvStore::open(&dir).unwrap();
-    let sled = SledEngine::open(&dir).unwrap();
+    let kvs = KvStore::open(dir).unwrap();
+    #[cfg(feature = "sled")]
+    let sled = SledEngine::open(dir).unwrap();
 
     let mut rng = thread_rng();
     for _ in 0..1000 {
-        let key_len: usize = rng.gen();
-        let val_len: usize = rng.gen();
+        let key_len: usize = rng.gen_range(1..100000);
+        let val_len: usize = rng.gen_range(1..100000);
         kv.push((
             Alphanumeric.sample_string(&mut rng, key_len),
             Alphanumeric.sample_string(&mut rng, val_len),
@@ -64,6 +70,7 @@ fn read(c: &mut Criterion) {
 
     for (k, v) in kv.clone() {
         kvs.set(k.clone(), v.clone()).expect("rb: kvs set failed");
+        #[cfg(feature = "sled")]
         sled.set(k, v).expect("rb: sled set failed");
     }
 
@@ -77,16 +84,411 @@ fn read(c: &mut Criterion) {
     }
     kvs_group.finish();
 
-    let mut sled_group = c.benchmark_group("sled read 1000 values");
-    for (id, k) in kv.iter().map(|kv| kv.0.to_string()).enumerate() {
-        sled_group.bench_with_input(BenchmarkId::from_parameter(id), &k, |b, k| {
+    #[cfg(feature = "sled")]
+    {
+        let mut sled_group = c.benchmark_group("sled read 1000 values");
+        for (id, k) in kv.iter().map(|kv| kv.0.to_string()).enumerate() {
+            sled_group.bench_with_input(BenchmarkId::from_parameter(id), &k, |b, k| {
+                b.iter(|| {
+                    sled.get(k.to_string()).unwrap().unwrap();
+                })
+            });
+        }
+        sled_group.finish();
+    }
+}
+
+fn read_missing(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path();
+
+    let plain = KvStore::open_with_options(dir, KvStoreOptions::new().log_name("plain")).unwrap();
+    let bloom = KvStore::open_with_options(
+        dir,
+        KvStoreOptions::new()
+            .log_name("bloom")
+            .bloom_filter(10000, 0.01),
+    )
+    .unwrap();
+    for i in 0..10000 {
+        plain.set(format!("key{i}"), format!("value{i}")).unwrap();
+        bloom.set(format!("key{i}"), format!("value{i}")).unwrap();
+    }
+
+    // 90% of lookups are for keys that don't exist.
+    let keys: Vec<String> = (0..1000)
+        .map(|i| match i % 10 {
+            0 => format!("key{i}"),
+            _ => format!("missing{i}"),
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("kvs read 1000 mostly missing keys");
+    for (name, store) in [("no filter", &plain), ("bloom filter", &bloom)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for k in &keys {
+                    store.get(k.to_string()).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Sample `n` indexes into `0..keys` following a zipfian distribution, so that a few
+/// keys account for most of the reads.
+fn zipfian(keys: usize, n: usize) -> Vec<usize> {
+    let weights: Vec<f64> = (1..=keys).map(|rank| 1.0 / rank as f64).collect();
+    let total: f64 = weights.iter().sum();
+    let cdf: Vec<f64> = weights
+        .iter()
+        .scan(0.0, |acc, w| {
+            *acc += w / total;
+            Some(*acc)
+        })
+        .collect();
+
+    let mut rng = thread_rng();
+    (0..n)
+        .map(|_| {
+            let p: f64 = rng.gen();
+            cdf.partition_point(|&c| c < p).min(keys - 1)
+        })
+        .collect()
+}
+
+fn read_skewed(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path();
+
+    let plain = KvStore::open_with_options(dir, KvStoreOptions::new().log_name("plain")).unwrap();
+    let cached = KvStore::open_with_options(
+        dir,
+        KvStoreOptions::new()
+            .log_name("cached")
+            .value_cache(1024 * 1024),
+    )
+    .unwrap();
+    let value = "x".repeat(1000);
+    for i in 0..10000 {
+        plain.set(format!("key{i}"), value.clone()).unwrap();
+        cached.set(format!("key{i}"), value.clone()).unwrap();
+    }
+
+    let keys: Vec<String> = zipfian(10000, 1000)
+        .into_iter()
+        .map(|i| format!("key{i}"))
+        .collect();
+
+    let mut group = c.benchmark_group("kvs read 1000 zipfian keys");
+    for (name, store) in [("no cache", &plain), ("value cache", &cached)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for k in &keys {
+                    store.get(k.to_string()).unwrap().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Compare getting values by keys passed as `String`s, allocated for every read, with
+/// getting them by borrowed keys.
+fn borrowed_reads(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let options = KvStoreOptions::new().value_cache(1024 * 1024);
+    let store = KvStore::open_with_options(dir.path(), options).unwrap();
+    for i in 0..1000 {
+        store.set(format!("key{i}"), "x".repeat(100)).unwrap();
+    }
+    let keys: Vec<String> = (0..1000).map(|i| format!("key{i}")).collect();
+
+    let mut group = c.benchmark_group("kvs read 1000 cached keys");
+    group.bench_function("allocated keys", |b| {
+        b.iter(|| {
+            for k in &keys {
+                store.get(k.to_string()).unwrap().unwrap();
+            }
+        })
+    });
+    group.bench_function("borrowed keys", |b| {
+        b.iter(|| {
+            for k in &keys {
+                store.get_ref(k).unwrap().unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn open_segmented(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path();
+
+    let options = KvStoreOptions::new()
+        .max_segment_size(Some(1024 * 1024))
+        .compaction_threshold(None);
+    let store = KvStore::open_with_options(dir, options.clone()).unwrap();
+    let value = "x".repeat(100);
+    for i in 0..200000 {
+        store
+            .set(format!("key{}", i % 50000), value.clone())
+            .unwrap();
+    }
+    drop(store);
+
+    let mut group = c.benchmark_group("kvs open a log split into 1MiB files");
+    group.sample_size(10);
+    for threads in [1, num_cpus::get().max(2)] {
+        let options = options.clone().replay_threads(threads);
+        group.bench_with_input(
+            BenchmarkId::new("threads", threads),
+            &options,
+            |b, options| b.iter(|| KvStore::open_with_options(dir, options.clone()).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+fn bulk_insert(c: &mut Criterion) {
+    const KEYS: usize = 100000;
+    let value = "x".repeat(100);
+
+    let mut group = c.benchmark_group("kvs insert 100k keys");
+    group.sample_size(10);
+    for reserve in [false, true] {
+        let id = if reserve { "reserved" } else { "unreserved" };
+        group.bench_function(id, |b| {
+            b.iter_batched(
+                || {
+                    let dir = TempDir::new().unwrap();
+                    let options = KvStoreOptions::new().compaction_threshold(None);
+                    let store = KvStore::open_with_options(dir.path(), options).unwrap();
+                    (dir, store)
+                },
+                |(dir, store)| {
+                    if reserve {
+                        store.reserve(KEYS, (KEYS * 150) as u64).unwrap();
+                    }
+                    for i in 0..KEYS {
+                        store.set(format!("key{}", i), value.clone()).unwrap();
+                    }
+                    (dir, store)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Compare setting 100k small records in sled one by one, flushing after each, with
+/// setting them in a single batch.
+#[cfg(feature = "sled")]
+fn sled_batch(c: &mut Criterion) {
+    let entries: Vec<(String, String)> = (0..100000)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+
+    let mut group = c.benchmark_group("sled set 100k small records");
+    group.sample_size(10);
+    for batched in [false, true] {
+        let id = if batched { "batch" } else { "looped sets" };
+        group.bench_function(id, |b| {
+            b.iter_batched(
+                || {
+                    let dir = TempDir::new().unwrap();
+                    let sled = SledEngine::open(dir.path()).unwrap();
+                    (dir, sled)
+                },
+                |(dir, sled)| {
+                    if batched {
+                        sled.populate(entries.clone()).unwrap();
+                    } else {
+                        for (key, value) in entries.clone() {
+                            sled.set(key, value).unwrap();
+                        }
+                    }
+                    (dir, sled)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+#[cfg(not(feature = "sled"))]
+fn sled_batch(_c: &mut Criterion) {}
+
+/// Compare reading 8MiB values from sled as copies with reading them where sled holds
+/// them.
+#[cfg(feature = "sled")]
+fn sled_large_reads(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let sled = SledEngine::open(dir.path()).unwrap();
+    let value = "x".repeat(8 << 20);
+    for i in 0..4 {
+        sled.set(format!("key{i}"), value.clone()).unwrap();
+    }
+
+    let mut group = c.benchmark_group("sled read 4 8MiB values");
+    group.bench_function("copied", |b| {
+        b.iter(|| {
+            for i in 0..4 {
+                sled.get_bytes(format!("key{i}").as_bytes())
+                    .unwrap()
+                    .unwrap();
+            }
+        })
+    });
+    group.bench_function("shared", |b| {
+        b.iter(|| {
+            for i in 0..4 {
+                sled.get_ivec(format!("key{i}").as_bytes())
+                    .unwrap()
+                    .unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+#[cfg(not(feature = "sled"))]
+fn sled_large_reads(_c: &mut Criterion) {}
+
+/// Compare sled's write throughput when flushing after every write with flushing in the
+/// background and only when asked to.
+#[cfg(feature = "sled")]
+fn sled_durability(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sled set 1000 small records");
+    group.sample_size(10);
+    for (id, durability) in [
+        ("flush every write", SledDurability::FlushEveryWrite),
+        ("background flush", SledDurability::Background(500)),
+        ("manual flush", SledDurability::Manual),
+    ] {
+        group.bench_function(id, |b| {
+            let dir = TempDir::new().unwrap();
+            let options = SledOptions::new().durability(durability);
+            let sled = SledEngine::open_with(dir.path(), options).unwrap();
             b.iter(|| {
-                sled.get(k.to_string()).unwrap().unwrap();
+                for i in 0..1000 {
+                    sled.set(format!("key{}", i), format!("value{}", i))
+                        .unwrap();
+                }
+                if durability == SledDurability::Manual {
+                    sled.flush().unwrap();
+                }
             })
         });
     }
-    sled_group.finish();
+    group.finish();
 }
 
-criterion_group!(benches, write, read);
+#[cfg(not(feature = "sled"))]
+fn sled_durability(_c: &mut Criterion) {}
+
+/// Compare syncing after every write with group commit, under concurrent writers.
+fn durable_writes(c: &mut Criterion) {
+    const WRITERS: usize = 20;
+    const WRITES_PER_WRITER: usize = 20;
+
+    let mut group = c.benchmark_group("kvs 20 concurrent durable writers");
+    group.sample_size(10);
+    for group_commit in [false, true] {
+        let id = if group_commit {
+            "group commit"
+        } else {
+            "sync per write"
+        };
+        group.bench_function(id, |b| {
+            let dir = TempDir::new().unwrap();
+            let durability = if group_commit {
+                Durability::EveryWrite
+            } else {
+                Durability::Buffered
+            };
+            let options = KvStoreOptions::new().durability(durability);
+            let store = KvStore::open_with_options(dir.path(), options).unwrap();
+            b.iter(|| {
+                std::thread::scope(|s| {
+                    for i in 0..WRITERS {
+                        let store = &store;
+                        s.spawn(move || {
+                            for j in 0..WRITES_PER_WRITER {
+                                store
+                                    .set(format!("key{}-{}", i, j), "x".to_owned())
+                                    .unwrap();
+                                if !group_commit {
+                                    store.sync().unwrap();
+                                }
+                            }
+                        });
+                    }
+                });
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Compare reading sealed log files through memory maps with reading them as files.
+#[cfg(feature = "mmap")]
+fn mmap_reads(c: &mut Criterion) {
+    const KEYS: usize = 100000;
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path();
+
+    let options = KvStoreOptions::new()
+        .max_segment_size(Some(4 * 1024 * 1024))
+        .compaction_threshold(None);
+    let store = KvStore::open_with_options(dir, options.clone()).unwrap();
+    let value = "x".repeat(100);
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), value.clone()).unwrap();
+    }
+    store.compact().unwrap();
+    drop(store);
+
+    let mut rng = thread_rng();
+    let keys: Vec<String> = (0..1000)
+        .map(|_| format!("key{}", rng.gen_range(0..KEYS)))
+        .collect();
+
+    let mut group = c.benchmark_group("kvs read 1000 random keys of 100k");
+    for mmap in [false, true] {
+        let id = if mmap { "mmap" } else { "file reads" };
+        let store = KvStore::open_with_options(dir, options.clone().mmap_reads(mmap)).unwrap();
+        group.bench_function(id, |b| {
+            b.iter(|| {
+                for k in &keys {
+                    store.get(k.to_string()).unwrap().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+#[cfg(not(feature = "mmap"))]
+fn mmap_reads(_c: &mut Criterion) {}
+
+criterion_group!(
+    benches,
+    write,
+    read,
+    read_missing,
+    read_skewed,
+    borrowed_reads,
+    open_segmented,
+    bulk_insert,
+    sled_batch,
+    sled_large_reads,
+    sled_durability,
+    durable_writes,
+    mmap_reads
+);
 criterion_main!(benches);
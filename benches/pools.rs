@@ -1,6 +1,8 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
-use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, SledEngine};
+#[cfg(feature = "sled")]
+use kvs::SledEngine;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::{Arc, Barrier};
 use tempfile::TempDir;
@@ -16,6 +18,7 @@ impl KvsEngineOpen for KvStore {
         KvStore::open(path.as_ref())
     }
 }
+#[cfg(feature = "sled")]
 impl KvsEngineOpen for SledEngine {
     fn open(path: impl AsRef<std::path::Path>) -> kvs::Result<Self> {
         SledEngine::open(path)
@@ -32,11 +35,8 @@ fn bench_writes<E: KvsEngine + KvsEngineOpen, T: ThreadPool + 'static>(c: &mut C
     let path = temp.path();
 
     let ipv4_addr = Ipv4Addr::new(127, 0, 0, 1);
-    let mut port = 4006;
-
-    for num_threads in inputs {
+    for (port, num_threads) in (4006..).zip(inputs) {
         let socket_addr = SocketAddr::new(IpAddr::V4(ipv4_addr), port);
-        port += 1;
 
         let pool = T::new(num_threads as u32).unwrap();
         let store = E::open(path).unwrap();
@@ -84,7 +84,7 @@ fn bench_reads<E: KvsEngine + KvsEngineOpen, T: ThreadPool + 'static>(c: &mut Cr
     let temp = TempDir::new().unwrap();
     let thread_pool = T::new(200).unwrap();
     let path = temp.path();
-    let store = KvStore::open(path.clone()).unwrap();
+    let store = E::open(path).unwrap();
 
     let ipv4_addr = Ipv4Addr::new(127, 0, 0, 1);
     let mut port = 4006;
@@ -151,30 +151,95 @@ fn bench_reads<E: KvsEngine + KvsEngineOpen, T: ThreadPool + 'static>(c: &mut Cr
     group.finish();
 }
 
+/// Compare applying writes on the pool, taking turns on the store's lock, with applying
+/// them all on a single writer thread, under a write-heavy load.
+fn bench_writer_models(c: &mut Criterion) {
+    let mut group = c.benchmark_group("writer_models");
+    let temp = TempDir::new().unwrap();
+    let ipv4_addr = Ipv4Addr::new(127, 0, 0, 1);
+
+    for (port, single_writer) in (4100..).zip([false, true]) {
+        let socket_addr = SocketAddr::new(IpAddr::V4(ipv4_addr), port);
+        let pool = SharedQueueThreadPool::new(num_cpus::get() as u32).unwrap();
+        let store = KvStore::open(temp.path()).unwrap();
+        let (mut server, close_handle) = KvsServer::bind(socket_addr, store, pool).unwrap();
+        server.set_single_writer(single_writer);
+        let server_thread = std::thread::spawn(|| {
+            server.run().unwrap();
+        });
+
+        let client_thread_pool = SharedQueueThreadPool::new(CONCURRENT_CLIENTS as u32).unwrap();
+        let benchmark_id = match single_writer {
+            true => "single writer thread",
+            false => "shared lock",
+        };
+        group.bench_function(benchmark_id, |b| {
+            b.iter(|| {
+                let barrier = Arc::new(Barrier::new(CONCURRENT_CLIENTS + 1));
+                for i in 0..CONCURRENT_CLIENTS {
+                    let b = Arc::clone(&barrier);
+                    let start = i * REQUESTS_PER_CLIENT;
+                    let end = start + REQUESTS_PER_CLIENT;
+                    client_thread_pool.spawn(move || {
+                        let mut client = KvsClient::connect(socket_addr).unwrap();
+                        for i in start..end {
+                            let key = format!("key{i:0>width$}", width = 5);
+                            client.set(key.clone(), "x".to_string()).unwrap();
+                            // One read for every four writes.
+                            if i % 4 == 0 {
+                                client.get(key).unwrap();
+                            }
+                        }
+                        client.shutdown().unwrap();
+                        b.wait();
+                    });
+                }
+                barrier.wait();
+            })
+        });
+
+        close_handle.shutdown().unwrap();
+        server_thread.join().unwrap();
+    }
+    group.finish();
+}
+
 fn shared_queue_kvstore_writes(c: &mut Criterion) {
     bench_writes::<KvStore, SharedQueueThreadPool>(c);
 }
 fn shared_queue_kvstore_reads(c: &mut Criterion) {
     bench_reads::<KvStore, SharedQueueThreadPool>(c);
 }
+#[cfg(feature = "sled")]
 fn shared_queue_sled_writes(c: &mut Criterion) {
     bench_writes::<SledEngine, SharedQueueThreadPool>(c);
 }
+#[cfg(not(feature = "sled"))]
+fn shared_queue_sled_writes(_c: &mut Criterion) {}
+#[cfg(feature = "sled")]
 fn shared_queue_sled_reads(c: &mut Criterion) {
     bench_reads::<SledEngine, SharedQueueThreadPool>(c);
 }
+#[cfg(not(feature = "sled"))]
+fn shared_queue_sled_reads(_c: &mut Criterion) {}
 fn rayon_kvstore_writes(c: &mut Criterion) {
     bench_writes::<KvStore, RayonThreadPool>(c);
 }
 fn rayon_kvstore_reads(c: &mut Criterion) {
     bench_reads::<KvStore, RayonThreadPool>(c);
 }
+#[cfg(feature = "sled")]
 fn rayon_sled_writes(c: &mut Criterion) {
     bench_reads::<SledEngine, RayonThreadPool>(c);
 }
+#[cfg(not(feature = "sled"))]
+fn rayon_sled_writes(_c: &mut Criterion) {}
+#[cfg(feature = "sled")]
 fn rayon_sled_reads(c: &mut Criterion) {
     bench_reads::<SledEngine, RayonThreadPool>(c);
 }
+#[cfg(not(feature = "sled"))]
+fn rayon_sled_reads(_c: &mut Criterion) {}
 
 criterion_group!(
     benches,
@@ -185,6 +250,7 @@ criterion_group!(
     rayon_kvstore_writes,
     rayon_kvstore_reads,
     rayon_sled_writes,
-    rayon_sled_reads
+    rayon_sled_reads,
+    bench_writer_models
 );
 criterion_main!(benches);
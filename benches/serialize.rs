@@ -0,0 +1,80 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use serde::Serialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs::File;
+use std::io::{prelude::*, BufWriter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::TempDir;
+
+/// Counts every allocation made by the benchmark.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Mirrors the shape of a `set` record in the log.
+#[derive(Serialize)]
+enum Op<'a> {
+    Set { key: &'a str, value: &'a str },
+}
+
+fn to_string(writer: &mut BufWriter<File>, op: &Op) {
+    writer
+        .write_all(serde_json::to_string(op).unwrap().as_bytes())
+        .unwrap();
+    writer.flush().unwrap();
+}
+
+fn to_writer(writer: &mut BufWriter<File>, op: &Op) {
+    serde_json::to_writer(&mut *writer, op).unwrap();
+    writer.flush().unwrap();
+}
+
+fn serialize(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let value = "x".repeat(1000);
+    let ops: Vec<(String, &str)> = (0..1000).map(|i| (format!("key{i}"), &*value)).collect();
+
+    let mut group = c.benchmark_group("serialize 1000 set records");
+    for (name, write) in [
+        ("to_string", to_string as fn(&mut BufWriter<File>, &Op)),
+        ("to_writer", to_writer),
+    ] {
+        let mut writer = BufWriter::new(File::create(dir.path().join(name)).unwrap());
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        for (key, value) in &ops {
+            write(&mut writer, &Op::Set { key, value });
+        }
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        println!(
+            "{name}: {allocations} allocations for {} records",
+            ops.len()
+        );
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for (key, value) in &ops {
+                    write(&mut writer, &Op::Set { key, value });
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialize);
+criterion_main!(benches);
@@ -1,39 +1,71 @@
 use clap::{Parser, Subcommand};
 use kvs::KvsClient;
-use std::net::SocketAddr;
+use log::LevelFilter;
 
 fn main() -> anyhow::Result<()> {
-    env_logger::init();
-
     let cli = Cli::parse();
+    init_logging(cli.log_level);
 
-    let socket_addr = cli.addr.parse::<SocketAddr>()?;
-    let mut client = KvsClient::connect(socket_addr)?;
+    let mut client = KvsClient::connect_to(cli.addr.as_str())?;
 
     match cli.command {
-        Command::Get { key } => match client.get(key)? {
+        Command::Get { key, meta: true } => match client.get_with_meta(key)? {
+            Some(entry) => println!("{}", serde_json::to_string_pretty(&entry)?),
+            None => println!("Key not found"),
+        },
+        Command::Get { key, meta: false } => match client.get(key)? {
             Some(val) => println!("{val}"),
             None => println!("Key not found"),
         },
         Command::Rm { key } => client.remove(key)?,
         Command::Set { key, value } => client.set(key, value)?,
+        Command::Incr { key, by } => println!("{}", client.increment(key, by)?),
+        Command::Cp {
+            src,
+            dst,
+            overwrite,
+        } => client.copy(src, dst, overwrite)?,
+        Command::Flush => client.flush()?,
+        Command::CompactionStatus => match client.compaction_status()? {
+            Some(status) => println!("{}", serde_json::to_string_pretty(&status)?),
+            None => println!("The server's engine doesn't report on compactions"),
+        },
     }
 
     Ok(())
 }
 
+/// Install the logger, at `level` and above, or as `RUST_LOG` says otherwise.
+///
+/// `level` overrides the level `RUST_LOG` sets for every module, but not those it sets
+/// for particular ones.
+fn init_logging(level: Option<LevelFilter>) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(level) = level {
+        builder.filter_level(level);
+    }
+    builder.init();
+}
+
 #[derive(Parser)]
 #[command(version)]
 pub struct Cli {
     #[command(subcommand)]
     command: Command,
     #[clap(
-        help = "The socket address to bind to",
+        help = "The address of the server, as host:port",
         long,
         default_value = "127.0.0.1:4000",
         global = true
     )]
     addr: String,
+    #[arg(
+        help = "Log at this level and above: off, error, warn, info, debug or trace",
+        long,
+        value_name = "LEVEL",
+        global = true
+    )]
+    log_level: Option<LevelFilter>,
 }
 
 #[derive(Subcommand)]
@@ -47,9 +79,33 @@ pub enum Command {
     Get {
         #[arg(help = "The key of the object we want to get")]
         key: String,
+        #[arg(help = "Also print when the value was written and its size", long)]
+        meta: bool,
     },
     Rm {
         #[arg(help = "The key of the object we want to remove")]
         key: String,
     },
+    Incr {
+        #[arg(help = "The key of the integer we want to increment")]
+        key: String,
+        #[arg(
+            help = "The amount to add",
+            default_value_t = 1,
+            allow_negative_numbers = true
+        )]
+        by: i64,
+    },
+    Cp {
+        #[arg(help = "The key of the object we want to copy")]
+        src: String,
+        #[arg(help = "The key to copy it to")]
+        dst: String,
+        #[arg(long, help = "Replace the object at the destination if there is one")]
+        overwrite: bool,
+    },
+    /// Make every write the server has acknowledged durable
+    Flush,
+    /// Print the state of the server's compactions as JSON
+    CompactionStatus,
 }
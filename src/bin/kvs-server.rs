@@ -1,15 +1,16 @@
 use clap::Parser;
-use env_logger::Target;
+use env_logger::{Env, Target};
 use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
-use kvs::{KvStore, KvsServer, SledEngine};
+#[cfg(feature = "sled")]
+use kvs::SledEngine;
+use kvs::{KvStore, KvStoreOptions, KvsServer};
 use log::*;
 use std::net::SocketAddr;
+use std::num::NonZeroU32;
 
 fn main() -> anyhow::Result<()> {
-    env_logger::Builder::new().target(Target::Stderr).build();
-    env_logger::init();
-
     let cli = Cli::parse();
+    init_logging(cli.log_level);
     info!("version {}", env!("CARGO_PKG_VERSION"));
 
     let cwd = std::env::current_dir()?;
@@ -43,18 +44,49 @@ fn main() -> anyhow::Result<()> {
         (Some(any), _) => StorageEngine::try_from_string(any)?,
     };
     info!("loading {} engine", engine.to_str());
+    if cli.read_only {
+        info!("serving read-only");
+    }
+    let follow = match &cli.follow {
+        Some(leader) => {
+            info!("following {}", leader);
+            Some(leader.parse::<SocketAddr>()?)
+        }
+        None => None,
+    };
     std::fs::write(&engine_lock_path, engine.to_str())?;
 
     let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
     match engine {
         StorageEngine::Kvs => {
-            let db = KvStore::open(cwd)?;
-            let (server, _) = KvsServer::bind(socket_addr, db, pool)?;
+            let options = KvStoreOptions::new().on_compaction(|report| {
+                info!(
+                    "compacted log from {} to {} bytes in {:?}",
+                    report.bytes_before, report.bytes_after, report.duration
+                )
+            });
+            let db = KvStore::open_with_options(cwd, options)?;
+            let (mut server, _) = KvsServer::bind(socket_addr, db, pool)?;
+            server.set_single_writer(cli.single_writer);
+            server.read_only_handle().set_read_only(cli.read_only);
+            server.set_leader(cli.leader);
+            server.set_max_rps_per_connection(cli.max_rps_per_connection);
+            if let Some(leader) = follow {
+                server.follow(leader);
+            }
             server.run()?;
         }
+        #[cfg(feature = "sled")]
         StorageEngine::Sled => {
             let db = SledEngine::open(cwd)?;
-            let (server, _) = KvsServer::bind(socket_addr, db, pool)?;
+            let (mut server, _) = KvsServer::bind(socket_addr, db, pool)?;
+            server.set_single_writer(cli.single_writer);
+            server.read_only_handle().set_read_only(cli.read_only);
+            server.set_leader(cli.leader);
+            server.set_max_rps_per_connection(cli.max_rps_per_connection);
+            if let Some(leader) = follow {
+                server.follow(leader);
+            }
             server.run()?;
         }
     }
@@ -62,6 +94,20 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Install the logger, writing to stderr at `level` and above, or as `RUST_LOG` says
+/// otherwise, or at `info` and above without either.
+///
+/// `level` overrides the level `RUST_LOG` sets for every module, but not those it sets
+/// for particular ones. This is the only place the logger is set up, so that its settings
+/// can't be built and then dropped in favour of another's defaults.
+fn init_logging(level: Option<LevelFilter>) {
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    if let Some(level) = level {
+        builder.filter_level(level);
+    }
+    builder.target(Target::Stderr).init();
+}
+
 #[derive(Parser)]
 #[command(version)]
 pub struct Cli {
@@ -69,11 +115,39 @@ pub struct Cli {
     socket_addr: String,
     #[arg(short, long, help = "kvs/sled: the engine to bind to")]
     engine: Option<String>,
+    #[arg(
+        long,
+        help = "Apply writes to the engine on a single thread of their own"
+    )]
+    single_writer: bool,
+    #[arg(long, help = "Serve reads, but refuse every write")]
+    read_only: bool,
+    #[arg(long, help = "Stream every write to the followers that subscribe")]
+    leader: bool,
+    #[arg(
+        long,
+        value_name = "LEADER",
+        help = "Replicate the leader at this address, serving reads but refusing writes"
+    )]
+    follow: Option<String>,
+    #[arg(
+        long,
+        value_name = "RPS",
+        help = "Refuse requests beyond this many per second on each connection"
+    )]
+    max_rps_per_connection: Option<NonZeroU32>,
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        help = "Log at this level and above: off, error, warn, info, debug or trace"
+    )]
+    log_level: Option<LevelFilter>,
 }
 
 #[derive(Eq, PartialEq)]
 pub enum StorageEngine {
     Kvs,
+    #[cfg(feature = "sled")]
     Sled,
 }
 
@@ -81,6 +155,7 @@ impl StorageEngine {
     pub fn to_str(&self) -> &str {
         match self {
             StorageEngine::Kvs => "kvs",
+            #[cfg(feature = "sled")]
             StorageEngine::Sled => "sled",
         }
     }
@@ -93,7 +168,12 @@ impl StorageEngine {
 
         match s {
             "kvs" => Ok(StorageEngine::Kvs),
+            #[cfg(feature = "sled")]
             "sled" => Ok(StorageEngine::Sled),
+            #[cfg(not(feature = "sled"))]
+            "sled" => Err(anyhow::anyhow!(
+                "This build of kvs-server doesn't include the sled engine"
+            )),
             _ => Err(anyhow::anyhow!("Invalid storage engine name")),
         }
     }
@@ -0,0 +1,122 @@
+use clap::{Parser, Subcommand};
+use kvs::{KvStore, KvsEngine};
+use std::io::Write;
+use std::path::PathBuf;
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::LogDump {
+            path,
+            from_offset,
+            limit,
+        } => {
+            let records = KvStore::inspect(path)?.from_offset(from_offset);
+            let mut stdout = std::io::stdout().lock();
+            for record in records.take(limit.unwrap_or(usize::MAX)) {
+                serde_json::to_writer(&mut stdout, &record)?;
+                writeln!(stdout)?;
+            }
+        }
+        Command::Verify { path, repair } => {
+            let report = if repair {
+                KvStore::repair(path)?
+            } else {
+                KvStore::verify(path)?
+            };
+            let mut stdout = std::io::stdout().lock();
+            serde_json::to_writer_pretty(&mut stdout, &report)?;
+            writeln!(stdout)?;
+            if !report.openable {
+                std::process::exit(1);
+            }
+        }
+        Command::Compact { path, dry_run } => {
+            let store = KvStore::open(path)?;
+            if dry_run {
+                let estimate = store.compaction_estimate()?;
+                let mut stdout = std::io::stdout().lock();
+                serde_json::to_writer_pretty(&mut stdout, &estimate)?;
+                writeln!(stdout)?;
+            } else {
+                let before = store.disk_usage()?;
+                store.compact()?;
+                println!(
+                    "compacted log from {} to {} bytes",
+                    before,
+                    store.disk_usage()?
+                );
+            }
+        }
+        Command::History { path, key, limit } => {
+            let store = KvStore::open(path)?;
+            let mut stdout = std::io::stdout().lock();
+            for version in store.history(key, limit)? {
+                serde_json::to_writer(&mut stdout, &version)?;
+                writeln!(stdout)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Offline tools for working with a KvStore's files.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Print every record in a log file as a line of JSON
+    LogDump {
+        #[arg(help = "The log file to dump")]
+        path: PathBuf,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Start at the first record at or after this offset"
+        )]
+        from_offset: u64,
+        #[arg(long, help = "The maximum number of records to print")]
+        limit: Option<usize>,
+    },
+    /// Check a store for damage, printing what was found as JSON and failing if it can't
+    /// be opened
+    Verify {
+        #[arg(help = "The directory of the store")]
+        path: PathBuf,
+        #[arg(long, help = "Repair what can be repaired first")]
+        repair: bool,
+    },
+    /// Compact a store, rewriting its log to hold only live entries
+    Compact {
+        #[arg(help = "The directory of the store")]
+        path: PathBuf,
+        #[arg(
+            long,
+            help = "Print what compaction would reclaim as JSON, without compacting"
+        )]
+        dry_run: bool,
+    },
+    /// Print the values a key has held since the last compaction, newest first, each as a
+    /// line of JSON
+    History {
+        #[arg(help = "The directory of the store")]
+        path: PathBuf,
+        #[arg(help = "The key to print the values of")]
+        key: String,
+        #[arg(
+            long,
+            default_value_t = 10,
+            help = "The maximum number of values to print"
+        )]
+        limit: usize,
+    },
+}
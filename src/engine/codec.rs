@@ -0,0 +1,355 @@
+//! How ops are encoded as records of the log.
+//!
+//! Each encoding implements [`LogCodec`], and every record read or written by the store
+//! goes through one. Log files record the codec they're written in in their header, so
+//! a store can hold files written in several.
+
+use super::compression::Compressed;
+use super::{bytes, Op};
+use crate::err::Result;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+/// The encodings log records can be written in.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Codec {
+    /// One JSON object per record, which stays readable with ordinary text tools.
+    #[default]
+    Json,
+    /// bincode, with each record prefixed by its length. Smaller, and quicker to read
+    /// and write, than JSON.
+    Bincode,
+}
+
+/// Encodes ops as records of the log, and decodes them back.
+pub(crate) trait LogCodec {
+    /// Append the record of `op` to `buf`.
+    fn encode(&self, op: &Op, buf: &mut Vec<u8>) -> Result<()>;
+
+    /// Decode the record at the start of `buf`, which may carry on past it, returning
+    /// the op and the length of the record.
+    ///
+    /// Returns `None` if `buf` holds nothing but padding, and an error describing what's
+    /// wrong if the record can't be read.
+    fn decode(&self, buf: &[u8]) -> std::result::Result<Option<(Op, usize)>, String>;
+
+    /// The length of the padding at the start of `buf`, that a record may be preceded by.
+    fn padding(&self, buf: &[u8]) -> usize;
+
+    /// Whether a record looks to start at the start of `buf`, to resume reading at after
+    /// one that can't be read.
+    fn at_record_start(&self, buf: &[u8]) -> bool;
+}
+
+impl LogCodec for Codec {
+    fn encode(&self, op: &Op, buf: &mut Vec<u8>) -> Result<()> {
+        self.codec().encode(op, buf)
+    }
+
+    fn decode(&self, buf: &[u8]) -> std::result::Result<Option<(Op, usize)>, String> {
+        self.codec().decode(buf)
+    }
+
+    fn padding(&self, buf: &[u8]) -> usize {
+        self.codec().padding(buf)
+    }
+
+    fn at_record_start(&self, buf: &[u8]) -> bool {
+        self.codec().at_record_start(buf)
+    }
+}
+
+impl Codec {
+    fn codec(self) -> &'static dyn LogCodec {
+        match self {
+            Codec::Json => &JsonCodec,
+            Codec::Bincode => &BincodeCodec,
+        }
+    }
+}
+
+/// Records as JSON objects, written back to back.
+struct JsonCodec;
+
+impl LogCodec for JsonCodec {
+    fn encode(&self, op: &Op, buf: &mut Vec<u8>) -> Result<()> {
+        serde_json::to_writer(buf, op)?;
+        Ok(())
+    }
+
+    fn decode(&self, buf: &[u8]) -> std::result::Result<Option<(Op, usize)>, String> {
+        let mut stream = Deserializer::from_slice(buf).into_iter::<Op>();
+        match stream.next() {
+            Some(Ok(op)) => Ok(Some((op, stream.byte_offset()))),
+            Some(Err(e)) => Err(e.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    /// Whitespace, which logs edited by hand may have between records.
+    fn padding(&self, buf: &[u8]) -> usize {
+        buf.iter().take_while(|b| b.is_ascii_whitespace()).count()
+    }
+
+    /// Quotes inside keys and values are always escaped, so this can't be fooled by a
+    /// value that happens to contain a record.
+    fn at_record_start(&self, buf: &[u8]) -> bool {
+        buf.starts_with(b"{\"Set\"")
+            || buf.starts_with(b"{\"Rm\"")
+            || buf.starts_with(b"{\"SetRange\"")
+    }
+}
+
+/// Records as bincode, each prefixed by its length as a little-endian `u32`.
+///
+/// The write time of a `set` or `setrange` follows its [`BincodeOp`] in the record, and
+/// then the op's sequence number, so that records written before there were either still
+/// read as they are. An `rm` has no write time, so only its sequence number follows it.
+/// A numbered `set` or `setrange` always has a write time, as the store stamps every
+/// write it numbers with one.
+struct BincodeCodec;
+
+/// The length(in bytes) of the prefix of each bincode record.
+const LEN_PREFIX: usize = 4;
+
+/// Mirrors [`Op`], without the fields it leaves out of JSON when they're empty, which a
+/// format that isn't self-describing can't tell are missing.
+#[derive(Deserialize)]
+enum BincodeOp {
+    Set {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+        compressed: Option<Compressed>,
+        bucket: Option<String>,
+    },
+    Rm {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        bucket: Option<String>,
+        batched: bool,
+    },
+    SetRange {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        offset: u64,
+        #[serde(with = "bytes")]
+        patch: Vec<u8>,
+        len: u64,
+        bucket: Option<String>,
+    },
+}
+
+/// [`BincodeOp`] borrowed from an [`Op`], to encode it without copying.
+#[derive(Serialize)]
+enum BincodeOpRef<'a> {
+    Set {
+        #[serde(serialize_with = "bytes::serialize")]
+        key: &'a [u8],
+        #[serde(serialize_with = "bytes::serialize")]
+        value: &'a [u8],
+        compressed: Option<Compressed>,
+        bucket: Option<&'a str>,
+    },
+    Rm {
+        #[serde(serialize_with = "bytes::serialize")]
+        key: &'a [u8],
+        bucket: Option<&'a str>,
+        batched: bool,
+    },
+    SetRange {
+        #[serde(serialize_with = "bytes::serialize")]
+        key: &'a [u8],
+        offset: u64,
+        #[serde(serialize_with = "bytes::serialize")]
+        patch: &'a [u8],
+        len: u64,
+        bucket: Option<&'a str>,
+    },
+}
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().reject_trailing_bytes()
+}
+
+impl LogCodec for BincodeCodec {
+    fn encode(&self, op: &Op, buf: &mut Vec<u8>) -> Result<()> {
+        let seq = op.seq();
+        assert!(
+            seq.is_none()
+                || matches!(
+                    op,
+                    Op::Rm { .. }
+                        | Op::Set {
+                            written_at: Some(_),
+                            ..
+                        }
+                        | Op::SetRange {
+                            written_at: Some(_),
+                            ..
+                        }
+                ),
+            "numbered sets and patches have a write time"
+        );
+        let (op, written_at) = match op {
+            Op::Set {
+                key,
+                value,
+                compressed,
+                bucket,
+                written_at,
+                ..
+            } => (
+                BincodeOpRef::Set {
+                    key,
+                    value,
+                    compressed: *compressed,
+                    bucket: bucket.as_deref(),
+                },
+                *written_at,
+            ),
+            Op::Rm {
+                key,
+                bucket,
+                batched,
+                ..
+            } => (
+                BincodeOpRef::Rm {
+                    key,
+                    bucket: bucket.as_deref(),
+                    batched: *batched,
+                },
+                None,
+            ),
+            Op::SetRange {
+                key,
+                offset,
+                patch,
+                len,
+                bucket,
+                written_at,
+                ..
+            } => (
+                BincodeOpRef::SetRange {
+                    key,
+                    offset: *offset,
+                    patch,
+                    len: *len,
+                    bucket: bucket.as_deref(),
+                },
+                *written_at,
+            ),
+        };
+        let mut record = bincode_options()
+            .serialize(&op)
+            .expect("ops only hold types bincode can encode");
+        for trailer in [written_at, seq].into_iter().flatten() {
+            bincode_options()
+                .serialize_into(&mut record, &trailer)
+                .expect("timestamps and sequence numbers are integers");
+        }
+        buf.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&record);
+        Ok(())
+    }
+
+    fn decode(&self, buf: &[u8]) -> std::result::Result<Option<(Op, usize)>, String> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let len = match buf.get(..LEN_PREFIX) {
+            Some(prefix) => u32::from_le_bytes(prefix.try_into().unwrap()) as usize,
+            None => return Err("unexpected end of record".to_string()),
+        };
+        let record = buf
+            .get(LEN_PREFIX..LEN_PREFIX + len)
+            .ok_or("unexpected end of record")?;
+        let mut rest = record;
+        let op = bincode_options()
+            .with_limit(len as u64)
+            .allow_trailing_bytes()
+            .deserialize_from(&mut rest)
+            .map_err(|e| e.to_string())?;
+        let op = match op {
+            BincodeOp::Set {
+                key,
+                value,
+                compressed,
+                bucket,
+            } => {
+                let (written_at, seq) = trailers(rest)?;
+                Op::Set {
+                    key,
+                    value,
+                    compressed,
+                    bucket,
+                    written_at,
+                    seq,
+                }
+            }
+            BincodeOp::SetRange {
+                key,
+                offset,
+                patch,
+                len,
+                bucket,
+            } => {
+                let (written_at, seq) = trailers(rest)?;
+                Op::SetRange {
+                    key,
+                    offset,
+                    patch,
+                    len,
+                    bucket,
+                    written_at,
+                    seq,
+                }
+            }
+            BincodeOp::Rm {
+                key,
+                bucket,
+                batched,
+            } => match trailers(rest)? {
+                (seq, None) => Op::Rm {
+                    key,
+                    bucket,
+                    batched,
+                    seq,
+                },
+                _ => return Err("trailing bytes after record".to_string()),
+            },
+        };
+        Ok(Some((op, LEN_PREFIX + len)))
+    }
+
+    fn padding(&self, _buf: &[u8]) -> usize {
+        0
+    }
+
+    /// Nothing marks the start of a record, so this is only whether one can be read.
+    fn at_record_start(&self, buf: &[u8]) -> bool {
+        matches!(self.decode(buf), Ok(Some(_)))
+    }
+}
+
+/// Read the integers that follow a bincode record's [`BincodeOp`], of which there are up
+/// to two.
+fn trailers(mut rest: &[u8]) -> std::result::Result<(Option<u64>, Option<u64>), String> {
+    let mut read = || -> std::result::Result<Option<u64>, String> {
+        if rest.is_empty() {
+            return Ok(None);
+        }
+        bincode_options()
+            .allow_trailing_bytes()
+            .deserialize_from(&mut rest)
+            .map(Some)
+            .map_err(|e| e.to_string())
+    };
+    let trailers = (read()?, read()?);
+    if !rest.is_empty() {
+        return Err("trailing bytes after record".to_string());
+    }
+    Ok(trailers)
+}
@@ -0,0 +1,80 @@
+//! Optional compression of values stored in the log.
+
+use crate::err::KvsError;
+use serde::{Deserialize, Serialize};
+
+/// A compression algorithm for values in the log.
+///
+/// Each algorithm is only available when the crate is built with the feature of the
+/// same name; `lz4` is enabled by default.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Lz4,
+    Zstd,
+}
+
+/// Marks a value in the log as compressed.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Compressed {
+    pub algorithm: Compression,
+    /// The length(in bytes) of the value once decompressed.
+    pub len: u64,
+}
+
+impl Compression {
+    /// Whether support for this algorithm was compiled in.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    pub(crate) fn compress(self, data: &[u8]) -> crate::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::compress(data)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::encode_all(data, 0)?),
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = data;
+                Err(self.unsupported())
+            }
+        }
+    }
+
+    /// Decompress `data`, checking that it expands to exactly `len` bytes.
+    pub(crate) fn decompress(self, data: &[u8], len: usize) -> crate::Result<Vec<u8>> {
+        let value = self.decompress_unchecked(data, len)?;
+        if value.len() != len {
+            return Err(KvsError::Compression(format!(
+                "expected {} bytes after decompression, got {}",
+                len,
+                value.len()
+            )));
+        }
+        Ok(value)
+    }
+
+    fn decompress_unchecked(self, data: &[u8], len: usize) -> crate::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                lz4_flex::decompress(data, len).map_err(|e| KvsError::Compression(e.to_string()))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::decode_all(data)?),
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = (data, len);
+                Err(self.unsupported())
+            }
+        }
+    }
+
+    pub(crate) fn unsupported(self) -> KvsError {
+        KvsError::Compression(format!("{:?} is not supported", self))
+    }
+}
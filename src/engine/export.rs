@@ -0,0 +1,195 @@
+//! A copy of an engine's data that either engine can read back, written by `export` and
+//! applied by `import`.
+//!
+//! An export is a line of JSON per record: a header naming the format, a `set` record,
+//! as [`KvStore::inspect`](super::KvStore::inspect) reads them, for every key in every
+//! bucket, then a trailer counting the records and holding a CRC32 of their lines. Keys
+//! and values that aren't valid UTF-8 are written in base64.
+//!
+//! An import checks the whole export against its trailer before changing any data, so a
+//! truncated or corrupt export is rejected without applying part of it. The records are
+//! spooled to a temporary file while they're checked, rather than held in memory.
+
+use super::Record;
+use crate::err::KvsError;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+/// The name an export's header gives its format.
+const FORMAT: &str = "kvs-export";
+/// The version of the format exports are written in.
+const VERSION: u32 = 1;
+/// The size(in bytes) of the keys and values an import sets in a single batch.
+pub(super) const BATCH_BYTES: usize = 4 << 20;
+
+/// What an import does with the data already in the engine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Remove every key, in every bucket, before applying the export.
+    Replace,
+    /// Keep the keys the export doesn't have, overwriting those it does.
+    Merge,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Trailer {
+    records: u64,
+    checksum: u32,
+}
+
+/// A line after the header: a record, or the trailer ending the export.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Trailer(Trailer),
+    Record(Record),
+}
+
+/// Writes the records of an export, and the trailer once they're all written.
+pub(super) struct Exporter<W: Write> {
+    writer: BufWriter<W>,
+    line: Vec<u8>,
+    records: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Exporter<W> {
+    /// Start an export to `writer`, writing its header.
+    pub fn new(writer: W) -> crate::Result<Self> {
+        let mut writer = BufWriter::new(writer);
+        let header = Header {
+            format: FORMAT.to_owned(),
+            version: VERSION,
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writer.write_all(b"\n")?;
+        Ok(Exporter {
+            writer,
+            line: vec![],
+            records: 0,
+            hasher: crc32fast::Hasher::new(),
+        })
+    }
+
+    /// Write the record setting `key` in `bucket` to `value`.
+    pub fn write(&mut self, bucket: Option<&str>, key: &[u8], value: &[u8]) -> crate::Result<()> {
+        let record = Record::Set {
+            bucket: bucket.map(str::to_owned),
+            key: key.to_vec(),
+            value: value.to_vec(),
+            compression: None,
+        };
+        self.line.clear();
+        serde_json::to_writer(&mut self.line, &record)?;
+        self.line.push(b'\n');
+        self.hasher.update(&self.line);
+        self.writer.write_all(&self.line)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Write the trailer and flush the export, returning the number of records in it.
+    pub fn finish(mut self) -> crate::Result<u64> {
+        let trailer = Trailer {
+            records: self.records,
+            checksum: self.hasher.finalize(),
+        };
+        serde_json::to_writer(&mut self.writer, &trailer)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        Ok(self.records)
+    }
+}
+
+/// The records of an export that's been checked against its trailer, read back from the
+/// temporary file they were spooled to.
+pub(super) struct Records {
+    reader: BufReader<File>,
+    line: Vec<u8>,
+}
+
+impl Iterator for Records {
+    /// The bucket, key and value of each record.
+    type Item = crate::Result<(Option<String>, Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.line.clear();
+        match self.reader.read_until(b'\n', &mut self.line) {
+            Ok(0) => None,
+            Ok(_) => Some(match serde_json::from_slice(&self.line) {
+                Ok(Record::Set {
+                    bucket, key, value, ..
+                }) => Ok((bucket, key, value)),
+                Ok(_) => unreachable!("only sets are spooled"),
+                Err(e) => Err(e.into()),
+            }),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+/// Read the export from `reader`, checking it against its trailer.
+///
+/// Fails with [`KvsError::Corrupt`] if the export isn't one, is truncated, or doesn't
+/// match its checksum.
+pub(super) fn read(reader: impl Read) -> crate::Result<Records> {
+    let corrupt = |why: &str| KvsError::Corrupt(format!("invalid export: {}", why));
+    let mut reader = BufReader::new(reader);
+    let mut line = vec![];
+    reader.read_until(b'\n', &mut line)?;
+    match serde_json::from_slice::<Header>(&line) {
+        Ok(header) if header.format == FORMAT && header.version == VERSION => {}
+        Ok(header) if header.format == FORMAT => {
+            return Err(corrupt(&format!("unknown version {}", header.version)));
+        }
+        _ => return Err(corrupt("missing header")),
+    }
+
+    let mut spool = BufWriter::new(tempfile::tempfile()?);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut records = 0;
+    let trailer = loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Err(corrupt("truncated"));
+        }
+        match serde_json::from_slice::<Line>(&line) {
+            Ok(Line::Record(Record::Set { .. })) => {
+                hasher.update(&line);
+                spool.write_all(&line)?;
+                records += 1;
+            }
+            Ok(Line::Trailer(trailer)) => break trailer,
+            Ok(Line::Record(_)) => return Err(corrupt("only sets can be imported")),
+            Err(_) => return Err(corrupt(&format!("unreadable record {}", records + 1))),
+        }
+    };
+    line.clear();
+    if reader.read_until(b'\n', &mut line)? > 0 {
+        return Err(corrupt("data after the trailer"));
+    }
+    if trailer.records != records {
+        return Err(corrupt(&format!(
+            "{} records, but the trailer counts {}",
+            records, trailer.records
+        )));
+    }
+    if trailer.checksum != hasher.finalize() {
+        return Err(corrupt("checksum mismatch"));
+    }
+
+    let mut file = spool.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(Records {
+        reader: BufReader::new(file),
+        line: vec![],
+    })
+}
@@ -0,0 +1,86 @@
+//! A bloom filter over the keys in the log, for answering negative lookups cheaply.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// A summary of a bloom filter's state.
+#[derive(Clone, Debug)]
+pub struct BloomStats {
+    /// The number of bits in the filter.
+    pub bits: usize,
+    /// The number of hash functions used per key.
+    pub hashes: u32,
+    /// The number of keys added since the filter was built.
+    pub keys: usize,
+    /// The estimated probability that a lookup of a missing key isn't short-circuited.
+    pub estimated_fpr: f64,
+}
+
+/// A fixed-size bloom filter.
+///
+/// Keys can't be removed, so the filter only ever over-approximates the live keys; it is
+/// rebuilt from the index whenever the log is compacted.
+pub(super) struct Bloom {
+    words: Vec<u64>,
+    hashes: u32,
+    keys: usize,
+}
+
+impl Bloom {
+    /// Create a filter sized for `expected_keys` keys at the given false-positive rate.
+    pub fn new(expected_keys: usize, false_positive_rate: f64) -> Self {
+        let n = expected_keys.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-n * false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0) as usize;
+        let hashes = ((bits as f64 / n) * ln2).round().clamp(1.0, 32.0) as u32;
+
+        Bloom {
+            words: vec![0; bits.div_ceil(64)],
+            hashes,
+            keys: 0,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in self.bits_for(key) {
+            self.words[bit / 64] |= 1 << (bit % 64);
+        }
+        self.keys += 1;
+    }
+
+    /// Returns false only if `key` was definitely never inserted.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bits_for(key)
+            .all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn stats(&self) -> BloomStats {
+        let m = self.bits() as f64;
+        let k = self.hashes as f64;
+        let n = self.keys as f64;
+        BloomStats {
+            bits: self.bits(),
+            hashes: self.hashes,
+            keys: self.keys,
+            estimated_fpr: (1.0 - (-k * n / m).exp()).powf(k),
+        }
+    }
+
+    fn bits(&self) -> usize {
+        self.words.len() * 64
+    }
+
+    /// The bits `key` maps to, derived from two hashes by double hashing.
+    fn bits_for(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let h1 = hasher.finish();
+        0xb10f.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+
+        let bits = self.bits() as u64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+}
@@ -0,0 +1,69 @@
+//! Named keyspaces within a store.
+
+use super::KvStore;
+
+/// A named keyspace within a [`KvStore`], opened with [`KvStore::bucket`].
+///
+/// Writes to a bucket go to the store's log like any other, and are compacted along
+/// with it, but its keys are kept apart from those of every other bucket.
+#[derive(Clone)]
+pub struct Bucket {
+    store: KvStore,
+    name: String,
+}
+
+impl Bucket {
+    pub(super) fn new(store: KvStore, name: &str) -> Self {
+        Bucket {
+            store,
+            name: name.to_owned(),
+        }
+    }
+
+    /// The name of the bucket.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set a key-value pair in the bucket.
+    pub fn set(&self, key: String, value: String) -> crate::Result<()> {
+        self.store
+            .set_in(Some(&self.name), key.as_bytes(), value.as_bytes())?;
+        Ok(())
+    }
+
+    /// Get a value by its key in the bucket.
+    pub fn get(&self, key: String) -> crate::Result<Option<String>> {
+        match self.store.get_in(Some(&self.name), key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove a key-value pair from the bucket, failing with
+    /// [`KvsError::KeyNotFound`](crate::KvsError::KeyNotFound) if it doesn't exist.
+    pub fn remove(&self, key: String) -> crate::Result<()> {
+        self.store.remove_in(Some(&self.name), key.as_bytes())?;
+        Ok(())
+    }
+
+    /// Get every key-value pair in the bucket whose key starts with `prefix`, in key order.
+    pub fn scan(&self, prefix: &str) -> crate::Result<Vec<(String, String)>> {
+        self.store.scan_in(Some(&self.name), prefix)
+    }
+
+    /// Remove every key in the bucket, returning how many there were.
+    pub fn clear(&self) -> crate::Result<usize> {
+        self.store.clear_in(Some(&self.name))
+    }
+
+    /// The number of keys in the bucket.
+    pub fn len(&self) -> crate::Result<usize> {
+        self.store.len_in(Some(&self.name))
+    }
+
+    /// Whether the bucket has no keys.
+    pub fn is_empty(&self) -> crate::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
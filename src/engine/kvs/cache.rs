@@ -0,0 +1,100 @@
+//! An LRU cache of recently read values.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// A summary of the value cache's state.
+#[derive(Clone, Debug)]
+pub struct CacheStats {
+    /// The maximum total size(in bytes) of cached values.
+    pub capacity: usize,
+    /// The total size(in bytes) of cached values.
+    pub size: usize,
+    /// The number of cached values.
+    pub entries: usize,
+    /// The number of reads served from the cache.
+    pub hits: u64,
+    /// The number of reads that had to go to the log.
+    pub misses: u64,
+}
+
+/// A cache of values bounded by their total size, evicting the least recently used first.
+pub(super) struct ValueCache {
+    capacity: usize,
+    size: usize,
+    /// Maps a key to its value and the tick it was last used at.
+    entries: HashMap<Vec<u8>, (Vec<u8>, u64)>,
+    /// Maps the tick each key was last used at back to the key, oldest first.
+    recency: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            size: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Get the cached value of `key`, marking it as recently used.
+    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some((value, last_used)) => {
+                let key = self.recency.remove(last_used).expect("cache out of sync");
+                self.recency.insert(self.tick, key);
+                *last_used = self.tick;
+                self.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache the value of `key`, evicting older values to make room.
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        if value.len() > self.capacity {
+            return;
+        }
+
+        self.invalidate(&key);
+        self.size += value.len();
+        while self.size > self.capacity {
+            let (_, oldest) = self.recency.pop_first().expect("cache out of sync");
+            let (evicted, _) = self.entries.remove(&oldest).expect("cache out of sync");
+            self.size -= evicted.len();
+        }
+
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    /// Drop any cached value of `key`.
+    pub fn invalidate(&mut self, key: &[u8]) {
+        if let Some((value, last_used)) = self.entries.remove(key) {
+            self.recency.remove(&last_used);
+            self.size -= value.len();
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            capacity: self.capacity,
+            size: self.size,
+            entries: self.entries.len(),
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
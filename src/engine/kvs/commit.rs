@@ -0,0 +1,143 @@
+//! Syncing writes to disk before they're acknowledged, with every writer waiting at the
+//! same time sharing a single sync.
+
+use super::KvStoreInner;
+use crate::err::KvsError;
+use crossbeam::channel::{self, Receiver, Sender};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// When writes to a store are synced to disk.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Durability {
+    /// Writes are handed to the OS as they're made, and only synced to disk by
+    /// [`sync`](crate::KvsEngine::sync), when a log file is sealed, and by compaction.
+    /// Nothing is lost if the process crashes, but recent writes may be if the machine
+    /// does.
+    #[default]
+    Buffered,
+    /// Each write is synced to disk before it returns.
+    ///
+    /// Writers that are waiting at the same time share a single sync: the first of them
+    /// syncs on behalf of all the others, so concurrent writes cost far fewer syncs than
+    /// there are writes. If a sync fails, every write waiting on it fails, and so does
+    /// every write after, as whatever the OS failed to write is lost.
+    EveryWrite,
+    /// Writes are synced to disk by a background thread every so often, flushing the
+    /// memtable first if there is one.
+    ///
+    /// Writes return as soon as they're handed to the OS, and a crash of the machine
+    /// loses at most the writes made in the last interval. Dropping the last handle to
+    /// the store stops the thread, syncing one last time.
+    Periodic(Duration),
+}
+
+/// Writers waiting for the records they appended to be synced.
+#[derive(Default)]
+pub(super) struct GroupCommit {
+    state: Mutex<CommitState>,
+    /// Notified whenever a sync finishes.
+    finished: Condvar,
+}
+
+#[derive(Default)]
+struct CommitState {
+    /// The number of appends known to be synced.
+    synced: u64,
+    /// Whether a writer is syncing.
+    syncing: bool,
+    /// How a sync failed, if one has.
+    failed: Option<(io::ErrorKind, String)>,
+}
+
+impl GroupCommit {
+    /// Wait until the first `appended` appends to the store are synced.
+    ///
+    /// If no other writer is syncing, this one does, after waiting `delay` for other
+    /// writers to join in. `sync` syncs every append made so far, returning how many
+    /// there have been.
+    pub fn wait(
+        &self,
+        appended: u64,
+        delay: Duration,
+        sync: impl FnOnce() -> crate::Result<u64>,
+    ) -> crate::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some((kind, error)) = &state.failed {
+                return Err(io::Error::new(*kind, error.clone()).into());
+            }
+            if state.synced >= appended {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = self.finished.wait(state).unwrap();
+        }
+        state.syncing = true;
+        drop(state);
+
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        let result = sync();
+        let mut state = self.state.lock().unwrap();
+        state.syncing = false;
+        match &result {
+            Ok(synced) => state.synced = state.synced.max(*synced),
+            Err(KvsError::Io(e)) => state.failed = Some((e.kind(), e.to_string())),
+            Err(e) => state.failed = Some((io::ErrorKind::Other, e.to_string())),
+        }
+        drop(state);
+        self.finished.notify_all();
+        result.map(|_| ())
+    }
+}
+
+/// The thread syncing a store every so often, with [`Durability::Periodic`], stopped and
+/// joined when dropped.
+pub(super) struct PeriodicSync {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PeriodicSync {
+    /// Start syncing `store` every `interval`.
+    pub fn start(store: &Arc<Mutex<KvStoreInner>>, interval: Duration) -> Self {
+        let (stop, stopped) = channel::bounded(1);
+        let store = Arc::clone(store);
+        let thread = thread::spawn(move || run(store, interval, stopped));
+        PeriodicSync {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for PeriodicSync {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(store: Arc<Mutex<KvStoreInner>>, interval: Duration, stopped: Receiver<()>) {
+    loop {
+        let stop = crossbeam::select! {
+            recv(stopped) -> _ => true,
+            default(interval) => false,
+        };
+        let flushed = store.lock().unwrap().flush_memtable();
+        if let Err(e) = flushed.and_then(|_| super::sync_appended(&store)) {
+            log::error!("failed to sync the log: {}", e);
+        }
+        if stop {
+            return;
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Observing and pausing compactions.
+
+use super::CompactionReport;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Whether a compaction is running.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CompactionPhase {
+    Idle,
+    /// Copying live entries, `progress` of the way through them, from 0 to 1.
+    Running {
+        progress: f64,
+    },
+}
+
+/// A point-in-time summary of a store's compactions.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CompactionStatus {
+    pub phase: CompactionPhase,
+    /// Whether compactions are paused.
+    pub paused: bool,
+    /// The most recent compaction to finish, if any.
+    pub last_run: Option<CompactionReport>,
+    /// The number of compactions finished since the store was opened.
+    pub runs: u64,
+    /// The total time spent in those compactions.
+    pub total_duration: Duration,
+    /// The total size(in bytes) they freed.
+    pub reclaimed_bytes: u64,
+}
+
+/// A handle to pause, resume, and observe a store's compactions, obtained from
+/// [`KvStore::compaction_controller`](super::KvStore::compaction_controller).
+#[derive(Clone)]
+pub struct CompactionController {
+    tracker: Arc<Tracker>,
+}
+
+impl CompactionController {
+    pub(super) fn new(tracker: Arc<Tracker>) -> Self {
+        CompactionController { tracker }
+    }
+
+    /// Pause compactions until [`resume`](CompactionController::resume) is called.
+    ///
+    /// A compaction that's running holds between one live entry and the next, with
+    /// nothing swapped in yet, and no new compaction starts automatically. Reads and
+    /// writes carry on as usual, but redundant space builds up meanwhile. An explicit
+    /// [`compact`](super::KvStore::compact) starts, but holds before copying anything.
+    pub fn pause(&self) {
+        self.tracker.state.lock().unwrap().paused = true;
+    }
+
+    /// Let compactions carry on.
+    pub fn resume(&self) {
+        self.tracker.state.lock().unwrap().paused = false;
+        self.tracker.resumed.notify_all();
+    }
+
+    /// Get a summary of the store's compactions.
+    pub fn status(&self) -> CompactionStatus {
+        self.tracker.status()
+    }
+}
+
+/// The state of a store's compactions, updated as they run.
+pub(super) struct Tracker {
+    state: Mutex<CompactionStatus>,
+    resumed: Condvar,
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Tracker {
+            state: Mutex::new(CompactionStatus {
+                phase: CompactionPhase::Idle,
+                paused: false,
+                last_run: None,
+                runs: 0,
+                total_duration: Duration::ZERO,
+                reclaimed_bytes: 0,
+            }),
+            resumed: Condvar::new(),
+        }
+    }
+}
+
+impl Tracker {
+    pub fn status(&self) -> CompactionStatus {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(
+            self.state.lock().unwrap().phase,
+            CompactionPhase::Running { .. }
+        )
+    }
+
+    /// Note that a compaction started, with nothing copied yet.
+    pub fn started(&self) {
+        self.state.lock().unwrap().phase = CompactionPhase::Running { progress: 0.0 };
+    }
+
+    /// Note that `copied` of `total` live entries have been copied, first waiting for
+    /// compactions to be resumed if they're paused.
+    pub fn copied(&self, copied: usize, total: usize) {
+        let progress = match total {
+            0 => 1.0,
+            total => copied as f64 / total as f64,
+        };
+        let mut state = self.state.lock().unwrap();
+        state.phase = CompactionPhase::Running { progress };
+        let _state = self
+            .resumed
+            .wait_while(state, |state| state.paused)
+            .unwrap();
+    }
+
+    /// Note that a compaction finished, successfully if there's a `report` of it.
+    pub fn finished(&self, report: Option<&CompactionReport>) {
+        let mut state = self.state.lock().unwrap();
+        state.phase = CompactionPhase::Idle;
+        if let Some(report) = report {
+            state.runs += 1;
+            state.total_duration += report.duration;
+            state.reclaimed_bytes += report.bytes_before.saturating_sub(report.bytes_after);
+            state.last_run = Some(report.clone());
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Notifying subscribers of writes to the store.
+
+use super::keys;
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+
+/// The number of events a subscriber can fall behind by before events are dropped.
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// A write to the store, as seen by a subscriber.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// `key` was set in `bucket`, or in the default bucket if `None`.
+    Set {
+        bucket: Option<String>,
+        key: Vec<u8>,
+    },
+    /// `key` was removed from `bucket`, or from the default bucket if `None`.
+    Removed {
+        bucket: Option<String>,
+        key: Vec<u8>,
+    },
+    /// `key` was evicted from `bucket`, or from the default bucket if `None`, to keep the
+    /// log under [`max_disk_bytes`](super::KvStoreOptions::max_disk_bytes).
+    Evicted {
+        bucket: Option<String>,
+        key: Vec<u8>,
+    },
+    /// The subscriber fell behind and `missed` events were dropped before the next one.
+    Lagged { missed: u64 },
+}
+
+impl ChangeEvent {
+    /// The key indexed as `encoded` was set.
+    pub(super) fn set(encoded: &[u8]) -> Self {
+        let (bucket, key) = keys::decode(encoded);
+        ChangeEvent::Set {
+            bucket: bucket.map(str::to_owned),
+            key: key.to_vec(),
+        }
+    }
+
+    /// The key indexed as `encoded` was removed.
+    pub(super) fn removed(encoded: &[u8]) -> Self {
+        let (bucket, key) = keys::decode(encoded);
+        ChangeEvent::Removed {
+            bucket: bucket.map(str::to_owned),
+            key: key.to_vec(),
+        }
+    }
+
+    /// The key indexed as `encoded` was evicted.
+    pub(super) fn evicted(encoded: &[u8]) -> Self {
+        let (bucket, key) = keys::decode(encoded);
+        ChangeEvent::Evicted {
+            bucket: bucket.map(str::to_owned),
+            key: key.to_vec(),
+        }
+    }
+}
+
+struct Subscriber {
+    sender: Sender<ChangeEvent>,
+    /// The number of events dropped since the last one delivered.
+    missed: u64,
+}
+
+/// The subscribers of a store.
+#[derive(Default)]
+pub(super) struct Subscribers {
+    subscribers: Vec<Subscriber>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel::bounded(SUBSCRIBER_CAPACITY);
+        self.subscribers.push(Subscriber { sender, missed: 0 });
+        receiver
+    }
+
+    /// Send `event` to every subscriber without blocking, dropping subscribers that have
+    /// gone away.
+    pub fn publish(&mut self, event: ChangeEvent) {
+        self.subscribers.retain_mut(|subscriber| {
+            if subscriber.missed > 0 {
+                let lagged = ChangeEvent::Lagged {
+                    missed: subscriber.missed,
+                };
+                match subscriber.sender.try_send(lagged) {
+                    Ok(()) => subscriber.missed = 0,
+                    Err(TrySendError::Full(_)) => {
+                        subscriber.missed += 1;
+                        return true;
+                    }
+                    Err(TrySendError::Disconnected(_)) => return false,
+                }
+            }
+            match subscriber.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.missed += 1;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
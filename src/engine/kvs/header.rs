@@ -0,0 +1,142 @@
+//! The header every log file starts with, recording the format it's written in.
+//!
+//! The header is a magic number, which also tells which [`Codec`] the file's records are
+//! written in, followed by the format version as a big-endian `u16`. Logs written before
+//! the header existed are version 1, and start straight away with a JSON record.
+
+use super::manifest::{self, Manifest, Segment, FORMAT_VERSION};
+use crate::engine::Codec;
+use crate::err::KvsError;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::Path;
+
+/// Identifies a log file of JSON records.
+const JSON_MAGIC: &[u8; 6] = b"KVSLOG";
+/// Identifies a log file of bincode records.
+const BINCODE_MAGIC: &[u8; 6] = b"KVSBIN";
+/// The length(in bytes) of the header, and so the offset of the first record.
+pub(super) const HEADER_LEN: usize = JSON_MAGIC.len() + 2;
+
+fn magic(codec: Codec) -> &'static [u8; 6] {
+    match codec {
+        Codec::Json => JSON_MAGIC,
+        Codec::Bincode => BINCODE_MAGIC,
+    }
+}
+
+/// The header of a log file of `codec` records in the current format.
+fn header(codec: Codec) -> [u8; HEADER_LEN] {
+    let magic = magic(codec);
+    let mut header = [0; HEADER_LEN];
+    header[..magic.len()].copy_from_slice(magic);
+    header[magic.len()..].copy_from_slice(&(FORMAT_VERSION as u16).to_be_bytes());
+    header
+}
+
+/// The format version a log file starting with `contents` is written in, or `None` if
+/// it's empty or holds only part of a header, as left by a crash as it was created.
+pub(super) fn version(contents: &[u8]) -> Option<u32> {
+    let partial = |magic| contents.len() < HEADER_LEN && header(magic).starts_with(contents);
+    if contents.is_empty() || partial(Codec::Json) || partial(Codec::Bincode) {
+        return None;
+    }
+    let rest = contents
+        .strip_prefix(JSON_MAGIC)
+        .or_else(|| contents.strip_prefix(BINCODE_MAGIC));
+    match rest {
+        Some(&[hi, lo, ..]) => Some(u16::from_be_bytes([hi, lo]) as u32),
+        _ => Some(1),
+    }
+}
+
+/// The codec the records of a log file starting with `contents` are written in.
+pub(super) fn codec(contents: &[u8]) -> Codec {
+    if contents.starts_with(BINCODE_MAGIC) {
+        Codec::Bincode
+    } else {
+        Codec::Json
+    }
+}
+
+/// Write the header of a new log file of `codec` records.
+pub(super) fn write(writer: &mut impl Write, codec: Codec) -> crate::Result<()> {
+    writer.write_all(&header(codec))?;
+    Ok(())
+}
+
+/// Read past the header of a log file in the current format, returning the codec its
+/// records are written in.
+pub(super) fn skip(reader: &mut impl Read) -> crate::Result<Codec> {
+    let mut found = [0; HEADER_LEN];
+    reader.read_exact(&mut found)?;
+    let codec = codec(&found);
+    if found != header(codec) {
+        return Err(KvsError::Corrupt(format!(
+            "log file has format version {:?}, expected {}",
+            version(&found),
+            FORMAT_VERSION
+        )));
+    }
+    Ok(codec)
+}
+
+/// Bring every log file of the store at `log_path` up to the current format.
+///
+/// Files in an older format are rewritten in place, by writing a temporary file and
+/// renaming it over the original, and missing or empty ones are given a header for
+/// `codec` records. The manifest is then updated, resealing every sealed file since its
+/// contents may have changed; their old checksums aren't verified, so migrate a store
+/// you suspect is corrupt only after checking it. Fails if any file is in a newer format.
+pub(super) fn migrate(log_path: &Path, manifest: &mut Manifest, codec: Codec) -> crate::Result<()> {
+    let mut migrated = manifest.version < FORMAT_VERSION;
+    let numbers = manifest.sealed.iter().map(|s| s.number);
+    for number in numbers.chain([manifest.active]) {
+        let path = manifest::log_file(log_path, number);
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let old = match version(&contents) {
+            Some(version) if version == FORMAT_VERSION => continue,
+            Some(version) if version > FORMAT_VERSION => {
+                return Err(KvsError::Corrupt(format!(
+                    "log file {} has unsupported format version {}",
+                    path.display(),
+                    version
+                )))
+            }
+            old => old,
+        };
+
+        // Version 1 is the only older format, and only lacks the header. Its records are
+        // always JSON.
+        log::info!("migrating {} from format {:?}", path.display(), old);
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".migrating");
+        let tmp_path = path.with_file_name(tmp_name);
+        let mut tmp = File::create(&tmp_path)?;
+        match old {
+            Some(_) => {
+                write(&mut tmp, Codec::Json)?;
+                tmp.write_all(&contents)?;
+            }
+            // Whatever part of a header there was is replaced by a whole one.
+            None => write(&mut tmp, codec)?,
+        }
+        tmp.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        migrated = true;
+    }
+
+    if migrated {
+        for segment in &mut manifest.sealed {
+            let len = fs::metadata(manifest::log_file(log_path, segment.number))?.len();
+            *segment = Segment::seal(log_path, segment.number, len)?;
+        }
+        manifest.version = FORMAT_VERSION;
+        manifest.store(log_path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+//! Earlier values of keys, read back with [`history`](super::KvStore::history).
+//!
+//! Overwriting a key leaves the record of its old value in the log until the next
+//! compaction, and the store keeps where each key's old records are, oldest first, as
+//! its history. A key's versions are its history, then its chain if it was patched, then
+//! the op it's indexed at. A version written by a `setrange` is read by applying it, and
+//! the patches before it, on top of the last `set` before them.
+//!
+//! Removing a key drops its history. Compaction drops the old records along with every
+//! key's history, but for versions written while it ran, which carry on from the copy of
+//! the key's value it made.
+
+use super::decode_value;
+use super::index::Offset;
+use super::patch;
+use crate::engine::Op;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where the records of the values each key was overwritten with are, oldest first.
+pub(super) type History = HashMap<Box<[u8]>, Vec<Offset>>;
+
+/// A value a key held, from [`history`](super::KvStore::history).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct VersionedValue {
+    pub value: String,
+    /// The number of the log file the value's record is in.
+    pub segment: u64,
+    /// The offset(in bytes) of the value's record in its log file.
+    pub offset: u64,
+    /// When the value was written, to the millisecond, or `None` if it was written
+    /// before the store recorded it.
+    pub written_at: Option<SystemTime>,
+}
+
+/// Read the newest `limit` of the versions whose records are at `offsets`, oldest first,
+/// reading each record with `read`. Returns them newest first.
+pub(super) fn read_versions(
+    offsets: &[Offset],
+    limit: usize,
+    read: impl Fn(&Offset) -> crate::Result<Op>,
+) -> crate::Result<Vec<VersionedValue>> {
+    if limit == 0 {
+        return Ok(vec![]);
+    }
+    // Read back from the newest version to a `set` no newer than the oldest one wanted.
+    let wanted = offsets.len().saturating_sub(limit);
+    let mut first = offsets.len();
+    let mut ops = VecDeque::new();
+    while first > 0 {
+        first -= 1;
+        let op = read(&offsets[first])?;
+        let whole = matches!(op, Op::Set { .. });
+        ops.push_front(op);
+        if whole && first <= wanted {
+            break;
+        }
+    }
+
+    let mut value = vec![];
+    let mut versions = vec![];
+    for (i, op) in (first..).zip(ops) {
+        let written_at = match op {
+            Op::Set {
+                value: whole,
+                compressed,
+                written_at,
+                ..
+            } => {
+                value = decode_value(whole, compressed)?;
+                written_at
+            }
+            Op::SetRange {
+                offset,
+                patch,
+                written_at,
+                ..
+            } => {
+                patch::apply(&mut value, offset as usize, &patch);
+                written_at
+            }
+            Op::Rm { .. } => unreachable!(),
+        };
+        if i >= wanted {
+            versions.push(VersionedValue {
+                value: String::from_utf8(value.clone())?,
+                segment: offsets[i].segment(),
+                offset: offsets[i].start() as u64,
+                written_at: written_at.map(|ms| UNIX_EPOCH + Duration::from_millis(ms)),
+            });
+        }
+    }
+    versions.reverse();
+    Ok(versions)
+}
@@ -0,0 +1,187 @@
+//! Walking the records of a log file, for debugging.
+
+use super::{decode_value, header};
+use crate::engine::codec::LogCodec;
+use crate::engine::{bytes, Codec, Compression, Op};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A record read back from a log file.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "op")]
+pub enum Record {
+    Set {
+        /// The bucket the key belongs to, or `None` for the default bucket.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bucket: Option<String>,
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        /// The value, decompressed if it was stored compressed.
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+        /// The algorithm the value was stored compressed with, if any.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        compression: Option<Compression>,
+    },
+    Rm {
+        /// The bucket the key belongs to, or `None` for the default bucket.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bucket: Option<String>,
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+    },
+    SetRange {
+        /// The bucket the key belongs to, or `None` for the default bucket.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bucket: Option<String>,
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        /// Where in the value the patch starts.
+        offset: u64,
+        /// The bytes written over the value from `offset` on.
+        #[serde(with = "bytes")]
+        patch: Vec<u8>,
+        /// The length of the whole value once patched.
+        len: u64,
+    },
+}
+
+/// What's found at a position in a log file.
+#[derive(Clone, Debug, Serialize)]
+pub struct RecordInfo {
+    /// The offset(in bytes) of the record in the file.
+    pub offset: u64,
+    /// The length(in bytes) of the record, or of the corrupt span if it couldn't be read.
+    pub len: u64,
+    /// The record, or `None` if it couldn't be read.
+    pub op: Option<Record>,
+    /// Why the record couldn't be read, if it couldn't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Whether the record matches its checksum, or `None` if it doesn't carry one.
+    pub valid_checksum: Option<bool>,
+}
+
+/// An iterator over every record in a log file, including corrupt ones.
+///
+/// When a record can't be read, the inspector reports the span up to the next thing that
+/// looks like the start of a record, and carries on from there.
+pub struct LogInspector {
+    contents: Vec<u8>,
+    /// The codec the file's records are written in.
+    codec: Codec,
+    /// Where the first record starts, past the header if there is one.
+    first: usize,
+    pos: usize,
+}
+
+impl LogInspector {
+    /// Inspect the log file at `path`.
+    pub(super) fn open(path: &Path) -> crate::Result<Self> {
+        Ok(Self::from_contents(std::fs::read(path)?))
+    }
+
+    /// Inspect a log file already read into memory.
+    pub(super) fn from_contents(contents: Vec<u8>) -> Self {
+        let first = match header::version(&contents) {
+            Some(1) | None => 0,
+            Some(_) => header::HEADER_LEN.min(contents.len()),
+        };
+        LogInspector {
+            codec: header::codec(&contents),
+            contents,
+            first,
+            pos: first,
+        }
+    }
+
+    /// Skip to the first record starting at or after `offset`.
+    pub fn from_offset(mut self, offset: u64) -> Self {
+        let offset = (offset as usize).clamp(self.first, self.contents.len());
+        self.pos = if offset == self.first || self.at_record_start(offset) {
+            offset
+        } else {
+            self.next_record_start(offset)
+        };
+        self
+    }
+
+    fn at_record_start(&self, pos: usize) -> bool {
+        self.codec.at_record_start(&self.contents[pos..])
+    }
+
+    fn next_record_start(&self, pos: usize) -> usize {
+        next_record_start(self.codec, &self.contents, pos)
+    }
+}
+
+/// Find the first position after `pos` in `contents`, written in `codec`, that looks
+/// like the start of a record, or the end of `contents` if there's none.
+pub(super) fn next_record_start(codec: Codec, contents: &[u8], pos: usize) -> usize {
+    (pos + 1..contents.len())
+        .find(|&p| codec.at_record_start(&contents[p..]))
+        .unwrap_or(contents.len())
+}
+
+impl Iterator for LogInspector {
+    type Item = RecordInfo;
+
+    fn next(&mut self) -> Option<RecordInfo> {
+        let start = self.pos + self.codec.padding(&self.contents[self.pos..]);
+        if start >= self.contents.len() {
+            return None;
+        }
+
+        let read = match self.codec.decode(&self.contents[start..]) {
+            Ok(Some((op, len))) => decode(op).map(|op| (op, len)),
+            Ok(None) => Err("unexpected end of log".to_string()),
+            Err(e) => Err(e),
+        };
+        let (op, error, len) = match read {
+            Ok((op, len)) => (Some(op), None, len),
+            Err(e) => (None, Some(e), self.next_record_start(start) - start),
+        };
+
+        self.pos = start + len;
+        Some(RecordInfo {
+            offset: start as u64,
+            len: len as u64,
+            op,
+            error,
+            valid_checksum: None,
+        })
+    }
+}
+
+/// The record of `op`, as read back from the log.
+pub(super) fn decode(op: Op) -> Result<Record, String> {
+    match op {
+        Op::Set {
+            key,
+            value,
+            compressed,
+            bucket,
+            ..
+        } => Ok(Record::Set {
+            bucket,
+            key,
+            compression: compressed.map(|c| c.algorithm),
+            value: decode_value(value, compressed).map_err(|e| e.to_string())?,
+        }),
+        Op::Rm { key, bucket, .. } => Ok(Record::Rm { bucket, key }),
+        Op::SetRange {
+            key,
+            offset,
+            patch,
+            len,
+            bucket,
+            ..
+        } => Ok(Record::SetRange {
+            bucket,
+            key,
+            offset,
+            patch,
+            len,
+        }),
+    }
+}
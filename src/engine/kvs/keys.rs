@@ -0,0 +1,93 @@
+//! The keys of the index, which keep buckets apart.
+//!
+//! Keys in the default bucket are indexed as a `0` byte followed by the key. Keys in a
+//! named bucket are indexed as a `1` byte, the length of the bucket's name as a
+//! big-endian `u32`, the name, and then the key. No choice of names or keys can make two
+//! of these collide, and each bucket's keys sort together.
+
+use crate::engine::Op;
+use std::ops::Bound;
+
+/// The prefix every key in `bucket` is indexed under.
+pub(super) fn bucket_prefix(bucket: Option<&str>) -> Vec<u8> {
+    match bucket {
+        None => vec![0],
+        Some(name) => {
+            let mut prefix = Vec::with_capacity(5 + name.len());
+            prefix.push(1);
+            prefix.extend_from_slice(&(name.len() as u32).to_be_bytes());
+            prefix.extend_from_slice(name.as_bytes());
+            prefix
+        }
+    }
+}
+
+/// The longest key whose index key [`with_encoded`] builds on the stack.
+const INLINE_KEY_LEN: usize = 63;
+
+/// Call `f` with the index key of `key` in `bucket`, built on the stack if the key is in
+/// the default bucket and short enough, so that looking it up doesn't allocate.
+pub(super) fn with_encoded<T>(bucket: Option<&str>, key: &[u8], f: impl FnOnce(&[u8]) -> T) -> T {
+    if bucket.is_none() && key.len() <= INLINE_KEY_LEN {
+        let mut encoded = [0; INLINE_KEY_LEN + 1];
+        encoded[1..=key.len()].copy_from_slice(key);
+        return f(&encoded[..=key.len()]);
+    }
+    f(&encode(bucket, key))
+}
+
+/// The index key of `key` in `bucket`.
+pub(super) fn encode(bucket: Option<&str>, key: &[u8]) -> Vec<u8> {
+    let mut encoded = bucket_prefix(bucket);
+    encoded.extend_from_slice(key);
+    encoded
+}
+
+/// The bounds on the index keys of the default bucket's keys between `start` and `end`.
+pub(super) fn encode_range(
+    start: Bound<&[u8]>,
+    end: Bound<&[u8]>,
+) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let encode = |key: &[u8]| encode(None, key);
+    let start = match start {
+        Bound::Unbounded => Bound::Included(bucket_prefix(None)),
+        bound => bound.map(encode),
+    };
+    // Named buckets' keys sort after the default bucket's, from a `1` byte on.
+    let end = match end {
+        Bound::Unbounded => Bound::Excluded(vec![1]),
+        bound => bound.map(encode),
+    };
+    (start, end)
+}
+
+/// Split an index key back into its bucket and key.
+pub(super) fn decode(encoded: &[u8]) -> (Option<&str>, &[u8]) {
+    match encoded.split_first() {
+        Some((1, rest)) => {
+            let (len, rest) = rest.split_at(4);
+            let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+            let (name, key) = rest.split_at(len);
+            let name = std::str::from_utf8(name).expect("bucket names are UTF-8");
+            (Some(name), key)
+        }
+        Some((_, key)) => (None, key),
+        None => (None, encoded),
+    }
+}
+
+/// The index key of the key `op` writes.
+pub(super) fn of_op(op: &Op) -> Vec<u8> {
+    encode(op.bucket(), op.key())
+}
+
+/// The `rm` op for the key indexed as `encoded`.
+pub(super) fn rm_op(encoded: &[u8]) -> Op {
+    let (bucket, key) = decode(encoded);
+    Op::Rm {
+        key: key.to_vec(),
+        bucket: bucket.map(str::to_owned),
+        batched: false,
+        seq: None,
+    }
+}
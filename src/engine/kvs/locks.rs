@@ -0,0 +1,55 @@
+//! Striped locks, taken around every write so that a read-modify-write of a key sees no
+//! other write to it land in between, without serializing writes to unrelated keys.
+//!
+//! Each key hashes to one of a fixed number of stripes. Operations on several keys take
+//! their stripes in ascending order, so they can't deadlock one another, and every
+//! stripe is taken before the store's own lock, never after.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+
+/// The number of stripes keys are spread over.
+const STRIPES: usize = 64;
+
+pub(super) struct KeyLocks {
+    stripes: Box<[Mutex<()>]>,
+}
+
+impl KeyLocks {
+    pub fn new() -> Self {
+        KeyLocks {
+            stripes: (0..STRIPES).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    fn stripe(&self, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % self.stripes.len()
+    }
+
+    /// Lock the stripe of `key`.
+    pub fn lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.stripes[self.stripe(key)].lock().unwrap()
+    }
+
+    /// Lock the stripes of every key in `keys`, in ascending order.
+    pub fn lock_many<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = keys.into_iter().map(|key| self.stripe(key)).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|i| self.stripes[i].lock().unwrap())
+            .collect()
+    }
+
+    /// Lock every stripe, for writes to keys that aren't known up front.
+    pub fn lock_all(&self) -> Vec<MutexGuard<'_, ()>> {
+        self.stripes.iter().map(|s| s.lock().unwrap()).collect()
+    }
+}
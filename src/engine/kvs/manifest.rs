@@ -0,0 +1,343 @@
+//! The manifest, recording which files make up a store and how far it has been compacted.
+//!
+//! The manifest lives next to the log as `<log name>.MANIFEST` and is rewritten
+//! atomically, by writing a temporary file and renaming it into place, whenever it
+//! changes. Log files are numbered: file `0` is the log itself, and file `n` is the log
+//! with `.seg-n` appended to its name. Since stores with different log names can share a
+//! directory, a log name can't itself end in `.seg-n`, so that every numbered file
+//! belongs to exactly one store. New log files, whether started because the active one
+//! filled up or written by a compaction, take the next numbers up from the active log
+//! and only become part of the store once the manifest lists them, so a crash at any
+//! point leaves the manifest describing a complete log.
+//!
+//! An open store also holds an exclusive lock on `<log name>.LOCK`, so that it's never
+//! opened twice at once.
+
+use crate::err::KvsError;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, TryLockError};
+use std::io::{prelude::*, BufReader};
+use std::path::{Path, PathBuf};
+
+/// The version of the on-disk format written by this build.
+///
+/// Version 2 added a header to the start of every log file.
+pub const FORMAT_VERSION: u32 = 2;
+/// What the names of numbered log files add to the log's name, ahead of the number.
+const SEGMENT_SUFFIX: &str = ".seg-";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(super) struct Manifest {
+    /// The version of the on-disk format.
+    pub version: u32,
+    /// The number of the log file new records are appended to.
+    pub active: u64,
+    /// The log files that are no longer appended to, oldest first.
+    #[serde(default)]
+    pub sealed: Vec<Segment>,
+    /// The number of compactions the store has gone through.
+    pub compactions: u64,
+    /// The length(in bytes) of the active log right after the last compaction.
+    pub compacted_len: u64,
+    /// The sequence number of the last write the log no longer holds the op of, having
+    /// been compacted away, or `0` if there's none.
+    #[serde(default)]
+    pub compacted_seq: u64,
+}
+
+/// A sealed log file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(super) struct Segment {
+    /// The number of the log file.
+    pub number: u64,
+    /// The length(in bytes) of the log file.
+    pub len: u64,
+    /// The CRC32 of the log file's contents.
+    pub checksum: u32,
+}
+
+impl Segment {
+    /// Describe the sealed log file numbered `number`, which is `len` bytes long.
+    pub fn seal(log_path: &Path, number: u64, len: u64) -> crate::Result<Segment> {
+        let mut reader = BufReader::new(File::open(log_file(log_path, number))?);
+        let mut hasher = crc32fast::Hasher::new();
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            hasher.update(buf);
+            let read = buf.len();
+            reader.consume(read);
+        }
+        Ok(Segment {
+            number,
+            len,
+            checksum: hasher.finalize(),
+        })
+    }
+}
+
+impl Manifest {
+    /// The manifest of a store made of a single, freshly compacted log file `len` bytes
+    /// long.
+    pub fn new(len: u64) -> Manifest {
+        Manifest {
+            version: FORMAT_VERSION,
+            active: 0,
+            sealed: vec![],
+            compactions: 0,
+            compacted_len: len,
+            compacted_seq: 0,
+        }
+    }
+
+    /// Load the manifest of the log at `log_path`, checking it against the files on disk.
+    ///
+    /// A store without a manifest, whether new or created before manifests existed, is
+    /// adopted as it is. Log files left behind by an interrupted or completed compaction
+    /// are deleted. If the manifest is unreadable or doesn't match the files on disk, the
+    /// store is refused unless `repair` is set, in which case unexpected log files are
+    /// deleted, missing ones are forgotten, and an unreadable manifest is rebuilt from
+    /// the log files on disk. Along with the manifest, returns the problems that were
+    /// repaired.
+    pub fn open(log_path: &Path, repair: bool) -> crate::Result<(Manifest, Vec<String>)> {
+        let mut manifest = match Manifest::load(log_path) {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) => return Manifest::adopt(log_path, repair),
+            Err(e) if repair => {
+                let (manifest, mut repairs) = Manifest::adopt(log_path, repair)?;
+                repairs.insert(0, e.to_string());
+                return Ok((manifest, repairs));
+            }
+            Err(e) => return Err(e),
+        };
+        if manifest.version > FORMAT_VERSION {
+            return Err(KvsError::Corrupt(format!(
+                "unsupported format version {}",
+                manifest.version
+            )));
+        }
+
+        for number in manifest.leftovers(&log_numbers(log_path)?) {
+            fs::remove_file(log_file(log_path, number))?;
+        }
+
+        let problems = manifest.problems(log_path)?;
+        if problems.is_empty() {
+            return Ok((manifest, problems));
+        }
+        if !repair {
+            return Err(KvsError::Corrupt(problems.join("; ")));
+        }
+
+        let numbers = log_numbers(log_path)?;
+        for &number in &numbers {
+            if !manifest.lists(number) {
+                fs::remove_file(log_file(log_path, number))?;
+            }
+        }
+        manifest.sealed.retain(|s| numbers.contains(&s.number));
+        manifest.store(log_path)?;
+        Ok((manifest, problems))
+    }
+
+    /// Build a manifest for the log files on disk.
+    ///
+    /// If there are several, there's no telling whether they all belong to the store, so
+    /// this fails unless `repair` is set, in which case they're all kept, in order, and
+    /// that's returned as a problem repaired.
+    fn adopt(log_path: &Path, repair: bool) -> crate::Result<(Manifest, Vec<String>)> {
+        let mut numbers = log_numbers(log_path)?;
+        let mut problems = vec![];
+        if numbers.len() > 1 {
+            problems.push("found several log files but no manifest".to_string());
+            if !repair {
+                return Err(KvsError::Corrupt(problems.remove(0)));
+            }
+        }
+        numbers.sort_unstable();
+        let active = numbers.pop().unwrap_or(0);
+        let sealed = numbers
+            .into_iter()
+            .map(|number| {
+                let len = fs::metadata(log_file(log_path, number))?.len();
+                Segment::seal(log_path, number, len)
+            })
+            .collect::<crate::Result<_>>()?;
+        let manifest = Manifest {
+            version: FORMAT_VERSION,
+            active,
+            sealed,
+            compactions: 0,
+            compacted_len: 0,
+            compacted_seq: 0,
+        };
+        manifest.store(log_path)?;
+        Ok((manifest, problems))
+    }
+
+    /// The log files among `numbers` left behind by an interrupted or completed
+    /// compaction, or by a new log file that was never swapped in.
+    pub fn leftovers(&self, numbers: &[u64]) -> Vec<u64> {
+        // Compactions and new log files are numbered upwards from the active log, and
+        // the files a compaction replaces are all older than the ones it writes.
+        let oldest = self.sealed.first().map_or(self.active, |s| s.number);
+        let newer = (self.active + 1..).take_while(|n| numbers.contains(n));
+        let older = numbers.iter().copied().filter(|&n| n < oldest);
+        newer.chain(older).collect()
+    }
+
+    /// Whether the log file numbered `number` is part of the store.
+    pub fn lists(&self, number: u64) -> bool {
+        number == self.active || self.sealed.iter().any(|s| s.number == number)
+    }
+
+    /// Read the manifest of the log at `log_path`, if there is one.
+    pub fn load(log_path: &Path) -> crate::Result<Option<Manifest>> {
+        let contents = match fs::read(manifest_path(log_path)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(|e| KvsError::Corrupt(format!("unreadable manifest: {}", e)))
+    }
+
+    /// Describe every way the files on disk differ from what the manifest records.
+    fn problems(&self, log_path: &Path) -> crate::Result<Vec<String>> {
+        let numbers = log_numbers(log_path)?;
+        let mut problems = vec![];
+        let listed = self.sealed.iter().map(|s| s.number).chain([self.active]);
+        for number in listed {
+            if !numbers.contains(&number) {
+                problems.push(format!(
+                    "missing log file {}",
+                    log_file(log_path, number).display()
+                ));
+            }
+        }
+        for number in numbers {
+            if !self.lists(number) {
+                problems.push(format!(
+                    "unexpected log file {}",
+                    log_file(log_path, number).display()
+                ));
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Atomically replace the manifest on disk with this one.
+    pub fn store(&self, log_path: &Path) -> crate::Result<()> {
+        let path = manifest_path(log_path);
+        let tmp_path = with_suffix(&path, ".tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec(self)?)?;
+        tmp.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// The path of the log file numbered `number`.
+pub(super) fn log_file(log_path: &Path, number: u64) -> PathBuf {
+    match number {
+        0 => log_path.to_path_buf(),
+        n => with_suffix(log_path, &format!("{}{}", SEGMENT_SUFFIX, n)),
+    }
+}
+
+/// The number of the log file named `name`, if it's one of the numbered log files of a
+/// log named `log_name`.
+fn segment_number(name: &str, log_name: &str) -> Option<u64> {
+    let digits = name.strip_prefix(log_name)?.strip_prefix(SEGMENT_SUFFIX)?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok().filter(|&n| n != 0)
+}
+
+/// Take the exclusive lock of the store whose log is at `log_path`, which is held for as
+/// long as the returned file is open.
+///
+/// Fails if the log's name is one the numbered log files of another store could have.
+pub(super) fn lock(log_path: &Path) -> crate::Result<File> {
+    let name = log_path.file_name().unwrap_or_default().to_string_lossy();
+    let numbered = name
+        .rfind(SEGMENT_SUFFIX)
+        .and_then(|at| segment_number(&name, &name[..at]));
+    if numbered.is_some() {
+        return Err(KvsError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("log name {} ends in {}<n>", name, SEGMENT_SUFFIX),
+        )));
+    }
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(with_suffix(log_path, ".LOCK"))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(KvsError::AlreadyLocked),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Delete every file belonging to the store whose log is at `log_path`, which must be
+/// locked by the caller. The lock file goes last.
+pub(super) fn remove_all(log_path: &Path) -> crate::Result<()> {
+    for number in log_numbers(log_path)? {
+        fs::remove_file(log_file(log_path, number))?;
+    }
+    let manifest = manifest_path(log_path);
+    for path in [
+        with_suffix(&manifest, ".tmp"),
+        manifest,
+        with_suffix(log_path, ".LOCK"),
+    ] {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The path of the manifest of the log at `log_path`.
+fn manifest_path(log_path: &Path) -> PathBuf {
+    with_suffix(log_path, ".MANIFEST")
+}
+
+pub(super) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// The numbers of the log files that exist on disk.
+pub(super) fn log_numbers(log_path: &Path) -> crate::Result<Vec<u64>> {
+    let mut numbers = vec![];
+    if log_path.is_file() {
+        numbers.push(0);
+    }
+
+    let (dir, name) = match (log_path.parent(), log_path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+        _ => return Ok(numbers),
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    for entry in fs::read_dir(dir)? {
+        let entry_name = entry?.file_name();
+        if let Some(n) = entry_name.to_str().and_then(|n| segment_number(n, &name)) {
+            numbers.push(n);
+        }
+    }
+    Ok(numbers)
+}
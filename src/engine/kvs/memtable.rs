@@ -0,0 +1,156 @@
+//! Writes held in memory, and appended to the log in batches by a background thread.
+//!
+//! With a memtable, `set` and `remove` return as soon as they're recorded here, without
+//! touching the log. Reads of a key consult the memtable before the index, so they see
+//! the latest write to it straight away. The ops are appended to the log in the order
+//! they were made, all in one batch, once the memtable reaches its size limit or its
+//! flush interval passes, whichever comes first.
+//!
+//! Until then they're only in memory: a crash loses every write since the last flush,
+//! and a store reopened in another process doesn't see them. Dropping the last handle
+//! to a store, or syncing it, flushes them.
+
+use super::{KvStoreInner, KvStoreOptions};
+use crate::engine::Op;
+use crossbeam::channel::{self, Receiver, Sender};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Writes not yet appended to the log.
+pub(super) struct Memtable {
+    /// The latest value written to each key, as indexed, or `None` if it was removed.
+    entries: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Every op not yet appended, in the order it was made, with the key it's for and
+    /// the length of its value.
+    ops: Vec<(Vec<u8>, usize, Op)>,
+    /// The total length(in bytes) of the keys and values of `ops`.
+    bytes: usize,
+    /// The size(in bytes) past which the flusher is woken early.
+    max_bytes: usize,
+    /// Wakes the flusher.
+    wake: Sender<()>,
+}
+
+impl Memtable {
+    fn new(max_bytes: usize, wake: Sender<()>) -> Self {
+        Memtable {
+            entries: HashMap::new(),
+            ops: vec![],
+            bytes: 0,
+            max_bytes,
+            wake,
+        }
+    }
+
+    /// The latest write to `key`: `Some(None)` if it was a removal, and `None` if
+    /// there's been none since the last flush.
+    pub fn get(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        self.entries.get(key).map(Option::as_deref)
+    }
+
+    /// Every key written to, with its latest value, or `None` if it was removed.
+    pub fn entries(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_deref()))
+    }
+
+    /// Record a `set` of `key` to `value`, as `op`.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>, op: Op) {
+        self.push(key.clone(), value.len(), op);
+        self.entries.insert(key, Some(value));
+    }
+
+    /// Record the removal of `key`.
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.push(key.clone(), 0, super::keys::rm_op(&key));
+        self.entries.insert(key, None);
+    }
+
+    fn push(&mut self, key: Vec<u8>, value_len: usize, op: Op) {
+        self.bytes += key.len() + value_len;
+        self.ops.push((key, value_len, op));
+        if self.bytes >= self.max_bytes {
+            // Already woken if this fails: the flusher only needs telling once.
+            let _ = self.wake.try_send(());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Every op not yet appended, in the order it was made, to number as it's appended.
+    pub fn ops_mut(&mut self) -> impl Iterator<Item = &mut Op> {
+        self.ops.iter_mut().map(|(_, _, op)| op)
+    }
+
+    /// Empty the memtable, once its ops have been appended, returning them with the keys
+    /// they're for and the lengths of their values.
+    pub fn take(&mut self) -> Vec<(Vec<u8>, usize, Op)> {
+        self.entries.clear();
+        self.bytes = 0;
+        std::mem::take(&mut self.ops)
+    }
+}
+
+/// The thread flushing a store's memtable, stopped and joined when dropped.
+pub(super) struct Flusher {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    /// Give `store` a memtable of up to `max_bytes`, and start flushing it at least every
+    /// `interval`.
+    pub fn start(
+        store: &Arc<Mutex<KvStoreInner>>,
+        options: &Arc<KvStoreOptions>,
+        max_bytes: usize,
+        interval: Duration,
+    ) -> Self {
+        let (wake, woken) = channel::bounded(1);
+        store.lock().unwrap().memtable = Some(Memtable::new(max_bytes, wake));
+        let (stop, stopped) = channel::bounded(1);
+        let (store, options) = (Arc::clone(store), Arc::clone(options));
+        let thread = thread::spawn(move || run(store, options, interval, woken, stopped));
+        Flusher {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(
+    store: Arc<Mutex<KvStoreInner>>,
+    options: Arc<KvStoreOptions>,
+    interval: Duration,
+    woken: Receiver<()>,
+    stopped: Receiver<()>,
+) {
+    loop {
+        crossbeam::select! {
+            recv(stopped) -> _ => return,
+            recv(woken) -> _ => {}
+            default(interval) => {}
+        }
+        let mut store = store.lock().unwrap();
+        if let Err(e) = store
+            .flush_memtable()
+            .and_then(|_| store.seal_if_full(&options))
+        {
+            log::error!("failed to flush the memtable: {}", e);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Sealed log files mapped into memory, so that reads from them skip the open, seek and
+//! read a file read takes.
+//!
+//! Only sealed files are mapped. The active file is still being appended to, so it's
+//! read as usual. A sealed file is never written to again, nor truncated: compaction
+//! writes the next generation to new files, and deletes the old ones only once it's
+//! swapped in, by which point their maps have been dropped under the store's lock.
+
+use super::index::Offset;
+use super::manifest::{self, Segment};
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+/// The maps of a store's sealed log files, by number.
+#[derive(Default)]
+pub(super) struct Maps {
+    maps: HashMap<u64, Mmap>,
+}
+
+impl Maps {
+    /// Map every file of `sealed` not mapped yet, and drop the maps of files no longer
+    /// among them.
+    pub fn update(&mut self, log_path: &Path, sealed: &[Segment]) -> crate::Result<()> {
+        self.maps
+            .retain(|number, _| sealed.iter().any(|s| s.number == *number));
+        for segment in sealed {
+            if self.maps.contains_key(&segment.number) {
+                continue;
+            }
+            let file = File::open(manifest::log_file(log_path, segment.number))?;
+            // SAFETY: sealed files are never modified while the store is open, and
+            // their maps are dropped before they're deleted. See the module docs.
+            let map = unsafe { Mmap::map(&file)? };
+            self.maps.insert(segment.number, map);
+        }
+        Ok(())
+    }
+
+    /// The record at `offset`, if its file is mapped.
+    pub fn get(&self, offset: &Offset) -> Option<&[u8]> {
+        let map = self.maps.get(&offset.segment())?;
+        map.get(offset.start()..offset.start() + offset.len())
+    }
+}
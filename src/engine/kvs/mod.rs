@@ -0,0 +1,2935 @@
+//! A store kept as an append-only log on disk, with an in-memory index from each live key
+//! to where its latest value is in the log.
+//!
+//! By default the path a store is opened at is a directory holding the log as
+//! `kvstore-logs`, beside its manifest and sealed segments. [`KvStoreOptions`] can move
+//! the store's files into a data directory under that path, rename the log, or treat the
+//! path as the log file itself.
+
+mod bloom;
+mod bucket;
+mod cache;
+mod commit;
+mod compaction;
+mod events;
+mod header;
+mod history;
+mod index;
+mod inspect;
+mod keys;
+mod locks;
+mod manifest;
+mod memtable;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod options;
+mod patch;
+mod recovery;
+mod sequence;
+mod snapshot;
+mod stream;
+mod verify;
+
+pub use bloom::BloomStats;
+pub use bucket::Bucket;
+pub use cache::CacheStats;
+pub use commit::Durability;
+pub use compaction::{CompactionController, CompactionPhase, CompactionStatus};
+pub use events::ChangeEvent;
+pub use history::VersionedValue;
+pub use inspect::{LogInspector, Record, RecordInfo};
+pub use manifest::FORMAT_VERSION;
+pub use options::KvStoreOptions;
+pub use recovery::{RecoveryMode, RecoveryReport};
+pub use sequence::OpStream;
+pub use snapshot::Snapshot;
+pub use stream::ValueReader;
+pub use verify::{CorruptRecord, VerifyReport};
+
+use bloom::Bloom;
+use cache::ValueCache;
+use commit::{GroupCommit, PeriodicSync};
+use compaction::Tracker;
+use events::Subscribers;
+use history::History;
+use index::{in_log_order, measure_prefix, with_prefix, Index, Offset, MAX_ENTRY_LEN};
+use locks::KeyLocks;
+use manifest::{Manifest, Segment};
+use memtable::{Flusher, Memtable};
+use patch::{Chains, MAX_CHAIN_LEN};
+
+use super::codec::{Codec, LogCodec};
+use super::compression::Compressed;
+use super::export::{self, Exporter, ImportMode};
+use super::{KvsEngine, Op};
+use crate::err::KvsError;
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crossbeam::channel::Receiver;
+use im::OrdMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fs::{self, File},
+    io::{self, prelude::*, BufWriter, SeekFrom},
+    ops::Bound,
+    path::Path,
+    sync::{mpsc, Arc, Mutex, MutexGuard, TryLockError},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+pub struct KvStore {
+    /// Flushes the memtable in the background, if enabled. Declared first, so that the
+    /// thread is stopped before the last handle lets go of the store.
+    flusher: Option<Arc<Flusher>>,
+    /// Syncs the log in the background, with [`Durability::Periodic`]. Stopped after the
+    /// flusher, so that its last sync covers the memtable's last flush.
+    syncer: Option<Arc<PeriodicSync>>,
+    inner: Arc<Mutex<KvStoreInner>>,
+    options: Arc<KvStoreOptions>,
+    /// Held for the duration of a compaction.
+    compaction: Arc<Mutex<()>>,
+    /// The state of compactions, and whether they're paused.
+    compactions: Arc<Tracker>,
+    /// Held around writes to a key, taken before `inner`.
+    key_locks: Arc<KeyLocks>,
+    /// Writers waiting for their writes to be synced, with
+    /// [`Durability::EveryWrite`].
+    commit: Arc<GroupCommit>,
+}
+
+impl Clone for KvStore {
+    fn clone(&self) -> Self {
+        KvStore {
+            flusher: self.flusher.clone(),
+            syncer: self.syncer.clone(),
+            inner: Arc::clone(&self.inner),
+            options: Arc::clone(&self.options),
+            compaction: Arc::clone(&self.compaction),
+            compactions: Arc::clone(&self.compactions),
+            key_locks: Arc::clone(&self.key_locks),
+            commit: Arc::clone(&self.commit),
+        }
+    }
+}
+
+/// A point-in-time summary of a store's state.
+#[derive(Clone, Debug)]
+pub struct KvStoreStats {
+    /// The number of live keys.
+    pub live_keys: usize,
+    /// The size(in bytes) taken up by redundant entries.
+    pub redundant_size: usize,
+    /// The size(in bytes) of the log, across all of its files.
+    pub log_size: u64,
+    /// The number of files the log is split into.
+    pub log_files: usize,
+    /// The total size(in bytes) of live values, as seen by callers.
+    pub value_bytes: u64,
+    /// The total size(in bytes) live values take up in the log, after compression.
+    pub stored_value_bytes: u64,
+    /// Roughly how much memory(in bytes) the index of live keys takes up.
+    pub index_bytes: u64,
+    /// The number of keys evicted to keep the log under
+    /// [`max_disk_bytes`](KvStoreOptions::max_disk_bytes) since the store was opened.
+    pub evicted_keys: u64,
+    /// The state of the bloom filter, if enabled.
+    pub bloom: Option<BloomStats>,
+    /// The state of the value cache, if enabled.
+    pub cache: Option<CacheStats>,
+    /// The state of compactions.
+    pub compaction: CompactionStatus,
+}
+
+/// A summary of a single compaction run.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CompactionReport {
+    /// The size(in bytes) of the log before compaction.
+    pub bytes_before: u64,
+    /// The size(in bytes) of the log after compaction.
+    pub bytes_after: u64,
+    /// How long the compaction took.
+    pub duration: Duration,
+}
+
+/// What a write did besides writing, from [`KvStore::set_verbose`] and
+/// [`KvStore::remove_verbose`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteOutcome {
+    /// Whether the write crossed the compaction threshold and compacted the log before
+    /// returning.
+    pub compacted: bool,
+    /// How long that compaction took, if there was one.
+    pub compaction_duration: Option<Duration>,
+}
+
+impl WriteOutcome {
+    fn new(compaction: Option<CompactionReport>) -> Self {
+        WriteOutcome {
+            compacted: compaction.is_some(),
+            compaction_duration: compaction.map(|report| report.duration),
+        }
+    }
+}
+
+/// What a compaction would reclaim if run now, worked out without running it.
+///
+/// Assumes values are stored as they are now: a compaction that changes the compression
+/// or codec in use may come out larger or smaller.
+#[derive(Clone, Debug, Serialize)]
+pub struct CompactionEstimate {
+    /// The size(in bytes) of the log, across all of its files.
+    pub log_size: u64,
+    /// The size(in bytes) of the records of live keys.
+    pub live_size: u64,
+    /// The size(in bytes) taken up by redundant entries.
+    pub redundant_size: u64,
+    /// The size(in bytes) the log is expected to be after compaction.
+    pub projected_size: u64,
+}
+
+impl CompactionEstimate {
+    /// The number of bytes compaction is expected to free.
+    pub fn reclaimed(&self) -> u64 {
+        self.log_size.saturating_sub(self.projected_size)
+    }
+}
+
+/// A summary of a checkpoint.
+#[derive(Clone, Debug)]
+pub struct CheckpointReport {
+    /// The number of live keys copied.
+    pub keys: usize,
+    /// The size(in bytes) of the checkpoint's log.
+    pub bytes: u64,
+    /// How long the checkpoint took.
+    pub duration: Duration,
+}
+
+/// The store.
+pub struct KvStoreInner {
+    /// The path to the logfile, as configured.
+    log_path: std::path::PathBuf,
+    /// The path to the log file currently appended to.
+    fp: std::path::PathBuf,
+    /// The handle to the log file currently appended to.
+    fh: BufWriter<File>,
+    /// An index mapping a key, as encoded by [`keys::encode`], to the log file and offsets
+    /// of its last `set` op.
+    ///
+    /// It's a persistent map, so that [`Snapshot`]s can share it.
+    index: Index,
+    /// The earlier ops the value of each key indexed at a `setrange` is built from.
+    chains: Chains,
+    /// The records of the earlier values of each key, still in the log.
+    history: History,
+    /// The codec each log file's records are written in, by number. The active log
+    /// file's is always the one the store was opened with.
+    codecs: HashMap<u64, Codec>,
+    /// The log files opened to read records from, by number, kept open so that reads
+    /// don't each open a file of their own.
+    readers: RefCell<HashMap<u64, File>>,
+    /// The size(in bytes) taken up by redundant entries.
+    redundant_size: usize,
+    /// When the last compaction was swapped in, if there's been one since the store was
+    /// opened.
+    last_compaction: Option<Instant>,
+    /// The total length(in bytes) of live values.
+    value_bytes: u64,
+    /// The total length(in bytes) of live values as stored in the log.
+    stored_value_bytes: u64,
+    /// The total length(in bytes) of the keys in the index.
+    key_bytes: u64,
+    /// The total length(in bytes) of the records of live keys.
+    live_size: u64,
+    /// The number of keys evicted since the store was opened.
+    evicted: u64,
+    /// A filter over every key set since the log was last replayed or compacted.
+    bloom: Option<Bloom>,
+    /// Recently read values.
+    cache: Option<ValueCache>,
+    /// Writes not yet appended to the log, if enabled.
+    memtable: Option<Memtable>,
+    /// The sealed log files, mapped into memory to read from, if enabled.
+    #[cfg(feature = "mmap")]
+    maps: Option<mmap::Maps>,
+    /// The manifest, as last written.
+    manifest: Manifest,
+    /// Whether a compaction is copying live entries, in which case the active log file
+    /// mustn't be sealed.
+    compacting: bool,
+    /// Notified of every write.
+    subscribers: Subscribers,
+    /// Keeps the store from being opened again until every handle is dropped.
+    _lock: File,
+    /// Whether records have been appended to the active log file since it was last synced.
+    unsynced: bool,
+    /// The sequence number of the last write appended to the log, or of the last one
+    /// compaction dropped if none has been appended since.
+    last_seq: u64,
+    /// The number of writes appended to the log since the store was opened.
+    appended: u64,
+    /// Whether files have been created or renamed in the store's directory since it was
+    /// last synced.
+    dir_unsynced: bool,
+}
+
+impl KvStoreInner {
+    /// Point `key` at a new `set` op, accounting for the entry it replaces.
+    fn insert_entry(&mut self, key: Vec<u8>, offset: Offset) {
+        self.live_size += offset.len() as u64;
+        self.value_bytes += offset.value_len() as u64;
+        self.stored_value_bytes += offset.stored_len() as u64;
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(&key);
+        }
+        let chain = self.chains.remove(key.as_slice()).unwrap_or_default();
+        self.forget_chain(&chain);
+        if let Some(&old) = self.index.get(key.as_slice()) {
+            self.push_history(&key, chain.into_iter().chain([old]));
+        }
+        let key_len = key.len() as u64;
+        match self.index.insert(key.into_boxed_slice(), offset) {
+            Some(old) => self.forget(old),
+            None => self.key_bytes += key_len,
+        }
+    }
+
+    /// Add the records of values `key` no longer has to its history.
+    fn push_history(&mut self, key: &[u8], replaced: impl IntoIterator<Item = Offset>) {
+        match self.history.get_mut(key) {
+            Some(history) => history.extend(replaced),
+            None => {
+                let history: Vec<Offset> = replaced.into_iter().collect();
+                if !history.is_empty() {
+                    self.history.insert(Box::from(key), history);
+                }
+            }
+        }
+    }
+
+    /// Point `key` at a new `setrange` op, whose value is built from the ops of `chain`,
+    /// and from the op `key` is indexed at now before them if `continues` is set.
+    ///
+    /// The ops built on stay live, as part of the key's chain.
+    fn insert_patch(&mut self, key: Vec<u8>, offset: Offset, continues: bool, chain: Vec<Offset>) {
+        let mut built = vec![];
+        if continues {
+            if let Some(old) = self.index.remove(key.as_slice()) {
+                self.key_bytes -= key.len() as u64;
+                self.value_bytes -= old.value_len() as u64;
+                built = self.chains.remove(key.as_slice()).unwrap_or_default();
+                built.push(old);
+            }
+        }
+        for link in &chain {
+            self.live_size += link.len() as u64;
+            self.stored_value_bytes += link.stored_len() as u64;
+        }
+        built.extend(chain);
+        self.insert_entry(key.clone(), offset);
+        self.chains.insert(key.into_boxed_slice(), built);
+    }
+
+    /// Drop `key` from the index, accounting for the entry it pointed at.
+    fn remove_entry(&mut self, key: &[u8]) -> Option<Offset> {
+        let old = self.index.remove(key)?;
+        self.key_bytes -= key.len() as u64;
+        self.forget(old);
+        if let Some(chain) = self.chains.remove(key) {
+            self.forget_chain(&chain);
+        }
+        self.history.remove(key);
+        Some(old)
+    }
+
+    /// Apply the ops replayed from a log file on top of those replayed from older ones.
+    fn merge(&mut self, mut replayed: ReplayedLog) {
+        self.redundant_size += replayed.redundant_size;
+        self.last_seq = self.last_seq.max(replayed.last_seq);
+        for (key, entry) in replayed.entries {
+            match entry {
+                Some(offset) => {
+                    let (continues, replaced) =
+                        replayed.history.remove(&key).unwrap_or((true, vec![]));
+                    // Removed earlier in the file, so older files' values are history no more.
+                    if !continues {
+                        self.remove_entry(&key);
+                    }
+                    match replayed.chains.remove(&key) {
+                        Some((continues, chain)) => {
+                            self.insert_patch(key.clone(), offset, continues, chain)
+                        }
+                        None => self.insert_entry(key.clone(), offset),
+                    }
+                    self.push_history(&key, replaced);
+                }
+                None => {
+                    self.remove_entry(&key);
+                }
+            }
+        }
+    }
+
+    fn forget(&mut self, old: Offset) {
+        self.redundant_size += old.len();
+        self.live_size -= old.len() as u64;
+        self.value_bytes -= old.value_len() as u64;
+        self.stored_value_bytes -= old.stored_len() as u64;
+    }
+
+    /// Account for the ops of a chain no longer being live, whose values were already
+    /// replaced by the ops after them.
+    fn forget_chain(&mut self, chain: &[Offset]) {
+        for link in chain {
+            self.redundant_size += link.len();
+            self.live_size -= link.len() as u64;
+            self.stored_value_bytes -= link.stored_len() as u64;
+        }
+    }
+
+    /// Look up the index entry of `key`, consulting the bloom filter first.
+    fn lookup(&self, key: &[u8]) -> Option<&Offset> {
+        match &self.bloom {
+            Some(bloom) if !bloom.may_contain(key) => None,
+            _ => self.index.get(key),
+        }
+    }
+
+    /// Every key in the index starting with `prefix`, in order.
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
+        with_prefix(&self.index, prefix)
+            .map(|(key, _)| key.to_vec())
+            .collect()
+    }
+
+    /// The latest write to `key` still in the memtable, if any: `Some(None)` if it was a
+    /// removal.
+    fn unflushed(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        self.memtable.as_ref()?.get(key)
+    }
+
+    /// How the writes still in the memtable to keys starting with `prefix` change the
+    /// number of live keys, and the size(in bytes) of their values and of their records,
+    /// counting a record not yet written as its key and value.
+    fn unflushed_change(&self, prefix: &[u8]) -> (i64, i64, i64) {
+        let mut change = (0, 0, 0);
+        let entries = self.memtable.iter().flat_map(Memtable::entries);
+        for (key, value) in entries.filter(|(key, _)| key.starts_with(prefix)) {
+            if let Some(old) = self.index.get(key) {
+                change.0 -= 1;
+                change.1 -= old.value_len() as i64;
+                change.2 -= old.len() as i64;
+            }
+            if let Some(value) = value {
+                change.0 += 1;
+                change.1 += value.len() as i64;
+                change.2 += (key.len() + value.len()) as i64;
+            }
+        }
+        change
+    }
+
+    /// The number of keys starting with `prefix`, and the size(in bytes) of their
+    /// records, counting writes still in the memtable.
+    fn measure_prefix(&self, prefix: &str) -> (usize, u64) {
+        let (count, size) = measure_prefix(&self.index, prefix);
+        let (keys, _, bytes) = self.unflushed_change(&keys::encode(None, prefix.as_bytes()));
+        (
+            count.saturating_add_signed(keys as isize),
+            size.saturating_add_signed(bytes),
+        )
+    }
+
+    /// Whether `key` exists, counting writes still in the memtable.
+    fn contains(&self, key: &[u8]) -> bool {
+        match self.unflushed(key) {
+            Some(value) => value.is_some(),
+            None => self.lookup(key).is_some(),
+        }
+    }
+
+    /// Read the current value of `key`, from the memtable or the cache if possible.
+    fn read_value(&mut self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        if let Some(value) = self.unflushed(key) {
+            return Ok(value.map(<[u8]>::to_vec));
+        }
+        let offset = match self.lookup(key) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        if let Some(value) = self.cache.as_mut().and_then(|cache| cache.get(key)) {
+            return Ok(Some(value));
+        }
+
+        let (value, _) = self.read_entry(key, &offset)?;
+        if let Some(cache) = &mut self.cache {
+            cache.insert(key.to_vec(), value.clone());
+        }
+        Ok(Some(value))
+    }
+
+    /// Read the value of `key` from the log, where it's indexed at `offset`, along with
+    /// when it was written.
+    fn read_entry(&self, key: &[u8], offset: &Offset) -> crate::Result<(Vec<u8>, Option<u64>)> {
+        let chain = self.chains.get(key).map_or(&[][..], Vec::as_slice);
+        let offsets = chain.iter().chain([offset]);
+        let (value, written_at, _) =
+            patch::assemble(offsets.map(|offset| self.read_record(offset)))?;
+        Ok((value, written_at))
+    }
+
+    /// Read the op at `offset`, from its file's map if it has one.
+    fn read_record(&self, offset: &Offset) -> crate::Result<Op> {
+        let codec = self.codecs[&offset.segment()];
+        #[cfg(feature = "mmap")]
+        if let Some(record) = self.maps.as_ref().and_then(|maps| maps.get(offset)) {
+            return decode_op(codec, record);
+        }
+        let mut readers = self.readers.borrow_mut();
+        let reader = open_reader(&mut readers, &self.log_path, offset)?;
+        read_op(reader, codec, offset)
+    }
+
+    /// Map the sealed log files into memory, if enabled, dropping the maps of files no
+    /// longer in the log.
+    fn map_sealed(&mut self) -> crate::Result<()> {
+        #[cfg(feature = "mmap")]
+        if let Some(maps) = &mut self.maps {
+            maps.update(&self.log_path, &self.manifest.sealed)?;
+        }
+        Ok(())
+    }
+
+    /// Append a `set` op for `key` to the log, whose value is `value_len` bytes long.
+    fn append_set(&mut self, key: Vec<u8>, value_len: usize, mut op: Op) -> crate::Result<()> {
+        self.flush_memtable()?;
+        let codec = self.active_codec();
+        let (start, end) = write_numbered(&mut self.fh, codec, &mut self.last_seq, [&mut op])?[0];
+        let offset = Offset::new(self.manifest.active, start, end, value_len, op.value_len());
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(&key);
+        }
+        self.appended();
+        self.subscribers.publish(ChangeEvent::set(&key));
+        self.insert_entry(key, offset);
+        Ok(())
+    }
+
+    /// Append a `setrange` op for `key` to the log, after which its value is `value_len`
+    /// bytes long.
+    fn append_patch(&mut self, key: Vec<u8>, value_len: usize, mut op: Op) -> crate::Result<()> {
+        self.flush_memtable()?;
+        let codec = self.active_codec();
+        let (start, end) = write_numbered(&mut self.fh, codec, &mut self.last_seq, [&mut op])?[0];
+        let offset = Offset::new(self.manifest.active, start, end, value_len, op.value_len());
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(&key);
+        }
+        self.appended();
+        self.subscribers.publish(ChangeEvent::set(&key));
+        self.insert_patch(key, offset, true, vec![]);
+        Ok(())
+    }
+
+    /// Append an `rm` op for `key` to the log, returning false if the key doesn't exist.
+    fn append_rm(&mut self, key: &[u8]) -> crate::Result<bool> {
+        self.flush_memtable()?;
+        if !self.index.contains_key(key) {
+            return Ok(false);
+        }
+
+        let codec = self.active_codec();
+        let mut op = keys::rm_op(key);
+        let (start, end) = write_numbered(&mut self.fh, codec, &mut self.last_seq, [&mut op])?[0];
+
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(key);
+        }
+        self.appended();
+        self.subscribers.publish(ChangeEvent::removed(key));
+        self.remove_entry(key);
+        self.redundant_size += end - start;
+        Ok(true)
+    }
+
+    /// Append `rm` ops for every key in `keys` that exists, in a single write, returning
+    /// which ones did. A key repeated in `keys` only counts as existing the first time.
+    ///
+    /// Subscribers are told of each removal as `event`.
+    fn append_rms(
+        &mut self,
+        keys: &[&[u8]],
+        event: fn(&[u8]) -> ChangeEvent,
+    ) -> crate::Result<Vec<bool>> {
+        self.flush_memtable()?;
+        let mut removed = HashSet::new();
+        let existed: Vec<bool> = keys
+            .iter()
+            .map(|&key| self.index.contains_key(key) && removed.insert(key))
+            .collect();
+        let mut ops: Vec<Op> = keys
+            .iter()
+            .zip(&existed)
+            .filter(|(_, &existed)| existed)
+            .map(|(&key, _)| keys::rm_op(key))
+            .collect();
+        if ops.is_empty() {
+            return Ok(existed);
+        }
+
+        let codec = self.active_codec();
+        let spans = write_numbered(&mut self.fh, codec, &mut self.last_seq, &mut ops)?;
+        self.appended();
+        for (op, (start, end)) in ops.iter().zip(spans) {
+            let key = keys::of_op(op);
+            if let Some(cache) = &mut self.cache {
+                cache.invalidate(&key);
+            }
+            self.remove_entry(&key);
+            self.redundant_size += end - start;
+            self.subscribers.publish(event(&key));
+        }
+        Ok(existed)
+    }
+
+    /// Copy the value of `from` to `to`, also removing `from` if `rename` is set.
+    ///
+    /// A rename removes `from` and sets `to` in a single write. The removal is batched
+    /// with the `set`, so that if the write is cut short neither takes effect.
+    fn append_copy(
+        &mut self,
+        from: &[u8],
+        to: Vec<u8>,
+        overwrite: bool,
+        rename: bool,
+        written_at: u64,
+    ) -> crate::Result<()> {
+        self.flush_memtable()?;
+        let offset = *self.index.get(from).ok_or(KvsError::KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        if !overwrite && self.index.contains_key(to.as_slice()) {
+            return Err(KvsError::KeyExists);
+        }
+
+        // The value is copied as it's stored, compressed or not, unless it was patched.
+        let (value, compressed) = match self.read_record(&offset)? {
+            Op::Set {
+                value, compressed, ..
+            } => (value, compressed),
+            Op::SetRange { .. } => (self.read_entry(from, &offset)?.0, None),
+            Op::Rm { .. } => unreachable!(),
+        };
+        let stored_len = value.len();
+        let (to_bucket, to_key) = keys::decode(&to);
+        let mut set = Op::Set {
+            key: to_key.to_vec(),
+            value,
+            compressed,
+            bucket: to_bucket.map(str::to_owned),
+            written_at: Some(written_at),
+            seq: None,
+        };
+
+        let mut rm = rename.then(|| {
+            let (from_bucket, from_key) = keys::decode(from);
+            Op::Rm {
+                key: from_key.to_vec(),
+                bucket: from_bucket.map(str::to_owned),
+                batched: true,
+                seq: None,
+            }
+        });
+        let codec = self.active_codec();
+        let ops = rm.iter_mut().chain([&mut set]);
+        let spans = write_numbered(&mut self.fh, codec, &mut self.last_seq, ops)?;
+        let removed = rm.is_some().then(|| spans[0]);
+        let (start, end) = spans[spans.len() - 1];
+        self.appended();
+
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(&to);
+        }
+        if let Some((rm_start, rm_end)) = removed {
+            if let Some(cache) = &mut self.cache {
+                cache.invalidate(from);
+            }
+            self.remove_entry(from);
+            self.redundant_size += rm_end - rm_start;
+            self.subscribers.publish(ChangeEvent::removed(from));
+        }
+        self.subscribers.publish(ChangeEvent::set(&to));
+        let active = self.manifest.active;
+        let offset = Offset::new(active, start, end, offset.value_len(), stored_len);
+        self.insert_entry(to, offset);
+        Ok(())
+    }
+
+    /// Record a `set` of `key` in the memtable, or append it to the log if there's none.
+    fn write_set(&mut self, key: Vec<u8>, value: &[u8], op: Op) -> crate::Result<()> {
+        let memtable = match &mut self.memtable {
+            Some(memtable) => memtable,
+            None => return self.append_set(key, value.len(), op),
+        };
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(&key);
+        }
+        self.subscribers.publish(ChangeEvent::set(&key));
+        memtable.set(key, value.to_vec(), op);
+        Ok(())
+    }
+
+    /// Record the removal of `key` in the memtable, or append it to the log if there's
+    /// none, returning false if the key doesn't exist.
+    fn write_rm(&mut self, key: &[u8]) -> crate::Result<bool> {
+        if self.memtable.is_none() {
+            return self.append_rm(key);
+        }
+        if !self.contains(key) {
+            return Ok(false);
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(key);
+        }
+        self.subscribers.publish(ChangeEvent::removed(key));
+        self.memtable.as_mut().unwrap().remove(key.to_vec());
+        Ok(true)
+    }
+
+    /// Append every op in the memtable to the log, in a single write.
+    fn flush_memtable(&mut self) -> crate::Result<()> {
+        let codec = self.active_codec();
+        let memtable = match &mut self.memtable {
+            Some(memtable) if !memtable.is_empty() => memtable,
+            _ => return Ok(()),
+        };
+        // Only emptied once the ops are written, so that a failed flush is retried.
+        let spans = write_numbered(&mut self.fh, codec, &mut self.last_seq, memtable.ops_mut())?;
+        let ops = memtable.take();
+
+        self.appended();
+        let active = self.manifest.active;
+        for ((key, value_len, op), (start, end)) in ops.into_iter().zip(spans) {
+            match op {
+                Op::Set { .. } => {
+                    let offset = Offset::new(active, start, end, value_len, op.value_len());
+                    self.insert_entry(key, offset);
+                }
+                Op::Rm { .. } => {
+                    self.remove_entry(&key);
+                    self.redundant_size += end - start;
+                }
+                Op::SetRange { .. } => unreachable!("patches are written straight to the log"),
+            }
+        }
+        Ok(())
+    }
+
+    /// The codec of the log file being appended to.
+    fn active_codec(&self) -> Codec {
+        self.codecs[&self.manifest.active]
+    }
+
+    /// Seal the active log file if it has outgrown the configured size, and start a new one.
+    fn seal_if_full(&mut self, options: &KvStoreOptions) -> crate::Result<()> {
+        let len = self.fh.stream_position()?;
+        match options.max_segment_size {
+            Some(max) if !self.compacting && len >= max as u64 => self.seal(options),
+            _ => Ok(()),
+        }
+    }
+
+    /// Seal the active log file, and start a new one.
+    fn seal(&mut self, options: &KvStoreOptions) -> crate::Result<()> {
+        self.fh.flush()?;
+        let len = self.fh.stream_position()?;
+        self.fh.get_ref().sync_all()?;
+        let segment = Segment::seal(&self.log_path, self.manifest.active, len)?;
+        let number = self.manifest.active + 1;
+        let (path, fh) = create_log_file(&self.log_path, number, options)?;
+        self.codecs.insert(number, options.codec);
+        self.manifest.sealed.push(segment);
+        self.manifest.active = number;
+        self.manifest.store(&self.log_path)?;
+        self.fp = path;
+        self.fh = fh;
+        self.unsynced = false;
+        self.dir_unsynced = true;
+        self.map_sealed()
+    }
+
+    /// Note that a write was appended to the active log file.
+    fn appended(&mut self) {
+        self.unsynced = true;
+        self.appended += 1;
+    }
+
+    /// Sync appended records, and then the directory entries of new files, to disk.
+    ///
+    /// Writes still in the memtable are appended first.
+    fn sync(&mut self) -> crate::Result<()> {
+        self.flush_memtable()?;
+        if self.unsynced {
+            self.fh.flush()?;
+            self.fh.get_ref().sync_data()?;
+            self.unsynced = false;
+        }
+        if self.dir_unsynced {
+            File::open(self.log_dir())?.sync_all()?;
+            self.dir_unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// The directory the log's files are in.
+    fn log_dir(&self) -> &Path {
+        match self.log_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    /// The size(in bytes) of the log, across all of its files.
+    fn log_size(&self) -> crate::Result<u64> {
+        let sealed: u64 = self.manifest.sealed.iter().map(|s| s.len).sum();
+        Ok(sealed + self.fh.get_ref().metadata()?.len())
+    }
+}
+
+/// The entries of `index` with keys between `start` and `end`, sharing its structure.
+fn index_within(
+    index: OrdMap<Box<[u8]>, Offset>,
+    (start, end): (Bound<Vec<u8>>, Bound<Vec<u8>>),
+) -> OrdMap<Box<[u8]>, Offset> {
+    let index = match start {
+        Bound::Included(key) => {
+            let (_, found, mut above) = index.split_lookup(&key[..]);
+            if let Some(offset) = found {
+                above.insert(key.into(), offset);
+            }
+            above
+        }
+        Bound::Excluded(key) => index.split(&key[..]).1,
+        Bound::Unbounded => index,
+    };
+    match end {
+        Bound::Included(key) => {
+            let (mut below, found, _) = index.split_lookup(&key[..]);
+            if let Some(offset) = found {
+                below.insert(key.into(), offset);
+            }
+            below
+        }
+        Bound::Excluded(key) => index.split(&key[..]).0,
+        Bound::Unbounded => index,
+    }
+}
+
+/// Sync every write appended to the log so far, returning how many there have been.
+///
+/// The store is only locked around the sync, so that writers carry on appending
+/// meanwhile.
+fn sync_appended(inner: &Mutex<KvStoreInner>) -> crate::Result<u64> {
+    let mut store = inner.lock().unwrap();
+    store.fh.flush()?;
+    let (appended, active) = (store.appended, store.manifest.active);
+    let file = store.fh.get_ref().try_clone()?;
+    let dir = store.dir_unsynced.then(|| store.log_dir().to_path_buf());
+    drop(store);
+
+    file.sync_data()?;
+    if let Some(dir) = &dir {
+        File::open(dir)?.sync_all()?;
+    }
+    let mut store = inner.lock().unwrap();
+    if store.manifest.active == active {
+        store.unsynced &= store.appended != appended;
+        store.dir_unsynced &= dir.is_none();
+    }
+    Ok(appended)
+}
+
+impl Drop for KvStoreInner {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_memtable() {
+            log::error!("failed to flush the memtable: {}", e);
+        }
+    }
+}
+
+/// The log files written by a compaction.
+struct Generation {
+    /// The log files filled up so far.
+    sealed: Vec<Segment>,
+    /// The number of the log file being written.
+    number: u64,
+    /// The handle to the log file being written.
+    fh: BufWriter<File>,
+    /// Where each live key was copied to.
+    index: HashMap<Box<[u8]>, Offset>,
+}
+
+/// The ops a single log file leaves behind, replayed on its own.
+struct ReplayedLog {
+    /// The last op on each key in the file: the `set` op's offsets, or `None` for `rm`.
+    entries: HashMap<Vec<u8>, Option<Offset>>,
+    /// The chain of each key whose last op in the file is a `setrange`: the ops before it
+    /// in the file it builds on, and whether it builds on the key's value from older
+    /// files too.
+    chains: HashMap<Vec<u8>, (bool, Vec<Offset>)>,
+    /// The ops of each key superseded within the file, since it was last removed in the
+    /// file, and whether it wasn't removed in the file at all, so its history from older
+    /// files carries on.
+    history: HashMap<Vec<u8>, (bool, Vec<Offset>)>,
+    /// The sequence number of the last op in the file that took effect, or `0` if none
+    /// was numbered.
+    last_seq: u64,
+    /// The size(in bytes) of `rm` ops, of ops superseded within the file, and of
+    /// records that couldn't be read.
+    redundant_size: usize,
+    /// Records that couldn't be read, in order.
+    corrupt: Vec<CorruptRecord>,
+    /// The codec the file's records are written in.
+    codec: Codec,
+}
+
+impl ReplayedLog {
+    /// Record `entry` as the last op on `key` so far, accounting for the ops it replaces.
+    fn replace(&mut self, key: Vec<u8>, entry: Option<Offset>) {
+        let chain = self.chains.remove(&key).map_or(vec![], |(_, chain)| chain);
+        self.redundant_size += chain.iter().map(Offset::len).sum::<usize>();
+        let old = self.entries.get(&key).copied().flatten();
+        if let Some(old) = old {
+            self.redundant_size += old.len();
+        }
+        if entry.is_none() {
+            self.history.insert(key.clone(), (false, vec![]));
+        } else if let Some(old) = old {
+            let (_, replaced) = self.history.entry(key.clone()).or_insert((true, vec![]));
+            replaced.extend(chain);
+            replaced.push(old);
+        }
+        self.entries.insert(key, entry);
+    }
+
+    /// Record a `setrange` at `offset` as the last op on `key` so far, building on the
+    /// ones before it.
+    fn patch(&mut self, key: Vec<u8>, offset: Offset) {
+        let chain = match self.entries.insert(key.clone(), Some(offset)) {
+            Some(Some(previous)) => {
+                let (continues, mut chain) = self.chains.remove(&key).unwrap_or_default();
+                chain.push(previous);
+                (continues, chain)
+            }
+            // Removed earlier in the file, so patching a fresh value.
+            Some(None) => (false, vec![]),
+            None => (true, vec![]),
+        };
+        self.chains.insert(key, chain);
+    }
+}
+
+/// Allocate disk space for `len` bytes past the end of `file`, without changing its
+/// length, so that appends don't fragment it. A no-op where that isn't supported.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) -> crate::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let end = file.metadata()?.len();
+    // SAFETY: the descriptor is valid for as long as `file` is borrowed.
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            end as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    match std::io::Error::last_os_error() {
+        e if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+        e => Err(e.into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &File, _len: u64) -> crate::Result<()> {
+    Ok(())
+}
+
+/// Replay the contents of the log file numbered `segment`, at `file`.
+///
+/// Records that can't be read are skipped, resuming at the next thing that looks like
+/// the start of a record, and left to the caller to deal with. So are ops batched with
+/// the op after them, if that op never made it.
+fn replay_log(contents: &[u8], file: &Path, segment: u64) -> crate::Result<ReplayedLog> {
+    let codec = header::skip(&mut &contents[..])?;
+    let mut replayed = ReplayedLog {
+        entries: HashMap::new(),
+        chains: HashMap::new(),
+        history: HashMap::new(),
+        last_seq: 0,
+        redundant_size: 0,
+        corrupt: vec![],
+        codec,
+    };
+    // Where the last op that took effect ended, and where the last op read ended.
+    let mut committed = header::HEADER_LEN;
+    let mut start = committed;
+    // Batched ops read since, waiting on the op that completes their batch.
+    let mut pending = vec![];
+    while start < contents.len() {
+        let error = loop {
+            let (op, end) = match codec.decode(&contents[start..]) {
+                Ok(Some((op, len))) => (op, start + len),
+                Ok(None) => break None,
+                Err(e) => break Some(e),
+            };
+            let key = keys::of_op(&op);
+            let seq = op.seq();
+            let (entry, patch) = match op {
+                Op::Set {
+                    value, compressed, ..
+                } => {
+                    let value_len = compressed.map_or(value.len(), |c| c.len as usize);
+                    let offset = Offset::new(segment, start, end, value_len, value.len());
+                    (Some(offset), false)
+                }
+                Op::SetRange { len, patch, .. } => {
+                    let offset = Offset::new(segment, start, end, len as usize, patch.len());
+                    (Some(offset), true)
+                }
+                Op::Rm { batched: true, .. } => {
+                    pending.push((key, end - start));
+                    start = end;
+                    continue;
+                }
+                Op::Rm { .. } => {
+                    replayed.redundant_size += end - start;
+                    (None, false)
+                }
+            };
+            for (key, len) in pending.drain(..) {
+                replayed.redundant_size += len;
+                replayed.replace(key, None);
+            }
+            match entry {
+                Some(offset) if patch => replayed.patch(key, offset),
+                entry => replayed.replace(key, entry),
+            }
+            // Batched ops are numbered before the op completing them, so this covers them.
+            replayed.last_seq = replayed.last_seq.max(seq.unwrap_or(0));
+            start = end;
+            committed = end;
+        };
+
+        // Anything read since the last op that took effect is given up on, along with
+        // the op that couldn't be read.
+        let skip_padding = |pos: usize| pos + codec.padding(&contents[pos..]);
+        let (resume, error) = match error {
+            Some(e) => (
+                inspect::next_record_start(codec, contents, skip_padding(start)),
+                e,
+            ),
+            None if !pending.is_empty() => (contents.len(), "incomplete batch".to_string()),
+            None => break,
+        };
+        let offset = skip_padding(committed);
+        replayed.corrupt.push(CorruptRecord {
+            file: file.to_path_buf(),
+            offset: offset as u64,
+            len: (resume - offset) as u64,
+            error,
+        });
+        replayed.redundant_size += resume - committed;
+        pending.clear();
+        start = resume;
+        committed = resume;
+    }
+    Ok(replayed)
+}
+
+/// Replay a sealed log file, returning the ops it leaves behind and its checksum.
+fn replay_sealed(log_path: &Path, segment: u64) -> crate::Result<(ReplayedLog, u32)> {
+    let file = manifest::log_file(log_path, segment);
+    let contents = fs::read(&file)?;
+    let checksum = crc32fast::hash(&contents);
+    Ok((replay_log(&contents, &file, segment)?, checksum))
+}
+
+/// Take the records of a log file that couldn't be read, failing unless `mode` allows
+/// skipping them.
+fn skip_corrupt(
+    corrupt: &mut Vec<CorruptRecord>,
+    mode: RecoveryMode,
+) -> crate::Result<Vec<CorruptRecord>> {
+    if let Some(record) = corrupt.first() {
+        if mode != RecoveryMode::SkipCorrupt {
+            return Err(KvsError::Corrupt(format!(
+                "unreadable record at offset {} of log file {}: {}",
+                record.offset,
+                record.file.display(),
+                record.error
+            )));
+        }
+    }
+    for record in corrupt.iter() {
+        log::warn!(
+            "skipping unreadable record at offset {} of log file {}: {}",
+            record.offset,
+            record.file.display(),
+            record.error
+        );
+    }
+    Ok(std::mem::take(corrupt))
+}
+
+/// Replay the sealed log files of a store, spread across up to `threads` threads.
+fn replay_all_sealed(
+    log_path: &Path,
+    sealed: &[Segment],
+    threads: usize,
+) -> crate::Result<Vec<(ReplayedLog, u32)>> {
+    if threads <= 1 || sealed.len() <= 1 {
+        return sealed
+            .iter()
+            .map(|s| replay_sealed(log_path, s.number))
+            .collect();
+    }
+
+    let pool = SharedQueueThreadPool::new(threads.min(sealed.len()) as u32)?;
+    let (sender, receiver) = mpsc::channel();
+    for (i, segment) in sealed.iter().enumerate() {
+        let sender = sender.clone();
+        let log_path = log_path.to_path_buf();
+        let number = segment.number;
+        pool.spawn(move || {
+            let _ = sender.send((i, replay_sealed(&log_path, number)));
+        });
+    }
+    drop(sender);
+
+    let mut replayed: Vec<_> = sealed.iter().map(|_| None).collect();
+    for (i, result) in receiver {
+        replayed[i] = Some(result?);
+    }
+    replayed
+        .into_iter()
+        .map(|r| r.ok_or_else(|| KvsError::Corrupt("a log file failed to replay".to_string())))
+        .collect()
+}
+
+/// Create the log file numbered `number`, truncating it if it already exists.
+fn create_log_file(
+    log_path: &Path,
+    number: u64,
+    options: &KvStoreOptions,
+) -> crate::Result<(std::path::PathBuf, BufWriter<File>)> {
+    let path = manifest::log_file(log_path, number);
+    let fh = File::options()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(&path)?;
+    let mut fh = BufWriter::with_capacity(options.write_buffer_size, fh);
+    header::write(&mut fh, options.codec)?;
+    fh.flush()?;
+    Ok((path, fh))
+}
+
+/// Create the directory that will hold `log_path`, along with any missing parents.
+fn create_log_dir(log_path: &Path) -> crate::Result<()> {
+    let dir = match log_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => return Ok(()),
+    };
+
+    if let Some(file) = dir.ancestors().find(|p| p.is_file()) {
+        return Err(KvsError::NotADirectory(file.to_path_buf()));
+    }
+    std::fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+impl Generation {
+    /// Move the generation's log files from beside `from` to beside `to`.
+    fn relocate(mut self, from: &Path, to: &Path, options: &KvStoreOptions) -> crate::Result<Self> {
+        self.fh.flush()?;
+        let numbers = self.sealed.iter().map(|s| s.number).chain([self.number]);
+        for number in numbers {
+            move_file(
+                &manifest::log_file(from, number),
+                &manifest::log_file(to, number),
+            )?;
+        }
+        let path = manifest::log_file(to, self.number);
+        let file = File::options().read(true).write(true).open(path)?;
+        self.fh = BufWriter::with_capacity(options.write_buffer_size, file);
+        self.fh.seek(SeekFrom::End(0))?;
+        Ok(self)
+    }
+
+    /// Append whatever was written to `store`'s active log file past `snapshot_len`, and
+    /// sync it, returning the length of that tail and the offset it was appended at.
+    fn carry_tail(
+        &mut self,
+        store: &mut KvStoreInner,
+        snapshot_len: u64,
+    ) -> crate::Result<(u64, u64)> {
+        let tail_len = store.fh.stream_position()? - snapshot_len;
+        let mut reader = File::open(&store.fp)?;
+        reader.seek(SeekFrom::Start(snapshot_len))?;
+        let compacted_len = self.fh.seek(SeekFrom::End(0))?;
+        std::io::copy(&mut reader.take(tail_len), &mut self.fh)?;
+        self.fh.flush()?;
+        self.fh.get_ref().sync_all()?;
+        Ok((tail_len, compacted_len))
+    }
+}
+
+/// Delete whatever log files of a generation numbered up from `first` were written
+/// beside `log_path`, after it failed.
+fn remove_generation(log_path: &Path, first: u64) {
+    for number in first.. {
+        if fs::remove_file(manifest::log_file(log_path, number)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Move the file at `from` to `to`, copying it if they're on different filesystems.
+fn move_file(from: &Path, to: &Path) -> crate::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            let tmp = manifest::with_suffix(to, ".tmp");
+            let copied = fs::copy(from, &tmp)
+                .and_then(|_| File::open(&tmp)?.sync_all())
+                .and_then(|_| fs::rename(&tmp, to));
+            if copied.is_err() {
+                let _ = fs::remove_file(&tmp);
+            }
+            copied?;
+            fs::remove_file(from)?;
+            Ok(())
+        }
+        moved => Ok(moved?),
+    }
+}
+
+/// Append `op` to the log behind `writer` in `codec`, returning the offsets it was
+/// written between.
+fn write_op(writer: &mut BufWriter<File>, codec: Codec, op: &Op) -> crate::Result<(usize, usize)> {
+    let start = writer.seek(SeekFrom::End(0))?;
+    let mut record = vec![];
+    codec.encode(op, &mut record)?;
+    writer.write_all(&record)?;
+    writer.flush()?;
+    let end = writer.stream_position()?;
+    Ok((start as usize, end as usize))
+}
+
+/// Append `ops` to the log in a single write, numbering them in order as the writes after
+/// `last_seq`, which moves on to the last of them once they're written. Returns the
+/// offsets each op was written between.
+fn write_numbered<'a>(
+    writer: &mut BufWriter<File>,
+    codec: Codec,
+    last_seq: &mut u64,
+    ops: impl IntoIterator<Item = &'a mut Op>,
+) -> crate::Result<Vec<(usize, usize)>> {
+    let mut seq = *last_seq;
+    let mut batch = Batch::new(writer, codec)?;
+    let spans = ops
+        .into_iter()
+        .map(|op| {
+            seq += 1;
+            op.set_seq(seq);
+            batch.write(op)
+        })
+        .collect::<crate::Result<Vec<_>>>()?;
+    batch.finish()?;
+    *last_seq = seq;
+    Ok(spans)
+}
+
+/// Ops appended to the log one after another, flushed together at the end.
+struct Batch<'a> {
+    writer: &'a mut BufWriter<File>,
+    codec: Codec,
+    /// Where the next op will start.
+    pos: usize,
+    /// Each op is serialized here first, to learn its length.
+    scratch: Vec<u8>,
+}
+
+impl<'a> Batch<'a> {
+    fn new(writer: &'a mut BufWriter<File>, codec: Codec) -> crate::Result<Self> {
+        let pos = writer.seek(SeekFrom::End(0))? as usize;
+        Ok(Batch {
+            writer,
+            codec,
+            pos,
+            scratch: vec![],
+        })
+    }
+
+    /// Append `op`, returning the offsets it will be written between.
+    fn write(&mut self, op: &Op) -> crate::Result<(usize, usize)> {
+        self.scratch.clear();
+        self.codec.encode(op, &mut self.scratch)?;
+        self.writer.write_all(&self.scratch)?;
+        let start = self.pos;
+        self.pos += self.scratch.len();
+        Ok((start, self.pos))
+    }
+
+    fn finish(self) -> crate::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Read the op at `offset` in the log, written in `codec`.
+fn read_op<R: Read + Seek>(mut reader: R, codec: Codec, offset: &Offset) -> crate::Result<Op> {
+    reader.seek(SeekFrom::Start(offset.start() as u64))?;
+    let mut record = vec![0; offset.len()];
+    reader.read_exact(&mut record)?;
+    decode_op(codec, &record)
+}
+
+/// The log file beside `log_path` that `offset` is in, from `readers` if it's been
+/// opened already.
+fn open_reader<'a>(
+    readers: &'a mut HashMap<u64, File>,
+    log_path: &Path,
+    offset: &Offset,
+) -> crate::Result<&'a mut File> {
+    Ok(match readers.entry(offset.segment()) {
+        Entry::Occupied(e) => e.into_mut(),
+        Entry::Vacant(e) => e.insert(File::open(manifest::log_file(log_path, offset.segment()))?),
+    })
+}
+
+/// Decode the op whose record is `record`, written in `codec`.
+fn decode_op(codec: Codec, record: &[u8]) -> crate::Result<Op> {
+    match codec.decode(record) {
+        Ok(Some((op, _))) => Ok(op),
+        Ok(None) => Err(KvsError::Corrupt("missing record".to_string())),
+        Err(e) => Err(KvsError::Corrupt(e)),
+    }
+}
+
+/// Undo any compression applied to a value when it was written.
+fn decode_value(value: Vec<u8>, compressed: Option<Compressed>) -> crate::Result<Vec<u8>> {
+    match compressed {
+        Some(Compressed { algorithm, len }) => algorithm.decompress(&value, len as usize),
+        None => Ok(value),
+    }
+}
+
+impl KvStore {
+    /// Open the KvStore at a given path.
+    ///
+    /// The store stays locked for as long as this handle or any clone of it is alive, so
+    /// opening it again in the meantime, even from the same process, fails with
+    /// [`KvsError::AlreadyLocked`].
+    pub fn open(path: impl Into<std::path::PathBuf>) -> crate::Result<Self> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Open the KvStore whose log is the file at `log_path`, rather than a directory to
+    /// keep the log in.
+    ///
+    /// Shorthand for opening with [`literal_path`](KvStoreOptions::literal_path) set.
+    pub fn open_file(log_path: impl AsRef<Path>) -> crate::Result<Self> {
+        let options = KvStoreOptions::new().literal_path(true);
+        Self::open_with_options(log_path.as_ref(), options)
+    }
+
+    /// Open the KvStore at a given path, laying out its files as described by `options`.
+    pub fn open_with_options(
+        path: impl Into<std::path::PathBuf>,
+        options: KvStoreOptions,
+    ) -> crate::Result<Self> {
+        Self::open_with_report(path, options).map(|(store, _)| store)
+    }
+
+    /// Open the KvStore at a given path like
+    /// [`open_with_options`](KvStore::open_with_options), also reporting what was given
+    /// up on to recover from damage, as allowed by
+    /// [`recovery_mode`](KvStoreOptions::recovery_mode).
+    pub fn open_with_report(
+        path: impl Into<std::path::PathBuf>,
+        options: KvStoreOptions,
+    ) -> crate::Result<(Self, RecoveryReport)> {
+        if let Some(algorithm) = options.compression {
+            if !algorithm.is_supported() {
+                return Err(algorithm.unsupported());
+            }
+        }
+
+        let mode = options.recovery_mode;
+        let repair = options.repair_manifest || mode == RecoveryMode::SkipCorrupt;
+        let mut report = RecoveryReport::default();
+        let log_path = options.log_path(&path.into());
+        create_log_dir(&log_path)?;
+        let lock = manifest::lock(&log_path)?;
+        let (mut manifest, repairs) = Manifest::open(&log_path, repair)?;
+        for problem in &repairs {
+            log::warn!("repaired manifest: {}", problem);
+        }
+        report.manifest_repairs = repairs;
+        header::migrate(&log_path, &mut manifest, options.codec)?;
+        let path = manifest::log_file(&log_path, manifest.active);
+
+        let mut sealed = replay_all_sealed(&log_path, &manifest.sealed, options.replay_threads)?;
+        let mut repaired = false;
+        for (segment, (replayed, checksum)) in manifest.sealed.iter_mut().zip(&mut sealed) {
+            if segment.checksum != *checksum {
+                let problem = format!(
+                    "checksum mismatch in log file {}",
+                    manifest::log_file(&log_path, segment.number).display()
+                );
+                if !repair {
+                    return Err(KvsError::Corrupt(problem));
+                }
+                log::warn!("repaired manifest: {}", problem);
+                report.manifest_repairs.push(problem);
+                segment.checksum = *checksum;
+                repaired = true;
+            }
+            report
+                .skipped
+                .extend(skip_corrupt(&mut replayed.corrupt, mode)?);
+        }
+        if repaired {
+            manifest.store(&log_path)?;
+        }
+
+        // The active log file is still being appended to, so it always replays last.
+        let contents = fs::read(&path)?;
+        let mut active = replay_log(&contents, &path, manifest.active)?;
+        let torn = active
+            .corrupt
+            .last()
+            .is_some_and(|r| r.offset + r.len == contents.len() as u64);
+        let truncate = torn && mode != RecoveryMode::Strict;
+        if truncate {
+            let tail = active.corrupt.pop().unwrap();
+            log::warn!(
+                "truncating {} at offset {}: {}",
+                path.display(),
+                tail.offset,
+                tail.error
+            );
+            active.redundant_size -= tail.len as usize;
+            report.truncated.push(tail);
+        }
+        report
+            .skipped
+            .extend(skip_corrupt(&mut active.corrupt, mode)?);
+
+        let fh = File::options()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path.clone())?;
+        let compacted_seq = manifest.compacted_seq;
+
+        let mut inner = KvStoreInner {
+            log_path,
+            fp: path,
+            fh: BufWriter::with_capacity(options.write_buffer_size, fh),
+            index: OrdMap::new(),
+            chains: Chains::new(),
+            history: History::new(),
+            codecs: HashMap::new(),
+            readers: RefCell::default(),
+            redundant_size: 0,
+            last_compaction: None,
+            value_bytes: 0,
+            stored_value_bytes: 0,
+            key_bytes: 0,
+            live_size: 0,
+            evicted: 0,
+            bloom: options.new_bloom(0),
+            cache: options.cache_capacity.map(ValueCache::new),
+            memtable: None,
+            #[cfg(feature = "mmap")]
+            maps: options.mmap_reads.then(mmap::Maps::default),
+            manifest,
+            compacting: false,
+            subscribers: Subscribers::default(),
+            _lock: lock,
+            unsynced: false,
+            last_seq: compacted_seq,
+            appended: 0,
+            dir_unsynced: true,
+        };
+
+        if let Some(tail) = report.truncated.first() {
+            let file = inner.fh.get_ref();
+            file.set_len(tail.offset)?;
+            file.sync_data()?;
+        }
+        inner.fh.seek(SeekFrom::End(0))?;
+        let numbers: Vec<u64> = inner.manifest.sealed.iter().map(|s| s.number).collect();
+        for (number, (replayed, _)) in numbers.into_iter().zip(sealed) {
+            inner.codecs.insert(number, replayed.codec);
+            inner.merge(replayed);
+        }
+        let active_codec = active.codec;
+        inner.codecs.insert(inner.manifest.active, active_codec);
+        inner.merge(active);
+        inner.map_sealed()?;
+        // Only ever append in the codec the store was opened with.
+        if active_codec != options.codec {
+            inner.seal(&options)?;
+        }
+
+        let inner = Arc::new(Mutex::new(inner));
+        let options = Arc::new(options);
+        let flusher = options.memtable.map(|(max_bytes, interval)| {
+            Arc::new(Flusher::start(&inner, &options, max_bytes, interval))
+        });
+        let syncer = match options.durability {
+            Durability::Periodic(interval) => Some(Arc::new(PeriodicSync::start(&inner, interval))),
+            _ => None,
+        };
+        let store = KvStore {
+            flusher,
+            syncer,
+            inner,
+            options,
+            compaction: Arc::new(Mutex::new(())),
+            compactions: Arc::new(Tracker::default()),
+            key_locks: Arc::new(KeyLocks::new()),
+            commit: Arc::new(GroupCommit::default()),
+        };
+        Ok((store, report))
+    }
+
+    /// Delete the store at `path`, leaving any other files in its directory alone.
+    ///
+    /// Fails with [`KvsError::AlreadyLocked`] if the store is open.
+    pub fn destroy(path: impl Into<std::path::PathBuf>) -> crate::Result<()> {
+        Self::destroy_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Delete the store at `path`, whose files are laid out as described by `options`.
+    pub fn destroy_with_options(
+        path: impl Into<std::path::PathBuf>,
+        options: KvStoreOptions,
+    ) -> crate::Result<()> {
+        let log_path = options.log_path(&path.into());
+        match log_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() && !dir.exists() => return Ok(()),
+            _ => {}
+        }
+        let _lock = manifest::lock(&log_path)?;
+        manifest::remove_all(&log_path)
+    }
+
+    /// Flush every write made so far, sync it to disk, and close this handle.
+    ///
+    /// Writes held in the memtable are appended to the log first. If clones of this
+    /// handle are still alive the store stays open for them, but everything written
+    /// before this was called is still synced.
+    pub fn close(self) -> crate::Result<()> {
+        let mut store = self.inner.lock().unwrap();
+        store.sync()?;
+        store.fh.get_ref().sync_all()?;
+        drop(store);
+        drop(self);
+        Ok(())
+    }
+
+    /// Close this handle and delete the store.
+    ///
+    /// Fails with [`KvsError::AlreadyLocked`] if any clones of this handle are still
+    /// alive.
+    pub fn close_and_destroy(self) -> crate::Result<()> {
+        let log_path = self.inner.lock().unwrap().log_path.clone();
+        drop(self);
+        let _lock = manifest::lock(&log_path)?;
+        manifest::remove_all(&log_path)
+    }
+
+    /// Check the store at `path` for damage, without opening or modifying it.
+    ///
+    /// Every record in every log file is read, sealed files are checked against their
+    /// checksums, and the manifest is checked against the files on disk.
+    pub fn verify(path: impl Into<std::path::PathBuf>) -> crate::Result<VerifyReport> {
+        Self::verify_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Check the store at `path`, whose files are laid out as described by `options`, like
+    /// [`verify`](KvStore::verify).
+    pub fn verify_with_options(
+        path: impl Into<std::path::PathBuf>,
+        options: KvStoreOptions,
+    ) -> crate::Result<VerifyReport> {
+        verify::verify(&options.log_path(&path.into()))
+    }
+
+    /// Repair what can be repaired of the store at `path`, returning what's left wrong.
+    ///
+    /// A corrupt record at the very end of the log file being appended to, as left by a
+    /// crash mid-write, is cut off, and the manifest is repaired as it is when opening
+    /// with [`repair_manifest`](KvStoreOptions::repair_manifest). Corruption anywhere
+    /// else is left alone.
+    pub fn repair(path: impl Into<std::path::PathBuf>) -> crate::Result<VerifyReport> {
+        Self::repair_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Repair the store at `path` like [`repair`](KvStore::repair), opening it with
+    /// `options`, which also describe how its files are laid out.
+    pub fn repair_with_options(
+        path: impl Into<std::path::PathBuf>,
+        options: KvStoreOptions,
+    ) -> crate::Result<VerifyReport> {
+        verify::repair(&path.into(), options)
+    }
+
+    /// Walk every record in the log file at `path`, without opening the store.
+    ///
+    /// `path` is a single log file, such as `kvstore-logs` or one of its numbered
+    /// segments. Corrupt records are reported rather than ending the walk.
+    pub fn inspect(path: impl AsRef<Path>) -> crate::Result<LogInspector> {
+        LogInspector::open(path.as_ref())
+    }
+
+    /// Build a new store at `path` by applying `ops` to it in turn, such as the records
+    /// read back from another store's log with [`KvStore::inspect`].
+    ///
+    /// Each op is written as the store would write it itself, so the store only depends
+    /// on the ops given: a `set` is written uncompressed whatever `compression` it was
+    /// read with, and the `len` of a `setrange` is worked out afresh. Removing a key that
+    /// doesn't exist does nothing, as it does when a log is replayed. Fails if `path`
+    /// already holds a store. If an op fails, the store built so far is deleted again.
+    pub fn from_ops(
+        path: impl Into<std::path::PathBuf>,
+        ops: impl IntoIterator<Item = Record>,
+    ) -> crate::Result<Self> {
+        let path = path.into();
+        let options = KvStoreOptions::default();
+        if options.log_path(&path).exists() {
+            let error = io::Error::new(io::ErrorKind::AlreadyExists, "a store already exists");
+            return Err(error.into());
+        }
+        let store = Self::open_with_options(&path, options.clone())?;
+        if let Err(e) = store.apply_ops(ops) {
+            drop(store);
+            if let Err(e) = Self::destroy_with_options(&path, options) {
+                log::warn!("failed to delete the partly built store: {}", e);
+            }
+            return Err(e);
+        }
+        Ok(store)
+    }
+
+    /// Apply each of `ops` in turn, as [`from_ops`](KvStore::from_ops) does.
+    fn apply_ops(&self, ops: impl IntoIterator<Item = Record>) -> crate::Result<()> {
+        for op in ops {
+            match op {
+                Record::Set {
+                    bucket, key, value, ..
+                } => {
+                    self.set_in(bucket.as_deref(), &key, &value)?;
+                }
+                Record::Rm { bucket, key } => match self.remove_in(bucket.as_deref(), &key) {
+                    Ok(_) | Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                },
+                Record::SetRange {
+                    bucket,
+                    key,
+                    offset,
+                    patch,
+                    ..
+                } => {
+                    let offset = offset.try_into().map_err(|_| KvsError::TooLarge)?;
+                    self.setrange_in(bucket.as_deref(), &key, offset, &patch)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Build the `set` op for the key indexed as `encoded`, written at `written_at`,
+    /// compressing the value if configured to.
+    fn encode_set(
+        &self,
+        encoded: &[u8],
+        value: Vec<u8>,
+        written_at: Option<u64>,
+    ) -> crate::Result<Op> {
+        if matches!(self.options.max_value_bytes, Some(max) if value.len() > max) {
+            return Err(KvsError::ValueTooLarge);
+        }
+        if encoded.len() + value.len() > MAX_ENTRY_LEN {
+            return Err(KvsError::TooLarge);
+        }
+        let (bucket, key) = keys::decode(encoded);
+        let (key, bucket) = (key.to_vec(), bucket.map(str::to_owned));
+        if let Some(algorithm) = self.options.compression {
+            if value.len() >= self.options.compression_min_size {
+                let compressed = algorithm.compress(&value)?;
+                // Incompressible values are cheaper to store and read as they are.
+                if compressed.len() < value.len() {
+                    return Ok(Op::Set {
+                        key,
+                        compressed: Some(Compressed {
+                            algorithm,
+                            len: value.len() as u64,
+                        }),
+                        value: compressed,
+                        bucket,
+                        written_at,
+                        seq: None,
+                    });
+                }
+            }
+        }
+        Ok(Op::Set {
+            key,
+            value,
+            compressed: None,
+            bucket,
+            written_at,
+            seq: None,
+        })
+    }
+
+    /// Rewrite the log so that it only contains live entries.
+    ///
+    /// This runs automatically once the redundant space crosses the configured
+    /// threshold, but can also be called explicitly. Values are re-encoded according to
+    /// the store's current compression setting. Live entries keep the order they were
+    /// last written in, rather than being sorted by key, so that recently written keys
+    /// stay together.
+    ///
+    /// Live entries are copied into the next generation of the log without holding the
+    /// store's lock, so reads and writes carry on while it runs. Once they're copied, the
+    /// store is locked just long enough to carry over whatever was written in the
+    /// meantime, point the manifest at the new generation, and swap it in.
+    pub fn compact(&self) -> crate::Result<()> {
+        let compacting = self.compaction.lock().unwrap();
+        self.compact_locked(compacting, None)?;
+        Ok(())
+    }
+
+    /// Compact like [`compact`](KvStore::compact), but write the new generation of the
+    /// log in the directory `scratch` rather than next to the old one.
+    ///
+    /// Each new log file is moved into the store's directory once it's written: renamed
+    /// if `scratch` is on the same filesystem, or else copied across, synced, and then
+    /// renamed into place. Only then is the new generation swapped in, so a failure at
+    /// any point leaves the store as it was. Copying across still needs room for the new
+    /// generation next to the old one, but only for as long as the swap takes, rather
+    /// than for the whole of the compaction.
+    pub fn compact_to(&self, scratch: impl AsRef<Path>) -> crate::Result<()> {
+        let compacting = self.compaction.lock().unwrap();
+        self.compact_locked(compacting, Some(scratch.as_ref()))?;
+        Ok(())
+    }
+
+    /// Lock the store, first appending any writes held in the memtable to the log, for
+    /// reads of the index.
+    fn flushed(&self) -> crate::Result<MutexGuard<'_, KvStoreInner>> {
+        let mut store = self.inner.lock().unwrap();
+        store.flush_memtable()?;
+        Ok(store)
+    }
+
+    /// Wait for the writes made so far to be synced to disk, with
+    /// [`Durability::EveryWrite`].
+    fn commit(&self) -> crate::Result<()> {
+        if self.options.durability != Durability::EveryWrite {
+            return Ok(());
+        }
+        let appended = self.flushed()?.appended;
+        self.commit.wait(appended, self.options.commit_delay, || {
+            sync_appended(&self.inner)
+        })
+    }
+
+    /// Compact if enough redundant space has built up, no compaction is running yet, and
+    /// compactions aren't paused.
+    ///
+    /// Keys are evicted first if the log has outgrown `max_disk_bytes`. Returns the
+    /// report of the compaction, if there was one.
+    fn maybe_compact(&self) -> crate::Result<Option<CompactionReport>> {
+        if self.compactions.is_paused() || self.compactions.is_running() {
+            return Ok(None);
+        }
+        let over_cap = self.over_disk_cap()?;
+        if !over_cap && !self.needs_compaction() {
+            return Ok(None);
+        }
+        match self.compaction.try_lock() {
+            Ok(compacting) => {
+                // A compaction may have finished between checking and taking the lock,
+                // leaving nothing to do.
+                let over_cap = over_cap && self.over_disk_cap()?;
+                if !over_cap && !self.needs_compaction() {
+                    return Ok(None);
+                }
+                if over_cap {
+                    self.evict()?;
+                }
+                self.compact_locked(compacting, None).map(Some)
+            }
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+        }
+    }
+
+    /// Whether the log has outgrown `max_disk_bytes`.
+    fn over_disk_cap(&self) -> crate::Result<bool> {
+        match self.options.max_disk_bytes {
+            Some(max) => Ok(self.inner.lock().unwrap().log_size()? > max),
+            None => Ok(false),
+        }
+    }
+
+    /// Remove the least recently written keys until the rest would compact to under the
+    /// low watermark of `max_disk_bytes`.
+    ///
+    /// The keys to evict are picked without holding the store's lock, so writes carry on
+    /// meanwhile; any key written since is no longer among the oldest, and is kept.
+    fn evict(&self) -> crate::Result<()> {
+        let max = match self.options.max_disk_bytes {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let target = max / 10 * 9;
+        let store = self.inner.lock().unwrap();
+        let index = store.index.clone();
+        let mut live_size = store.live_size;
+        drop(store);
+
+        let mut victims = vec![];
+        for (key, offset) in in_log_order(&index) {
+            if self.projected_size(live_size) <= target {
+                break;
+            }
+            live_size -= offset.len() as u64;
+            victims.push((key, *offset));
+        }
+        if victims.is_empty() {
+            return Ok(());
+        }
+
+        let guards = self
+            .key_locks
+            .lock_many(victims.iter().map(|(key, _)| *key));
+        let mut store = self.flushed()?;
+        let keys: Vec<&[u8]> = victims
+            .iter()
+            .filter(|(key, offset)| store.index.get(*key) == Some(offset))
+            .map(|(key, _)| *key)
+            .collect();
+        store.append_rms(&keys, ChangeEvent::evicted)?;
+        store.evicted += keys.len() as u64;
+        store.seal_if_full(&self.options)?;
+        drop(store);
+        drop(guards);
+        log::info!("evicted {} keys to stay under {} bytes", keys.len(), max);
+        Ok(())
+    }
+
+    /// The size(in bytes) the log would be after a compaction, if its live records took
+    /// up `live_size` bytes.
+    fn projected_size(&self, live_size: u64) -> u64 {
+        // Every file of the new generation starts with a header.
+        let files = match self.options.max_segment_size {
+            Some(max) => live_size / max.max(1) as u64 + 1,
+            None => 1,
+        };
+        live_size + files * header::HEADER_LEN as u64
+    }
+
+    fn compact_locked(
+        &self,
+        _compacting: MutexGuard<()>,
+        scratch: Option<&Path>,
+    ) -> crate::Result<CompactionReport> {
+        self.compactions.started();
+        let result = self.rewrite_log(scratch);
+        self.compactions.finished(result.as_ref().ok());
+        let report = result?;
+        if let Some(on_compaction) = &self.options.on_compaction {
+            on_compaction(report.clone());
+        }
+        Ok(report)
+    }
+
+    /// Compact the log, writing the new generation in `scratch` if given, with the
+    /// compaction lock held.
+    fn rewrite_log(&self, scratch: Option<&Path>) -> crate::Result<CompactionReport> {
+        let started = Instant::now();
+
+        let mut store = self.flushed()?;
+        store.compacting = true;
+        let log_path = store.log_path.clone();
+        let active = store.manifest.active;
+        let old_logs: Vec<u64> = store.manifest.sealed.iter().map(|s| s.number).collect();
+        let snapshot_len = store.fh.stream_position()?;
+        let compacted_seq = store.last_seq;
+        // Sharing the index's nodes, so that this is cheap however many keys there are.
+        let offsets = store.index.clone();
+        let chains = store.chains.clone();
+        let codecs = store.codecs.clone();
+        drop(store);
+
+        let copied = match scratch {
+            Some(scratch) => {
+                let target = scratch.join(log_path.file_name().unwrap_or_default());
+                let copied = self
+                    .copy_live(&log_path, &target, active + 1, offsets, &chains, &codecs)
+                    .and_then(|generation| generation.relocate(&target, &log_path, &self.options));
+                if copied.is_err() {
+                    remove_generation(&target, active + 1);
+                }
+                copied
+            }
+            None => self.copy_live(&log_path, &log_path, active + 1, offsets, &chains, &codecs),
+        };
+        let mut store = self.inner.lock().unwrap();
+        store.compacting = false;
+        if copied.is_err() {
+            remove_generation(&log_path, active + 1);
+        }
+        let mut generation = copied?;
+
+        // Carry over whatever was written to the active log file since the snapshot.
+        let carried = store.log_size().and_then(|bytes_before| {
+            let (tail_len, compacted_len) = generation.carry_tail(&mut store, snapshot_len)?;
+            #[cfg(feature = "failpoints")]
+            if self.options.fail_compaction_before_swap {
+                return Err(std::io::Error::other("compaction failed before the swap").into());
+            }
+            Ok((bytes_before, tail_len, compacted_len))
+        });
+        let (bytes_before, tail_len, compacted_len) = match carried {
+            Ok(carried) => carried,
+            Err(e) => {
+                drop(generation);
+                remove_generation(&log_path, active + 1);
+                return Err(e);
+            }
+        };
+
+        // Remap the index in place rather than rebuilding it, as this holds up every
+        // reader and writer.
+        let (snapshot_len, compacted_len) = (snapshot_len as usize, compacted_len as usize);
+        let inner = &mut *store;
+        let in_tail =
+            |offset: &Offset| offset.segment() == active && offset.start() >= snapshot_len;
+        for (key, copied) in &generation.index {
+            // Keys removed or overwritten since the snapshot aren't where they were copied from.
+            match inner.index.get_mut(key) {
+                Some(offset) if !in_tail(offset) => *offset = *copied,
+                _ => {}
+            }
+        }
+        let tail: Vec<Box<[u8]>> = inner
+            .index
+            .iter()
+            .filter(|(_, offset)| in_tail(offset))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut live_tail = 0;
+        let moved = |offset: &Offset| {
+            offset.moved(
+                generation.number,
+                offset.start() - snapshot_len + compacted_len,
+            )
+        };
+        // History went with the old records, but for versions written since, which carry
+        // on from the copy, unless the key's chain does.
+        for (key, history) in std::mem::take(&mut inner.history) {
+            if !inner.index.get(&key).is_some_and(in_tail) {
+                continue;
+            }
+            let chain = inner.chains.get(&key).map_or(&[][..], Vec::as_slice);
+            let mut carried = vec![];
+            if !history.iter().all(in_tail) && chain.iter().all(in_tail) {
+                carried.push(generation.index[&key]);
+            }
+            carried.extend(history.iter().filter(|link| in_tail(link)).map(moved));
+            if !carried.is_empty() {
+                inner.history.insert(key, carried);
+            }
+        }
+        // Chains were folded into the copies of their keys, but for the patches written
+        // since, which carry on from the copy.
+        for (key, chain) in std::mem::take(&mut inner.chains) {
+            if !inner.index.get(&key).is_some_and(in_tail) {
+                continue;
+            }
+            let mut carried = vec![];
+            if !chain.iter().all(in_tail) {
+                carried.push(generation.index[&key]);
+            }
+            for link in chain.iter().filter(|link| in_tail(link)) {
+                live_tail += link.len();
+                carried.push(moved(link));
+            }
+            inner.chains.insert(key, carried);
+        }
+        for key in tail {
+            let offset = inner.index.get_mut(&key).expect("tail keys are live");
+            live_tail += offset.len();
+            *offset = moved(offset);
+        }
+        inner.bloom = self.options.new_bloom(inner.index.len());
+        inner.live_size = 0;
+        inner.value_bytes = 0;
+        inner.stored_value_bytes = 0;
+        for (key, offset) in inner.index.iter() {
+            inner.live_size += offset.len() as u64;
+            inner.value_bytes += offset.value_len() as u64;
+            inner.stored_value_bytes += offset.stored_len() as u64;
+            if let Some(bloom) = &mut inner.bloom {
+                bloom.insert(key);
+            }
+        }
+        for link in inner.chains.values().flatten() {
+            inner.live_size += link.len() as u64;
+            inner.stored_value_bytes += link.stored_len() as u64;
+        }
+        inner.redundant_size = tail_len as usize - live_tail;
+
+        let old_path = std::mem::replace(
+            &mut store.fp,
+            manifest::log_file(&log_path, generation.number),
+        );
+        store.fh = generation.fh;
+        store.codecs = generation
+            .sealed
+            .iter()
+            .map(|s| s.number)
+            .chain([generation.number])
+            .map(|number| (number, self.options.codec))
+            .collect();
+        store.readers.get_mut().clear();
+        store.manifest.sealed = generation.sealed;
+        store.manifest.active = generation.number;
+        store.manifest.compactions += 1;
+        store.last_compaction = Some(Instant::now());
+        store.manifest.compacted_len = compacted_len as u64;
+        store.manifest.compacted_seq = compacted_seq;
+        store.manifest.store(&log_path)?;
+        store.map_sealed()?;
+        // The new generation was synced as it was written, but not the directory.
+        store.unsynced = false;
+        store.dir_unsynced = true;
+        let bytes_after = store.log_size()?;
+        drop(store);
+
+        // Reads open log files afresh under the lock, so nothing refers to the old ones now.
+        for number in old_logs {
+            fs::remove_file(manifest::log_file(&log_path, number))?;
+        }
+        fs::remove_file(old_path)?;
+
+        Ok(CompactionReport {
+            bytes_before,
+            bytes_after,
+            duration: started.elapsed(),
+        })
+    }
+
+    /// Copy the ops at `offsets`, in log files beside `log_path` written in `codecs`, into
+    /// new log files beside `target` numbered up from `first`, folding in `chains`.
+    fn copy_live(
+        &self,
+        log_path: &Path,
+        target: &Path,
+        first: u64,
+        offsets: Index,
+        chains: &Chains,
+        codecs: &HashMap<u64, Codec>,
+    ) -> crate::Result<Generation> {
+        let (_, fh) = create_log_file(target, first, &self.options)?;
+        let mut generation = Generation {
+            sealed: vec![],
+            number: first,
+            fh,
+            index: HashMap::with_capacity(offsets.len()),
+        };
+        let mut readers = HashMap::new();
+        let live = in_log_order(&offsets);
+        for (copied, &(key, offset)) in live.iter().enumerate() {
+            self.compactions.copied(copied, live.len());
+            let chain = chains.get(key).map_or(&[][..], Vec::as_slice);
+            let ops = chain.iter().chain([offset]).map(|offset| {
+                let reader = open_reader(&mut readers, log_path, offset)?;
+                read_op(reader, codecs[&offset.segment()], offset)
+            });
+            // Keeping the time the value was written, rather than copied, and the number
+            // of the write, so that the copy is the size of the record it replaces.
+            let (value, written_at, seq) = patch::assemble(ops)?;
+            let mut op = self.encode_set(key, value, written_at)?;
+            if let Some(seq) = seq {
+                op.set_seq(seq);
+            }
+            let (start, end) = write_op(&mut generation.fh, self.options.codec, &op)?;
+            let offset = Offset::new(
+                generation.number,
+                start,
+                end,
+                offset.value_len(),
+                op.value_len(),
+            );
+            generation.index.insert(Box::from(key), offset);
+
+            if matches!(self.options.max_segment_size, Some(max) if end >= max) {
+                generation.fh.get_ref().sync_all()?;
+                let segment = Segment::seal(target, generation.number, end as u64)?;
+                generation.sealed.push(segment);
+                generation.number += 1;
+                (_, generation.fh) = create_log_file(target, generation.number, &self.options)?;
+            }
+        }
+        Ok(generation)
+    }
+
+    /// Write a compacted copy of the store, as it is now, to a new store at `dest`.
+    ///
+    /// Only the index is snapshotted under the store's lock; the live entries are then
+    /// copied without it, so reads and writes carry on meanwhile but don't show up in the
+    /// copy. Compactions wait until the checkpoint is done, so that the log files it
+    /// copies from stay put. The copy is synced to disk before this returns, and can be
+    /// opened with [`KvStore::open`] straight away. Fails if `dest` already holds a store.
+    pub fn checkpoint(&self, dest: impl AsRef<Path>) -> crate::Result<CheckpointReport> {
+        let started = Instant::now();
+        let _compacting = self.compaction.lock().unwrap();
+
+        let store = self.flushed()?;
+        let log_path = store.log_path.clone();
+        let index = store.index.clone();
+        let chains = store.chains.clone();
+        let codecs = store.codecs.clone();
+        let last_seq = store.last_seq;
+        drop(store);
+        let live = in_log_order(&index);
+
+        let dest_log = KvStoreOptions::default().log_path(dest.as_ref());
+        create_log_dir(&dest_log)?;
+        let file = File::options()
+            .create_new(true)
+            .write(true)
+            .open(&dest_log)?;
+        let mut fh = BufWriter::with_capacity(self.options.write_buffer_size, file);
+        let codec = self.options.codec;
+        header::write(&mut fh, codec)?;
+
+        // Copy each record as it is, compressed or not, re-encoding only those written
+        // in another codec. Patched values are written whole.
+        let mut readers = HashMap::new();
+        let mut record = vec![];
+        for &(key, offset) in &live {
+            if let Some(chain) = chains.get(key) {
+                let ops = chain.iter().chain([offset]).map(|offset| {
+                    let reader = open_reader(&mut readers, &log_path, offset)?;
+                    read_op(reader, codecs[&offset.segment()], offset)
+                });
+                let (value, written_at, seq) = patch::assemble(ops)?;
+                let mut op = self.encode_set(key, value, written_at)?;
+                if let Some(seq) = seq {
+                    op.set_seq(seq);
+                }
+                record.clear();
+                codec.encode(&op, &mut record)?;
+                fh.write_all(&record)?;
+                continue;
+            }
+            let reader = open_reader(&mut readers, &log_path, offset)?;
+            if codecs[&offset.segment()] != codec {
+                let op = read_op(reader, codecs[&offset.segment()], offset)?;
+                record.clear();
+                codec.encode(&op, &mut record)?;
+                fh.write_all(&record)?;
+                continue;
+            }
+            record.resize(offset.len(), 0);
+            reader.seek(SeekFrom::Start(offset.start() as u64))?;
+            reader.read_exact(&mut record)?;
+            fh.write_all(&record)?;
+        }
+        fh.flush()?;
+        let file = fh.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        let bytes = file.metadata()?.len();
+
+        // Every op before the checkpoint was compacted away, as far as the copy goes.
+        let manifest = Manifest {
+            compacted_seq: last_seq,
+            ..Manifest::new(bytes)
+        };
+        manifest.store(&dest_log)?;
+        let dir = match dest_log.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+
+        Ok(CheckpointReport {
+            keys: live.len(),
+            bytes,
+            duration: started.elapsed(),
+        })
+    }
+
+    /// Write every key in every bucket to `writer` in the export format, returning the
+    /// number of keys written.
+    ///
+    /// The export is of the store as it was when this was called, read from a
+    /// [`snapshot`](KvStore::snapshot) of it, so writes carry on meanwhile. Either engine
+    /// can [`import`](KvStore::import) it, which suits backups and moving data between
+    /// engines. Values are read and written one at a time, rather than all held in memory.
+    pub fn export(&self, writer: impl Write) -> crate::Result<u64> {
+        let mut exporter = Exporter::new(writer)?;
+        self.snapshot()?.export(&mut exporter)?;
+        exporter.finish()
+    }
+
+    /// Apply the export read from `reader`, as written by [`export`](KvStore::export) or
+    /// [`SledEngine::export`](crate::SledEngine::export), returning the number of keys
+    /// imported.
+    ///
+    /// The whole export is checked against its checksum before anything is written, and
+    /// fails with [`KvsError::Corrupt`] if it doesn't match. With [`ImportMode::Replace`],
+    /// every key in every bucket is then removed; with [`ImportMode::Merge`], keys the
+    /// export doesn't have are kept. The keys are set in batches, each under a single
+    /// lock, so if this fails part way through, the keys before the failed batch stay
+    /// imported.
+    pub fn import(&self, reader: impl Read, mode: ImportMode) -> crate::Result<u64> {
+        let records = export::read(reader)?;
+        if mode == ImportMode::Replace {
+            self.clear_prefix(&[])?;
+        }
+        let mut imported = 0;
+        let mut batch = vec![];
+        let mut batch_bytes = 0;
+        for record in records {
+            let (bucket, key, value) = record?;
+            batch_bytes += key.len() + value.len();
+            batch.push((keys::encode(bucket.as_deref(), &key), value));
+            if batch_bytes >= export::BATCH_BYTES {
+                imported += batch.len() as u64;
+                self.write_sets(batch.drain(..))?;
+                batch_bytes = 0;
+            }
+        }
+        if !batch.is_empty() {
+            imported += batch.len() as u64;
+            self.write_sets(batch)?;
+        }
+        Ok(imported)
+    }
+
+    /// Prepare for a bulk import of about `approx_keys` keys taking up `approx_bytes` of
+    /// log.
+    ///
+    /// Disk space is allocated for the log up front, without changing its length, so it
+    /// doesn't grow one small append at a time; with `max_segment_size` set, only the
+    /// active file's worth is allocated. The bloom filter, if enabled, is resized for
+    /// the extra keys now rather than saturating during the import. The index can't be
+    /// grown ahead of time.
+    pub fn reserve(&self, approx_keys: usize, approx_bytes: u64) -> crate::Result<()> {
+        let mut store = self.inner.lock().unwrap();
+        let len = match self.options.max_segment_size {
+            Some(max) => approx_bytes.min(max as u64),
+            None => approx_bytes,
+        };
+        store.fh.flush()?;
+        preallocate(store.fh.get_ref(), len)?;
+
+        let keys = store.index.len() + approx_keys;
+        if let Some(mut bloom) = self.options.new_bloom(keys) {
+            for key in store.index.keys() {
+                bloom.insert(key);
+            }
+            store.bloom = Some(bloom);
+        }
+        Ok(())
+    }
+
+    /// Receive a [`ChangeEvent`] for every write made from now on, in the order they hit
+    /// the log.
+    ///
+    /// Events are sent without blocking writers: a subscriber that falls too far behind
+    /// misses events, and is told how many with a [`ChangeEvent::Lagged`] once it catches
+    /// up. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<ChangeEvent> {
+        self.inner.lock().unwrap().subscribers.subscribe()
+    }
+
+    /// Remove every key in `keys` under a single lock and write, returning which ones
+    /// existed.
+    ///
+    /// Unlike [`remove`](KvsEngine::remove), missing keys don't fail the batch.
+    pub fn remove_many(&self, keys: &[String]) -> crate::Result<Vec<bool>> {
+        let keys: Vec<Vec<u8>> = keys
+            .iter()
+            .map(|key| keys::encode(None, key.as_bytes()))
+            .collect();
+        let keys: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+        let guards = self.key_locks.lock_many(keys.iter().copied());
+        let mut store = self.inner.lock().unwrap();
+        let existed = store.append_rms(&keys, ChangeEvent::removed)?;
+        store.seal_if_full(&self.options)?;
+        drop(store);
+        drop(guards);
+
+        self.commit()?;
+        self.maybe_compact()?;
+        Ok(existed)
+    }
+
+    /// Set every key-value pair in `entries` under a single lock, flushing the log once at
+    /// the end rather than after each pair.
+    ///
+    /// If this fails part way through, pairs already written may reappear when the store
+    /// is next opened.
+    pub fn populate(
+        &self,
+        entries: impl IntoIterator<Item = (String, String)>,
+    ) -> crate::Result<()> {
+        let pairs = entries
+            .into_iter()
+            .map(|(key, value)| (keys::encode(None, key.as_bytes()), value.into_bytes()));
+        self.write_sets(pairs)
+    }
+
+    /// Set every key, as encoded by [`keys::encode`], to its value in `pairs` under a
+    /// single lock, as [`populate`](KvStore::populate) does.
+    fn write_sets(&self, pairs: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>) -> crate::Result<()> {
+        let mut ops = pairs
+            .into_iter()
+            .map(|(key, value)| {
+                let value_len = value.len();
+                let op = self.encode_set(&key, value, Some(self.options.timestamp()))?;
+                Ok((key, value_len, op))
+            })
+            .collect::<crate::Result<Vec<_>>>()?;
+
+        let guards = self
+            .key_locks
+            .lock_many(ops.iter().map(|(key, ..)| key.as_slice()));
+        let mut store = self.flushed()?;
+        let active = store.manifest.active;
+        let codec = store.active_codec();
+        let inner = &mut *store;
+        let numbered = ops.iter_mut().map(|(_, _, op)| op);
+        let spans = write_numbered(&mut inner.fh, codec, &mut inner.last_seq, numbered)?;
+        let written: Vec<_> = ops
+            .into_iter()
+            .zip(spans)
+            .map(|((key, value_len, op), (start, end))| {
+                (
+                    key,
+                    Offset::new(active, start, end, value_len, op.value_len()),
+                )
+            })
+            .collect();
+
+        store.appended();
+        for (key, offset) in written {
+            if let Some(cache) = &mut store.cache {
+                cache.invalidate(&key);
+            }
+            store.subscribers.publish(ChangeEvent::set(&key));
+            store.insert_entry(key, offset);
+        }
+        store.seal_if_full(&self.options)?;
+        drop(store);
+        drop(guards);
+
+        self.commit()?;
+        self.maybe_compact()?;
+        Ok(())
+    }
+
+    /// Get a value by its key as [`get`](KvsEngine::get) does, but fail with
+    /// [`KvsError::WouldBlock`] rather than wait if another call holds the store's lock.
+    ///
+    /// For reads that would rather serve a stale value or shed load than stall behind a
+    /// write, or the end of a compaction.
+    pub fn try_get(&self, key: String) -> crate::Result<Option<String>> {
+        let mut store = match self.inner.try_lock() {
+            Ok(store) => store,
+            Err(TryLockError::WouldBlock) => return Err(KvsError::WouldBlock),
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+        };
+        match keys::with_encoded(None, key.as_bytes(), |encoded| store.read_value(encoded))? {
+            Some(value) => String::from_utf8(value)
+                .map(Some)
+                .map_err(|_| KvsError::InvalidUtf8(key)),
+            None => Ok(None),
+        }
+    }
+
+    /// Check whether `key` exists, without reading its value.
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        let key = keys::encode(None, key);
+        self.inner.lock().unwrap().contains(&key)
+    }
+
+    /// Atomically replace the value of `key` with the result of applying `f` to it.
+    ///
+    /// `f` receives the current value, or `None` if the key doesn't exist, and returns
+    /// the new value, or `None` to remove the key. The key stays locked throughout, so
+    /// no other write to it can land in between, but writes to other keys carry on
+    /// while `f` runs. Returns the new value.
+    pub fn update<F>(&self, key: String, f: F) -> crate::Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String>,
+    {
+        let key = keys::encode(None, key.as_bytes());
+        let guard = self.key_locks.lock(&key);
+        let current = match self.inner.lock().unwrap().read_value(&key)? {
+            Some(value) => Some(String::from_utf8(value)?),
+            None => None,
+        };
+
+        let new = f(current);
+        let op = match &new {
+            Some(value) => Some(self.encode_set(
+                &key,
+                value.clone().into_bytes(),
+                Some(self.options.timestamp()),
+            )?),
+            None => None,
+        };
+        let mut store = self.inner.lock().unwrap();
+        match (op, &new) {
+            (Some(op), Some(value)) => store.write_set(key, value.as_bytes(), op)?,
+            _ => {
+                store.write_rm(&key)?;
+            }
+        }
+        store.seal_if_full(&self.options)?;
+        drop(store);
+        drop(guard);
+
+        self.commit()?;
+        self.maybe_compact()?;
+
+        Ok(new)
+    }
+
+    /// Overwrite the bytes of the value of `key` from `offset` on with `patch`, returning
+    /// the new length of the value.
+    ///
+    /// A value shorter than `offset` is padded with zeros up to it first, and a key that
+    /// doesn't exist is patched as an empty value. Only the patch is written to the log;
+    /// reads apply it on top of the value it patches, until the next compaction writes
+    /// the value out whole.
+    pub fn setrange(&self, key: String, offset: usize, patch: &[u8]) -> crate::Result<usize> {
+        self.setrange_in(None, key.as_bytes(), offset, patch)
+    }
+
+    /// The sequence number of the last write made to the store, or `0` if there's been
+    /// none.
+    ///
+    /// Every write is numbered one up from the last as it's appended to the log, and a
+    /// batch takes a number for each of its ops, so there are no gaps. Writes held in a
+    /// memtable are numbered once it's flushed. The numbers carry on where they left off
+    /// when the store is reopened.
+    pub fn last_sequence(&self) -> u64 {
+        self.inner.lock().unwrap().last_seq
+    }
+
+    /// Read back every write numbered after `seq`, in order, up to the last one made
+    /// before this was called.
+    ///
+    /// Fails with [`KvsError::Compacted`] if compaction has dropped any of the ops after
+    /// `seq`: it carries the number of the last one dropped, after which writes can still
+    /// be read back. A checkpoint can't read back the writes made before it was taken.
+    pub fn ops_since(&self, seq: u64) -> crate::Result<OpStream> {
+        let mut store = self.flushed()?;
+        let compacted = store.manifest.compacted_seq;
+        if seq < compacted {
+            return Err(KvsError::Compacted(compacted));
+        }
+        let active_len = store.fh.stream_position()?;
+        let lens = store.manifest.sealed.iter().map(|s| (s.number, s.len));
+        let files = lens
+            .chain([(store.manifest.active, active_len)])
+            .map(|(number, len)| {
+                let file = File::open(manifest::log_file(&store.log_path, number))?;
+                Ok((file, store.codecs[&number], len))
+            })
+            .collect::<crate::Result<_>>()?;
+        Ok(OpStream::new(files, seq))
+    }
+
+    /// Get up to `limit` of the values `key` has held, newest first, starting with its
+    /// current value. A key that doesn't exist has no values.
+    ///
+    /// The earlier values are read from the records they were written in, so only go back
+    /// as far as the last compaction, which leaves just the current value, and the last
+    /// time the key was removed.
+    pub fn history(&self, key: String, limit: usize) -> crate::Result<Vec<VersionedValue>> {
+        let key = keys::encode(None, key.as_bytes());
+        let store = self.flushed()?;
+        let Some(&current) = store.index.get(key.as_slice()) else {
+            return Ok(vec![]);
+        };
+        let offsets: Vec<Offset> = [
+            store.history.get(key.as_slice()),
+            store.chains.get(key.as_slice()),
+        ]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .copied()
+        .chain([current])
+        .collect();
+        history::read_versions(&offsets, limit, |offset| store.read_record(offset))
+    }
+
+    /// Set a key-value pair like [`set`](KvsEngine::set), reporting whether the write
+    /// compacted the log before returning, and how long that took.
+    ///
+    /// Writes that cross the compaction threshold compact synchronously, so this tells
+    /// which of them a latency spike came from.
+    pub fn set_verbose(&self, key: String, value: String) -> crate::Result<WriteOutcome> {
+        self.set_in(None, key.as_bytes(), value.as_bytes())
+    }
+
+    /// Remove a key like [`remove`](KvsEngine::remove), reporting whether the removal
+    /// compacted the log before returning, and how long that took.
+    pub fn remove_verbose(&self, key: String) -> crate::Result<WriteOutcome> {
+        self.remove_in(None, key.as_bytes())
+    }
+
+    /// Open the bucket called `name`, creating it if it doesn't exist yet.
+    ///
+    /// A bucket is a separate keyspace within the store: its keys never clash with those
+    /// of other buckets, or of the store itself, which is the default bucket.
+    pub fn bucket(&self, name: &str) -> Bucket {
+        Bucket::new(self.clone(), name)
+    }
+
+    /// Get every key-value pair whose key starts with `prefix`, in key order.
+    pub fn scan(&self, prefix: &str) -> crate::Result<Vec<(String, String)>> {
+        self.scan_in(None, prefix)
+    }
+
+    /// Atomically move the value of `from` to `to`.
+    ///
+    /// Fails with [`KvsError::KeyNotFound`] if `from` doesn't exist, or with
+    /// [`KvsError::KeyExists`] if `to` does and `overwrite` isn't set. Renaming a key to
+    /// itself does nothing. Either both keys change or neither does, even if the store
+    /// crashes part way through the write; a store left with half a rename opens with
+    /// [`RecoveryMode::TruncateTail`] or [`RecoveryMode::SkipCorrupt`].
+    pub fn rename(&self, from: String, to: String, overwrite: bool) -> crate::Result<()> {
+        let from = keys::encode(None, from.as_bytes());
+        let to = keys::encode(None, to.as_bytes());
+        let guards = self.key_locks.lock_many([from.as_slice(), to.as_slice()]);
+        let mut store = self.inner.lock().unwrap();
+        store.append_copy(&from, to, overwrite, true, self.options.timestamp())?;
+        store.seal_if_full(&self.options)?;
+        drop(store);
+        drop(guards);
+
+        self.commit()?;
+        self.maybe_compact()?;
+        Ok(())
+    }
+
+    /// Take a read-only view of the store as it is now, unaffected by later writes and
+    /// compactions.
+    ///
+    /// This shares the index rather than copying it, and opens each of the store's log
+    /// files, so it's cheap enough to take for every request.
+    pub fn snapshot(&self) -> crate::Result<Snapshot> {
+        let store = self.flushed()?;
+        let numbers = store.manifest.sealed.iter().map(|s| s.number);
+        let files = numbers
+            .chain([store.manifest.active])
+            .map(|number| {
+                let file = File::open(manifest::log_file(&store.log_path, number))?;
+                Ok((number, (file, store.codecs[&number])))
+            })
+            .collect::<crate::Result<_>>()?;
+        Ok(Snapshot::new(
+            store.index.clone(),
+            store.chains.clone(),
+            files,
+        ))
+    }
+
+    /// The length(in bytes) of the value stored at `key` as it is in the log, which is
+    /// less than its [`value_len`](KvsEngine::value_len) if it was compressed.
+    pub fn stored_value_len(&self, key: String) -> crate::Result<Option<u64>> {
+        let key = keys::encode(None, key.as_bytes());
+        let store = self.flushed()?;
+        let offset = store.index.get(key.as_slice());
+        Ok(offset.map(|offset| offset.stored_len() as u64))
+    }
+
+    /// Count the keys starting with `prefix`, without reading any values.
+    pub fn count_prefix(&self, prefix: &str) -> crate::Result<usize> {
+        Ok(self.inner.lock().unwrap().measure_prefix(prefix).0)
+    }
+
+    /// Estimate the size(in bytes) of the keys starting with `prefix` and their values,
+    /// from the length of their records in the log, without reading any values. Writes
+    /// still in the memtable count as the length of their key and value.
+    pub fn estimate_size_prefix(&self, prefix: &str) -> crate::Result<u64> {
+        Ok(self.inner.lock().unwrap().measure_prefix(prefix).1)
+    }
+
+    fn set_in(
+        &self,
+        bucket: Option<&str>,
+        key: &[u8],
+        value: &[u8],
+    ) -> crate::Result<WriteOutcome> {
+        let key = keys::encode(bucket, key);
+        let op = self.encode_set(&key, value.to_vec(), Some(self.options.timestamp()))?;
+
+        let guard = self.key_locks.lock(&key);
+        let mut store = self.inner.lock().unwrap();
+        store.write_set(key, value, op)?;
+        store.seal_if_full(&self.options)?;
+        drop(store);
+        drop(guard);
+
+        self.commit()?;
+        Ok(WriteOutcome::new(self.maybe_compact()?))
+    }
+
+    fn setrange_in(
+        &self,
+        bucket: Option<&str>,
+        key: &[u8],
+        offset: usize,
+        patch: &[u8],
+    ) -> crate::Result<usize> {
+        let key = keys::encode(bucket, key);
+        let end = offset.checked_add(patch.len()).ok_or(KvsError::TooLarge)?;
+        let guard = self.key_locks.lock(&key);
+        let mut store = self.flushed()?;
+        let current = store.index.get(key.as_slice()).map_or(0, Offset::value_len);
+        let len = current.max(end);
+        if matches!(self.options.max_value_bytes, Some(max) if len > max) {
+            return Err(KvsError::ValueTooLarge);
+        }
+        if key.len() + len > MAX_ENTRY_LEN {
+            return Err(KvsError::TooLarge);
+        }
+
+        let written_at = Some(self.options.timestamp());
+        let chain_len = store.chains.get(key.as_slice()).map_or(0, Vec::len);
+        if chain_len >= MAX_CHAIN_LEN {
+            let indexed = *store
+                .index
+                .get(key.as_slice())
+                .expect("chained keys are live");
+            let (mut value, _) = store.read_entry(&key, &indexed)?;
+            patch::apply(&mut value, offset, patch);
+            let op = self.encode_set(&key, value.clone(), written_at)?;
+            store.write_set(key, &value, op)?;
+        } else {
+            let (bucket, unencoded) = keys::decode(&key);
+            let op = Op::SetRange {
+                key: unencoded.to_vec(),
+                offset: offset as u64,
+                patch: patch.to_vec(),
+                len: len as u64,
+                bucket: bucket.map(str::to_owned),
+                written_at,
+                seq: None,
+            };
+            store.append_patch(key, len, op)?;
+        }
+        store.seal_if_full(&self.options)?;
+        drop(store);
+        drop(guard);
+
+        self.commit()?;
+        self.maybe_compact()?;
+        Ok(len)
+    }
+
+    fn remove_in(&self, bucket: Option<&str>, key: &[u8]) -> crate::Result<WriteOutcome> {
+        let key = keys::encode(bucket, key);
+        let guard = self.key_locks.lock(&key);
+        let mut store = self.inner.lock().unwrap();
+        if !store.write_rm(&key)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        store.seal_if_full(&self.options)?;
+        drop(store);
+        drop(guard);
+
+        self.commit()?;
+        Ok(WriteOutcome::new(self.maybe_compact()?))
+    }
+
+    fn get_in(&self, bucket: Option<&str>, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        keys::with_encoded(bucket, key, |key| {
+            self.inner.lock().unwrap().read_value(key)
+        })
+    }
+
+    fn scan_in(&self, bucket: Option<&str>, prefix: &str) -> crate::Result<Vec<(String, String)>> {
+        let prefix = keys::encode(bucket, prefix.as_bytes());
+        let mut store = self.flushed()?;
+        let matching = store.keys_with_prefix(&prefix);
+        let mut pairs = Vec::with_capacity(matching.len());
+        for encoded in matching {
+            let value = store.read_value(&encoded)?.expect("scanned keys are live");
+            let key = keys::decode(&encoded).1.to_vec();
+            pairs.push((String::from_utf8(key)?, String::from_utf8(value)?));
+        }
+        Ok(pairs)
+    }
+
+    /// The number of keys in `bucket`.
+    fn len_in(&self, bucket: Option<&str>) -> crate::Result<usize> {
+        let prefix = keys::bucket_prefix(bucket);
+        Ok(with_prefix(&self.flushed()?.index, &prefix).count())
+    }
+
+    /// Remove every key in `bucket` in a single write, returning how many there were.
+    fn clear_in(&self, bucket: Option<&str>) -> crate::Result<usize> {
+        self.clear_prefix(&keys::bucket_prefix(bucket))
+    }
+
+    /// Remove every key whose encoding starts with `prefix` in a single write, returning
+    /// how many there were.
+    fn clear_prefix(&self, prefix: &[u8]) -> crate::Result<usize> {
+        let guards = self.key_locks.lock_all();
+        let mut store = self.flushed()?;
+        let matching = store.keys_with_prefix(prefix);
+        let keys: Vec<&[u8]> = matching.iter().map(Vec::as_slice).collect();
+        store.append_rms(&keys, ChangeEvent::removed)?;
+        store.seal_if_full(&self.options)?;
+        drop(store);
+        drop(guards);
+
+        self.commit()?;
+        self.maybe_compact()?;
+        Ok(matching.len())
+    }
+
+    fn needs_compaction(&self) -> bool {
+        let Some(threshold) = self.options.compaction_threshold else {
+            return false;
+        };
+        let store = self.inner.lock().unwrap();
+        match (self.options.min_compaction_interval, store.last_compaction) {
+            (Some(interval), Some(last)) if last.elapsed() < interval => {
+                store.redundant_size > threshold.saturating_mul(2)
+            }
+            _ => store.redundant_size > threshold,
+        }
+    }
+
+    /// Whether enough redundant space has built up that the next write will compact.
+    pub fn compaction_pending(&self) -> bool {
+        self.needs_compaction()
+    }
+
+    /// Whether a compaction is running, automatic or not.
+    ///
+    /// Only one runs at a time: writes that find enough redundant space built up while
+    /// one is running leave it to that one, rather than compacting again after it.
+    pub fn is_compacting(&self) -> bool {
+        self.compactions.is_running()
+    }
+
+    /// Estimate how much a compaction would reclaim, from the sizes of the live records
+    /// and of the log files, without reading or rewriting any of the log.
+    pub fn compaction_estimate(&self) -> crate::Result<CompactionEstimate> {
+        let store = self.flushed()?;
+        Ok(CompactionEstimate {
+            log_size: store.log_size()?,
+            live_size: store.live_size,
+            redundant_size: store.redundant_size as u64,
+            projected_size: self.projected_size(store.live_size),
+        })
+    }
+
+    /// Get a handle to pause, resume, and observe the store's compactions.
+    pub fn compaction_controller(&self) -> CompactionController {
+        CompactionController::new(Arc::clone(&self.compactions))
+    }
+
+    /// Get a summary of the store's current state.
+    ///
+    /// The number of live keys and the size of their values count writes still in the
+    /// memtable, without flushing them; the rest describes the log as it is.
+    pub fn stats(&self) -> crate::Result<KvStoreStats> {
+        let store = self.inner.lock().unwrap();
+        let (keys, value_bytes, _) = store.unflushed_change(&[]);
+        Ok(KvStoreStats {
+            live_keys: store.index.len().saturating_add_signed(keys as isize),
+            redundant_size: store.redundant_size,
+            log_size: store.log_size()?,
+            log_files: store.manifest.sealed.len() + 1,
+            value_bytes: store.value_bytes.saturating_add_signed(value_bytes),
+            stored_value_bytes: store.stored_value_bytes,
+            index_bytes: index::approximate_size(store.index.len(), store.key_bytes),
+            evicted_keys: store.evicted,
+            bloom: store.bloom.as_ref().map(Bloom::stats),
+            cache: store.cache.as_ref().map(ValueCache::stats),
+            compaction: self.compactions.status(),
+        })
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
+        self.set_in(None, key, value)?;
+        Ok(())
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> crate::Result<()> {
+        self.remove_in(None, key)?;
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        self.get_in(None, key)
+    }
+
+    fn disk_usage(&self) -> crate::Result<u64> {
+        self.inner.lock().unwrap().log_size()
+    }
+
+    fn sync(&self) -> crate::Result<()> {
+        self.inner.lock().unwrap().sync()
+    }
+
+    fn increment(&self, key: String, by: i64) -> crate::Result<i64> {
+        let mut result = Ok(0);
+        self.update(key, |current| {
+            result = super::add_to_integer(current.as_deref(), by);
+            match &result {
+                Ok(n) => Some(n.to_string()),
+                Err(_) => current,
+            }
+        })?;
+        result
+    }
+
+    /// Only the index is snapshotted under the store's lock, which is cheap however many
+    /// keys there are. Each value is then read under the lock on its own, as it's
+    /// reached, so keys removed before then are skipped and keys overwritten before
+    /// then read as overwritten.
+    fn iter(&self) -> crate::Result<impl Iterator<Item = crate::Result<(String, String)>>> {
+        let index = self.flushed()?.index.clone();
+        let prefix = keys::bucket_prefix(None);
+        let store = self.clone();
+        let pairs = index
+            .into_iter()
+            .take_while(move |(encoded, _)| encoded.starts_with(&prefix))
+            .filter_map(move |(encoded, _)| {
+                let value = match store.inner.lock().unwrap().read_value(&encoded) {
+                    Ok(Some(value)) => value,
+                    Ok(None) => return None,
+                    Err(e) => return Some(Err(e)),
+                };
+                let key = keys::decode(&encoded).1.to_vec();
+                Some(super::pair_from_utf8(key, value))
+            });
+        Ok(pairs)
+    }
+
+    fn range_bytes(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> crate::Result<impl DoubleEndedIterator<Item = crate::Result<(Vec<u8>, Vec<u8>)>>> {
+        let index = self.flushed()?.index.clone();
+        let index = index_within(index, keys::encode_range(start, end));
+        let store = self.clone();
+        let pairs = index.into_iter().filter_map(move |(encoded, _)| {
+            let value = match store.inner.lock().unwrap().read_value(&encoded) {
+                Ok(Some(value)) => value,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            Some(Ok((keys::decode(&encoded).1.to_vec(), value)))
+        });
+        Ok(pairs)
+    }
+
+    fn value_len(&self, key: String) -> crate::Result<Option<u64>> {
+        let key = keys::encode(None, key.as_bytes());
+        let store = self.inner.lock().unwrap();
+        let len = match store.unflushed(&key) {
+            Some(value) => value.map(<[u8]>::len),
+            None => store.index.get(key.as_slice()).map(Offset::value_len),
+        };
+        Ok(len.map(|len| len as u64))
+    }
+
+    fn get_with_meta(&self, key: String) -> crate::Result<Option<super::Entry>> {
+        let key = keys::encode(None, key.as_bytes());
+        let store = self.flushed()?;
+        let offset = match store.lookup(&key) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let (value, written_at) = store.read_entry(&key, &offset)?;
+        Ok(Some(super::Entry {
+            value: String::from_utf8(value)?,
+            written_at: written_at.map(|ms| UNIX_EPOCH + Duration::from_millis(ms)),
+            size: offset.stored_len() as u64,
+        }))
+    }
+
+    fn get_reader(&self, key: String) -> crate::Result<Option<ValueReader>> {
+        let key = keys::encode(None, key.as_bytes());
+        let store = self.flushed()?;
+        let offset = match store.lookup(&key) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        // Patched values are built in memory, from every op of their chain.
+        if store.chains.contains_key(key.as_slice()) {
+            let (value, _) = store.read_entry(&key, &offset)?;
+            return Ok(Some(ValueReader::from_bytes(value)));
+        }
+        let file = File::open(manifest::log_file(&store.log_path, offset.segment()))?;
+        let codec = store.codecs[&offset.segment()];
+        drop(store);
+        ValueReader::open(file, codec, &offset).map(Some)
+    }
+
+    fn compaction_status(&self) -> crate::Result<Option<CompactionStatus>> {
+        Ok(Some(self.compactions.status()))
+    }
+
+    fn copy(&self, src: String, dst: String, overwrite: bool) -> crate::Result<()> {
+        let src = keys::encode(None, src.as_bytes());
+        let dst = keys::encode(None, dst.as_bytes());
+        let guards = self.key_locks.lock_many([src.as_slice(), dst.as_slice()]);
+        let mut store = self.inner.lock().unwrap();
+        store.append_copy(&src, dst, overwrite, false, self.options.timestamp())?;
+        store.seal_if_full(&self.options)?;
+        drop(store);
+        drop(guards);
+
+        self.commit()?;
+        self.maybe_compact()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,370 @@
+//! Options for opening a [`KvStore`](super::KvStore).
+
+use super::{CompactionReport, Durability, RecoveryMode};
+use crate::engine::{Codec, Compression};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// The default name of the log file inside the store's directory.
+const DEFAULT_LOG_NAME: &str = "kvstore-logs";
+/// The default maximum redundant space(in bytes) before the log needs to be compacted.
+const DEFAULT_COMPACTION_THRESHOLD: usize = 1024 * 1024;
+/// The default capacity(in bytes) of the buffer records are serialized into.
+const DEFAULT_WRITE_BUFFER_SIZE: usize = 8 * 1024;
+/// The default size(in bytes) from which values are compressed, if compression is enabled.
+const DEFAULT_COMPRESSION_MIN_SIZE: usize = 256;
+
+/// A builder for the options a [`KvStore`](super::KvStore) is opened with.
+///
+/// By default the path given to `open` is treated as a directory and the log lives
+/// directly inside it as `kvstore-logs`.
+#[derive(Clone)]
+pub struct KvStoreOptions {
+    /// An optional directory, relative to the opened path, holding the store's files.
+    data_dir: Option<PathBuf>,
+    /// The name of the log file.
+    log_name: String,
+    /// Whether the opened path is the log file itself rather than a directory.
+    literal_path: bool,
+    /// The redundant space(in bytes) above which writes trigger a compaction.
+    pub(super) compaction_threshold: Option<usize>,
+    /// How long after a compaction writes wait to compact again, unless the redundant
+    /// space grows past twice the threshold.
+    pub(super) min_compaction_interval: Option<Duration>,
+    /// The capacity(in bytes) of the buffer records are serialized into.
+    pub(super) write_buffer_size: usize,
+    /// The size(in bytes) past which the active log file is sealed and a new one started.
+    pub(super) max_segment_size: Option<usize>,
+    /// The size(in bytes) of the log past which the oldest keys are evicted.
+    pub(super) max_disk_bytes: Option<u64>,
+    /// The largest value(in bytes) that can be set, if limited.
+    pub(super) max_value_bytes: Option<usize>,
+    /// The number of threads sealed log files are replayed on when opening the store.
+    pub(super) replay_threads: usize,
+    /// The algorithm new values are compressed with, if any.
+    pub(super) compression: Option<Compression>,
+    /// The size(in bytes) from which values are compressed.
+    pub(super) compression_min_size: usize,
+    /// The encoding records of new log files are written in.
+    pub(super) codec: Codec,
+    /// The expected number of keys and target false-positive rate of the bloom filter.
+    pub(super) bloom_filter: Option<(usize, f64)>,
+    /// The total size(in bytes) of recently read values to keep in memory, if any.
+    pub(super) cache_capacity: Option<usize>,
+    /// Whether sealed log files are mapped into memory to read from.
+    #[cfg(feature = "mmap")]
+    pub(super) mmap_reads: bool,
+    /// The size(in bytes) and age past which writes held in memory are flushed, if they
+    /// are.
+    pub(super) memtable: Option<(usize, Duration)>,
+    /// Whether to repair a manifest that doesn't match the files on disk, instead of failing.
+    pub(super) repair_manifest: bool,
+    /// What to do about damage found on open.
+    pub(super) recovery_mode: RecoveryMode,
+    /// When writes are synced to disk.
+    pub(super) durability: Durability,
+    /// How long a writer syncing on behalf of others waits for more to join in.
+    pub(super) commit_delay: Duration,
+    /// Tells the time writes are stamped with, if not the system clock.
+    clock: Option<Arc<dyn Fn() -> SystemTime + Send + Sync>>,
+    /// Invoked after every compaction, outside of the store's lock.
+    pub(super) on_compaction: Option<Arc<dyn Fn(CompactionReport) + Send + Sync>>,
+    /// Whether compactions fail right before the new log files are swapped in.
+    #[cfg(feature = "failpoints")]
+    pub(super) fail_compaction_before_swap: bool,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            data_dir: None,
+            log_name: DEFAULT_LOG_NAME.to_string(),
+            literal_path: false,
+            compaction_threshold: Some(DEFAULT_COMPACTION_THRESHOLD),
+            min_compaction_interval: None,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            max_segment_size: None,
+            max_disk_bytes: None,
+            max_value_bytes: None,
+            replay_threads: num_cpus::get(),
+            compression: None,
+            compression_min_size: DEFAULT_COMPRESSION_MIN_SIZE,
+            codec: Codec::default(),
+            bloom_filter: None,
+            cache_capacity: None,
+            #[cfg(feature = "mmap")]
+            mmap_reads: false,
+            memtable: None,
+            repair_manifest: false,
+            recovery_mode: RecoveryMode::default(),
+            durability: Durability::default(),
+            commit_delay: Duration::ZERO,
+            clock: None,
+            on_compaction: None,
+            #[cfg(feature = "failpoints")]
+            fail_compaction_before_swap: false,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the store's files in `name`, a directory below the opened path.
+    pub fn data_dir(mut self, name: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(name.into());
+        self
+    }
+
+    /// Name the log file `name` instead of `kvstore-logs`.
+    pub fn log_name(mut self, name: impl Into<String>) -> Self {
+        self.log_name = name.into();
+        self
+    }
+
+    /// Treat the opened path as the log file itself.
+    ///
+    /// When set, `data_dir` and `log_name` are ignored. The manifest, and the generations
+    /// of the log written by compaction, are kept next to it with the same name plus a
+    /// suffix.
+    pub fn literal_path(mut self, literal: bool) -> Self {
+        self.literal_path = literal;
+        self
+    }
+
+    /// Compact automatically once the redundant space exceeds `threshold` bytes.
+    ///
+    /// `None` disables automatic compaction; [`KvStore::compact`](super::KvStore::compact)
+    /// can still be called explicitly.
+    pub fn compaction_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.compaction_threshold = threshold;
+        self
+    }
+
+    /// Compact automatically at most once every `interval`, unless the redundant space
+    /// grows past twice the threshold in the meantime.
+    ///
+    /// Each automatic compaction stalls the write that triggers it, so a burst of
+    /// overwrites that crosses the threshold again soon after a compaction would stall
+    /// writes over and over. This lets the redundant space build up further instead,
+    /// smoothing out write latency at the cost of more space on disk. There's no minimum
+    /// interval by default.
+    pub fn min_compaction_interval(mut self, interval: Option<Duration>) -> Self {
+        self.min_compaction_interval = interval;
+        self
+    }
+
+    /// Serialize records into a buffer of `size` bytes before writing them to the log.
+    ///
+    /// Each record is flushed as soon as it's serialized, so this only affects how many
+    /// writes a record larger than the buffer is split into.
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = size;
+        self
+    }
+
+    /// Split the log into files of about `size` bytes each, or keep it in a single file
+    /// if `None`.
+    ///
+    /// Once the file being appended to reaches `size` bytes it's sealed, and never
+    /// written to again until compaction replaces it. Sealed files are checksummed in the
+    /// manifest and replayed in parallel when the store is opened.
+    pub fn max_segment_size(mut self, size: Option<usize>) -> Self {
+        self.max_segment_size = size;
+        self
+    }
+
+    /// Keep the log below `max` bytes on disk by evicting keys, or let it grow without
+    /// bound if `None`.
+    ///
+    /// Once a write takes the log past `max`, the least recently written keys are removed
+    /// until the live ones would compact to under nine tenths of it, and the log is
+    /// compacted. Evictions are counted in [`stats`](super::KvStore::stats) and reported
+    /// to subscribers as [`ChangeEvent::Evicted`](super::ChangeEvent::Evicted).
+    pub fn max_disk_bytes(mut self, max: Option<u64>) -> Self {
+        self.max_disk_bytes = max;
+        self
+    }
+
+    /// Reject values longer than `max` bytes with
+    /// [`KvsError::ValueTooLarge`](crate::KvsError::ValueTooLarge), before anything is
+    /// written, or accept values up to the store's own limit if `None`.
+    ///
+    /// The limit applies to values as they're given, before any compression.
+    pub fn max_value_bytes(mut self, max: Option<usize>) -> Self {
+        self.max_value_bytes = max;
+        self
+    }
+
+    /// Replay sealed log files on up to `threads` threads when opening the store.
+    ///
+    /// Defaults to the number of CPUs.
+    pub fn replay_threads(mut self, threads: usize) -> Self {
+        self.replay_threads = threads;
+        self
+    }
+
+    /// Compress values written from now on with `algorithm`, or store them as-is if `None`.
+    ///
+    /// Logs may freely mix compressed and uncompressed values, so this can be changed
+    /// between opens; compaction rewrites every value according to the current setting.
+    pub fn compression(mut self, algorithm: Option<Compression>) -> Self {
+        self.compression = algorithm;
+        self
+    }
+
+    /// Only compress values of at least `size` bytes.
+    pub fn compression_min_size(mut self, size: usize) -> Self {
+        self.compression_min_size = size;
+        self
+    }
+
+    /// Write the records of new log files as `codec`, JSON by default.
+    ///
+    /// Each log file records the codec it's written in, so a store can be reopened with
+    /// another codec: the active log file is sealed on open if it's in a different one,
+    /// and older files are read as they are until compaction rewrites them.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Sync writes to disk as described by `durability`, rather than leaving it to the
+    /// OS.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// With [`Durability::EveryWrite`], wait up to `delay` before each sync for more
+    /// writers to share it, which is no wait by default.
+    ///
+    /// Writers that arrive while a sync is running share the next one regardless; a
+    /// delay trades the latency of each write for fewer syncs when writes trickle in.
+    pub fn commit_delay(mut self, delay: Duration) -> Self {
+        self.commit_delay = delay;
+        self
+    }
+
+    /// Keep a bloom filter of the keys in the log, consulted before the index on reads.
+    ///
+    /// The filter is sized for `expected_keys` (or the number of live keys, whichever is
+    /// larger) at `false_positive_rate`, and is rebuilt on open and after each compaction.
+    pub fn bloom_filter(mut self, expected_keys: usize, false_positive_rate: f64) -> Self {
+        self.bloom_filter = Some((expected_keys, false_positive_rate));
+        self
+    }
+
+    /// Keep up to `capacity` bytes of recently read values in memory, evicting the least
+    /// recently used values first.
+    pub fn value_cache(mut self, capacity: usize) -> Self {
+        self.cache_capacity = Some(capacity);
+        self
+    }
+
+    /// Read from sealed log files through memory maps rather than file reads, saving a
+    /// few syscalls on every read of a small value.
+    ///
+    /// The log file being appended to is still read as a file. Only available with the
+    /// `mmap` feature, which is enabled by default.
+    #[cfg(feature = "mmap")]
+    pub fn mmap_reads(mut self, mmap: bool) -> Self {
+        self.mmap_reads = mmap;
+        self
+    }
+
+    /// Hold writes in memory, appending them to the log in batches from a background
+    /// thread once they add up to `max_bytes` of keys and values, or every
+    /// `flush_interval`, whichever comes first.
+    ///
+    /// Writes return without touching the log, and reads see them straight away, but
+    /// they aren't durable until flushed: a crash loses everything written in the last
+    /// `flush_interval`. [`sync`](crate::KvsEngine::sync) flushes them, as does dropping
+    /// the last handle to the store. With [`Durability::EveryWrite`] each write is
+    /// flushed before it's synced, which leaves nothing to batch.
+    pub fn memtable(mut self, max_bytes: usize, flush_interval: Duration) -> Self {
+        self.memtable = Some((max_bytes, flush_interval));
+        self
+    }
+
+    /// Repair the store's manifest on open if it doesn't match the log files on disk.
+    ///
+    /// By default such a store fails to open. When repairing, log files the manifest
+    /// doesn't know about are deleted, and a store whose log file has gone missing is
+    /// reopened empty.
+    pub fn repair_manifest(mut self, repair: bool) -> Self {
+        self.repair_manifest = repair;
+        self
+    }
+
+    /// Choose what to do about damage found on open. Defaults to
+    /// [`RecoveryMode::Strict`].
+    ///
+    /// [`KvStore::open_with_report`](super::KvStore::open_with_report) reports what was
+    /// given up on.
+    pub fn recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.recovery_mode = mode;
+        self
+    }
+
+    /// Stamp each write with the time `clock` returns, rather than the system clock's.
+    ///
+    /// Write times are stored to the millisecond, and read back with
+    /// [`get_with_meta`](crate::KvsEngine::get_with_meta).
+    pub fn clock(mut self, clock: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Call `f` with a [`CompactionReport`] after each compaction.
+    ///
+    /// The callback runs after the store's lock is released, so it doesn't stall writers,
+    /// but it does run on the thread whose write triggered the compaction.
+    pub fn on_compaction(mut self, f: impl Fn(CompactionReport) + Send + Sync + 'static) -> Self {
+        self.on_compaction = Some(Arc::new(f));
+        self
+    }
+
+    /// Make every compaction fail once the new log files are written and synced, but
+    /// before the manifest points at them, as if the process had died there.
+    ///
+    /// For testing crash safety only.
+    #[cfg(feature = "failpoints")]
+    #[doc(hidden)]
+    pub fn fail_compaction_before_swap(mut self, fail: bool) -> Self {
+        self.fail_compaction_before_swap = fail;
+        self
+    }
+
+    /// The time to stamp a write made now with, in milliseconds since the Unix epoch.
+    pub(super) fn timestamp(&self) -> u64 {
+        let now = self
+            .clock
+            .as_ref()
+            .map_or_else(SystemTime::now, |clock| clock());
+        now.duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+
+    /// Create an empty bloom filter for a log of about `keys` keys, if enabled.
+    pub(super) fn new_bloom(&self, keys: usize) -> Option<super::Bloom> {
+        self.bloom_filter
+            .map(|(expected, fpr)| super::Bloom::new(expected.max(keys), fpr))
+    }
+
+    /// Resolve the path of the log file for a store opened at `path`.
+    pub(crate) fn log_path(&self, path: &Path) -> PathBuf {
+        if self.literal_path {
+            return path.to_path_buf();
+        }
+
+        let mut dir = path.to_path_buf();
+        if let Some(data_dir) = &self.data_dir {
+            dir.push(data_dir);
+        }
+        dir.join(&self.log_name)
+    }
+}
@@ -0,0 +1,68 @@
+//! Values patched in place with [`setrange`](super::KvStore::setrange).
+//!
+//! A `setrange` writes only its patch to the log, as an op of its own. The index points
+//! at it like any other op, and the key gets a chain: the earlier ops its value is built
+//! from, oldest first, starting with the `set` it patches if there was one. Reading the
+//! value reads the ops of the chain in turn, then applies the patch on top.
+//!
+//! Compaction folds each chain into a single `set` of the whole value. So does a
+//! `setrange` of a key whose chain has grown to [`MAX_CHAIN_LEN`], so that reads never
+//! have too many records to read.
+
+use super::decode_value;
+use super::index::Offset;
+use crate::engine::Op;
+
+/// The ops each patched key's value is built from, before the one it's indexed at.
+///
+/// It's a persistent map, like the index, so that [`Snapshot`](super::Snapshot)s can
+/// share it.
+pub(super) type Chains = im::HashMap<Box<[u8]>, Vec<Offset>>;
+
+/// The longest a chain gets before a `setrange` writes the whole value instead.
+pub(super) const MAX_CHAIN_LEN: usize = 32;
+
+/// Build a value from the ops of its chain, oldest first, returning it along with when
+/// the last of them was written and its sequence number.
+pub(super) fn assemble(
+    ops: impl IntoIterator<Item = crate::Result<Op>>,
+) -> crate::Result<(Vec<u8>, Option<u64>, Option<u64>)> {
+    let mut value = vec![];
+    let (mut written, mut seq) = (None, None);
+    for op in ops {
+        let op = op?;
+        seq = op.seq();
+        match op {
+            Op::Set {
+                value: base,
+                compressed,
+                written_at,
+                ..
+            } => {
+                value = decode_value(base, compressed)?;
+                written = written_at;
+            }
+            Op::SetRange {
+                offset,
+                patch,
+                written_at,
+                ..
+            } => {
+                apply(&mut value, offset as usize, &patch);
+                written = written_at;
+            }
+            Op::Rm { .. } => unreachable!(),
+        }
+    }
+    Ok((value, written, seq))
+}
+
+/// Overwrite `value` from `offset` on with `patch`, padding it with zeros up to `offset`
+/// if it's shorter.
+pub(super) fn apply(value: &mut Vec<u8>, offset: usize, patch: &[u8]) {
+    let end = offset + patch.len();
+    if value.len() < end {
+        value.resize(end, 0);
+    }
+    value[offset..end].copy_from_slice(patch);
+}
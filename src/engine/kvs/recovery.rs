@@ -0,0 +1,40 @@
+//! How a store deals with damage it finds while opening.
+
+use super::verify::CorruptRecord;
+
+/// What to do about damage found while opening a store.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RecoveryMode {
+    /// Refuse to open a store with any damage: a record that can't be read, a sealed log
+    /// file that doesn't match its checksum, or a manifest that doesn't match the log
+    /// files on disk.
+    #[default]
+    Strict,
+    /// Like [`Strict`](RecoveryMode::Strict), except that a partial record at the end of
+    /// the active log file, as left behind by a crash mid-write, is truncated away.
+    TruncateTail,
+    /// Open whatever can be read. Records that can't be are skipped with a warning, a
+    /// partial record at the end of the active log file is truncated away, and the
+    /// manifest is repaired as with
+    /// [`repair_manifest`](super::KvStoreOptions::repair_manifest).
+    SkipCorrupt,
+}
+
+/// What was given up on while opening a store.
+#[derive(Clone, Debug, Default)]
+pub struct RecoveryReport {
+    /// Records that couldn't be read and were skipped. They stay in the log until the
+    /// next compaction.
+    pub skipped: Vec<CorruptRecord>,
+    /// Partial records truncated from the end of the active log file.
+    pub truncated: Vec<CorruptRecord>,
+    /// Ways the manifest didn't match the log files on disk, that were repaired.
+    pub manifest_repairs: Vec<String>,
+}
+
+impl RecoveryReport {
+    /// Whether the store opened without giving up on anything.
+    pub fn is_clean(&self) -> bool {
+        self.skipped.is_empty() && self.truncated.is_empty() && self.manifest_repairs.is_empty()
+    }
+}
@@ -0,0 +1,100 @@
+//! Reading back the writes made to a store, in the order they were numbered.
+//!
+//! Every write appended to the log is numbered one up from the last, under the store's
+//! lock, and the number is kept in its record. A batch takes a run of numbers, in the
+//! order its ops are written. The numbers pick up where they left off when the store is
+//! reopened, as the highest one in the log, or the last one compaction dropped if that's
+//! higher.
+//!
+//! Compaction copies each live value along with the number of the write it was last
+//! written by, and records the number of the last write made before it started. Ops up
+//! to that one can no longer be read back, and the copies, which are all numbered up to
+//! it, are skipped.
+
+use super::header;
+use super::inspect::{self, Record};
+use crate::engine::codec::LogCodec;
+use crate::engine::Codec;
+use crate::err::KvsError;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+
+/// The writes made to a store after a given one, from
+/// [`ops_since`](super::KvStore::ops_since), with their sequence numbers.
+///
+/// The stream holds the log files it reads from open, so a compaction meanwhile doesn't
+/// cut it short, and ends at the last write made before it was opened.
+pub struct OpStream {
+    /// The log files left to read, oldest first, with the codec each is written in and
+    /// its length when the stream was opened.
+    files: VecDeque<(File, Codec, u64)>,
+    /// The contents of the log file being read, its codec, and where its next record
+    /// starts.
+    current: Option<(Vec<u8>, Codec, usize)>,
+    /// The sequence number of the last op read, or of the one to read after.
+    after: u64,
+}
+
+impl OpStream {
+    pub(super) fn new(files: VecDeque<(File, Codec, u64)>, after: u64) -> Self {
+        OpStream {
+            files,
+            current: None,
+            after,
+        }
+    }
+
+    fn read_next(&mut self) -> crate::Result<Option<(u64, Record)>> {
+        loop {
+            let (contents, codec, pos) = match &mut self.current {
+                Some(current) => current,
+                None => {
+                    let Some((file, codec, len)) = self.files.pop_front() else {
+                        return Ok(None);
+                    };
+                    let mut contents = vec![];
+                    file.take(len).read_to_end(&mut contents)?;
+                    let first = header::HEADER_LEN.min(contents.len());
+                    self.current.insert((contents, codec, first))
+                }
+            };
+            let start = *pos + codec.padding(&contents[*pos..]);
+            let (op, len) = match codec.decode(&contents[start..]) {
+                Ok(Some(read)) => read,
+                Ok(None) => {
+                    self.current = None;
+                    continue;
+                }
+                Err(e) => return Err(KvsError::Corrupt(e)),
+            };
+            *pos = start + len;
+            // Skipping the ops asked to be skipped, the copies compaction made among them.
+            match op.seq() {
+                Some(seq) if seq > self.after => {
+                    self.after = seq;
+                    let record = inspect::decode(op).map_err(KvsError::Corrupt)?;
+                    return Ok(Some((seq, record)));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Iterator for OpStream {
+    type Item = crate::Result<(u64, Record)>;
+
+    /// Ends after the first error, as the records after one can't be trusted to be read
+    /// from where they start.
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_next() {
+            Ok(read) => read.map(Ok),
+            Err(e) => {
+                self.files.clear();
+                self.current = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
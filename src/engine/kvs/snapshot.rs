@@ -0,0 +1,108 @@
+//! Read-only views of a store at a point in time.
+
+use super::index::{measure_prefix, with_prefix, Index, Offset};
+use super::patch::{self, Chains};
+use super::{keys, read_op};
+use crate::engine::export::Exporter;
+use crate::engine::Codec;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// A read-only view of a [`KvStore`](super::KvStore) as it was when
+/// [`snapshot`](super::KvStore::snapshot) was called.
+///
+/// Writes and compactions carry on as usual while a snapshot is held, but none of them
+/// show up in it. The snapshot keeps the log files it reads from open, so a compaction
+/// that deletes them only frees their disk space once the snapshot is dropped.
+pub struct Snapshot {
+    index: Index,
+    chains: Chains,
+    /// The log files the index points into, and the codecs they're written in, by number.
+    files: Mutex<HashMap<u64, (File, Codec)>>,
+}
+
+impl Snapshot {
+    pub(super) fn new(index: Index, chains: Chains, files: HashMap<u64, (File, Codec)>) -> Self {
+        Snapshot {
+            index,
+            chains,
+            files: Mutex::new(files),
+        }
+    }
+
+    /// The number of keys in the snapshot.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Whether the snapshot has no keys.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Get a value by its key, failing if the stored value isn't valid UTF-8.
+    pub fn get(&self, key: String) -> crate::Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get a value of arbitrary bytes by its key.
+    pub fn get_bytes(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        let key = keys::encode(None, key);
+        match self.index.get(key.as_slice()) {
+            Some(offset) => Ok(Some(self.read(&key, offset)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get every key-value pair whose key starts with `prefix`, in key order.
+    pub fn scan(&self, prefix: &str) -> crate::Result<Vec<(String, String)>> {
+        let prefix = keys::encode(None, prefix.as_bytes());
+        let mut pairs = vec![];
+        for (encoded, offset) in with_prefix(&self.index, &prefix) {
+            let key = keys::decode(encoded).1.to_vec();
+            pairs.push((
+                String::from_utf8(key)?,
+                String::from_utf8(self.read(encoded, offset)?)?,
+            ));
+        }
+        Ok(pairs)
+    }
+
+    /// Count the keys starting with `prefix`, without reading any values.
+    pub fn count_prefix(&self, prefix: &str) -> crate::Result<usize> {
+        Ok(measure_prefix(&self.index, prefix).0)
+    }
+
+    /// Estimate the size(in bytes) of the keys starting with `prefix` and their values,
+    /// from the length of their records in the log, without reading any values.
+    pub fn estimate_size_prefix(&self, prefix: &str) -> crate::Result<u64> {
+        Ok(measure_prefix(&self.index, prefix).1)
+    }
+
+    /// Write every key in every bucket of the snapshot to `exporter`.
+    pub(super) fn export(&self, exporter: &mut Exporter<impl Write>) -> crate::Result<()> {
+        for (encoded, offset) in &self.index {
+            let (bucket, key) = keys::decode(encoded);
+            exporter.write(bucket, key, &self.read(encoded, offset)?)?;
+        }
+        Ok(())
+    }
+
+    /// Read the value of `key`, indexed at `offset`.
+    fn read(&self, key: &[u8], offset: &Offset) -> crate::Result<Vec<u8>> {
+        let mut files = self.files.lock().unwrap();
+        let chain = self.chains.get(key).map_or(&[][..], Vec::as_slice);
+        let ops = chain.iter().chain([offset]).map(|offset| {
+            let (file, codec) = files
+                .get_mut(&offset.segment())
+                .expect("the log files of a snapshot stay open");
+            read_op(file, *codec, offset)
+        });
+        Ok(patch::assemble(ops)?.0)
+    }
+}
@@ -0,0 +1,352 @@
+//! Streaming values out of the log, without reading them into memory whole.
+//!
+//! A value is read straight from its span of the record, which is found by reading
+//! just the start of the record. In bincode that span is the value's bytes as they are.
+//! In JSON it's a string to unescape, or base64 to decode if the value isn't UTF-8,
+//! both done a few bytes at a time as the value is read.
+//!
+//! Compressed values are the exception: lz4 values are a single block, which can only
+//! be decompressed whole, so compressed values of either algorithm are read into memory
+//! and decompressed when the reader is opened. So are records that don't start as this
+//! build writes them, such as ones edited by hand.
+
+use super::{decode_value, read_op, Offset};
+use crate::engine::Codec;
+use crate::engine::Op;
+use base64::engine::general_purpose::STANDARD;
+use base64::read::DecoderReader;
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader, Cursor, SeekFrom, Take};
+
+/// The JSON every record of a `set` starts with, up to its key.
+const JSON_SET: &[u8] = br#"{"Set":{"key":"#;
+/// The JSON between the key of a `set` and its value.
+const JSON_VALUE: &[u8] = br#","value":"#;
+/// The JSON a value that isn't UTF-8 starts with, up to its base64.
+const JSON_BASE64: &[u8] = br#"{"base64":""#;
+
+/// Reads a value a piece at a time, from
+/// [`get_reader`](crate::KvsEngine::get_reader).
+///
+/// The reader holds the log file it reads from open, so a compaction that deletes the
+/// file meanwhile only frees its disk space once the reader is dropped.
+pub struct ValueReader {
+    source: Source,
+    /// The length(in bytes) of the value.
+    len: u64,
+    /// The number of bytes of the value not read yet.
+    remaining: u64,
+}
+
+enum Source {
+    /// The value's bytes, as they are in the log.
+    Raw(Take<BufReader<File>>),
+    /// A JSON string to unescape.
+    Json(JsonString<BufReader<File>>),
+    /// A JSON string of base64 to decode.
+    Base64(
+        Box<DecoderReader<'static, base64::engine::GeneralPurpose, JsonString<BufReader<File>>>>,
+    ),
+    /// The value, read into memory whole.
+    Memory(Cursor<InMemory>),
+}
+
+/// A value in memory, in whatever buffer holds it.
+struct InMemory(Box<dyn AsRef<[u8]> + Send>);
+
+impl AsRef<[u8]> for InMemory {
+    fn as_ref(&self) -> &[u8] {
+        (*self.0).as_ref()
+    }
+}
+
+impl ValueReader {
+    /// A reader of the value of the `set` at `offset`, in `file` written in `codec`.
+    pub(super) fn open(file: File, codec: Codec, offset: &Offset) -> crate::Result<Self> {
+        let len = offset.value_len() as u64;
+        // Only compressed values are stored at a different length.
+        if offset.stored_len() == offset.value_len() {
+            let mut reader = BufReader::new(file.try_clone()?);
+            reader.seek(SeekFrom::Start(offset.start() as u64))?;
+            let source = match codec {
+                Codec::Json => json_source(reader)?,
+                Codec::Bincode => bincode_source(reader, len)?,
+            };
+            if let Some(source) = source {
+                return Ok(ValueReader::new(source, len));
+            }
+        }
+        match read_op(BufReader::new(file), codec, offset)? {
+            Op::Set {
+                value, compressed, ..
+            } => Ok(ValueReader::from_bytes(decode_value(value, compressed)?)),
+            // Patched values are read into memory before they get here.
+            Op::Rm { .. } | Op::SetRange { .. } => unreachable!(),
+        }
+    }
+
+    /// A reader of a value already in memory.
+    pub(crate) fn from_bytes(value: Vec<u8>) -> Self {
+        Self::from_shared(value)
+    }
+
+    /// A reader of a value already in memory, read from where it is rather than copied,
+    /// such as from a buffer shared with an engine's cache.
+    pub(crate) fn from_shared(value: impl AsRef<[u8]> + Send + 'static) -> Self {
+        let len = value.as_ref().len() as u64;
+        ValueReader::new(Source::Memory(Cursor::new(InMemory(Box::new(value)))), len)
+    }
+
+    fn new(source: Source, len: u64) -> Self {
+        ValueReader {
+            source,
+            len,
+            remaining: len,
+        }
+    }
+
+    /// The length(in bytes) of the whole value, however much of it has been read.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the value is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Skip the next `n` bytes of the value, or what's left of it if that's fewer.
+    ///
+    /// Bytes stored as they are, or already in memory, are seeked past; JSON strings
+    /// have to be read through to find where the value picks up again.
+    pub(crate) fn skip(&mut self, n: u64) -> io::Result<()> {
+        let n = n.min(self.remaining);
+        match &mut self.source {
+            Source::Raw(reader) => {
+                let limit = reader.limit();
+                reader.get_mut().seek_relative(n as i64)?;
+                reader.set_limit(limit - n);
+            }
+            Source::Memory(reader) => reader.set_position(reader.position() + n),
+            Source::Json(reader) => skip_read(reader, n)?,
+            Source::Base64(reader) => skip_read(reader, n)?,
+        }
+        self.remaining -= n;
+        Ok(())
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let limit = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let buf = &mut buf[..limit];
+        let read = match &mut self.source {
+            Source::Raw(reader) => reader.read(buf)?,
+            Source::Json(reader) => reader.read(buf)?,
+            Source::Base64(reader) => reader.read(buf)?,
+            Source::Memory(reader) => reader.read(buf)?,
+        };
+        if read == 0 && !buf.is_empty() {
+            return Err(corrupt("the value ends early"));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Read past the next `n` bytes of `reader`, failing if it ends first.
+fn skip_read(reader: &mut impl Read, n: u64) -> io::Result<()> {
+    if io::copy(&mut reader.take(n), &mut io::sink())? < n {
+        return Err(corrupt("the value ends early"));
+    }
+    Ok(())
+}
+
+fn corrupt(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Read past `expected` at the start of `reader`, returning whether it was there.
+fn skip_literal(reader: &mut impl BufRead, expected: &[u8]) -> io::Result<bool> {
+    let mut found = vec![0; expected.len()];
+    match reader.read_exact(&mut found) {
+        Ok(()) => Ok(found == expected),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_byte(reader: &mut impl BufRead) -> io::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Find the value of the JSON record at the start of `reader`, or `None` if the record
+/// isn't laid out as expected.
+fn json_source(mut reader: BufReader<File>) -> crate::Result<Option<Source>> {
+    if !skip_literal(&mut reader, JSON_SET)? {
+        return Ok(None);
+    }
+    // The key, as a string or an object of base64, neither of which holds a brace.
+    match read_byte(&mut reader)? {
+        b'"' => io::copy(&mut JsonString::new(&mut reader), &mut io::sink()).map(drop)?,
+        b'{' => reader.skip_until(b'}').map(drop)?,
+        _ => return Ok(None),
+    }
+    if !skip_literal(&mut reader, JSON_VALUE)? {
+        return Ok(None);
+    }
+    match reader.fill_buf()?.first().copied() {
+        Some(b'"') => {
+            reader.consume(1);
+            Ok(Some(Source::Json(JsonString::new(reader))))
+        }
+        Some(b'{') if skip_literal(&mut reader, JSON_BASE64)? => {
+            let base64 = DecoderReader::new(JsonString::new(reader), &STANDARD);
+            Ok(Some(Source::Base64(Box::new(base64))))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Find the value of the bincode record at the start of `reader`, whose value is `len`
+/// bytes long, or `None` if the record isn't laid out as expected.
+///
+/// After its length, the record is the index of `Set`, then the key and the value, each
+/// as its length followed by its bytes, with every integer encoded as a varint.
+fn bincode_source(mut reader: BufReader<File>, len: u64) -> crate::Result<Option<Source>> {
+    let mut prefix = [0; 4];
+    reader.read_exact(&mut prefix)?;
+    if read_varint(&mut reader)? != 0 {
+        return Ok(None);
+    }
+    let key_len = read_varint(&mut reader)?;
+    reader.seek_relative(key_len as i64)?;
+    if read_varint(&mut reader)? != len {
+        return Ok(None);
+    }
+    Ok(Some(Source::Raw(reader.take(len))))
+}
+
+/// Read an integer in bincode's varint encoding: a byte of its own below 251, or else a
+/// marker byte followed by a little-endian `u16`, `u32` or `u64`.
+fn read_varint(reader: &mut impl BufRead) -> io::Result<u64> {
+    let width = match read_byte(reader)? {
+        n @ 0..=250 => return Ok(n as u64),
+        251 => 2,
+        252 => 4,
+        253 => 8,
+        _ => return Err(corrupt("integer too large")),
+    };
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes[..width])?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// The contents of a JSON string, unescaped, from just after its opening quote up to
+/// its closing one.
+struct JsonString<R> {
+    reader: R,
+    /// The UTF-8 of an escaped character not yet read, and how much of it is left.
+    pending: ([u8; 4], usize),
+    /// Whether the closing quote has been read.
+    done: bool,
+}
+
+impl<R: BufRead> JsonString<R> {
+    fn new(reader: R) -> Self {
+        JsonString {
+            reader,
+            pending: ([0; 4], 0),
+            done: false,
+        }
+    }
+
+    /// Read the escape sequence after a backslash, returning the character it stands for.
+    fn unescape(&mut self) -> io::Result<char> {
+        let c = match read_byte(&mut self.reader)? {
+            b @ (b'"' | b'\\' | b'/') => b as char,
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let unit = self.read_hex()?;
+                let code = if (0xD800..0xDC00).contains(&unit) {
+                    // A character outside the BMP, as a pair of surrogates.
+                    if !skip_literal(&mut self.reader, b"\\u")? {
+                        return Err(corrupt("unpaired surrogate in string"));
+                    }
+                    let low = self.read_hex()?;
+                    0x10000 + ((unit - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+                } else {
+                    unit
+                };
+                return char::from_u32(code).ok_or_else(|| corrupt("invalid escape in string"));
+            }
+            _ => return Err(corrupt("invalid escape in string")),
+        };
+        Ok(c)
+    }
+
+    fn read_hex(&mut self) -> io::Result<u32> {
+        let mut hex = [0; 4];
+        self.reader.read_exact(&mut hex)?;
+        std::str::from_utf8(&hex)
+            .ok()
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| corrupt("invalid escape in string"))
+    }
+}
+
+impl<R: BufRead> Read for JsonString<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            let (pending, left) = &mut self.pending;
+            if *left > 0 {
+                let start = pending.len() - *left;
+                let n = (*left).min(buf.len() - read);
+                buf[read..read + n].copy_from_slice(&pending[start..start + n]);
+                *left -= n;
+                read += n;
+                continue;
+            }
+            if self.done {
+                break;
+            }
+
+            let available = self.reader.fill_buf()?;
+            if available.is_empty() {
+                return Err(corrupt("unterminated string"));
+            }
+            let plain = available
+                .iter()
+                .position(|&b| b == b'"' || b == b'\\')
+                .unwrap_or(available.len());
+            if plain > 0 {
+                let n = plain.min(buf.len() - read);
+                buf[read..read + n].copy_from_slice(&available[..n]);
+                self.reader.consume(n);
+                read += n;
+                continue;
+            }
+            let quote = available[0] == b'"';
+            self.reader.consume(1);
+            if quote {
+                self.done = true;
+                continue;
+            }
+            let c = self.unescape()?;
+            let len = c.len_utf8();
+            let mut utf8 = [0; 4];
+            c.encode_utf8(&mut utf8[4 - len..]);
+            self.pending = (utf8, len);
+        }
+        Ok(read)
+    }
+}
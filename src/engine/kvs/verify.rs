@@ -0,0 +1,195 @@
+//! Checking a store's files for damage, without opening it.
+
+use super::inspect::{LogInspector, Record};
+use super::manifest::{self, Manifest, FORMAT_VERSION};
+use super::{header, keys, KvStore, KvStoreOptions};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// A stretch of a log file that couldn't be read as a record.
+#[derive(Clone, Debug, Serialize)]
+pub struct CorruptRecord {
+    /// The log file the record is in.
+    pub file: PathBuf,
+    /// The offset(in bytes) of the record in the file.
+    pub offset: u64,
+    /// The length(in bytes) of the corrupt span.
+    pub len: u64,
+    /// Why the record couldn't be read.
+    pub error: String,
+}
+
+/// What [`KvStore::verify`] found.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct VerifyReport {
+    /// The number of records read, including corrupt ones.
+    pub records_scanned: usize,
+    /// The number of keys the readable records leave behind.
+    pub live_keys: usize,
+    /// Records that couldn't be read.
+    pub corrupt_records: Vec<CorruptRecord>,
+    /// Log files the manifest doesn't list.
+    pub orphaned_files: Vec<PathBuf>,
+    /// Log files the manifest lists that don't exist.
+    pub missing_files: Vec<PathBuf>,
+    /// Sealed log files whose contents don't match the checksum in the manifest.
+    pub checksum_mismatches: Vec<PathBuf>,
+    /// Why the manifest couldn't be used, if it couldn't.
+    pub manifest_error: Option<String>,
+    /// Whether [`KvStore::open`] would succeed.
+    pub openable: bool,
+}
+
+/// Check the files of the store whose log is at `log_path`.
+pub(super) fn verify(log_path: &Path) -> crate::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let mut numbers = manifest::log_numbers(log_path)?;
+    numbers.sort_unstable();
+
+    // A store without a usable manifest is read as if every log file on disk was listed.
+    let adopted = || {
+        let mut manifest = Manifest::new(0);
+        manifest.active = numbers.last().copied().unwrap_or(0);
+        manifest
+    };
+    let manifest = match Manifest::load(log_path) {
+        Ok(Some(manifest)) if manifest.version > FORMAT_VERSION => {
+            report.manifest_error =
+                Some(format!("unsupported format version {}", manifest.version));
+            manifest
+        }
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => {
+            if numbers.len() > 1 {
+                report.manifest_error = Some("found several log files but no manifest".into());
+            }
+            adopted()
+        }
+        Err(e) => {
+            report.manifest_error = Some(e.to_string());
+            adopted()
+        }
+    };
+    let checksums = |number| manifest.sealed.iter().find(|s| s.number == number);
+
+    let leftovers = manifest.leftovers(&numbers);
+    let mut unexpected = false;
+    for &number in &numbers {
+        if !manifest.lists(number) && !leftovers.contains(&number) {
+            unexpected = true;
+        }
+        if !manifest.lists(number) {
+            report
+                .orphaned_files
+                .push(manifest::log_file(log_path, number));
+        }
+    }
+
+    let mut live = HashSet::new();
+    let listed = manifest
+        .sealed
+        .iter()
+        .map(|s| s.number)
+        .chain([manifest.active]);
+    for number in listed {
+        let path = manifest::log_file(log_path, number);
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // A brand new store has yet to create its log file.
+                if number != manifest.active || !numbers.is_empty() {
+                    report.missing_files.push(path);
+                }
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(segment) = checksums(number) {
+            if crc32fast::hash(&contents) != segment.checksum {
+                report.checksum_mismatches.push(path.clone());
+            }
+        }
+        if let Some(version) = header::version(&contents).filter(|&v| v > FORMAT_VERSION) {
+            report.corrupt_records.push(CorruptRecord {
+                file: path,
+                offset: 0,
+                len: contents.len() as u64,
+                error: format!("unsupported format version {}", version),
+            });
+            continue;
+        }
+
+        for record in LogInspector::from_contents(contents) {
+            report.records_scanned += 1;
+            match record.op {
+                Some(Record::Set { bucket, key, .. } | Record::SetRange { bucket, key, .. }) => {
+                    live.insert(keys::encode(bucket.as_deref(), &key));
+                }
+                Some(Record::Rm { bucket, key }) => {
+                    live.remove(&keys::encode(bucket.as_deref(), &key));
+                }
+                None => report.corrupt_records.push(CorruptRecord {
+                    file: path.clone(),
+                    offset: record.offset,
+                    len: record.len,
+                    error: record.error.unwrap_or_default(),
+                }),
+            }
+        }
+    }
+    report.live_keys = live.len();
+
+    report.openable = report.manifest_error.is_none()
+        && !unexpected
+        && report.missing_files.is_empty()
+        && report.checksum_mismatches.is_empty()
+        && report.corrupt_records.is_empty();
+    Ok(report)
+}
+
+/// Repair what can be repaired of the store at `path`, whose files are laid out as
+/// described by `options`.
+pub(super) fn repair(path: &Path, options: KvStoreOptions) -> crate::Result<VerifyReport> {
+    let log_path = &options.log_path(path);
+    let report = verify(log_path)?;
+    if report.openable {
+        return Ok(report);
+    }
+
+    {
+        let _lock = manifest::lock(log_path)?;
+        // A record cut short at the end of the log file being appended to is what a
+        // crash mid-write leaves behind, and nothing after it is lost by dropping it.
+        let active = match Manifest::load(log_path) {
+            Ok(Some(manifest)) => manifest::log_file(log_path, manifest.active),
+            _ => {
+                let numbers = manifest::log_numbers(log_path)?;
+                manifest::log_file(log_path, numbers.into_iter().max().unwrap_or(0))
+            }
+        };
+        let len = fs::metadata(&active).map_or(0, |m| m.len());
+        let tail = report
+            .corrupt_records
+            .iter()
+            .find(|r| r.file == active && r.offset + r.len == len);
+        if let Some(tail) = tail {
+            log::warn!(
+                "truncating {} at offset {}: {}",
+                active.display(),
+                tail.offset,
+                tail.error
+            );
+            let file = File::options().write(true).open(&active)?;
+            file.set_len(tail.offset)?;
+            file.sync_all()?;
+        }
+    }
+
+    // Opening with repair on fixes up the manifest to match the files.
+    if let Err(e) = KvStore::open_with_options(path, options.repair_manifest(true)) {
+        log::warn!("store still can't be opened: {}", e);
+    }
+    verify(log_path)
+}
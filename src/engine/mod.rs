@@ -1,34 +1,317 @@
+pub(crate) mod bytes;
+mod codec;
+mod compression;
+mod export;
 mod kvs;
+#[cfg(feature = "sled")]
 mod sled_engine;
 
-pub use kvs::KvStore;
-pub use sled_engine::SledEngine;
+pub use codec::Codec;
+pub use compression::Compression;
+pub use export::ImportMode;
+pub use kvs::{
+    BloomStats, Bucket, CacheStats, ChangeEvent, CheckpointReport, CompactionController,
+    CompactionEstimate, CompactionPhase, CompactionReport, CompactionStatus, CorruptRecord,
+    Durability, KvStore, KvStoreOptions, KvStoreStats, LogInspector, OpStream, Record, RecordInfo,
+    RecoveryMode, RecoveryReport, Snapshot, ValueReader, VerifyReport, VersionedValue,
+    WriteOutcome, FORMAT_VERSION,
+};
+#[cfg(feature = "sled")]
+pub use sled_engine::{
+    SledBucket, SledDurability, SledEngine, SledMode, SledOptions, SledStats, SledTransaction,
+    SledTxResult,
+};
 
-use crate::err::Result;
+use crate::err::{KvsError, Result};
+use compression::Compressed;
 use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::ops::{Bound, Range};
+use std::time::SystemTime;
 
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Set a key-value pair of arbitrary bytes.
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()>;
+    /// Get a value of arbitrary bytes by its key.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Remove a key-value pair by its key.
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+
     /// Set a key-value pair.
-    fn set(&self, key: String, value: String) -> Result<()>;
-    /// Get a value by its key.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+    /// Get a value by its key, failing if the stored value isn't valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_ref(&key)
+    }
+    /// Get a value by a borrowed key, failing with [`KvsError::InvalidUtf8`] if the
+    /// stored value isn't valid UTF-8.
+    ///
+    /// The same as [`get`](KvsEngine::get), for callers that hold the key as a `&str`
+    /// and would otherwise allocate a `String` just to look it up.
+    fn get_ref(&self, key: &str) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => String::from_utf8(value)
+                .map(Some)
+                .map_err(|_| KvsError::InvalidUtf8(key.to_owned())),
+            None => Ok(None),
+        }
+    }
+    /// Get a value by its key, failing with [`KvsError::KeyNotFound`] if it doesn't exist.
+    ///
+    /// Unlike [`get`](KvsEngine::get), a missing key is an error here, matching the
+    /// behaviour of [`remove`](KvsEngine::remove).
+    fn get_expect(&self, key: String) -> Result<String> {
+        self.get(key)?.ok_or(KvsError::KeyNotFound)
+    }
     /// Remove a key-value pair by its key.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_ref(&key)
+    }
+    /// Remove a key-value pair by a borrowed key, as [`remove`](KvsEngine::remove) does.
+    fn remove_ref(&self, key: &str) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// The space(in bytes) the engine's files take up on disk.
+    fn disk_usage(&self) -> Result<u64>;
+
+    /// Make every write that has returned so far durable, surviving a crash or power
+    /// loss. Cheap if nothing has been written since the last sync.
+    fn sync(&self) -> Result<()>;
+
+    /// Atomically add `by` to the integer stored at `key`, returning the new value.
+    ///
+    /// A missing key counts as zero. Fails with [`KvsError::NotAnInteger`] if the current
+    /// value isn't an integer, or if the result would overflow.
+    fn increment(&self, key: String, by: i64) -> Result<i64>;
+
+    /// Atomically copy the value of `src` to `dst`, without handing it to the caller.
+    ///
+    /// Fails with [`KvsError::KeyNotFound`] if `src` doesn't exist, or with
+    /// [`KvsError::KeyExists`] if `dst` does and `overwrite` isn't set. Later changes to
+    /// either key don't affect the other.
+    fn copy(&self, src: String, dst: String, overwrite: bool) -> Result<()>;
+
+    /// The length(in bytes) of the value stored at `key`, or `None` if it doesn't exist.
+    ///
+    /// Cheaper than [`get`](KvsEngine::get) for large values, as the value itself isn't
+    /// read.
+    fn value_len(&self, key: String) -> Result<Option<u64>>;
+
+    /// Get a value by its key, along with when it was written and how much space it
+    /// takes up, failing if the stored value isn't valid UTF-8.
+    fn get_with_meta(&self, key: String) -> Result<Option<Entry>>;
+
+    /// Get a reader of the value stored at `key`, or `None` if it doesn't exist.
+    ///
+    /// Unlike [`get`](KvsEngine::get), this doesn't need the whole value in memory at
+    /// once, where the engine can avoid it, which suits very large values. The value is
+    /// read as it was when this was called, whatever is written to `key` meanwhile.
+    fn get_reader(&self, key: String) -> Result<Option<ValueReader>>;
+
+    /// Get bytes `range` of the value stored at `key`, or `None` if it doesn't exist.
+    ///
+    /// Like Redis's `GETRANGE`, the range is clamped to the value: a range running past
+    /// its end stops there, and one starting past it gets an empty value. Only the range
+    /// is read where the engine can seek to it, which suits slices of large values.
+    fn getrange_bytes(&self, key: String, range: Range<usize>) -> Result<Option<Vec<u8>>> {
+        let mut reader = match self.get_reader(key)? {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+        let len = reader.len();
+        let start = (range.start as u64).min(len);
+        let end = (range.end as u64).clamp(start, len);
+        reader.skip(start)?;
+        let mut slice = Vec::with_capacity((end - start) as usize);
+        reader.take(end - start).read_to_end(&mut slice)?;
+        Ok(Some(slice))
+    }
+
+    /// Get bytes `range` of the value stored at `key`, as
+    /// [`getrange_bytes`](KvsEngine::getrange_bytes) does, failing if they aren't valid
+    /// UTF-8.
+    fn getrange(&self, key: String, range: Range<usize>) -> Result<Option<String>> {
+        match self.getrange_bytes(key, range)? {
+            Some(slice) => Ok(Some(String::from_utf8(slice)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// A summary of the engine's compactions, or `None` if it doesn't report on them.
+    fn compaction_status(&self) -> Result<Option<CompactionStatus>>;
+
+    /// Every key-value pair, in key order, read one at a time as the iterator is advanced.
+    ///
+    /// Writes made while iterating, removals included, may or may not show up. Fails part
+    /// way through if a value isn't valid UTF-8.
+    fn iter(&self) -> Result<impl Iterator<Item = Result<(String, String)>>>;
+
+    /// The key-value pairs of arbitrary bytes whose keys lie between `start` and `end`,
+    /// in key order, read one at a time as the iterator is advanced. Advancing it from
+    /// the back goes through them in reverse.
+    ///
+    /// Keys are compared as raw bytes, so every engine orders them alike. Writes made
+    /// while iterating may or may not show up.
+    fn range_bytes(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Result<impl DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
+
+    /// The key-value pairs whose keys lie between `start` and `end`, as
+    /// [`range_bytes`](KvsEngine::range_bytes) reads them. Fails part way through if a
+    /// value isn't valid UTF-8.
+    fn range(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+    ) -> Result<impl DoubleEndedIterator<Item = Result<(String, String)>>> {
+        let pairs = self.range_bytes(start.map(str::as_bytes), end.map(str::as_bytes))?;
+        Ok(pairs.map(|pair| {
+            let (key, value) = pair?;
+            pair_from_utf8(key, value)
+        }))
+    }
 }
 
-/// Serializable write operations on the Kvstore.
+/// A value read back along with its metadata, by
+/// [`get_with_meta`](KvsEngine::get_with_meta).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub value: String,
+    /// When the value was last written, to the millisecond, or `None` if the engine
+    /// doesn't record it, or if it was written before the engine did.
+    pub written_at: Option<SystemTime>,
+    /// The size(in bytes) the value takes up in storage, after any compression.
+    pub size: u64,
+}
+
+/// A key-value pair read back as bytes, as a pair of strings.
+fn pair_from_utf8(key: Vec<u8>, value: Vec<u8>) -> Result<(String, String)> {
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+}
+
+/// Add `by` to an integer value, treating a missing value as zero.
+fn add_to_integer(current: Option<&str>, by: i64) -> Result<i64> {
+    let current = match current {
+        Some(value) => value.parse::<i64>().map_err(|_| KvsError::NotAnInteger)?,
+        None => 0,
+    };
+    current.checked_add(by).ok_or(KvsError::NotAnInteger)
+}
+
+/// Serializable write operations on the Kvstore, as records of its log.
+///
+/// These are the effects of the mutating network `Command`s a server accepts, at the
+/// level of bytes on disk: an increment is logged as the `Set` of its result, a copy as
+/// the `Set` of the copied value, and reads and watches aren't logged at all. Storage
+/// details, such as compression, belong here rather than on the wire. How each op is
+/// encoded in the log is up to a [`LogCodec`](codec::LogCodec).
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub(crate) enum Op {
-    Set { key: String, value: String },
-    Rm { key: String },
+    Set {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+        /// Set if `value` is stored compressed.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compressed: Option<Compressed>,
+        /// The bucket the key belongs to, or `None` for the default bucket.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bucket: Option<String>,
+        /// When the value was written, in milliseconds since the Unix epoch, or `None`
+        /// for records written before timestamps were.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        written_at: Option<u64>,
+        /// The sequence number of the write, or `None` for records written before writes
+        /// were numbered.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    Rm {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        /// The bucket the key belongs to, or `None` for the default bucket.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bucket: Option<String>,
+        /// Set if the removal only takes effect along with the op after it, which was
+        /// written in the same batch.
+        #[serde(default, skip_serializing_if = "is_false")]
+        batched: bool,
+        /// The sequence number of the write, or `None` for records written before writes
+        /// were numbered.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// Overwrites the bytes of a value from `offset` on with `patch`, padding the value
+    /// with zeros up to `offset` if it's shorter, as written by
+    /// [`setrange`](KvStore::setrange).
+    SetRange {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        offset: u64,
+        #[serde(with = "bytes")]
+        patch: Vec<u8>,
+        /// The length of the whole value once patched.
+        len: u64,
+        /// The bucket the key belongs to, or `None` for the default bucket.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bucket: Option<String>,
+        /// When the patch was written, in milliseconds since the Unix epoch.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        written_at: Option<u64>,
+        /// The sequence number of the write, or `None` for records written before writes
+        /// were numbered.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
 }
 
 impl Op {
-    pub fn set(key: String, value: String) -> Self {
-        Op::Set { key, value }
+    /// The key this op writes.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Op::Set { key, .. } | Op::Rm { key, .. } | Op::SetRange { key, .. } => key,
+        }
+    }
+
+    /// The bucket of the key this op writes, or `None` for the default bucket.
+    pub fn bucket(&self) -> Option<&str> {
+        match self {
+            Op::Set { bucket, .. } | Op::Rm { bucket, .. } | Op::SetRange { bucket, .. } => {
+                bucket.as_deref()
+            }
+        }
+    }
+
+    /// The sequence number of the write, if it was numbered.
+    pub fn seq(&self) -> Option<u64> {
+        match self {
+            Op::Set { seq, .. } | Op::Rm { seq, .. } | Op::SetRange { seq, .. } => *seq,
+        }
+    }
+
+    /// Number the write as `n`.
+    pub fn set_seq(&mut self, n: u64) {
+        match self {
+            Op::Set { seq, .. } | Op::Rm { seq, .. } | Op::SetRange { seq, .. } => *seq = Some(n),
+        }
     }
 
-    pub fn rm(key: String) -> Self {
-        Op::Rm { key }
+    /// The length of the value this op writes to the log, if any.
+    pub fn value_len(&self) -> usize {
+        match self {
+            Op::Set { value, .. } => value.len(),
+            Op::SetRange { patch, .. } => patch.len(),
+            Op::Rm { .. } => 0,
+        }
     }
 }
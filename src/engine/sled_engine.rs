@@ -1,54 +1,757 @@
-use super::KvsEngine;
+use super::export::{self, Exporter, ImportMode};
+use super::{CompactionStatus, Entry, KvsEngine, ValueReader};
 use crate::err::KvsError;
+use serde::Serialize;
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionalTree};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long the counts in [`SledStats`] are reused for before they're taken afresh.
+const STATS_TTL: Duration = Duration::from_millis(250);
+/// The prefix of the names of the trees backing buckets.
+const BUCKET_TREE_PREFIX: &str = "bucket/";
 
 #[allow(dead_code)]
 #[derive(Clone)]
 pub struct SledEngine {
     db: sled::Db,
+    /// The options the database was opened with.
+    options: SledOptions,
+    /// The largest value(in bytes) that can be set, if limited.
+    max_value_bytes: Option<usize>,
+    /// The trees backing the buckets opened so far, by bucket name.
+    trees: Arc<Mutex<HashMap<String, sled::Tree>>>,
+    /// Whether writes have been made that the engine hasn't flushed since.
+    unflushed: Arc<AtomicBool>,
+    /// The key count and size on disk last taken for [`stats`](SledEngine::stats), and
+    /// when, as taking them scans the default tree and the database's files.
+    counts: Arc<Mutex<Option<(Instant, usize, u64)>>>,
+}
+
+/// What sled optimizes its storage for, from [`SledOptions::mode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub enum SledMode {
+    /// Write faster, at the cost of more disk space.
+    HighThroughput,
+    /// Take up less disk space, at the cost of slower writes.
+    #[default]
+    LowSpace,
+}
+
+/// When writes through a [`SledEngine`] are flushed to disk, from
+/// [`SledOptions::durability`].
+///
+/// Flushing after every write is by far the slowest: each write waits for sled to write
+/// out and sync its log. The others return as soon as sled has the write in memory, so
+/// writes made since the last flush are lost if the process crashes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub enum SledDurability {
+    /// Each write is flushed before it returns.
+    #[default]
+    FlushEveryWrite,
+    /// Writes are flushed by sled in the background every so many milliseconds, losing at
+    /// most the writes of the last interval in a crash.
+    ///
+    /// Sled's background work can keep the database locked for a moment after the last
+    /// handle to it is dropped, so reopening it straight away in the same process may
+    /// fail.
+    Background(u64),
+    /// Writes are only flushed by [`SledEngine::flush`] or [`sync`](KvsEngine::sync),
+    /// and when the last handle to the database is dropped.
+    Manual,
+}
+
+/// A builder for the options a [`SledEngine`] is opened with, each mapping onto the
+/// setting of `sled::Config` of the same name. The defaults are sled's own.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SledOptions {
+    /// The size(in bytes) of sled's page cache.
+    pub cache_capacity: u64,
+    /// What sled optimizes its storage for.
+    pub mode: SledMode,
+    /// Whether sled compresses what it writes with zstd.
+    pub use_compression: bool,
+    /// How often(in milliseconds) sled flushes writes in the background, if it does.
+    /// Only used with [`SledDurability::FlushEveryWrite`], as the others set it.
+    pub flush_every_ms: Option<u64>,
+    /// When writes are flushed to disk.
+    pub durability: SledDurability,
+    /// The size(in bytes) of the segments sled's log is made of.
+    pub segment_size: usize,
+}
+
+impl Default for SledOptions {
+    fn default() -> Self {
+        SledOptions {
+            cache_capacity: 1024 * 1024 * 1024,
+            mode: SledMode::default(),
+            use_compression: false,
+            flush_every_ms: Some(500),
+            durability: SledDurability::default(),
+            segment_size: 512 * 1024,
+        }
+    }
+}
+
+impl SledOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give sled's page cache `bytes` bytes.
+    pub fn cache_capacity(mut self, bytes: u64) -> Self {
+        self.cache_capacity = bytes;
+        self
+    }
+
+    /// Optimize sled's storage for `mode`.
+    pub fn mode(mut self, mode: SledMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Compress what sled writes with zstd.
+    ///
+    /// This needs sled's `compression` feature, which can't be built alongside this
+    /// crate's own zstd, so opening fails with [`KvsError::Sled`] if it's set.
+    pub fn use_compression(mut self, enabled: bool) -> Self {
+        self.use_compression = enabled;
+        self
+    }
+
+    /// Flush writes in the background every `ms` milliseconds, or never if `None`.
+    ///
+    /// Writes through [`SledEngine`] are flushed before they return regardless, unless
+    /// the [`durability`](SledOptions::durability) says otherwise, which then takes the
+    /// place of this.
+    pub fn flush_every_ms(mut self, ms: Option<u64>) -> Self {
+        self.flush_every_ms = ms;
+        self
+    }
+
+    /// Flush writes to disk as `durability` says, which is after every write by default.
+    pub fn durability(mut self, durability: SledDurability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Make sled's log of segments of `bytes` bytes, which must be a power of two.
+    pub fn segment_size(mut self, bytes: usize) -> Self {
+        self.segment_size = bytes;
+        self
+    }
+}
+
+/// A point-in-time summary of a [`SledEngine`]'s state.
+///
+/// The key count and size on disk are taken at most every quarter of a second, and
+/// reused in between, so they can lag the writes made in that time.
+#[derive(Clone, Debug, Serialize)]
+pub struct SledStats {
+    /// The number of keys, not counting those in buckets.
+    pub keys: usize,
+    /// The space(in bytes) the database takes up on disk.
+    pub size_on_disk: u64,
+    /// The names of the buckets in the database, in order.
+    pub buckets: Vec<String>,
+    /// Whether writes have been made that the engine hasn't flushed since, with a
+    /// [`durability`](SledOptions::durability) that doesn't flush every write. Flushes
+    /// sled makes in the background aren't seen, so this can stay set after them.
+    pub flush_pending: bool,
+    /// Whether the database was recovered from the files of an earlier process, rather
+    /// than created afresh. Sled recovers alike after a clean shutdown and a crash, so
+    /// this doesn't tell the two apart.
+    pub was_recovered: bool,
+    /// The options the database was opened with.
+    pub options: SledOptions,
 }
 
 impl SledEngine {
     const LOG_LOCATION: &str = "sled-logs";
 
+    /// Open the sled database in the `sled-logs` directory of `path`, creating it if
+    /// needed.
+    ///
+    /// Earlier versions kept the database in `path` itself. A database found there is
+    /// opened where it is, so existing deployments keep their data.
     pub fn open<T: AsRef<std::path::Path>>(t: T) -> crate::Result<SledEngine> {
+        Self::open_with(t, SledOptions::default())
+    }
+
+    /// Open the sled database at `path` as [`open`](SledEngine::open) does, with
+    /// `options`.
+    ///
+    /// Reopening the database with a different cache capacity, mode or flush interval
+    /// reads the data already there, but its segment size is fixed when it's created:
+    /// opening it with another fails with [`KvsError::Sled`].
+    pub fn open_with<T: AsRef<std::path::Path>>(
+        t: T,
+        options: SledOptions,
+    ) -> crate::Result<SledEngine> {
         let path = t.as_ref();
-        path.to_path_buf().push(Self::LOG_LOCATION);
+        let path = if Self::is_database(path) {
+            path.to_path_buf()
+        } else {
+            path.join(Self::LOG_LOCATION)
+        };
+
+        std::fs::create_dir_all(&path)?;
+        let mode = match options.mode {
+            SledMode::HighThroughput => sled::Mode::HighThroughput,
+            SledMode::LowSpace => sled::Mode::LowSpace,
+        };
+        let flush_every_ms = match options.durability {
+            SledDurability::FlushEveryWrite => options.flush_every_ms,
+            SledDurability::Background(ms) => Some(ms),
+            SledDurability::Manual => None,
+        };
+        let db = sled::Config::new()
+            .path(path)
+            .cache_capacity(options.cache_capacity)
+            .mode(mode)
+            .use_compression(options.use_compression)
+            .flush_every_ms(flush_every_ms)
+            .segment_size(options.segment_size)
+            .open()?;
+
+        Ok(SledEngine {
+            db,
+            options,
+            max_value_bytes: None,
+            trees: Arc::default(),
+            unflushed: Arc::default(),
+            counts: Arc::default(),
+        })
+    }
+
+    /// The options the database was opened with.
+    pub fn config(&self) -> &SledOptions {
+        &self.options
+    }
+
+    /// Flush every write made so far to disk, returning once it's there, for writes
+    /// that aren't flushed as they're made.
+    pub fn flush(&self) -> crate::Result<()> {
+        self.unflushed.store(false, Ordering::SeqCst);
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Flush a write just made, if every write is flushed.
+    fn flush_write(&self) -> crate::Result<()> {
+        if self.options.durability == SledDurability::FlushEveryWrite {
+            self.db.flush()?;
+        } else {
+            self.unflushed.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
 
-        let db = sled::open(path)?;
+    /// Get a summary of the database's current state.
+    pub fn stats(&self) -> crate::Result<SledStats> {
+        let (keys, size_on_disk) = {
+            let mut counts = self.counts.lock().unwrap();
+            match *counts {
+                Some((taken, keys, size)) if taken.elapsed() < STATS_TTL => (keys, size),
+                _ => {
+                    let (keys, size) = (self.db.len(), self.db.size_on_disk()?);
+                    *counts = Some((Instant::now(), keys, size));
+                    (keys, size)
+                }
+            }
+        };
+        Ok(SledStats {
+            keys,
+            size_on_disk,
+            buckets: self.bucket_names(),
+            flush_pending: self.unflushed.load(Ordering::SeqCst),
+            was_recovered: self.db.was_recovered(),
+            options: self.options.clone(),
+        })
+    }
+
+    /// The names of the buckets in the database, in order.
+    fn bucket_names(&self) -> Vec<String> {
+        self.db
+            .tree_names()
+            .iter()
+            .filter_map(|name| {
+                let name = std::str::from_utf8(name).ok()?;
+                Some(name.strip_prefix(BUCKET_TREE_PREFIX)?.to_owned())
+            })
+            .collect()
+    }
+
+    /// Write every key in every bucket to `writer` in the export format, returning the
+    /// number of keys written, as [`KvStore::export`](crate::KvStore::export) does.
+    ///
+    /// Unlike a store's, sled's export isn't of a single point in time: each key is
+    /// exported as it is when it's reached, so writes made meanwhile may or may not be
+    /// seen. Values are read and written one at a time, rather than all held in memory.
+    pub fn export(&self, writer: impl Write) -> crate::Result<u64> {
+        let mut exporter = Exporter::new(writer)?;
+        for pair in self.db.iter() {
+            let (key, value) = pair?;
+            exporter.write(None, &key, &value)?;
+        }
+        for name in self.bucket_names() {
+            for pair in self.tree(&name)?.iter() {
+                let (key, value) = pair?;
+                exporter.write(Some(&name), &key, &value)?;
+            }
+        }
+        exporter.finish()
+    }
+
+    /// Apply the export read from `reader`, as [`KvStore::import`](crate::KvStore::import)
+    /// does, returning the number of keys imported.
+    ///
+    /// The whole export is checked against its checksum before anything is written, and
+    /// fails with [`KvsError::Corrupt`] if it doesn't match. The keys are inserted in
+    /// batches, so if this fails part way through, the keys before the failed batch stay
+    /// imported.
+    pub fn import(&self, reader: impl Read, mode: ImportMode) -> crate::Result<u64> {
+        let records = export::read(reader)?;
+        if mode == ImportMode::Replace {
+            self.db.clear()?;
+            for name in self.bucket_names() {
+                self.tree(&name)?.clear()?;
+            }
+        }
+        let apply = |bucket: &Option<String>, batch| -> crate::Result<()> {
+            match bucket {
+                Some(name) => self.tree(name)?.apply_batch(batch)?,
+                None => self.db.apply_batch(batch)?,
+            }
+            Ok(())
+        };
+
+        let mut imported = 0;
+        let mut bucket = None;
+        let mut batch = sled::Batch::default();
+        let mut batch_bytes = 0;
+        for record in records {
+            let (record_bucket, key, value) = record?;
+            if matches!(self.max_value_bytes, Some(max) if value.len() > max) {
+                return Err(KvsError::ValueTooLarge);
+            }
+            if record_bucket != bucket || batch_bytes >= export::BATCH_BYTES {
+                apply(&bucket, std::mem::take(&mut batch))?;
+                bucket = record_bucket;
+                batch_bytes = 0;
+            }
+            batch_bytes += key.len() + value.len();
+            batch.insert(key, value);
+            imported += 1;
+        }
+        apply(&bucket, batch)?;
+        self.flush_write()?;
+        Ok(imported)
+    }
+
+    /// Whether `path` holds a sled database, which has a `conf` and a `db` file.
+    fn is_database(path: &std::path::Path) -> bool {
+        path.join("conf").is_file() && path.join("db").is_file()
+    }
+
+    /// Reject values longer than `max` bytes with [`KvsError::ValueTooLarge`], or accept
+    /// values of any length if `None`.
+    pub fn max_value_bytes(mut self, max: Option<usize>) -> Self {
+        self.max_value_bytes = max;
+        self
+    }
+
+    /// Count the keys starting with `prefix`.
+    pub fn count_prefix(&self, prefix: &str) -> crate::Result<usize> {
+        let mut count = 0;
+        for entry in self.db.scan_prefix(prefix) {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Set every key-value pair in `entries` in a single batch, flushed once at the end
+    /// rather than after each pair if every write is flushed.
+    ///
+    /// Either every pair is set or, if a value is too large, none are.
+    pub fn populate(
+        &self,
+        entries: impl IntoIterator<Item = (String, String)>,
+    ) -> crate::Result<()> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in entries {
+            if matches!(self.max_value_bytes, Some(max) if value.len() > max) {
+                return Err(KvsError::ValueTooLarge);
+            }
+            batch.insert(key.as_bytes(), value.as_bytes());
+        }
+        self.db.apply_batch(batch)?;
+        self.flush_write()?;
+        Ok(())
+    }
+
+    /// Remove every key in `keys` in a single transaction, returning which ones existed,
+    /// as [`KvStore::remove_many`](crate::KvStore::remove_many) does.
+    ///
+    /// Unlike [`remove`](KvsEngine::remove), missing keys don't fail the batch.
+    pub fn remove_many(&self, keys: &[String]) -> crate::Result<Vec<bool>> {
+        self.transaction(|tx| keys.iter().map(|key| tx.remove(key)).collect())
+    }
+
+    /// Run `f` as a single transaction over the engine's keys, not counting those in
+    /// buckets: either every write it makes through the handle it's given is applied,
+    /// or none are.
+    ///
+    /// Reads see the writes made earlier in the transaction, and no one else's made
+    /// since it started. If another write conflicts with the transaction, sled runs `f`
+    /// again from the start, so it may be called several times and shouldn't have other
+    /// side effects. Returning an error from `f`, such as one converted from a
+    /// [`KvsError`] with `?`, aborts the transaction, failing with
+    /// [`KvsError::TransactionAborted`].
+    pub fn transaction<F, R>(&self, f: F) -> crate::Result<R>
+    where
+        F: Fn(&SledTransaction<'_>) -> SledTxResult<R>,
+    {
+        // Only whether the final (committed) attempt wrote anything counts.
+        let wrote = Cell::new(false);
+        let result = self.db.transaction(|tree| {
+            wrote.set(false);
+            f(&SledTransaction {
+                tree,
+                max_value_bytes: self.max_value_bytes,
+                wrote: &wrote,
+            })
+        });
+        let result = result.map_err(|e| match e {
+            TransactionError::Abort(e) => KvsError::TransactionAborted(Box::new(e)),
+            TransactionError::Storage(e) => KvsError::Sled(e),
+        })?;
+        if wrote.get() {
+            self.flush_write()?;
+        }
+        Ok(result)
+    }
+
+    /// Open the bucket called `name`, as [`KvStore::bucket`](crate::KvStore::bucket)
+    /// does, creating it once it's first used.
+    ///
+    /// Each bucket is a sled tree of its own, so its keys are kept apart from every other
+    /// bucket's, and from those of the engine itself, which are the default tree's.
+    pub fn bucket(&self, name: &str) -> SledBucket {
+        SledBucket {
+            engine: self.clone(),
+            name: name.to_owned(),
+        }
+    }
+
+    /// The tree backing the bucket called `name`, opening it if it isn't open yet.
+    fn tree(&self, name: &str) -> crate::Result<sled::Tree> {
+        let mut trees = self.trees.lock().unwrap();
+        if let Some(tree) = trees.get(name) {
+            return Ok(tree.clone());
+        }
+        // Named apart from sled's own default tree.
+        let tree = self
+            .db
+            .open_tree(format!("{}{}", BUCKET_TREE_PREFIX, name))?;
+        trees.insert(name.to_owned(), tree.clone());
+        Ok(tree)
+    }
 
-        Ok(SledEngine { db })
+    /// Get the value of `key` as sled holds it, without copying it.
+    ///
+    /// The value is shared with sled's cache, and is only copied if it's changed.
+    pub fn get_ivec(&self, key: &[u8]) -> crate::Result<Option<sled::IVec>> {
+        Ok(self.db.get(key)?)
+    }
+
+    /// Estimate the size(in bytes) of the keys starting with `prefix` and their values.
+    pub fn estimate_size_prefix(&self, prefix: &str) -> crate::Result<u64> {
+        let mut size = 0;
+        for entry in self.db.scan_prefix(prefix) {
+            let (key, value) = entry?;
+            size += (key.len() + value.len()) as u64;
+        }
+        Ok(size)
+    }
+}
+
+/// A named keyspace within a [`SledEngine`], opened with [`SledEngine::bucket`].
+#[derive(Clone)]
+pub struct SledBucket {
+    engine: SledEngine,
+    name: String,
+}
+
+impl SledBucket {
+    /// The name of the bucket.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set a key-value pair in the bucket.
+    pub fn set(&self, key: String, value: String) -> crate::Result<()> {
+        if matches!(self.engine.max_value_bytes, Some(max) if value.len() > max) {
+            return Err(KvsError::ValueTooLarge);
+        }
+        self.tree()?.insert(key, value.as_bytes())?;
+        self.engine.flush_write()
+    }
+
+    /// Get a value by its key in the bucket.
+    pub fn get(&self, key: String) -> crate::Result<Option<String>> {
+        match self.tree()?.get(&key)? {
+            Some(value) => match std::str::from_utf8(&value) {
+                Ok(value) => Ok(Some(value.to_owned())),
+                Err(_) => Err(KvsError::InvalidUtf8(key)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Remove a key-value pair from the bucket, failing with [`KvsError::KeyNotFound`] if
+    /// it doesn't exist.
+    pub fn remove(&self, key: String) -> crate::Result<()> {
+        match self.tree()?.remove(key)? {
+            Some(_) => self.engine.flush_write(),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// Get every key-value pair in the bucket whose key starts with `prefix`, in key order.
+    pub fn scan(&self, prefix: &str) -> crate::Result<Vec<(String, String)>> {
+        self.tree()?
+            .scan_prefix(prefix)
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((
+                    String::from_utf8(key.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Remove every key in the bucket, returning how many there were.
+    pub fn clear(&self) -> crate::Result<usize> {
+        let tree = self.tree()?;
+        let len = tree.len();
+        tree.clear()?;
+        if len > 0 {
+            self.engine.flush_write()?;
+        }
+        Ok(len)
+    }
+
+    /// The number of keys in the bucket.
+    pub fn len(&self) -> crate::Result<usize> {
+        Ok(self.tree()?.len())
+    }
+
+    /// Whether the bucket has no keys.
+    pub fn is_empty(&self) -> crate::Result<bool> {
+        Ok(self.tree()?.is_empty())
+    }
+
+    fn tree(&self) -> crate::Result<sled::Tree> {
+        self.engine.tree(&self.name)
+    }
+}
+
+/// The result of a read or write within a [`SledEngine::transaction`].
+///
+/// Its error is either a conflict, which sled handles by running the transaction again,
+/// or a [`KvsError`] that aborts the transaction.
+pub type SledTxResult<T> = Result<T, ConflictableTransactionError<KvsError>>;
+
+impl From<KvsError> for ConflictableTransactionError<KvsError> {
+    fn from(e: KvsError) -> Self {
+        ConflictableTransactionError::Abort(e)
+    }
+}
+
+/// The handle a [`SledEngine::transaction`] reads and writes keys through.
+pub struct SledTransaction<'a> {
+    tree: &'a TransactionalTree,
+    max_value_bytes: Option<usize>,
+    /// Whether this attempt at the transaction has written anything.
+    wrote: &'a Cell<bool>,
+}
+
+impl SledTransaction<'_> {
+    /// Get a value by its key, aborting with [`KvsError::InvalidUtf8`] if it isn't valid
+    /// UTF-8.
+    pub fn get(&self, key: &str) -> SledTxResult<Option<String>> {
+        match self.tree.get(key)? {
+            Some(value) => match std::str::from_utf8(&value) {
+                Ok(value) => Ok(Some(value.to_owned())),
+                Err(_) => Err(KvsError::InvalidUtf8(key.to_owned()).into()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Get a value of arbitrary bytes by its key.
+    pub fn get_bytes(&self, key: &[u8]) -> SledTxResult<Option<Vec<u8>>> {
+        Ok(self.tree.get(key)?.map(|value| value.to_vec()))
+    }
+
+    /// Set a key-value pair, aborting with [`KvsError::ValueTooLarge`] if the value is
+    /// longer than the engine allows.
+    pub fn set(&self, key: &str, value: &str) -> SledTxResult<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// Set a key-value pair of arbitrary bytes, as [`set`](SledTransaction::set) does.
+    pub fn set_bytes(&self, key: &[u8], value: &[u8]) -> SledTxResult<()> {
+        if matches!(self.max_value_bytes, Some(max) if value.len() > max) {
+            return Err(KvsError::ValueTooLarge.into());
+        }
+        self.tree.insert(key, value)?;
+        self.wrote.set(true);
+        Ok(())
+    }
+
+    /// Remove a key, returning whether it existed.
+    pub fn remove(&self, key: &str) -> SledTxResult<bool> {
+        let existed = self.tree.remove(key)?.is_some();
+        if existed {
+            self.wrote.set(true);
+        }
+        Ok(existed)
     }
 }
 
 impl KvsEngine for SledEngine {
-    fn get(&self, key: String) -> crate::Result<Option<String>> {
-        let res = self
-            .db
-            .get(key)
-            .map_err(Into::<crate::err::KvsError>::into)?;
-        match res {
-            Some(v) => Ok(Some(String::from_utf8(v.to_vec())?)),
+    fn get_bytes(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        Ok(self.get_ivec(key)?.map(|value| value.to_vec()))
+    }
+
+    /// The value is checked to be UTF-8 where sled holds it, so it's only copied once,
+    /// into the string returned.
+    fn get_ref(&self, key: &str) -> crate::Result<Option<String>> {
+        match self.get_ivec(key.as_bytes())? {
+            Some(value) => match std::str::from_utf8(&value) {
+                Ok(value) => Ok(Some(value.to_owned())),
+                Err(_) => Err(KvsError::InvalidUtf8(key.to_owned())),
+            },
             None => Ok(None),
         }
     }
 
-    fn remove(&self, key: String) -> crate::Result<()> {
+    fn remove_bytes(&self, key: &[u8]) -> crate::Result<()> {
         let old = self.db.remove(key)?;
         match old {
             Some(_) => {
-                self.db.flush()?;
+                self.flush_write()?;
                 Ok(())
             }
             None => Err(KvsError::KeyNotFound),
         }
     }
 
-    fn set(&self, key: String, value: String) -> crate::Result<()> {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
+        if matches!(self.max_value_bytes, Some(max) if value.len() > max) {
+            return Err(KvsError::ValueTooLarge);
+        }
         self.db
-            .insert(key, value.as_bytes())
+            .insert(key, value)
             .map(|_| ())
             .map_err(Into::<crate::err::KvsError>::into)?;
-        self.db.flush()?;
+        self.flush_write()?;
+        Ok(())
+    }
+
+    fn disk_usage(&self) -> crate::Result<u64> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    fn sync(&self) -> crate::Result<()> {
+        self.flush()
+    }
+
+    fn increment(&self, key: String, by: i64) -> crate::Result<i64> {
+        // sled may call the closure several times under contention, so only the result
+        // of the final (successful) attempt is kept.
+        let mut result = Ok(0);
+        self.db.update_and_fetch(key, |current| {
+            result = match current.map(std::str::from_utf8) {
+                Some(Ok(value)) => super::add_to_integer(Some(value), by),
+                Some(Err(_)) => Err(KvsError::NotAnInteger),
+                None => super::add_to_integer(None, by),
+            };
+            match &result {
+                Ok(n) => Some(n.to_string().into_bytes()),
+                Err(_) => current.map(|v| v.to_vec()),
+            }
+        })?;
+        self.flush_write()?;
+        result
+    }
+
+    fn value_len(&self, key: String) -> crate::Result<Option<u64>> {
+        Ok(self.db.get(key)?.map(|value| value.len() as u64))
+    }
+
+    /// sled doesn't record when values were written.
+    fn get_with_meta(&self, key: String) -> crate::Result<Option<Entry>> {
+        match self.db.get(key)? {
+            Some(value) => Ok(Some(Entry {
+                size: value.len() as u64,
+                value: String::from_utf8(value.to_vec())?,
+                written_at: None,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// sled hands back values whole, so they're read from memory, where sled holds them.
+    fn get_reader(&self, key: String) -> crate::Result<Option<ValueReader>> {
+        Ok(self.get_ivec(key.as_bytes())?.map(ValueReader::from_shared))
+    }
+
+    /// sled compacts its files on its own, without reporting on it.
+    fn compaction_status(&self) -> crate::Result<Option<CompactionStatus>> {
+        Ok(None)
+    }
+
+    fn iter(&self) -> crate::Result<impl Iterator<Item = crate::Result<(String, String)>>> {
+        Ok(self.db.iter().map(|entry| {
+            let (key, value) = entry?;
+            super::pair_from_utf8(key.to_vec(), value.to_vec())
+        }))
+    }
+
+    /// Read from sled's own ordered range, which compares keys as raw bytes too.
+    fn range_bytes(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> crate::Result<impl DoubleEndedIterator<Item = crate::Result<(Vec<u8>, Vec<u8>)>>> {
+        Ok(self.db.range::<&[u8], _>((start, end)).map(|entry| {
+            let (key, value) = entry?;
+            Ok((key.to_vec(), value.to_vec()))
+        }))
+    }
+
+    fn copy(&self, src: String, dst: String, overwrite: bool) -> crate::Result<()> {
+        let value = self.db.get(src)?.ok_or(KvsError::KeyNotFound)?;
+        if overwrite {
+            self.db.insert(dst, value)?;
+        } else if self
+            .db
+            .compare_and_swap(dst, None as Option<&[u8]>, Some(value))?
+            .is_err()
+        {
+            return Err(KvsError::KeyExists);
+        }
+        self.flush_write()?;
         Ok(())
     }
 }
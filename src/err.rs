@@ -8,8 +8,27 @@ pub enum KvsError {
     Serde(Option<serde_json::Error>),
     Io(std::io::Error),
     KeyNotFound,
+    #[cfg(feature = "sled")]
     Sled(sled::Error),
     StrConvert(std::string::FromUtf8Error),
+    NotADirectory(std::path::PathBuf),
+    Compression(String),
+    NotAnInteger,
+    Corrupt(String),
+    AlreadyLocked,
+    KeyExists,
+    TooLarge,
+    ValueTooLarge,
+    /// The ops up to and including the one numbered this have been compacted away.
+    Compacted(u64),
+    /// The value of this key was read as a string, but isn't valid UTF-8.
+    InvalidUtf8(String),
+    /// The store is busy, and the call would have waited for it.
+    WouldBlock,
+    /// A [`SledEngine::transaction`](crate::SledEngine::transaction) was aborted, for
+    /// this reason.
+    #[cfg(feature = "sled")]
+    TransactionAborted(Box<KvsError>),
 }
 impl std::fmt::Debug for KvsError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -17,8 +36,26 @@ impl std::fmt::Debug for KvsError {
             KvsError::Serde(e) => write!(f, "Error during serialization/deserialization: {:?}", e),
             KvsError::Io(e) => write!(f, "Io: {:?}", e),
             KvsError::KeyNotFound => write!(f, "Key not found."),
+            #[cfg(feature = "sled")]
             KvsError::Sled(e) => write!(f, "Sled: {:?}", e),
             KvsError::StrConvert(e) => write!(f, "str convert: {:?}", e),
+            KvsError::NotADirectory(p) => {
+                write!(f, "Expected a directory but found a file: {}", p.display())
+            }
+            KvsError::Compression(e) => write!(f, "compression: {}", e),
+            KvsError::NotAnInteger => write!(f, "Value is not an integer or would overflow."),
+            KvsError::Corrupt(e) => write!(f, "Store is corrupt: {}", e),
+            KvsError::AlreadyLocked => write!(f, "Store is already open elsewhere."),
+            KvsError::KeyExists => write!(f, "Key already exists."),
+            KvsError::TooLarge => write!(f, "Key and value are too large to store."),
+            KvsError::ValueTooLarge => write!(f, "Value is larger than the store allows."),
+            KvsError::Compacted(seq) => {
+                write!(f, "Ops up to sequence number {} were compacted away.", seq)
+            }
+            KvsError::InvalidUtf8(key) => write!(f, "Value of key {:?} is not valid UTF-8.", key),
+            KvsError::WouldBlock => write!(f, "Store is busy; the call would block."),
+            #[cfg(feature = "sled")]
+            KvsError::TransactionAborted(e) => write!(f, "Transaction aborted: {:?}", e),
         }
     }
 }
@@ -39,6 +76,7 @@ impl From<std::io::Error> for KvsError {
         KvsError::Io(e)
     }
 }
+#[cfg(feature = "sled")]
 impl From<sled::Error> for KvsError {
     fn from(e: sled::Error) -> Self {
         KvsError::Sled(e)
@@ -3,6 +3,20 @@ mod err;
 mod network;
 pub mod thread_pool;
 
-pub use engine::{KvStore, KvsEngine, SledEngine};
-pub use err::Result;
-pub use network::{KvsClient, KvsServer};
+pub use engine::{
+    BloomStats, Bucket, CacheStats, ChangeEvent, CheckpointReport, Codec, CompactionController,
+    CompactionEstimate, CompactionPhase, CompactionReport, CompactionStatus, Compression,
+    CorruptRecord, Durability, Entry, ImportMode, KvStore, KvStoreOptions, KvStoreStats, KvsEngine,
+    LogInspector, OpStream, Record, RecordInfo, RecoveryMode, RecoveryReport, Snapshot,
+    ValueReader, VerifyReport, VersionedValue, WriteOutcome, FORMAT_VERSION,
+};
+#[cfg(feature = "sled")]
+pub use engine::{
+    SledBucket, SledDurability, SledEngine, SledMode, SledOptions, SledStats, SledTransaction,
+    SledTxResult,
+};
+pub use err::{KvsError, Result};
+pub use network::{
+    ChannelHandle, KvsClient, KvsClientPool, KvsServer, PooledClient, ReadOnlyHandle, Watch,
+    WatchEvent,
+};
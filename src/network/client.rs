@@ -1,31 +1,145 @@
-use super::{ClientError, Command, NetRequest, NetResponse, Response};
+use super::replication::{new_subscribe_req, Subscription};
+use super::transport::{ChannelHandle, Connection};
+use super::{ClientError, Command, NetRequest, NetResponse, Response, Watch};
+use crate::{CompactionStatus, Entry};
+use serde::Deserialize;
 use std::io::prelude::*;
-use std::io::BufWriter;
-use std::net::{SocketAddr, TcpStream};
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::Range;
 
 // Used internally by this module.
 type Result<T> = std::result::Result<T, ClientError>;
 
 /// Represents a client connection to a kvs server.
+///
+/// Keys and values are taken as anything that converts into a `String`, so a `&str` can
+/// be passed as it is, and an owned `String` is moved into the request without copying.
 pub struct KvsClient {
-    stream: TcpStream,
+    stream: Connection,
+    /// Responses are read through this, as they may arrive split across several reads.
+    reader: BufReader<Connection>,
+    /// The crate version of the server, as it reported when connecting.
+    server_version: String,
 }
 
 impl KvsClient {
     pub fn connect(server_addr: SocketAddr) -> Result<Self> {
-        let stream = TcpStream::connect(server_addr)?;
-        Ok(KvsClient { stream })
+        Self::connect_to(server_addr)
+    }
+
+    /// Connect to the server at `server_addr`, which may be a hostname.
+    ///
+    /// Once connected, the client and server exchange their versions; see
+    /// [`server_version`](KvsClient::server_version). `TCP_NODELAY` is set on the connection; see [`set_nodelay`](KvsClient::set_nodelay).
+    ///
+    /// A hostname can resolve to several addresses, such as an IPv6 and an IPv4 one.
+    /// Each is tried in turn until one accepts the connection, failing with the last
+    /// error if none does.
+    pub fn connect_to(server_addr: impl ToSocketAddrs) -> Result<Self> {
+        let mut last_err = None;
+        let mut stream = None;
+        for addr in server_addr.to_socket_addrs()? {
+            match TcpStream::connect(addr) {
+                Ok(s) => {
+                    stream = Some(s);
+                    break;
+                }
+                Err(e) => {
+                    log::debug!("Failed to connect to {}: {}", addr, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        let stream = match (stream, last_err) {
+            (Some(stream), _) => stream,
+            (None, Some(e)) => return Err(e.into()),
+            (None, None) => return Err("Address resolved to nothing".to_string().into()),
+        };
+        stream.set_nodelay(true)?;
+        Self::handshake(Connection::Tcp(stream))
+    }
+
+    /// Connect to the server bound to `handle` in the same process, over in-memory
+    /// channels rather than a socket; see
+    /// [`KvsServer::bind_channel`](super::KvsServer::bind_channel).
+    pub fn connect_channel(handle: &ChannelHandle) -> Result<Self> {
+        Self::handshake(Connection::Channel(handle.connect()?))
+    }
+
+    /// Exchange versions with the server at the other end of `stream`.
+    fn handshake(stream: Connection) -> Result<Self> {
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut client = KvsClient {
+            stream,
+            reader,
+            server_version: String::new(),
+        };
+        let response = client.send_request(new_hello_req())?;
+        match response.response {
+            Response::Err(e) => return Err(e.into()),
+            Response::Hello { version } => client.server_version = version,
+            _ => return Err("Unexpected response to hello".to_string().into()),
+        }
+        Ok(client)
+    }
+
+    /// The crate version of the server, as it reported when the client connected.
+    pub fn server_version(&self) -> &str {
+        &self.server_version
+    }
+
+    /// Set whether `TCP_NODELAY` is set on the connection, which it is by default.
+    ///
+    /// Requests are small, so without it each one can wait on Nagle's algorithm.
+    /// Disabling it may help throughput when pipelining many requests. This does
+    /// nothing on an in-memory connection, which sends each request as it's written.
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        match &self.stream {
+            Connection::Tcp(stream) => Ok(stream.set_nodelay(nodelay)?),
+            Connection::Channel(_) => Ok(()),
+        }
+    }
+
+    /// Whether the connection can still be used: the server hasn't closed it, and
+    /// there's nothing left unread on it from an earlier request.
+    pub(super) fn is_alive(&self) -> bool {
+        if !self.reader.buffer().is_empty() {
+            return false;
+        }
+        let stream = match self.reader.get_ref() {
+            Connection::Tcp(stream) => stream,
+            Connection::Channel(pipe) => return !pipe.has_unread(),
+        };
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut buf = [0; 1];
+        let alive = matches!(
+            stream.peek(&mut buf),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock
+        );
+        alive && stream.set_nonblocking(false).is_ok()
+    }
+
+    /// Whether `TCP_NODELAY` is set on the connection, which an in-memory one reports as
+    /// it doesn't delay requests either.
+    pub fn nodelay(&self) -> Result<bool> {
+        match &self.stream {
+            Connection::Tcp(stream) => Ok(stream.nodelay()?),
+            Connection::Channel(_) => Ok(true),
+        }
     }
 
     fn send_request(&mut self, req: NetRequest) -> Result<NetResponse> {
-        let writer = BufWriter::new(&self.stream);
+        let mut writer = BufWriter::new(&self.stream);
 
-        serde_json::to_writer(writer, &req)?;
+        serde_json::to_writer(&mut writer, &req)?;
+        writer.flush()?;
         log::debug!("Sent request: {:#?}", req);
 
-        let mut buf = [0u8; 4096];
-        let nbytes = self.stream.read(&mut buf)?;
-        let response: NetResponse = serde_json::from_slice(&buf[..nbytes])?;
+        let mut responses = serde_json::Deserializer::from_reader(&mut self.reader);
+        let response = NetResponse::deserialize(&mut responses)?;
 
         log::debug!("Got response: {:#?}", response);
         if response.id != req.id {
@@ -35,42 +149,221 @@ impl KvsClient {
         Ok(response)
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let response = self.send_request(new_get_req(key))?;
+    pub fn get(&mut self, key: impl Into<String>) -> Result<Option<String>> {
+        let response = self.send_request(new_get_req(key.into(), false))?;
+
+        match response.response {
+            Response::Err(e) => Err(e.into()),
+            Response::Value(value) => Ok(value),
+            _ => Err("Unexpected response to get".to_string().into()),
+        }
+    }
+
+    /// Get the value stored at `key` a chunk at a time, writing each to `out` as it
+    /// arrives, and returning the value's length, or `None` if the key doesn't exist.
+    ///
+    /// Neither the client nor the server holds the whole value in memory at once, which
+    /// suits very large values. If this fails part way through, `out` is left with the
+    /// chunks written so far.
+    pub fn get_to(&mut self, key: impl Into<String>, mut out: impl Write) -> Result<Option<u64>> {
+        let req = new_get_stream_req(key.into());
+        let len = match self.send_request(req.clone())?.response {
+            Response::Err(e) => return Err(e.into()),
+            Response::Length(None) => return Ok(None),
+            Response::Length(Some(len)) => len,
+            _ => return Err("Unexpected response to get_to".to_string().into()),
+        };
+
+        let mut received = 0;
+        while received < len {
+            let response = NetResponse::deserialize(&mut serde_json::Deserializer::from_reader(
+                &mut self.reader,
+            ))?;
+            if response.id != req.id {
+                return Err("Invalid response".to_string().into());
+            }
+            match response.response {
+                Response::Err(e) => return Err(e.into()),
+                Response::Chunk(chunk) => {
+                    out.write_all(&chunk)?;
+                    received += chunk.len() as u64;
+                }
+                _ => return Err("Unexpected response to get_to".to_string().into()),
+            }
+        }
+        Ok(Some(len))
+    }
+
+    /// Get a value along with when it was written and its size, or `None` if the key
+    /// doesn't exist.
+    pub fn get_with_meta(&mut self, key: impl Into<String>) -> Result<Option<Entry>> {
+        let response = self.send_request(new_get_req(key.into(), true))?;
+        match response.response {
+            Response::Err(e) => Err(e.into()),
+            Response::Entry(entry) => Ok(entry),
+            _ => Err("Unexpected response to get_with_meta".to_string().into()),
+        }
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        let response = self.send_request(new_set_req(key.into(), value.into()))?;
+        match response.response {
+            Response::Err(e) => Err(e.into()),
+            Response::Ok => Ok(()),
+            _ => Err("Unexpected response to set".to_string().into()),
+        }
+    }
+
+    pub fn remove(&mut self, key: impl Into<String>) -> Result<()> {
+        let response = self.send_request(new_rm_req(key.into()))?;
+        match response.response {
+            Response::Err(e) => Err(e.into()),
+            Response::Ok => Ok(()),
+            _ => Err("Unexpected response to remove".to_string().into()),
+        }
+    }
+
+    /// Atomically add `by` to the integer stored at `key`, returning the new value.
+    pub fn increment(&mut self, key: impl Into<String>, by: i64) -> Result<i64> {
+        let response = self.send_request(new_increment_req(key.into(), by))?;
+        match response.response {
+            Response::Err(e) => Err(e.into()),
+            Response::Integer(n) => Ok(n),
+            _ => Err("Unexpected response to increment".to_string().into()),
+        }
+    }
+
+    /// Get bytes `range` of the value stored at `key`, clamped to its length, without
+    /// fetching the rest of the value.
+    pub fn getrange(
+        &mut self,
+        key: impl Into<String>,
+        range: Range<usize>,
+    ) -> Result<Option<String>> {
+        let response = self.send_request(new_getrange_req(key.into(), range))?;
+        match response.response {
+            Response::Err(e) => Err(e.into()),
+            Response::Value(value) => Ok(value),
+            _ => Err("Unexpected response to getrange".to_string().into()),
+        }
+    }
+
+    /// Get the length(in bytes) of the value stored at `key`, without fetching the value.
+    pub fn value_len(&mut self, key: impl Into<String>) -> Result<Option<u64>> {
+        let response = self.send_request(new_value_len_req(key.into()))?;
+        match response.response {
+            Response::Err(e) => Err(e.into()),
+            Response::Length(len) => Ok(len),
+            _ => Err("Unexpected response to value_len".to_string().into()),
+        }
+    }
+
+    /// Make every write the server has acknowledged so far, from any client, durable,
+    /// returning once it's on disk.
+    pub fn flush(&mut self) -> Result<()> {
+        let response = self.send_request(new_flush_req())?;
+        match response.response {
+            Response::Err(e) => Err(e.into()),
+            Response::Ok => Ok(()),
+            _ => Err("Unexpected response to flush".to_string().into()),
+        }
+    }
 
+    /// Get the state of the server's compactions, or `None` if its engine doesn't report
+    /// on them.
+    pub fn compaction_status(&mut self) -> Result<Option<CompactionStatus>> {
+        let response = self.send_request(new_compaction_status_req())?;
         match response.response {
             Response::Err(e) => Err(e.into()),
-            Response::Success(None) => Ok(None),
-            Response::Success(Some(value)) => Ok(Some(value)),
+            Response::Compaction(status) => Ok(status),
+            _ => Err("Unexpected response to compaction_status"
+                .to_string()
+                .into()),
         }
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let response = self.send_request(new_set_req(key, value))?;
+    /// Copy the value of `src` to `dst` on the server, failing if `dst` exists unless
+    /// `overwrite` is set.
+    pub fn copy(
+        &mut self,
+        src: impl Into<String>,
+        dst: impl Into<String>,
+        overwrite: bool,
+    ) -> Result<()> {
+        let response = self.send_request(new_copy_req(src.into(), dst.into(), overwrite))?;
         match response.response {
             Response::Err(e) => Err(e.into()),
-            Response::Success(_) => Ok(()),
+            Response::Ok => Ok(()),
+            _ => Err("Unexpected response to copy".to_string().into()),
         }
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let response = self.send_request(new_rm_req(key))?;
+    /// Watch for changes to keys starting with `prefix`, made through the server.
+    ///
+    /// The returned iterator yields each change as it happens, and ends if the server
+    /// closes the connection, which it does to watchers that fall too far behind.
+    pub fn watch(mut self, prefix: impl Into<String>) -> Result<Watch> {
+        let req = NetRequest {
+            id: rand::random::<u64>(),
+            command: Command::Watch {
+                prefix: prefix.into(),
+            },
+        };
+        let response = self.send_request(req.clone())?;
         match response.response {
+            Response::Ok => Ok(Watch {
+                reader: self.reader,
+                id: req.id,
+            }),
             Response::Err(e) => Err(e.into()),
-            Response::Success(_) => Ok(()),
+            _ => Err("Unexpected response to watch".to_string().into()),
+        }
+    }
+
+    /// Subscribe to the writes made through the server, a leader, from `from_offset` of
+    /// its stream of writes `stream` on, for a follower to apply.
+    pub(super) fn subscribe(self, stream: Option<u64>, from_offset: u64) -> Result<Subscription> {
+        let req = new_subscribe_req(stream, from_offset);
+        let mut writer = BufWriter::new(&self.stream);
+        serde_json::to_writer(&mut writer, &req)?;
+        writer.flush()?;
+        drop(writer);
+        Ok(Subscription {
+            reader: self.reader,
+            id: req.id,
+        })
+    }
+
+    /// Another handle to the TCP connection, to close it from another thread.
+    pub(super) fn try_clone_stream(&self) -> Result<TcpStream> {
+        match &self.stream {
+            Connection::Tcp(stream) => Ok(stream.try_clone()?),
+            Connection::Channel(_) => Err("Not a TCP connection".to_string().into()),
         }
     }
 
+    /// Close the connection. An in-memory one is closed by dropping the client.
     pub fn shutdown(self) -> Result<()> {
-        self.stream.shutdown(std::net::Shutdown::Both)?;
+        if let Connection::Tcp(stream) = &self.stream {
+            stream.shutdown(std::net::Shutdown::Both)?;
+        }
         Ok(())
     }
 }
 
-fn new_get_req(key: String) -> NetRequest {
+fn new_hello_req() -> NetRequest {
+    NetRequest {
+        id: rand::random::<u64>(),
+        command: Command::Hello {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+    }
+}
+
+fn new_get_req(key: String, meta: bool) -> NetRequest {
     NetRequest {
         id: rand::random::<u64>(),
-        command: Command::Get { key },
+        command: Command::Get { key, meta },
     }
 }
 fn new_set_req(key: String, value: String) -> NetRequest {
@@ -85,3 +378,59 @@ fn new_rm_req(key: String) -> NetRequest {
         command: Command::Rm { key },
     }
 }
+fn new_increment_req(key: String, by: i64) -> NetRequest {
+    NetRequest {
+        id: rand::random::<u64>(),
+        command: Command::Increment { key, by },
+    }
+}
+
+fn new_get_stream_req(key: String) -> NetRequest {
+    NetRequest {
+        id: rand::random::<u64>(),
+        command: Command::GetStream { key },
+    }
+}
+
+fn new_getrange_req(key: String, range: Range<usize>) -> NetRequest {
+    NetRequest {
+        id: rand::random::<u64>(),
+        command: Command::GetRange {
+            key,
+            start: range.start as u64,
+            end: range.end as u64,
+        },
+    }
+}
+
+fn new_value_len_req(key: String) -> NetRequest {
+    NetRequest {
+        id: rand::random::<u64>(),
+        command: Command::ValueLen { key },
+    }
+}
+
+fn new_flush_req() -> NetRequest {
+    NetRequest {
+        id: rand::random::<u64>(),
+        command: Command::Flush,
+    }
+}
+
+fn new_compaction_status_req() -> NetRequest {
+    NetRequest {
+        id: rand::random::<u64>(),
+        command: Command::CompactionStatus,
+    }
+}
+
+fn new_copy_req(src: String, dst: String, overwrite: bool) -> NetRequest {
+    NetRequest {
+        id: rand::random::<u64>(),
+        command: Command::Copy {
+            src,
+            dst,
+            overwrite,
+        },
+    }
+}
@@ -1,11 +1,21 @@
 mod client;
+mod pool;
+mod rate_limit;
+mod replication;
 mod server;
+mod transport;
+mod watch;
+mod writer;
 
 use crate::err::KvsError;
+use crate::{CompactionStatus, Entry, Record};
 use serde::{Deserialize, Serialize};
 
 pub use client::KvsClient;
-pub use server::KvsServer;
+pub use pool::{KvsClientPool, PooledClient};
+pub use server::{KvsServer, ReadOnlyHandle};
+pub use transport::ChannelHandle;
+pub use watch::{Watch, WatchEvent};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 /// A command sent from the client to a KvsEngine server.
@@ -28,10 +38,54 @@ impl NetResponse {
             response: Response::Err(format!("{:?}", e)),
         }
     }
-    pub fn success(req: &NetRequest, res: Option<String>) -> Self {
+    pub fn ok(req: &NetRequest) -> Self {
         NetResponse {
             id: req.id,
-            response: Response::Success(res),
+            response: Response::Ok,
+        }
+    }
+    pub fn value(req: &NetRequest, value: Option<String>) -> Self {
+        NetResponse {
+            id: req.id,
+            response: Response::Value(value),
+        }
+    }
+    pub fn integer(req: &NetRequest, n: i64) -> Self {
+        NetResponse {
+            id: req.id,
+            response: Response::Integer(n),
+        }
+    }
+    pub fn chunk(req: &NetRequest, data: Vec<u8>) -> Self {
+        NetResponse {
+            id: req.id,
+            response: Response::Chunk(data),
+        }
+    }
+    pub fn length(req: &NetRequest, len: Option<u64>) -> Self {
+        NetResponse {
+            id: req.id,
+            response: Response::Length(len),
+        }
+    }
+    pub fn entry(req: &NetRequest, entry: Option<Entry>) -> Self {
+        NetResponse {
+            id: req.id,
+            response: Response::Entry(entry),
+        }
+    }
+    pub fn hello(req: &NetRequest) -> Self {
+        NetResponse {
+            id: req.id,
+            response: Response::Hello {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+        }
+    }
+    pub fn compaction(req: &NetRequest, status: Option<CompactionStatus>) -> Self {
+        NetResponse {
+            id: req.id,
+            response: Response::Compaction(status),
         }
     }
 }
@@ -41,16 +95,118 @@ impl NetResponse {
 enum Response {
     /// Error response containing the error message.
     Err(String),
-    /// Success response expected to only contain a `Some(_)` for get requests.
-    Success(Option<String>),
+    /// Acknowledges a command that doesn't return anything, such as a set or remove.
+    Ok,
+    /// The value found by a get, or `None` if the key doesn't exist. An empty value is
+    /// `Some("")`.
+    Value(Option<String>),
+    /// The value found by a get asking for metadata, along with it, or `None` if the key
+    /// doesn't exist.
+    Entry(Option<Entry>),
+    /// The new value of an incremented key.
+    Integer(i64),
+    /// The length of a value, or `None` if the key doesn't exist.
+    ///
+    /// Answering a `GetStream`, this is followed by `Chunk`s of the value, until they
+    /// add up to its length.
+    Length(Option<u64>),
+    /// A piece of a value, streamed after its `Length`.
+    Chunk(#[serde(with = "crate::engine::bytes")] Vec<u8>),
+    /// A change to a watched key, with its new value or `None` if it was removed.
+    Changed { key: String, value: Option<String> },
+    /// The state of the engine's compactions, or `None` if it doesn't report on them.
+    Compaction(Option<CompactionStatus>),
+    /// Answers a client's hello with the server's crate version.
+    Hello { version: String },
+    /// Answering a `Subscribe` the leader can't resume, starts a copy of all of its data,
+    /// which replaces the follower's: a `Snapshot` of each key, then `Synced`.
+    Resync,
+    /// A key and its value, in a copy of a leader's data.
+    Snapshot(Record),
+    /// The follower is up to date with the leader's stream of writes `stream` up to
+    /// `offset`, from which `Replicated` writes follow.
+    Synced { stream: u64, offset: u64 },
+    /// A write made through a leader, at `offset` in its stream of writes.
+    Replicated { offset: u64, op: Record },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 /// Serializable commands for the network protocol.
+///
+/// `Set`, `Rm`, `Increment` and `Copy` change the store, and are written to a
+/// [`KvStore`]'s log as the engine's own ops; the rest only read it.
+///
+/// [`KvStore`]: crate::KvStore
 enum Command {
-    Get { key: String },
-    Rm { key: String },
-    Set { key: String, value: String },
+    /// Sent by a client as it connects, with its crate version, to learn the server's.
+    Hello {
+        version: String,
+    },
+    /// Get a value, along with when it was written and its size if `meta` is set.
+    Get {
+        key: String,
+        #[serde(default)]
+        meta: bool,
+    },
+    Rm {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Increment {
+        key: String,
+        by: i64,
+    },
+    /// Get a value a chunk at a time, so that neither side needs all of it in memory.
+    GetStream {
+        key: String,
+    },
+    /// Get bytes `start..end` of a value, clamped to its length.
+    GetRange {
+        key: String,
+        start: u64,
+        end: u64,
+    },
+    /// Get the length of a value, without sending the value itself.
+    ValueLen {
+        key: String,
+    },
+    /// Copy the value of `src` to `dst`, failing if `dst` exists unless `overwrite` is set.
+    Copy {
+        src: String,
+        dst: String,
+        overwrite: bool,
+    },
+    /// Stream every subsequent change to keys starting with `prefix`.
+    Watch {
+        prefix: String,
+    },
+    /// Get the state of the engine's compactions, for administration.
+    CompactionStatus,
+    /// Make every write the server has acknowledged durable, answering once it is.
+    Flush,
+    /// Sent by a follower to a leader, to be streamed every write made through the
+    /// leader from `from_offset` of its stream of writes `stream` on, or from a copy of
+    /// all of its data if it no longer has them or `stream` is `None`.
+    Subscribe {
+        from_offset: u64,
+        stream: Option<u64>,
+    },
+}
+
+impl Command {
+    /// Whether the command changes the store, so is refused by a read-only server.
+    fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Rm { .. }
+                | Command::Increment { .. }
+                | Command::Copy { .. }
+        )
+    }
 }
 
 pub enum ServerError {
@@ -58,6 +214,12 @@ pub enum ServerError {
     Io(std::io::Error),
     Serde(serde_json::Error),
     Crossbeam(anyhow::Error),
+    /// A write was refused, as the server is read-only.
+    ReadOnly,
+    /// A follower subscribed to a server that isn't a leader.
+    NotLeader,
+    /// A request was refused, as its connection sent more than the server allows.
+    RateLimited,
 }
 
 #[derive(Debug)]
@@ -74,6 +236,9 @@ impl std::fmt::Debug for ServerError {
             }
             ServerError::Core(e) => write!(f, "core error: {:?}", e),
             ServerError::Crossbeam(e) => write!(f, "crossbeam: {:?}", e),
+            ServerError::ReadOnly => write!(f, "the server is read-only"),
+            ServerError::NotLeader => write!(f, "the server isn't a leader"),
+            ServerError::RateLimited => write!(f, "rate limited: too many requests"),
         }
     }
 }
@@ -112,6 +277,11 @@ impl std::fmt::Display for ClientError {
 }
 impl std::error::Error for ClientError {}
 
+impl From<KvsError> for ClientError {
+    fn from(e: KvsError) -> ClientError {
+        ClientError::Any(e.to_string())
+    }
+}
 impl From<String> for ClientError {
     fn from(s: String) -> ClientError {
         ClientError::Any(s)
@@ -0,0 +1,111 @@
+use super::{ClientError, KvsClient};
+use crossbeam::channel::{self, Receiver, Sender};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Used internally by this module.
+type Result<T> = std::result::Result<T, ClientError>;
+
+/// A fixed number of connections to a kvs server, shared between threads.
+///
+/// Each connection is handed out to one caller at a time by [`get`](KvsClientPool::get),
+/// and goes back to the pool when the returned guard is dropped. Connections the server
+/// has closed are replaced the next time they're handed out.
+#[derive(Clone)]
+pub struct KvsClientPool {
+    addrs: Arc<[SocketAddr]>,
+    /// Every slot of the pool that isn't handed out, holding its connection if it has one.
+    idle: Receiver<Option<KvsClient>>,
+    returns: Sender<Option<KvsClient>>,
+    /// The number of connections currently open, whether idle or handed out.
+    open: Arc<AtomicUsize>,
+}
+
+impl KvsClientPool {
+    /// Open `size` connections to the server at `server_addr`, which may be a hostname.
+    pub fn connect(server_addr: impl ToSocketAddrs, size: usize) -> Result<Self> {
+        if size == 0 {
+            return Err("A pool needs at least one connection".to_string().into());
+        }
+        let addrs: Arc<[SocketAddr]> = server_addr.to_socket_addrs()?.collect();
+        let (returns, idle) = channel::bounded(size);
+        let pool = KvsClientPool {
+            addrs,
+            idle,
+            returns,
+            open: Arc::new(AtomicUsize::new(0)),
+        };
+        for _ in 0..size {
+            let client = pool.open_connection()?;
+            pool.returns
+                .send(Some(client))
+                .expect("the pool holds a receiver");
+        }
+        Ok(pool)
+    }
+
+    /// Take a connection from the pool, waiting for one if they're all in use.
+    pub fn get(&self) -> Result<PooledClient> {
+        let slot = self.idle.recv().expect("the pool holds a sender");
+        let client = match slot {
+            Some(client) if client.is_alive() => client,
+            Some(dead) => {
+                log::debug!("Replacing a connection the server closed");
+                drop(dead);
+                self.open.fetch_sub(1, Ordering::SeqCst);
+                self.reconnect()?
+            }
+            None => self.reconnect()?,
+        };
+        Ok(PooledClient {
+            client: Some(client),
+            pool: self.clone(),
+        })
+    }
+
+    /// The number of connections the pool has open, whether idle or handed out.
+    pub fn open_connections(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    fn open_connection(&self) -> Result<KvsClient> {
+        let client = KvsClient::connect_to(&self.addrs[..])?;
+        self.open.fetch_add(1, Ordering::SeqCst);
+        Ok(client)
+    }
+
+    /// Open a connection for an empty slot, giving the slot back if that fails.
+    fn reconnect(&self) -> Result<KvsClient> {
+        self.open_connection().inspect_err(|_| {
+            let _ = self.returns.send(None);
+        })
+    }
+}
+
+/// A connection taken from a [`KvsClientPool`], returned to it when dropped.
+pub struct PooledClient {
+    client: Option<KvsClient>,
+    pool: KvsClientPool,
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().expect("only taken on drop")
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().expect("only taken on drop")
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let _ = self.pool.returns.send(self.client.take());
+    }
+}
@@ -0,0 +1,44 @@
+//! Capping the rate of requests on each connection, for servers that opt in with
+//! [`set_max_rps_per_connection`](super::KvsServer::set_max_rps_per_connection).
+//!
+//! Each connection has a token bucket holding up to a second's worth of requests, which
+//! refills at the rate allowed. A request takes a token, and is refused if there's none
+//! left, so a client can burst up to the limit at once but not keep up more than it.
+
+use std::num::NonZeroU32;
+use std::time::Instant;
+
+/// The token bucket of a single connection.
+pub(super) struct RateLimiter {
+    /// The requests allowed per second, which is also the most tokens held.
+    rate: f64,
+    tokens: f64,
+    /// When the tokens were last topped up.
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// A full bucket, allowing `rate` requests per second.
+    pub fn new(rate: NonZeroU32) -> Self {
+        let rate = rate.get() as f64;
+        RateLimiter {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Take a token for a request, returning whether there was one.
+    pub fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Shipping the writes made through a leader server to the follower servers replicating
+//! it.
+//!
+//! A leader numbers every write made through it in the order it applies them, and keeps
+//! the latest of them in a backlog. A follower subscribes from the offset of the next
+//! write it needs; if that's still in the backlog, the leader streams the writes from
+//! there on. Otherwise, such as when the follower first connects or the leader has
+//! restarted since, the leader first sends a copy of all of its data, which replaces the
+//! follower's.
+
+use super::transport::Connection;
+use super::{ClientError, Command, KvsClient, NetRequest, NetResponse, Response, ServerError};
+use crate::engine::KvsEngine;
+use crate::err::KvsError;
+use crate::Record;
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::io::BufReader;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The number of the latest writes a leader keeps, for followers to resume from.
+const REPLICATION_BACKLOG: usize = 16 * 1024;
+/// The number of writes a follower can fall behind by before it's disconnected.
+const FOLLOWER_BACKLOG: usize = 1024;
+/// How long a follower waits before reconnecting to its leader.
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A write streamed to a follower, with its offset.
+type Replicated = (u64, Record);
+
+/// The writes made through a leader, shared by its connections.
+#[derive(Clone)]
+pub(super) struct Leader {
+    inner: Arc<Mutex<Backlog>>,
+}
+
+/// The latest writes made through a leader, and the followers to stream new ones to.
+pub(super) struct Backlog {
+    /// Identifies the leader's stream of writes, which starts afresh whenever the leader
+    /// does, so that followers don't resume from offsets of an earlier one.
+    stream: u64,
+    /// The offset of the first write in `ops`.
+    start: u64,
+    ops: VecDeque<Record>,
+    followers: Vec<Sender<Replicated>>,
+}
+
+impl Leader {
+    pub fn new() -> Self {
+        let backlog = Backlog {
+            stream: rand::random(),
+            start: 0,
+            ops: VecDeque::new(),
+            followers: vec![],
+        };
+        Leader {
+            inner: Arc::new(Mutex::new(backlog)),
+        }
+    }
+
+    /// Take the backlog, holding up every other write until it's dropped, so that writes
+    /// are numbered in the order they're applied.
+    pub fn lock(&self) -> MutexGuard<'_, Backlog> {
+        self.inner.lock().unwrap()
+    }
+}
+
+impl Backlog {
+    /// The offset the next write will be given.
+    fn end(&self) -> u64 {
+        self.start + self.ops.len() as u64
+    }
+
+    /// Number a write, and send it to every follower.
+    ///
+    /// Followers that have gone away, or fallen too far behind, are dropped; the latter
+    /// see their connection closed, and resume from where they left off.
+    pub fn push(&mut self, op: Record) {
+        let offset = self.end();
+        self.followers
+            .retain(|follower| match follower.try_send((offset, op.clone())) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("dropping follower: too far behind");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+        self.ops.push_back(op);
+        if self.ops.len() > REPLICATION_BACKLOG {
+            self.ops.pop_front();
+            self.start += 1;
+        }
+    }
+
+    /// The `set` of `key` to `value`, numbered and sent to every follower.
+    pub fn push_set(&mut self, key: &str, value: &str) {
+        self.push(Record::Set {
+            bucket: None,
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+            compression: None,
+        });
+    }
+
+    /// The removal of `key`, numbered and sent to every follower.
+    pub fn push_rm(&mut self, key: &str) {
+        self.push(Record::Rm {
+            bucket: None,
+            key: key.as_bytes().to_vec(),
+        });
+    }
+
+    /// Bring up to date the follower that asked with `req` to be sent the writes of
+    /// `stream` from `from_offset` on, returning where to receive the writes after.
+    ///
+    /// Writes through the leader wait until the follower has been sent every write so
+    /// far, a copy of all of the data included if it can't resume where it left off.
+    pub fn subscribe<E: KvsEngine>(
+        &mut self,
+        engine: &E,
+        req: &NetRequest,
+        stream: Option<u64>,
+        from_offset: u64,
+        writer: &mut impl std::io::Write,
+    ) -> Result<Receiver<Replicated>, ServerError> {
+        let mut send = |response| -> Result<(), ServerError> {
+            let response = NetResponse {
+                id: req.id,
+                response,
+            };
+            serde_json::to_writer(&mut *writer, &response)?;
+            Ok(())
+        };
+        let resumes =
+            stream == Some(self.stream) && (self.start..=self.end()).contains(&from_offset);
+        let from_offset = if resumes {
+            from_offset
+        } else {
+            send(Response::Resync)?;
+            for pair in engine.iter()? {
+                let (key, value) = pair?;
+                send(Response::Snapshot(Record::Set {
+                    bucket: None,
+                    key: key.into_bytes(),
+                    value: value.into_bytes(),
+                    compression: None,
+                }))?;
+            }
+            self.end()
+        };
+        send(Response::Synced {
+            stream: self.stream,
+            offset: from_offset,
+        })?;
+        let missed = (from_offset - self.start) as usize;
+        for (offset, op) in (from_offset..).zip(self.ops.range(missed..)) {
+            send(Response::Replicated {
+                offset,
+                op: op.clone(),
+            })?;
+        }
+        writer.flush()?;
+
+        let (sender, receiver) = channel::bounded(FOLLOWER_BACKLOG);
+        self.followers.push(sender);
+        Ok(receiver)
+    }
+}
+
+/// The responses streamed to a follower by a `subscribe` request.
+pub(super) struct Subscription {
+    pub(super) reader: BufReader<Connection>,
+    pub(super) id: u64,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Response, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut responses = serde_json::Deserializer::from_reader(&mut self.reader);
+        let response = match NetResponse::deserialize(&mut responses) {
+            Ok(response) => response,
+            Err(e) if e.is_eof() => return None,
+            Err(e) => return Some(Err(e.into())),
+        };
+        if response.id != self.id {
+            return Some(Err("Invalid response".to_string().into()));
+        }
+        Some(Ok(response.response))
+    }
+}
+
+/// The subscribe request for the writes of `stream` from `from_offset` on.
+pub(super) fn new_subscribe_req(stream: Option<u64>, from_offset: u64) -> NetRequest {
+    NetRequest {
+        id: rand::random::<u64>(),
+        command: Command::Subscribe {
+            from_offset,
+            stream,
+        },
+    }
+}
+
+/// A thread keeping an engine a copy of a leader's, from
+/// [`KvsServer::follow`](super::KvsServer::follow).
+pub(super) struct Follower {
+    stopped: Arc<AtomicBool>,
+    /// The connection to the leader, if there is one, to close when stopping.
+    connection: Arc<Mutex<Option<TcpStream>>>,
+    thread: JoinHandle<()>,
+}
+
+impl Follower {
+    /// Start replicating the leader at `leader` to `engine`, reconnecting whenever the
+    /// connection to it fails.
+    pub fn spawn<E: KvsEngine>(engine: E, leader: SocketAddr) -> Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let connection = Arc::new(Mutex::new(None));
+        let thread = thread::spawn({
+            let (stopped, connection) = (stopped.clone(), connection.clone());
+            move || {
+                // The stream followed, and the offset of the next write needed from it.
+                let mut position = None;
+                while !stopped.load(Ordering::SeqCst) {
+                    let result = follow(&engine, leader, &mut position, &connection, &stopped);
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match result {
+                        Ok(()) => log::warn!("leader {} closed the connection", leader),
+                        Err(e) => log::warn!("replicating from {} failed: {}", leader, e),
+                    }
+                    thread::sleep(RETRY_INTERVAL);
+                }
+            }
+        });
+        Follower {
+            stopped,
+            connection,
+            thread,
+        }
+    }
+
+    /// Stop replicating, waiting for the write being applied, if any.
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(connection) = self.connection.lock().unwrap().take() {
+            let _ = connection.shutdown(Shutdown::Both);
+        }
+        let _ = self.thread.join();
+    }
+}
+
+/// Connect to `leader`, and apply the writes it streams to `engine` until the connection
+/// ends, keeping `position` up to date.
+fn follow<E: KvsEngine>(
+    engine: &E,
+    leader: SocketAddr,
+    position: &mut Option<(u64, u64)>,
+    connection: &Mutex<Option<TcpStream>>,
+    stopped: &AtomicBool,
+) -> Result<(), ClientError> {
+    let client = KvsClient::connect(leader)?;
+    *connection.lock().unwrap() = Some(client.try_clone_stream()?);
+    if stopped.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let (stream, from_offset) = match *position {
+        Some((stream, offset)) => (Some(stream), offset),
+        None => (None, 0),
+    };
+    for response in client.subscribe(stream, from_offset)? {
+        match response? {
+            Response::Resync => {
+                log::info!("copying all of the data of {}", leader);
+                *position = None;
+                clear(engine)?;
+            }
+            Response::Snapshot(op) => apply(engine, op)?,
+            Response::Synced { stream, offset } => *position = Some((stream, offset)),
+            Response::Replicated { offset, op } => match position {
+                Some((_, next)) if *next == offset => {
+                    apply(engine, op)?;
+                    *next += 1;
+                }
+                _ => return Err(format!("unexpected write at offset {}", offset).into()),
+            },
+            Response::Err(e) => return Err(e.into()),
+            _ => return Err("Unexpected response to subscribe".to_string().into()),
+        }
+    }
+    Ok(())
+}
+
+/// Apply a write streamed from the leader.
+fn apply<E: KvsEngine>(engine: &E, op: Record) -> crate::Result<()> {
+    match op {
+        Record::Set {
+            bucket: None,
+            key,
+            value,
+            ..
+        } => engine.set_bytes(&key, &value),
+        Record::Rm { bucket: None, key } => match engine.remove_bytes(&key) {
+            Ok(()) | Err(KvsError::KeyNotFound) => Ok(()),
+            Err(e) => Err(e),
+        },
+        // Only the writes the protocol makes are streamed.
+        op => {
+            log::warn!("skipping write the protocol can't make: {:?}", op);
+            Ok(())
+        }
+    }
+}
+
+/// Remove every key, before copying the leader's.
+fn clear<E: KvsEngine>(engine: &E) -> crate::Result<()> {
+    let keys = engine
+        .iter()?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<crate::Result<Vec<_>>>()?;
+    for key in keys {
+        match engine.remove(key) {
+            Ok(()) | Err(KvsError::KeyNotFound) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -1,27 +1,80 @@
-use super::{Command, NetRequest, NetResponse, ServerError};
-use crate::engine::KvsEngine;
+use super::rate_limit::RateLimiter;
+use super::replication::{Follower, Leader};
+use super::transport::{ChannelHandle, Connection, Pipe};
+use super::watch::Watchers;
+use super::writer::Writer;
+use super::{Command, NetRequest, NetResponse, Response, ServerError};
+use crate::engine::{KvsEngine, ValueReader};
 use crate::thread_pool::ThreadPool;
 use crossbeam::channel::{self, Receiver, Sender};
-use std::io::Write;
 use std::io::{BufReader, BufWriter};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 // Used internally by this module.
 type Result<T> = std::result::Result<T, ServerError>;
 
+/// The largest piece(in bytes) of a value sent in one response to a `GetStream`.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// How long to stop accepting connections for once the process runs out of file
+/// descriptors, for connections being served to finish and free some.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
 /// The KVS server.
 pub struct KvsServer<Engine, Tp> {
-    /// A TCP listener for receiving wire messages.
-    listener: TcpListener,
+    /// TCP listeners for receiving wire messages, one per bound address.
+    listeners: Vec<TcpListener>,
+    /// The in-memory connections opened through a [`ChannelHandle`], if the server was
+    /// bound to one.
+    channel: Option<Receiver<Pipe>>,
     /// The kvstore instance for this server.
     engine: Engine,
     /// The threadpool for servicing stream requests.
     thread_pool: Tp,
     shutdown_init_rx: Receiver<()>,
+    /// Clients watching for changes.
+    watchers: Watchers,
+    /// Whether to set `TCP_NODELAY` on accepted connections.
+    nodelay: bool,
+    /// The thread all writes are applied on, if they aren't applied on the pool.
+    writer: Option<Writer<Engine>>,
+    /// Whether writes are refused, shared with every [`ReadOnlyHandle`].
+    read_only: Arc<AtomicBool>,
+    /// The writes made through the server, if it's a leader.
+    leader: Option<Leader>,
+    /// The leader the server replicates, if it's a follower.
+    follow: Option<SocketAddr>,
+    /// The most requests each connection may send per second, if limited.
+    max_rps_per_connection: Option<NonZeroU32>,
 }
 
 pub struct ShutdownHandle(Sender<()>);
 
+/// A handle to turn a server's read-only mode on and off while it runs, from
+/// [`KvsServer::read_only_handle`].
+///
+/// While it's on, the server refuses every command that changes the store with an
+/// error, and keeps serving the rest.
+#[derive(Clone)]
+pub struct ReadOnlyHandle(Arc<AtomicBool>);
+
+impl ReadOnlyHandle {
+    /// Turn read-only mode on or off, for every connection from their next command.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.0.store(read_only, Ordering::SeqCst);
+    }
+
+    /// Whether read-only mode is on.
+    pub fn is_read_only(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
 impl ShutdownHandle {
     pub fn shutdown(self) -> Result<()> {
         self.0.send(()).map_err(|e| anyhow::anyhow!(e))?;
@@ -35,22 +88,167 @@ impl<Engine: KvsEngine, Tp: ThreadPool + 'static> KvsServer<Engine, Tp> {
         engine: Engine,
         thread_pool: Tp,
     ) -> Result<(Self, ShutdownHandle)> {
-        let listener = TcpListener::bind(bind_addr)?;
-        listener.set_nonblocking(true).unwrap();
+        Self::bind_many(&[bind_addr], engine, thread_pool)
+    }
+
+    /// Listen on every address in `bind_addrs`, such as an IPv4 and an IPv6 one, serving
+    /// connections from all of them with the same engine and thread pool.
+    pub fn bind_many(
+        bind_addrs: &[SocketAddr],
+        engine: Engine,
+        thread_pool: Tp,
+    ) -> Result<(Self, ShutdownHandle)> {
+        if bind_addrs.is_empty() {
+            return Err(anyhow::anyhow!("no address to bind to").into());
+        }
+        let listeners = bind_addrs
+            .iter()
+            .map(|addr| {
+                let listener = TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                Ok(listener)
+            })
+            .collect::<Result<Vec<_>>>()?;
 
         let (shutdown_init_tx, shutdown_init_rx) = channel::bounded::<()>(1);
 
         let server = KvsServer {
-            listener,
+            listeners,
+            channel: None,
             engine,
             thread_pool,
             shutdown_init_rx,
+            watchers: Watchers::default(),
+            nodelay: true,
+            writer: None,
+            read_only: Arc::default(),
+            leader: None,
+            follow: None,
+            max_rps_per_connection: None,
         };
         let shutdown = ShutdownHandle(shutdown_init_tx);
         Ok((server, shutdown))
     }
 
+    /// Serve clients in the same process over in-memory channels rather than sockets,
+    /// returning a handle they connect through with
+    /// [`KvsClient::connect_channel`](super::KvsClient::connect_channel).
+    ///
+    /// Requests and responses are encoded as they would be over TCP, so this exercises
+    /// the whole protocol, such as in tests, without binding a port. The server doesn't
+    /// listen on any address.
+    pub fn bind_channel(
+        engine: Engine,
+        thread_pool: Tp,
+    ) -> Result<(Self, ShutdownHandle, ChannelHandle)> {
+        let (shutdown_init_tx, shutdown_init_rx) = channel::bounded::<()>(1);
+        let (connect_tx, connect_rx) = channel::unbounded();
+
+        let server = KvsServer {
+            listeners: vec![],
+            channel: Some(connect_rx),
+            engine,
+            thread_pool,
+            shutdown_init_rx,
+            watchers: Watchers::default(),
+            nodelay: true,
+            writer: None,
+            read_only: Arc::default(),
+            leader: None,
+            follow: None,
+            max_rps_per_connection: None,
+        };
+        let shutdown = ShutdownHandle(shutdown_init_tx);
+        Ok((server, shutdown, ChannelHandle(connect_tx)))
+    }
+
+    /// Set whether `TCP_NODELAY` is set on accepted connections, which it is by default.
+    ///
+    /// Requests and responses are small, so without it each one can wait on Nagle's
+    /// algorithm. Disabling it may help throughput when clients pipeline requests.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    /// Set whether writes are applied on a single thread of their own, rather than on
+    /// the pool alongside reads, which they aren't by default.
+    ///
+    /// With an engine like [`KvStore`](crate::KvStore), whose writes take turns on a
+    /// single lock anyway, this saves handing the lock from thread to thread under a
+    /// heavy write load. Reads are still served on the pool.
+    pub fn set_single_writer(&mut self, single_writer: bool) {
+        self.writer = single_writer.then(|| Writer::spawn(self.engine.clone()));
+    }
+
+    /// Limit each connection to `max` requests per second, or let them send any number
+    /// if `None`, which is the default.
+    ///
+    /// A connection can send up to `max` requests at once, after which requests beyond
+    /// the limit are refused with an error rather than served, so that one client can't
+    /// starve the others. Watches and subscriptions, once started, aren't limited.
+    pub fn set_max_rps_per_connection(&mut self, max: Option<NonZeroU32>) {
+        self.max_rps_per_connection = max;
+    }
+
+    /// A handle to turn read-only mode on and off, which it's off by default, such as for
+    /// a maintenance window.
+    ///
+    /// Turning it on before running the server starts it read-only.
+    pub fn read_only_handle(&self) -> ReadOnlyHandle {
+        ReadOnlyHandle(self.read_only.clone())
+    }
+
+    /// A handle to the engine the server serves, for reading and writing it in-process
+    /// while the server also serves it over TCP.
+    ///
+    /// Writes made through the handle aren't streamed to followers, or to clients
+    /// watching for changes, as those are only told of writes made through the server.
+    pub fn engine_handle(&self) -> Engine {
+        self.engine.clone()
+    }
+
+    /// Set whether the server is a leader, streaming every write made through it to
+    /// follower servers, which it isn't by default.
+    ///
+    /// Writes through a leader are applied one at a time, in the order they're streamed.
+    /// Writes made to the engine other than through the server aren't replicated.
+    pub fn set_leader(&mut self, leader: bool) {
+        self.leader = leader.then(Leader::new);
+    }
+
+    /// Make the server a follower of the leader at `leader`, keeping its engine a copy of
+    /// the leader's once it's running.
+    ///
+    /// The engine's own data is replaced with the leader's when the follower first
+    /// connects. Read-only mode is turned on, so that the server serves reads but refuses
+    /// writes; turning it off lets the follower drift from the leader.
+    pub fn follow(&mut self, leader: SocketAddr) {
+        self.follow = Some(leader);
+        self.read_only.store(true, Ordering::SeqCst);
+    }
+
+    /// The address the server is listening on, or the first of them if it's listening
+    /// on several.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        match self.listeners.first() {
+            Some(listener) => Ok(listener.local_addr()?),
+            None => Err(anyhow::anyhow!("not listening on any address").into()),
+        }
+    }
+
+    /// Every address the server is listening on, in the order they were bound.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(self
+            .listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<std::io::Result<_>>()?)
+    }
+
     pub fn run(self) -> Result<()> {
+        let follower = self
+            .follow
+            .map(|leader| Follower::spawn(self.engine.clone(), leader));
         loop {
             match self.shutdown_init_rx.try_recv() {
                 Ok(_) => {
@@ -62,31 +260,91 @@ impl<Engine: KvsEngine, Tp: ThreadPool + 'static> KvsServer<Engine, Tp> {
                 }
             }
 
-            match self.listener.accept() {
-                Ok((stream, addr)) => {
-                    log::debug!("New connection from {addr}");
-                    let engine = self.engine.clone();
-
-                    self.thread_pool.spawn(move || {
-                        if let Err(err) = run(engine, stream) {
-                            log::error!("run error: {err}");
+            for listener in &self.listeners {
+                match listener.accept() {
+                    Ok((stream, addr)) => {
+                        log::debug!("New connection from {addr}");
+                        if let Err(e) = stream.set_nodelay(self.nodelay) {
+                            log::warn!("Failed to set TCP_NODELAY for {addr}: {e}");
                         }
-                    });
+                        self.serve(Connection::Tcp(stream));
+                    }
+                    Err(e) if out_of_fds(&e) => {
+                        log::warn!(
+                            "out of file descriptors, pausing accepts for {:?}: {e}",
+                            ACCEPT_BACKOFF
+                        );
+                        thread::sleep(ACCEPT_BACKOFF);
+                    }
+                    Err(e) => log::debug!("Accept error: {e}"),
+                }
+            }
+            if let Some(channel) = &self.channel {
+                for pipe in channel.try_iter() {
+                    self.serve(Connection::Channel(pipe));
                 }
-                Err(e) => log::debug!("Accept error: {e}"),
             }
         }
         log::debug!("waiting for streams shutdown");
+        if let Some(follower) = follower {
+            follower.stop();
+        }
 
         Ok(())
     }
+
+    /// Serve the requests sent over `stream` on the thread pool.
+    fn serve(&self, stream: Connection) {
+        let engine = self.engine.clone();
+        let watchers = self.watchers.clone();
+        let writer = self.writer.clone();
+        let read_only = self.read_only_handle();
+        let leader = self.leader.clone();
+        let limiter = self.max_rps_per_connection.map(RateLimiter::new);
+
+        self.thread_pool.spawn(move || {
+            let result = run(engine, stream, watchers, writer, read_only, leader, limiter);
+            if let Err(err) = result {
+                log::error!("run error: {err}");
+            }
+        });
+    }
+}
+
+/// Whether `e` is the OS refusing to open another file descriptor, as `EMFILE` once the
+/// process has used up its limit or `ENFILE` once the system has.
+#[cfg(unix)]
+fn out_of_fds(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+#[cfg(not(unix))]
+fn out_of_fds(_: &std::io::Error) -> bool {
+    false
+}
+
+/// Apply `write` to `engine`, on the writer thread if there is one.
+fn apply_write<T: KvsEngine, R: Send + 'static>(
+    engine: &T,
+    writer_thread: &Option<Writer<T>>,
+    write: impl FnOnce(&T) -> R + Send + 'static,
+) -> R {
+    match writer_thread {
+        Some(writer_thread) => writer_thread.run(write),
+        None => write(engine),
+    }
 }
 
-fn run<T: KvsEngine>(engine: T, stream: TcpStream) -> Result<()> {
-    log::debug!(
-        "received new connection from {:?}",
-        stream.peer_addr().unwrap()
-    );
+fn run<T: KvsEngine>(
+    engine: T,
+    stream: Connection,
+    watchers: Watchers,
+    writer_thread: Option<Writer<T>>,
+    read_only: ReadOnlyHandle,
+    leader: Option<Leader>,
+    mut limiter: Option<RateLimiter>,
+) -> Result<()> {
+    log::debug!("received new connection from {}", stream);
     let reader = BufReader::new(&stream);
     let mut writer = BufWriter::new(&stream);
 
@@ -94,34 +352,251 @@ fn run<T: KvsEngine>(engine: T, stream: TcpStream) -> Result<()> {
     for request in requests {
         let req = request?;
         log::debug!("Received request: {:?}", req);
+        if !limiter.as_mut().is_none_or(RateLimiter::allow) {
+            respond(
+                &mut writer,
+                NetResponse::err(&req, ServerError::RateLimited),
+            )?;
+            continue;
+        }
+        if req.command.is_write() && read_only.is_read_only() {
+            respond(&mut writer, NetResponse::err(&req, ServerError::ReadOnly))?;
+            continue;
+        }
+        // Writes through a leader are numbered in the order they're applied.
+        let mut backlog = match &leader {
+            Some(leader) if req.command.is_write() => Some(leader.lock()),
+            _ => None,
+        };
         let response = match &req.command {
-            Command::Get { key } => {
+            Command::Hello { version } => {
+                log::debug!("client version {}", version);
+                NetResponse::hello(&req)
+            }
+            Command::Get { key, meta: true } => match engine.get_with_meta(key.clone()) {
+                Ok(entry) => NetResponse::entry(&req, entry),
+                Err(e) => NetResponse::err(&req, e.into()),
+            },
+            Command::Get { key, meta: false } => {
                 let res = engine.get(key.clone());
                 match res {
+                    Ok(value) => NetResponse::value(&req, value),
+                    Err(e) => NetResponse::err(&req, e.into()),
+                }
+            }
+            Command::GetStream { key } => match engine.get_reader(key.clone()) {
+                Ok(Some(reader)) => {
+                    send_chunks(&req, reader, &mut writer)?;
+                    continue;
+                }
+                Ok(None) => NetResponse::length(&req, None),
+                Err(e) => NetResponse::err(&req, e.into()),
+            },
+            Command::GetRange { key, start, end } => {
+                // Past the end of any value this platform can hold, either way.
+                let clamp = |n: u64| usize::try_from(n).unwrap_or(usize::MAX);
+                let range = clamp(*start)..clamp(*end);
+                match engine.getrange(key.clone(), range) {
+                    Ok(value) => NetResponse::value(&req, value),
                     Err(e) => NetResponse::err(&req, e.into()),
-                    Ok(None) => NetResponse::success(&req, None),
-                    Ok(some_value) => NetResponse::success(&req, some_value),
                 }
             }
+            Command::ValueLen { key } => match engine.value_len(key.clone()) {
+                Ok(len) => NetResponse::length(&req, len),
+                Err(e) => NetResponse::err(&req, e.into()),
+            },
+            Command::Flush => match engine.sync() {
+                Ok(()) => NetResponse::ok(&req),
+                Err(e) => NetResponse::err(&req, e.into()),
+            },
+            Command::CompactionStatus => match engine.compaction_status() {
+                Ok(status) => NetResponse::compaction(&req, status),
+                Err(e) => NetResponse::err(&req, e.into()),
+            },
             Command::Rm { key } => {
-                let res = engine.remove(key.clone());
+                let res = apply_write(&engine, &writer_thread, {
+                    let key = key.clone();
+                    move |engine| engine.remove(key)
+                });
                 match res {
-                    Ok(()) => NetResponse::success(&req, None),
+                    Ok(()) => {
+                        if let Some(backlog) = &mut backlog {
+                            backlog.push_rm(key);
+                        }
+                        watchers.publish(key, None);
+                        NetResponse::ok(&req)
+                    }
                     Err(e) => NetResponse::err(&req, e.into()),
                 }
             }
             Command::Set { key, value } => {
-                let res = engine.set(key.clone(), value.clone());
+                let res = apply_write(&engine, &writer_thread, {
+                    let (key, value) = (key.clone(), value.clone());
+                    move |engine| engine.set(key, value)
+                });
                 match res {
-                    Ok(()) => NetResponse::success(&req, None),
+                    Ok(()) => {
+                        if let Some(backlog) = &mut backlog {
+                            backlog.push_set(key, value);
+                        }
+                        watchers.publish(key, Some(value));
+                        NetResponse::ok(&req)
+                    }
+                    Err(e) => NetResponse::err(&req, e.into()),
+                }
+            }
+            Command::Increment { key, by } => {
+                let res = apply_write(&engine, &writer_thread, {
+                    let (key, by) = (key.clone(), *by);
+                    move |engine| engine.increment(key, by)
+                });
+                match res {
+                    Ok(n) => {
+                        if let Some(backlog) = &mut backlog {
+                            backlog.push_set(key, &n.to_string());
+                        }
+                        watchers.publish(key, Some(&n.to_string()));
+                        NetResponse::integer(&req, n)
+                    }
                     Err(e) => NetResponse::err(&req, e.into()),
                 }
             }
+            Command::Copy {
+                src,
+                dst,
+                overwrite,
+            } => {
+                let res = apply_write(&engine, &writer_thread, {
+                    let (src, dst, overwrite) = (src.clone(), dst.clone(), *overwrite);
+                    move |engine| engine.copy(src, dst, overwrite)
+                });
+                match res {
+                    Ok(()) => {
+                        if backlog.is_some() || watchers.watching(dst) {
+                            let value = engine.get(dst.clone()).ok().flatten();
+                            if let (Some(backlog), Some(value)) = (&mut backlog, &value) {
+                                backlog.push_set(dst, value);
+                            }
+                            watchers.publish(dst, value.as_deref());
+                        }
+                        NetResponse::ok(&req)
+                    }
+                    Err(e) => NetResponse::err(&req, e.into()),
+                }
+            }
+            Command::Watch { prefix } => {
+                let events = watchers.register(prefix.clone());
+                writer.write_all(&serde_json::to_vec(&NetResponse::ok(&req))?)?;
+                writer.flush()?;
+
+                // The connection is given over to the watch from here on, on its own
+                // thread so that it doesn't hold up the pool.
+                let stream = stream.try_clone()?;
+                std::thread::spawn(move || {
+                    if let Err(err) = stream_changes(req, events, stream) {
+                        log::debug!("watch ended: {err}");
+                    }
+                });
+                return Ok(());
+            }
+            Command::Subscribe {
+                from_offset,
+                stream: followed,
+            } => {
+                let Some(leader) = &leader else {
+                    respond(&mut writer, NetResponse::err(&req, ServerError::NotLeader))?;
+                    continue;
+                };
+                let writes =
+                    leader
+                        .lock()
+                        .subscribe(&engine, &req, *followed, *from_offset, &mut writer)?;
+                drop(writer);
+
+                // Like a watch, the connection is given over to streaming writes.
+                let stream = stream.try_clone()?;
+                std::thread::spawn(move || {
+                    if let Err(err) = stream_writes(req, writes, stream) {
+                        log::debug!("follower went away: {err}");
+                    }
+                });
+                return Ok(());
+            }
+        };
+        drop(backlog);
+
+        respond(&mut writer, response)?;
+    }
+    Ok(())
+}
+
+fn respond(writer: &mut impl Write, response: NetResponse) -> Result<()> {
+    log::debug!("responding: {:?}", response);
+    let response = serde_json::to_vec(&response)?;
+    writer.write_all(&response)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Send the value read by `reader` to the client that asked for it with `req`: its
+/// length, then its chunks.
+///
+/// If reading the value fails part way through, an error is sent in place of the rest.
+fn send_chunks(req: &NetRequest, mut reader: ValueReader, writer: &mut impl Write) -> Result<()> {
+    let send = |writer: &mut dyn Write, response| -> Result<()> {
+        serde_json::to_writer(&mut *writer, &response)?;
+        Ok(())
+    };
+    send(writer, NetResponse::length(req, Some(reader.len())))?;
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => send(writer, NetResponse::chunk(req, chunk[..n].to_vec()))?,
+            Err(e) => {
+                send(writer, NetResponse::err(req, ServerError::Io(e)))?;
+                break;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Send each write received on `writes` to the follower that asked for them with `req`.
+fn stream_writes(
+    req: NetRequest,
+    writes: Receiver<(u64, crate::Record)>,
+    stream: Connection,
+) -> Result<()> {
+    let mut writer = BufWriter::new(&stream);
+    for (offset, op) in writes {
+        let response = NetResponse {
+            id: req.id,
+            response: Response::Replicated { offset, op },
         };
+        writer.write_all(&serde_json::to_vec(&response)?)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
 
-        log::debug!("responding: {:?}", response);
-        let response = serde_json::to_vec(&response)?;
-        writer.write_all(&response)?;
+/// Send each change received on `events` to the client that asked for them with `req`.
+fn stream_changes(
+    req: NetRequest,
+    events: Receiver<super::WatchEvent>,
+    stream: Connection,
+) -> Result<()> {
+    let mut writer = BufWriter::new(&stream);
+    for event in events {
+        let response = NetResponse {
+            id: req.id,
+            response: Response::Changed {
+                key: event.key,
+                value: event.value,
+            },
+        };
+        writer.write_all(&serde_json::to_vec(&response)?)?;
         writer.flush()?;
     }
     Ok(())
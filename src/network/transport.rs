@@ -0,0 +1,176 @@
+//! The connections requests and responses are sent over: TCP streams, or in-memory pipes
+//! between a client and a server in the same process.
+
+use crossbeam::channel::{self, Receiver, Sender};
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+
+/// A connection between a client and a server.
+pub(super) enum Connection {
+    Tcp(TcpStream),
+    Channel(Pipe),
+}
+
+impl Connection {
+    /// Another handle to the same connection.
+    ///
+    /// Only one handle to an in-memory connection should read from it, as what one has
+    /// received but not yet read isn't seen by the others.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Connection::Tcp(stream) => stream.try_clone().map(Connection::Tcp),
+            Connection::Channel(pipe) => Ok(Connection::Channel(pipe.clone())),
+        }
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Connection::Tcp(stream) => match stream.peer_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => f.write_str("a closed TCP connection"),
+            },
+            Connection::Channel(_) => f.write_str("an in-memory channel"),
+        }
+    }
+}
+
+impl Read for &Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => (&*stream).read(buf),
+            Connection::Channel(pipe) => (&*pipe).read(buf),
+        }
+    }
+}
+
+impl Write for &Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => (&*stream).write(buf),
+            Connection::Channel(pipe) => (&*pipe).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => (&*stream).flush(),
+            Connection::Channel(pipe) => (&*pipe).flush(),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+/// One end of an in-memory connection: what's written to it is read from the other end,
+/// a write at a time.
+///
+/// Reading from it once every handle to the other end is dropped reads the end of the
+/// stream, and writing to it fails.
+pub(super) struct Pipe {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    /// What's left of the last write received, not yet read.
+    unread: Mutex<Cursor<Vec<u8>>>,
+}
+
+impl Pipe {
+    /// The two ends of a new connection.
+    pub fn pair() -> (Pipe, Pipe) {
+        let (to_b, from_a) = channel::unbounded();
+        let (to_a, from_b) = channel::unbounded();
+        let end = |sender, receiver| Pipe {
+            sender,
+            receiver,
+            unread: Mutex::default(),
+        };
+        (end(to_b, from_b), end(to_a, from_a))
+    }
+
+    /// Whether there's anything received on this end not yet read.
+    pub fn has_unread(&self) -> bool {
+        let unread = self.unread.lock().unwrap();
+        (unread.position() as usize) < unread.get_ref().len() || !self.receiver.is_empty()
+    }
+}
+
+impl Clone for Pipe {
+    fn clone(&self) -> Self {
+        Pipe {
+            sender: self.sender.clone(),
+            receiver: self.receiver.clone(),
+            unread: Mutex::default(),
+        }
+    }
+}
+
+impl Read for &Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut unread = self.unread.lock().unwrap();
+        loop {
+            let n = unread.read(buf)?;
+            if n > 0 {
+                return Ok(n);
+            }
+            match self.receiver.recv() {
+                Ok(received) => *unread = Cursor::new(received),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+impl Write for &Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.sender
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the other end was closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A handle for connecting clients to a server in the same process without going
+/// through sockets, from [`KvsServer::bind_channel`](super::KvsServer::bind_channel).
+///
+/// Clients connect with [`KvsClient::connect_channel`](super::KvsClient::connect_channel),
+/// and speak the same protocol as they would over TCP.
+#[derive(Clone)]
+pub struct ChannelHandle(pub(super) Sender<Pipe>);
+
+impl ChannelHandle {
+    /// Open a new connection, handing the server its end and returning the client's.
+    pub(super) fn connect(&self) -> io::Result<Pipe> {
+        let (client, server) = Pipe::pair();
+        self.0.send(server).map_err(|_| {
+            io::Error::new(io::ErrorKind::ConnectionRefused, "the server has stopped")
+        })?;
+        Ok(client)
+    }
+}
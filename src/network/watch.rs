@@ -0,0 +1,98 @@
+//! Forwarding changes made through the server to clients watching for them.
+
+use super::transport::Connection;
+use super::{ClientError, NetResponse, Response};
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use serde::Deserialize;
+use std::io::BufReader;
+use std::sync::{Arc, Mutex};
+
+/// The number of changes a watcher can fall behind by before it's disconnected.
+const WATCH_BACKLOG: usize = 1024;
+
+/// A change to a key, as seen by a watcher.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// The key that changed.
+    pub key: String,
+    /// The key's new value, or `None` if it was removed.
+    pub value: Option<String>,
+}
+
+/// A registered watcher: the prefix it's watching, and where to send changes.
+type Watcher = (String, Sender<WatchEvent>);
+
+/// The watchers registered with a server.
+#[derive(Clone, Default)]
+pub(super) struct Watchers {
+    inner: Arc<Mutex<Vec<Watcher>>>,
+}
+
+impl Watchers {
+    /// Register a watcher for keys starting with `prefix`.
+    pub fn register(&self, prefix: String) -> Receiver<WatchEvent> {
+        let (sender, receiver) = channel::bounded(WATCH_BACKLOG);
+        self.inner.lock().unwrap().push((prefix, sender));
+        receiver
+    }
+
+    /// Whether any watcher's prefix matches `key`.
+    pub fn watching(&self, key: &str) -> bool {
+        let watchers = self.inner.lock().unwrap();
+        watchers
+            .iter()
+            .any(|(prefix, _)| key.starts_with(prefix.as_str()))
+    }
+
+    /// Send a change to every watcher of a matching prefix.
+    ///
+    /// Watchers that have gone away, or fallen too far behind, are dropped; the latter
+    /// see their connection closed once they catch up.
+    pub fn publish(&self, key: &str, value: Option<&str>) {
+        let mut watchers = self.inner.lock().unwrap();
+        watchers.retain(|(prefix, sender)| {
+            if !key.starts_with(prefix.as_str()) {
+                return true;
+            }
+            let event = WatchEvent {
+                key: key.to_owned(),
+                value: value.map(str::to_owned),
+            };
+            match sender.try_send(event) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("dropping watcher of {:?}: too far behind", prefix);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+/// The changes streamed to a client by a `watch` request.
+pub struct Watch {
+    pub(super) reader: BufReader<Connection>,
+    pub(super) id: u64,
+}
+
+impl Iterator for Watch {
+    type Item = Result<WatchEvent, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut responses = serde_json::Deserializer::from_reader(&mut self.reader);
+        let response = match NetResponse::deserialize(&mut responses) {
+            Ok(response) => response,
+            Err(e) if e.is_eof() => return None,
+            Err(e) => return Some(Err(e.into())),
+        };
+        if response.id != self.id {
+            return Some(Err("Invalid response".to_string().into()));
+        }
+        match response.response {
+            Response::Changed { key, value } => Some(Ok(WatchEvent { key, value })),
+            Response::Err(e) => Some(Err(e.into())),
+            _ => Some(Err("Unexpected response to watch".to_string().into())),
+        }
+    }
+}
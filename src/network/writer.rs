@@ -0,0 +1,52 @@
+//! A single thread that applies every write to the engine, for servers that opt in with
+//! [`set_single_writer`](super::KvsServer::set_single_writer).
+//!
+//! Connection threads hand each write over a channel and wait for its result, so writes
+//! are applied one at a time by the same thread instead of contending for the engine's
+//! lock from every thread of the pool.
+
+use crate::engine::KvsEngine;
+use crossbeam::channel::{self, Sender};
+use std::panic::{self, AssertUnwindSafe};
+
+type Job<Engine> = Box<dyn FnOnce(&Engine) + Send>;
+
+/// A handle to the writer thread, which runs until every handle is dropped.
+pub(super) struct Writer<Engine> {
+    jobs: Sender<Job<Engine>>,
+}
+
+impl<Engine> Clone for Writer<Engine> {
+    fn clone(&self) -> Self {
+        Writer {
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+
+impl<Engine: KvsEngine> Writer<Engine> {
+    /// Start a writer thread applying writes to `engine`.
+    pub fn spawn(engine: Engine) -> Self {
+        let (jobs, received) = channel::unbounded::<Job<Engine>>();
+        std::thread::spawn(move || {
+            for job in received {
+                // A write that panics fails its own request, but not the ones after it.
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| job(&engine)));
+            }
+        });
+        Writer { jobs }
+    }
+
+    /// Apply `write` on the writer thread, waiting for its result.
+    pub fn run<R: Send + 'static>(&self, write: impl FnOnce(&Engine) -> R + Send + 'static) -> R {
+        let (result, received) = channel::bounded(1);
+        self.jobs
+            .send(Box::new(move |engine| {
+                let _ = result.send(write(engine));
+            }))
+            .expect("the writer thread runs while there are handles to it");
+        received
+            .recv()
+            .expect("the write panicked on the writer thread")
+    }
+}
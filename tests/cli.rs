@@ -1,4 +1,6 @@
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -20,28 +22,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +54,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +93,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +125,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +136,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,25 +147,41 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-server --max-rps-per-connection 0` should be refused, as it would refuse every
+// request
+#[test]
+fn server_cli_zero_rate_limit() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4008", "--max-rps-per-connection", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-server` should log its version and settings to stderr, without `RUST_LOG` set
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .env_remove("RUST_LOG")
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -171,22 +189,77 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// `kvs-server --log-level debug` should log debug output, which it doesn't by default,
+// whatever `RUST_LOG` says
+#[test]
+fn server_cli_log_level() {
+    let server_stderr = |args: &[&str]| {
+        let temp_dir = TempDir::new().unwrap();
+        let stderr_path = temp_dir.path().join("stderr");
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", "127.0.0.1:4007"])
+            .args(args)
+            .env("RUST_LOG", "warn")
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+        fs::read_to_string(&stderr_path).expect("unable to read from stderr file")
+    };
+
+    let debug = server_stderr(&["--log-level", "debug"]);
+    assert!(debug.contains("DEBUG"), "{}", debug);
+    assert!(debug.contains(env!("CARGO_PKG_VERSION")), "{}", debug);
+    let default = server_stderr(&[]);
+    assert!(!default.contains("DEBUG"), "{}", default);
+    assert!(!default.contains("INFO"), "{}", default);
+}
+
+// `kvs-client --log-level debug` should log debug output, which it doesn't by default
 #[test]
+fn client_cli_log_level() {
+    let temp_dir = TempDir::new().unwrap();
+    // Nothing listens on port 1, so connecting fails, which is logged at debug.
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(["get", "key", "--addr", "127.0.0.1:1"])
+            .args(args)
+            .env_remove("RUST_LOG")
+            .current_dir(&temp_dir);
+        cmd
+    };
+    client(&["--log-level", "debug"])
+        .assert()
+        .failure()
+        .stderr(contains("DEBUG"));
+    client(&[])
+        .assert()
+        .failure()
+        .stderr(contains("DEBUG").not());
+}
+
+#[test]
+#[cfg(feature = "sled")]
 fn cli_wrong_engine() {
     // sled first, kvs second
     {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,39 +270,42 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
     }
 }
 
-fn cli_access_server(engine: &str, addr: &str) {
+fn cli_access_server(args: &[&str], addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(args)
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +313,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +321,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +329,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +337,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +345,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +353,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,12 +361,44 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cp", "key2", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key4", "", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cp", "key2", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key already exists"));
+
     sender.send(()).unwrap();
     handle.join().unwrap();
     thread::sleep(Duration::from_secs(1));
@@ -299,26 +407,35 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(args)
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value3"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -327,12 +444,191 @@ fn cli_access_server(engine: &str, addr: &str) {
     handle.join().unwrap();
 }
 
+// `kvs-server --engine sled` should fail when built without the `sled` feature, which
+// `cargo test --no-default-features --features lz4,mmap` checks
+#[test]
+#[cfg(not(feature = "sled"))]
+fn cli_sled_disabled() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("doesn't include the sled engine"));
+}
+
 #[test]
 fn cli_access_server_kvs_engine() {
-    cli_access_server("kvs", "127.0.0.1:4004");
+    cli_access_server(&["--engine", "kvs"], "127.0.0.1:4004");
 }
 
 #[test]
+#[cfg(feature = "sled")]
 fn cli_access_server_sled_engine() {
-    cli_access_server("sled", "127.0.0.1:4005");
+    cli_access_server(&["--engine", "sled"], "127.0.0.1:4005");
+}
+
+// `kvs-server --single-writer` should serve the sled engine as it does the kvs engine
+#[test]
+#[cfg(feature = "sled")]
+fn cli_access_server_sled_single_writer() {
+    cli_access_server(&["--engine", "sled", "--single-writer"], "127.0.0.1:4009");
+}
+
+// `kvs log-dump <log>` should print every record of the log as a line of JSON
+#[test]
+fn cli_log_dump() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value\n2".to_owned()).unwrap();
+    store.remove("key1".to_owned()).unwrap();
+    drop(store);
+
+    let set1 = r#"{"offset":8,"len":74,"op":{"op":"set","key":"key1","value":"value1"},"valid_checksum":null}"#;
+    let set2 = r#"{"offset":82,"len":76,"op":{"op":"set","key":"key2","value":"value\n2"},"valid_checksum":null}"#;
+    let rm = r#"{"offset":158,"len":29,"op":{"op":"rm","key":"key1"},"valid_checksum":null}"#;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["log-dump", "kvstore-logs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{set1}\n{set2}\n{rm}\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "log-dump",
+            "kvstore-logs",
+            "--from-offset",
+            "82",
+            "--limit",
+            "1",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{set2}\n"));
+
+    // A corrupt record in the middle is reported, and the dump carries on past it
+    let log = temp_dir.path().join("kvstore-logs");
+    let contents = fs::read_to_string(&log)
+        .unwrap()
+        .replacen("key2", "key2\"", 1);
+    fs::write(&log, contents).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["log-dump", "kvstore-logs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(set1))
+        .stdout(contains(r#"{"offset":82,"len":77,"op":null,"error":"#))
+        .stdout(contains(
+            r#"{"offset":159,"len":29,"op":{"op":"rm","key":"key1"}"#,
+        ));
+}
+
+// `kvs compact --dry-run <dir>` should estimate what compaction reclaims, leaving the log alone
+#[test]
+fn cli_compact() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for _ in 0..10 {
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+    drop(store);
+    let log = temp_dir.path().join("kvstore-logs");
+    let before = fs::metadata(&log).unwrap().len();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", ".", "--dry-run"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!(r#""log_size": {}"#, before)))
+        .stdout(contains(r#""live_size": 75"#))
+        .stdout(contains(r#""projected_size": 83"#));
+    assert_eq!(fs::metadata(&log).unwrap().len(), before);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("compacted log from {} to 83 bytes\n", before));
+}
+
+// `kvs verify <dir>` should report on a store, failing if it can't be opened
+#[test]
+fn cli_verify() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(r#""live_keys": 2"#))
+        .stdout(contains(r#""openable": true"#));
+
+    let log = temp_dir.path().join("kvstore-logs");
+    let contents = fs::read(&log).unwrap();
+    fs::write(&log, &contents[..contents.len() - 3]).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains(r#""openable": false"#));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", ".", "--repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(r#""live_keys": 1"#));
+}
+
+// `kvs history <dir> <key>` should print the values of a key, newest first
+#[test]
+fn cli_history() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..3 {
+        store.set("key1".to_owned(), format!("value{}", i)).unwrap();
+    }
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["history", ".", "key1", "--limit", "2"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with(r#"{"value":"value2","segment":0,"offset":156,"#));
+    assert!(lines[1].starts_with(r#"{"value":"value1","segment":0,"offset":82,"#));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["history", ".", "missing"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
 }
@@ -0,0 +1,98 @@
+//! Serving connections once the process runs out of file descriptors.
+//!
+//! This runs in a binary of its own, as it lowers the limit on file descriptors for the
+//! whole process and watches every warning logged.
+#![cfg(target_os = "linux")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, Result};
+use log::{Level, Log, Metadata, Record};
+use std::fs;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Counts the warnings about running out of file descriptors.
+struct Warnings;
+
+static OUT_OF_FDS: AtomicUsize = AtomicUsize::new(0);
+
+impl Log for Warnings {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Warn
+            && record
+                .args()
+                .to_string()
+                .contains("out of file descriptors")
+        {
+            OUT_OF_FDS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Set the soft limit on the process's file descriptors to `limit`.
+fn set_fd_limit(limit: u64) {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit), 0);
+        rlimit.rlim_cur = limit.min(rlimit.rlim_max);
+        assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit), 0);
+    }
+}
+
+// Should back off from accepting connections while out of file descriptors, rather than
+// retrying in a hot loop, and serve new connections once some are freed
+#[test]
+fn accept_backs_off_out_of_fds() -> Result<()> {
+    log::set_logger(&Warnings).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let (server, shutdown) = KvsServer::bind("127.0.0.1:0".parse().unwrap(), store, pool).unwrap();
+    let addr = server.local_addr().unwrap();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    // Connect until there are no descriptors left, leaving connections waiting to be
+    // accepted.
+    let open = fs::read_dir("/proc/self/fd")?.count() as u64;
+    set_fd_limit(open + 16);
+    let mut connections = vec![];
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(connection) => connections.push(connection),
+            Err(e) if e.raw_os_error() == Some(libc::EMFILE) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let before = OUT_OF_FDS.load(Ordering::SeqCst);
+    thread::sleep(Duration::from_secs(1));
+    let warnings = OUT_OF_FDS.load(Ordering::SeqCst) - before;
+    assert!(
+        (1..=20).contains(&warnings),
+        "warned of running out {} times in a second",
+        warnings
+    );
+
+    drop(connections);
+    set_fd_limit(open + 1024);
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("key1").unwrap(), Some("value1".to_owned()));
+    drop(client);
+    shutdown.shutdown().unwrap();
+    server_thread.join().unwrap();
+    Ok(())
+}
@@ -1,6 +1,23 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+#[cfg(feature = "lz4")]
+use kvs::Compression;
+use kvs::{
+    Bucket, ChangeEvent, Codec, CompactionPhase, Durability, ImportMode, KvStore, KvStoreOptions,
+    KvsEngine, KvsError, Record, RecordInfo, RecoveryMode, RecoveryReport, Result, ValueReader,
+    WriteOutcome,
+};
+#[cfg(feature = "sled")]
+use kvs::{
+    SledBucket, SledDurability, SledEngine, SledMode, SledOptions, SledTransaction, SledTxResult,
+};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::Read;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -132,15 +149,16 @@ fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
-        });
+        }));
     }
     barrier.wait();
 
@@ -148,8 +166,11 @@ fn concurrent_set() -> Result<()> {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data, once every clone is gone
     drop(store);
+    for handle in handles {
+        handle.join().unwrap();
+    }
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
@@ -209,3 +230,4233 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should lay the log out according to the given options and find it again on reopen
+#[test]
+fn open_with_custom_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::new()
+            .data_dir("data")
+            .log_name("custom-log")
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("data").join("custom-log").is_file());
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // A store with another log name in the same directory is independent.
+    let other = KvStore::open_with_options(temp_dir.path(), options().log_name("other-log"))?;
+    assert_eq!(other.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should keep the files of stores whose log names share a prefix apart
+#[test]
+fn stores_sharing_a_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |name: &str| {
+        KvStoreOptions::new()
+            .log_name(name)
+            .max_segment_size(Some(64))
+            .compaction_threshold(None)
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options("log"))?;
+    let other = KvStore::open_with_options(temp_dir.path(), options("log.1"))?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        other.set(format!("key{}", i), "other".to_owned())?;
+    }
+    drop(store);
+    drop(other);
+
+    let repair = options("log").repair_manifest(true);
+    let store = KvStore::open_with_options(temp_dir.path(), repair)?;
+    store.compact()?;
+    drop(store);
+    KvStore::destroy_with_options(temp_dir.path(), options("log"))?;
+    let other = KvStore::open_with_options(temp_dir.path(), options("log.1"))?;
+    for i in 0..10 {
+        assert_eq!(other.get(format!("key{}", i))?, Some("other".to_owned()));
+    }
+    drop(other);
+
+    // A log name numbered files could have is refused
+    assert!(KvStore::open_with_options(temp_dir.path(), options("log.seg-1")).is_err());
+
+    Ok(())
+}
+
+// Should treat the path as the log file itself when asked to
+#[test]
+fn open_literal_log_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("nested").join("store.log");
+    let options = || KvStoreOptions::new().literal_path(true);
+
+    let store = KvStore::open_with_options(&log_path, options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(log_path.is_file());
+
+    let store = KvStore::open_with_options(&log_path, options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should open a log file under any name, such as a copy taken as a backup
+#[test]
+fn open_log_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup = backup_dir.path().join("backup.log");
+    fs::copy(temp_dir.path().join("kvstore-logs"), &backup)?;
+
+    let store = KvStore::open_file(&backup)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_file(&backup)?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(
+        KvStore::open(temp_dir.path())?.get("key3".to_owned())?,
+        None
+    );
+
+    Ok(())
+}
+
+// Should create missing directories, and refuse to treat a file as one
+#[test]
+fn open_creates_missing_directories() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("a").join("b").join("c");
+
+    let store = KvStore::open(&path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let file_path = temp_dir.path().join("file");
+    fs::write(&file_path, "not a directory")?;
+    match KvStore::open(&file_path) {
+        Err(KvsError::NotADirectory(p)) => assert_eq!(p, file_path),
+        other => panic!("expected NotADirectory, got {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// Should compact once per crossing of the threshold under concurrent writers, rather
+// than again for each writer that saw it crossed before the first compaction finished
+#[test]
+fn compaction_once_per_threshold() -> Result<()> {
+    const THRESHOLD: usize = 64 * 1024;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&reports);
+    let options = KvStoreOptions::new()
+        .compaction_threshold(Some(THRESHOLD))
+        .on_compaction(move |report| sink.lock().unwrap().push(report));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let barrier = Arc::new(Barrier::new(8));
+    let writers: Vec<_> = (0..8)
+        .map(|t| {
+            let (store, barrier) = (store.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..2000 {
+                    store
+                        .set(format!("key{}", (t * 7 + i) % 1000), "x".repeat(100))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let reports = reports.lock().unwrap();
+    assert!(!reports.is_empty(), "never compacted");
+    assert!(!store.is_compacting());
+    // Each compaction started with more than the threshold of redundant space, so it
+    // reclaimed near that much.
+    for report in reports.iter() {
+        let reclaimed = report.bytes_before.saturating_sub(report.bytes_after);
+        assert!(
+            reclaimed > THRESHOLD as u64 / 2,
+            "compaction reclaimed {} bytes of {}",
+            reclaimed,
+            report.bytes_before
+        );
+    }
+    drop(reports);
+
+    // Reported as running from when it starts.
+    let controller = store.compaction_controller();
+    controller.pause();
+    let compaction = thread::spawn({
+        let store = store.clone();
+        move || store.compact().unwrap()
+    });
+    while !store.is_compacting() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    controller.resume();
+    compaction.join().unwrap();
+    assert!(!store.is_compacting());
+    Ok(())
+}
+
+// Should compact again within the minimum interval only once the redundant space is
+// past twice the threshold
+#[test]
+fn min_compaction_interval() -> Result<()> {
+    const THRESHOLD: usize = 16 * 1024;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(Some(THRESHOLD))
+        .min_compaction_interval(Some(Duration::from_secs(3600)));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let runs = || store.compaction_controller().status().runs;
+    let mut writes = 0;
+    let mut overwrite = || {
+        writes += 1;
+        store.set("key".to_owned(), format!("{:0100}", writes))
+    };
+
+    while runs() == 0 {
+        overwrite()?;
+    }
+    // Crossing the threshold again so soon doesn't compact.
+    while store.compaction_estimate()?.redundant_size <= THRESHOLD as u64 * 3 / 2 {
+        overwrite()?;
+    }
+    assert_eq!(runs(), 1);
+    assert!(!store.compaction_pending());
+
+    // Going past twice the threshold does.
+    while runs() == 1 {
+        overwrite()?;
+        assert!(store.compaction_estimate()?.redundant_size <= THRESHOLD as u64 * 5 / 2);
+    }
+    assert_eq!(runs(), 2);
+    Ok(())
+}
+
+// Should report which writes compacted the log, and how long it took
+#[test]
+fn write_outcome() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(Some(16 * 1024));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let first = store.set_verbose("key".to_owned(), "value".to_owned())?;
+    assert_eq!(first, WriteOutcome::default());
+    assert!(!first.compacted);
+    let mut writes = 0;
+    let outcome = loop {
+        writes += 1;
+        let outcome = store.set_verbose("key".to_owned(), format!("{:0100}", writes))?;
+        if outcome.compacted {
+            break outcome;
+        }
+        assert_eq!(outcome.compaction_duration, None);
+        assert!(writes < 1000, "never compacted");
+    };
+    assert!(outcome.compaction_duration.unwrap() > Duration::ZERO);
+    assert_eq!(store.compaction_controller().status().runs, 1);
+
+    let removed = store.remove_verbose("key".to_owned())?;
+    assert!(!removed.compacted);
+    assert!(matches!(
+        store.remove_verbose("key".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// Should report each compaction through the `on_compaction` callback
+#[test]
+fn compaction_callback() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&reports);
+    let options =
+        KvStoreOptions::new().on_compaction(move |report| sink.lock().unwrap().push(report));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for iter in 0..1000 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        if !reports.lock().unwrap().is_empty() {
+            break;
+        }
+    }
+
+    let reports = reports.lock().unwrap();
+    let report = reports.first().expect("No compaction reported");
+    assert!(report.bytes_before > 1024 * 1024);
+    assert!(report.bytes_after > 0);
+    assert!(report.bytes_after < report.bytes_before);
+    assert!(report.duration > Duration::ZERO);
+
+    Ok(())
+}
+
+// Should copy live entries in the order they were last written, not by key
+#[test]
+fn compaction_keeps_log_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["b", "d", "a", "c", "b", "e", "d"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.remove("e".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let keys: Vec<Vec<u8>> = KvStore::inspect(temp_dir.path().join("kvstore-logs.seg-1"))?
+        .map(|record| match record.op {
+            Some(Record::Set { key, .. }) => key,
+            other => panic!("expected a set, got {:?}", other),
+        })
+        .collect();
+    assert_eq!(keys, [b"a", b"c", b"b", b"d"]);
+
+    Ok(())
+}
+
+// Should evict the oldest keys to keep the log under the configured size
+#[test]
+fn max_disk_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let max = 64 * 1024;
+    let options = KvStoreOptions::new().max_disk_bytes(Some(max));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let changes = store.subscribe();
+    let value = "x".repeat(1000);
+    for key_id in 0..300 {
+        store.set(format!("key{:03}", key_id), value.clone())?;
+    }
+    assert!(store.disk_usage()? <= max);
+
+    let stats = store.stats()?;
+    assert!(stats.evicted_keys > 0);
+    assert_eq!(stats.live_keys as u64 + stats.evicted_keys, 300);
+    for key_id in 300 - stats.live_keys..300 {
+        assert_eq!(store.get(format!("key{:03}", key_id))?, Some(value.clone()));
+    }
+    assert_eq!(store.get("key000".to_owned())?, None);
+    let evicted: Vec<_> = changes
+        .try_iter()
+        .filter(|event| matches!(event, ChangeEvent::Evicted { .. }))
+        .collect();
+    assert_eq!(evicted.len() as u64, stats.evicted_keys);
+    assert_eq!(
+        evicted[0],
+        ChangeEvent::Evicted {
+            bucket: None,
+            key: b"key000".to_vec()
+        }
+    );
+
+    // Rewriting an old key keeps it from being evicted next
+    store.set("key299".to_owned(), value.clone())?;
+    for key_id in 300..400 {
+        store.set(format!("key{:03}", key_id), value.clone())?;
+        store.set("key299".to_owned(), value.clone())?;
+    }
+    assert!(store.disk_usage()? <= max);
+    assert_eq!(store.get("key299".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key399".to_owned())?, Some(value));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key000".to_owned())?, None);
+    assert!(store.disk_usage()? <= max);
+
+    Ok(())
+}
+
+// Should estimate what a compaction reclaims without running it
+#[test]
+fn compaction_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(None)
+        .max_segment_size(Some(4096));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..10 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in 0..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let estimate = store.compaction_estimate()?;
+    assert_eq!(estimate.log_size, store.disk_usage()?);
+    assert!(estimate.live_size < estimate.projected_size);
+    assert!(estimate.live_size + estimate.redundant_size <= estimate.log_size);
+
+    store.compact()?;
+    let reclaimed = estimate.log_size - store.disk_usage()?;
+    let error = reclaimed.abs_diff(estimate.reclaimed());
+    assert!(
+        error * 100 <= reclaimed,
+        "{} vs {}",
+        reclaimed,
+        estimate.reclaimed()
+    );
+
+    Ok(())
+}
+
+// Should never compact automatically when the threshold is disabled
+#[test]
+fn compaction_disabled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(None);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let mut log_size = 0;
+    for iter in 0..500 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        let stats = store.stats()?;
+        assert!(stats.log_size > log_size, "log was rewritten");
+        log_size = stats.log_size;
+    }
+    let stats = store.stats()?;
+    assert!(stats.redundant_size > 1024 * 1024);
+
+    // An explicit compaction still works.
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.redundant_size, 0);
+    assert!(stats.log_size < log_size);
+    assert_eq!(store.get("key1".to_owned())?, Some("499".to_owned()));
+
+    Ok(())
+}
+
+// Should estimate the index's memory from the live keys alone
+#[test]
+fn index_memory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.index_bytes, 0);
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let index_bytes = store.stats()?.index_bytes;
+    assert!(index_bytes > 1000 * "key999".len() as u64);
+
+    // Overwrites, compaction and a replay of the log don't change the keys
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "a longer value".to_owned())?;
+    }
+    assert_eq!(store.stats()?.index_bytes, index_bytes);
+    store.compact()?;
+    assert_eq!(store.stats()?.index_bytes, index_bytes);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.index_bytes, index_bytes);
+
+    for key_id in 0..1000 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert_eq!(store.stats()?.index_bytes, 0);
+
+    Ok(())
+}
+
+// Should fail straight away rather than wait while the store's lock is held, and read
+// as `get` does once it's free
+#[test]
+fn try_get_would_block() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Copies read the clock while holding the store's lock, so a clock that waits holds
+    // the lock for as long as it waits.
+    let (entered_tx, entered_rx) = crossbeam::channel::bounded(0);
+    let (release_tx, release_rx) = crossbeam::channel::bounded::<()>(0);
+    let hold = Arc::new(AtomicBool::new(false));
+    let options = KvStoreOptions::new().clock({
+        let hold = hold.clone();
+        move || {
+            if hold.swap(false, Ordering::SeqCst) {
+                entered_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            }
+            std::time::SystemTime::now()
+        }
+    });
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.try_get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.try_get("missing".to_owned())?, None);
+
+    hold.store(true, Ordering::SeqCst);
+    let copier = thread::spawn({
+        let store = store.clone();
+        move || store.copy("key1".to_owned(), "key2".to_owned(), false)
+    });
+    entered_rx.recv().unwrap();
+    let started = std::time::Instant::now();
+    assert!(matches!(
+        store.try_get("key1".to_owned()),
+        Err(KvsError::WouldBlock)
+    ));
+    assert!(started.elapsed() < Duration::from_millis(100));
+
+    release_tx.send(()).unwrap();
+    copier.join().unwrap()?;
+    assert_eq!(store.try_get("key2".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+fn binary_round_trip<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let blob: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i * 7 % 251) as u8).collect();
+    let entries: Vec<(&[u8], &[u8])> = vec![
+        (b"nul\0key", b"value\0with\0nuls"),
+        (b"\xff\xfe", b"\xc3\x28 invalid utf-8"),
+        (b"blob", &blob),
+    ];
+
+    let store = open()?;
+    for (key, value) in &entries {
+        store.set_bytes(key, value)?;
+    }
+    for (key, value) in &entries {
+        assert_eq!(store.get_bytes(key)?.as_deref(), Some(*value));
+    }
+    assert!(store.get("\u{0}".to_owned())?.is_none());
+    assert!(store
+        .get(String::from_utf8_lossy(b"\xff\xfe").into_owned())?
+        .is_none());
+    assert!(matches!(
+        store.get("blob".to_owned()),
+        Err(KvsError::InvalidUtf8(key)) if key == "blob"
+    ));
+    assert!(matches!(
+        store.get_ref("blob"),
+        Err(KvsError::InvalidUtf8(key)) if key == "blob"
+    ));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open()?;
+    for (key, value) in &entries {
+        assert_eq!(store.get_bytes(key)?.as_deref(), Some(*value));
+    }
+    store.remove_bytes(b"nul\0key")?;
+    assert_eq!(store.get_bytes(b"nul\0key")?, None);
+
+    Ok(())
+}
+
+// Should round-trip arbitrary bytes through reopen
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_round_trip(|| KvStore::open(temp_dir.path()))?;
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        binary_round_trip(|| SledEngine::open(temp_dir.path()))?;
+    }
+
+    Ok(())
+}
+
+// Should read sled's values where sled holds them, as the copying reads do
+#[test]
+#[cfg(feature = "sled")]
+fn sled_shared_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledEngine::open(temp_dir.path())?;
+    let large: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    sled.set_bytes(b"large", &large)?;
+    sled.set("text".to_owned(), "value".to_owned())?;
+
+    assert_eq!(sled.get_ivec(b"large")?.as_deref(), Some(&large[..]));
+    assert_eq!(sled.get_ivec(b"missing")?, None);
+    let mut read = vec![];
+    let mut reader = sled.get_reader("large".to_owned())?.unwrap();
+    assert_eq!(reader.len(), large.len() as u64);
+    reader.read_to_end(&mut read)?;
+    assert_eq!(read, large);
+
+    assert_eq!(sled.get_ref("text")?, Some("value".to_owned()));
+    let error = sled.get_ref("large").unwrap_err();
+    assert!(error.to_string().contains("\"large\""), "{}", error);
+    Ok(())
+}
+
+// Should keep binary values intact through compaction
+#[test]
+fn binary_values_survive_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let blob: Vec<u8> = (0..2 * 1024 * 1024).map(|i| (i % 256) as u8).collect();
+    store.set_bytes(b"blob", &blob)?;
+    store.set_bytes(b"blob", &blob)?;
+    store.compact()?;
+    assert_eq!(store.get_bytes(b"blob")?, Some(blob.clone()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(b"blob")?, Some(blob));
+
+    Ok(())
+}
+
+// Should open stores at a deeply nested path that doesn't exist yet
+#[test]
+fn open_deeply_nested_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = (0..10).fold(temp_dir.path().to_path_buf(), |path, depth| {
+        path.join(format!("level{}", depth))
+    });
+
+    let store = KvStore::open(path.join("kvs"))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    #[cfg(feature = "sled")]
+    {
+        let store = SledEngine::open(path.join("sled"))?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should keep sled's files in a directory of their own, apart from a `KvStore` opened on
+// the same path
+#[test]
+#[cfg(feature = "sled")]
+fn sled_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+
+    let store = KvStore::open(path)?;
+    let sled = SledEngine::open(path)?;
+    store.set("key1".to_owned(), "kvs".to_owned())?;
+    sled.set("key1".to_owned(), "sled".to_owned())?;
+    sled.set("key2".to_owned(), "sled".to_owned())?;
+    sled.sync()?;
+    assert!(path.join("sled-logs").join("conf").is_file());
+    assert!(!path.join("conf").exists());
+    assert!(!path.join("db").exists());
+
+    assert_eq!(store.get("key1".to_owned())?, Some("kvs".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(sled.get("key1".to_owned())?, Some("sled".to_owned()));
+    store.compact()?;
+    drop(store);
+    drop(sled);
+
+    let store = KvStore::open(path)?;
+    let sled = SledEngine::open(path)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("kvs".to_owned()));
+    assert_eq!(sled.get("key1".to_owned())?, Some("sled".to_owned()));
+    assert_eq!(sled.get("key2".to_owned())?, Some("sled".to_owned()));
+    Ok(())
+}
+
+// Should open a sled database where earlier versions put it, in the path itself
+#[test]
+#[cfg(feature = "sled")]
+fn sled_legacy_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+
+    let db = sled::open(path)?;
+    db.insert("key1", "value1")?;
+    db.flush()?;
+    drop(db);
+
+    let sled = SledEngine::open(path)?;
+    assert_eq!(sled.get("key1".to_owned())?, Some("value1".to_owned()));
+    sled.set("key2".to_owned(), "value2".to_owned())?;
+    drop(sled);
+    assert!(!path.join("sled-logs").exists());
+
+    let sled = SledEngine::open(path)?;
+    assert_eq!(sled.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should open sled with the options given, reporting them back, and reopen its data
+// with others
+#[test]
+#[cfg(feature = "sled")]
+fn sled_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+
+    let sled = SledEngine::open(path)?;
+    assert_eq!(sled.config(), &SledOptions::default());
+    drop(sled);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let options = SledOptions::new()
+        .cache_capacity(1024 * 1024)
+        .mode(SledMode::HighThroughput)
+        .flush_every_ms(None)
+        .segment_size(64 * 1024);
+    let sled = SledEngine::open_with(path, options.clone())?;
+    assert_eq!(sled.config(), &options);
+    for i in 0..100 {
+        sled.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let stats = sled.stats()?;
+    assert_eq!(stats.keys, 100);
+    assert_eq!(stats.options, options);
+    assert!(stats.size_on_disk > 0);
+    drop(sled);
+
+    // Everything but the segment size can change between opens.
+    let reopened = options
+        .clone()
+        .cache_capacity(64 * 1024 * 1024)
+        .mode(SledMode::LowSpace)
+        .flush_every_ms(Some(100));
+    let sled = SledEngine::open_with(path, reopened.clone())?;
+    assert_eq!(sled.config(), &reopened);
+    assert_eq!(sled.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(sled.stats()?.keys, 100);
+    drop(sled);
+    assert!(matches!(
+        SledEngine::open_with(path, options.clone().segment_size(128 * 1024)),
+        Err(KvsError::Sled(_))
+    ));
+
+    // This build's sled can't compress.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        SledEngine::open_with(temp_dir.path(), SledOptions::new().use_compression(true)),
+        Err(KvsError::Sled(_))
+    ));
+    Ok(())
+}
+
+// Should report sled's key count, size on disk, buckets, pending flushes and recovery
+#[test]
+#[cfg(feature = "sled")]
+fn sled_stats() -> Result<()> {
+    const VALUES: usize = 1000;
+    const VALUE_LEN: usize = 1024;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    let sled = SledEngine::open(path)?;
+    let before = sled.stats()?;
+    assert_eq!(before.keys, 0);
+    assert!(before.buckets.is_empty());
+    assert!(!before.flush_pending);
+    assert!(!before.was_recovered);
+
+    for i in 0..VALUES {
+        sled.set(format!("key{}", i), "x".repeat(VALUE_LEN))?;
+    }
+    sled.bucket("users")
+        .set("alice".to_owned(), "user".to_owned())?;
+    sled.bucket("sessions")
+        .set("alice".to_owned(), "session".to_owned())?;
+    // The counts are taken afresh once the last ones are old enough.
+    thread::sleep(Duration::from_millis(300));
+    let after = sled.stats()?;
+    assert_eq!(after.keys, VALUES);
+    assert!(
+        after.size_on_disk > before.size_on_disk + (VALUES * VALUE_LEN) as u64 / 2,
+        "grew from {} to {} bytes",
+        before.size_on_disk,
+        after.size_on_disk
+    );
+    assert_eq!(after.buckets, vec!["sessions", "users"]);
+    assert!(!after.flush_pending);
+    assert!(serde_json::to_string(&after).is_ok());
+    drop(sled);
+
+    // Writes that aren't flushed as they're made are pending until they are.
+    let options = SledOptions::new().durability(SledDurability::Manual);
+    let sled = SledEngine::open_with(path, options)?;
+    let stats = sled.stats()?;
+    assert!(stats.was_recovered);
+    assert_eq!(stats.keys, VALUES);
+    sled.set("key0".to_owned(), "changed".to_owned())?;
+    assert!(sled.stats()?.flush_pending);
+    sled.flush()?;
+    assert!(!sled.stats()?.flush_pending);
+    Ok(())
+}
+
+/// The records of an export, whatever order they were written in.
+#[cfg(feature = "sled")]
+fn exported_records(export: &[u8]) -> BTreeSet<&[u8]> {
+    let lines: Vec<&[u8]> = export.split(|&b| b == b'\n').collect();
+    // Past the header, and up to the trailer and the empty line after it.
+    lines[1..lines.len() - 2].iter().copied().collect()
+}
+
+// Should import a store's export into another, replacing or merging with its keys, and
+// reject a corrupt export without changing anything
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("source"))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set_bytes(b"\xff\x00key", b"\x80value")?;
+    store
+        .bucket("users")
+        .set("alice".to_owned(), "user".to_owned())?;
+    let mut export = vec![];
+    assert_eq!(store.export(&mut export)?, 102);
+
+    let copy = KvStore::open(temp_dir.path().join("copy"))?;
+    copy.set("stale".to_owned(), "value".to_owned())?;
+    assert_eq!(copy.import(export.as_slice(), ImportMode::Replace)?, 102);
+    assert_eq!(copy.get("stale".to_owned())?, None);
+    assert_eq!(copy.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(copy.get_bytes(b"\xff\x00key")?, Some(b"\x80value".to_vec()));
+    assert_eq!(
+        copy.bucket("users").get("alice".to_owned())?,
+        Some("user".to_owned())
+    );
+    let mut copied = vec![];
+    copy.export(&mut copied)?;
+    assert_eq!(copied, export);
+
+    let merged = KvStore::open(temp_dir.path().join("merged"))?;
+    merged.set("kept".to_owned(), "value".to_owned())?;
+    merged.set("key0".to_owned(), "overwritten".to_owned())?;
+    merged.import(export.as_slice(), ImportMode::Merge)?;
+    assert_eq!(merged.get("kept".to_owned())?, Some("value".to_owned()));
+    assert_eq!(merged.get("key0".to_owned())?, Some("value0".to_owned()));
+
+    let mut truncated = export.clone();
+    truncated.truncate(export.len() / 2);
+    let mut tampered = export.clone();
+    let at = tampered.windows(7).position(|w| w == b"value42").unwrap();
+    tampered[at + 5] = b'7';
+    for corrupt in [truncated, tampered] {
+        assert!(matches!(
+            merged.import(corrupt.as_slice(), ImportMode::Replace),
+            Err(KvsError::Corrupt(_))
+        ));
+        assert_eq!(merged.get("kept".to_owned())?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+// Should move every key, bucket and large value between the engines through an export,
+// in both directions
+#[test]
+#[cfg(feature = "sled")]
+fn export_import_across_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let large: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i * 7 % 251) as u8).collect();
+    let store = KvStore::open(temp_dir.path().join("kvs"))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set_bytes(b"\xff\x00key", &large)?;
+    store
+        .bucket("users")
+        .set("alice".to_owned(), "user".to_owned())?;
+    store
+        .bucket("sessions")
+        .set("alice".to_owned(), "session".to_owned())?;
+    let mut from_kvs = vec![];
+    assert_eq!(store.export(&mut from_kvs)?, 103);
+
+    let sled = SledEngine::open(temp_dir.path().join("sled"))?;
+    sled.set("stale".to_owned(), "value".to_owned())?;
+    assert_eq!(sled.import(from_kvs.as_slice(), ImportMode::Replace)?, 103);
+    assert_eq!(sled.get("stale".to_owned())?, None);
+    assert_eq!(sled.get_bytes(b"\xff\x00key")?, Some(large.clone()));
+    assert_eq!(
+        sled.bucket("sessions").get("alice".to_owned())?,
+        Some("session".to_owned())
+    );
+    let mut from_sled = vec![];
+    assert_eq!(sled.export(&mut from_sled)?, 103);
+    assert_eq!(exported_records(&from_sled), exported_records(&from_kvs));
+
+    let back = KvStore::open(temp_dir.path().join("back"))?;
+    assert_eq!(back.import(from_sled.as_slice(), ImportMode::Merge)?, 103);
+    let mut round_trip = vec![];
+    back.export(&mut round_trip)?;
+    assert_eq!(round_trip, from_kvs);
+
+    let truncated = &from_sled[..from_sled.len() - 10];
+    assert!(matches!(
+        sled.import(truncated, ImportMode::Replace),
+        Err(KvsError::Corrupt(_))
+    ));
+    assert_eq!(sled.get("key7".to_owned())?, Some("value7".to_owned()));
+    Ok(())
+}
+
+// Should serialize conflicting transactions, so that concurrent transfers between two
+// counters conserve their total, and apply none of an aborted transaction's writes
+#[test]
+#[cfg(feature = "sled")]
+fn sled_transactions() -> Result<()> {
+    const THREADS: usize = 8;
+    const TRANSFERS: usize = 50;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledEngine::open(temp_dir.path())?;
+    sled.set("a".to_owned(), "1000".to_owned())?;
+    sled.set("b".to_owned(), "1000".to_owned())?;
+    let balance = |tx: &SledTransaction<'_>, key: &str| -> SledTxResult<i64> {
+        let value = tx.get(key)?.ok_or(KvsError::KeyNotFound)?;
+        Ok(value.parse().map_err(|_| KvsError::NotAnInteger)?)
+    };
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread_id| {
+            let sled = sled.clone();
+            thread::spawn(move || -> Result<()> {
+                let (from, to) = if thread_id % 2 == 0 {
+                    ("a", "b")
+                } else {
+                    ("b", "a")
+                };
+                for i in 0..TRANSFERS {
+                    let amount = (thread_id + i) as i64 % 7 + 1;
+                    sled.transaction(|tx| {
+                        tx.set(from, &(balance(tx, from)? - amount).to_string())?;
+                        tx.set(to, &(balance(tx, to)? + amount).to_string())?;
+                        Ok(())
+                    })?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let total = sled.transaction(|tx| Ok(balance(tx, "a")? + balance(tx, "b")?))?;
+    assert_eq!(total, 2000);
+    assert_ne!(sled.get("a".to_owned())?, Some("1000".to_owned()));
+
+    // Aborting part way through undoes the writes made before.
+    let a = sled.get("a".to_owned())?;
+    let result = sled.transaction(|tx| {
+        tx.set("a", "0")?;
+        tx.remove("b")?;
+        balance(tx, "missing")
+    });
+    match result {
+        Err(KvsError::TransactionAborted(e)) => assert!(matches!(*e, KvsError::KeyNotFound)),
+        other => panic!("expected an aborted transaction, got {:?}", other),
+    }
+    assert_eq!(sled.get("a".to_owned())?, a);
+    assert!(sled.get("b".to_owned())?.is_some());
+
+    let sled = sled.max_value_bytes(Some(4));
+    assert!(matches!(
+        sled.transaction(|tx| tx.set("a", "too long")),
+        Err(KvsError::TransactionAborted(e)) if matches!(*e, KvsError::ValueTooLarge)
+    ));
+    Ok(())
+}
+
+// Should keep writes made without flushing each one once they're flushed by hand,
+// through a reopen
+#[test]
+#[cfg(feature = "sled")]
+fn sled_manual_durability() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path();
+    assert_eq!(
+        SledOptions::default().durability,
+        SledDurability::FlushEveryWrite
+    );
+    let options = SledOptions::new().durability(SledDurability::Manual);
+    let sled = SledEngine::open_with(path, options)?;
+    assert_eq!(sled.config().durability, SledDurability::Manual);
+    for i in 0..100 {
+        sled.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    sled.remove("key0".to_owned())?;
+    sled.increment("counter".to_owned(), 5)?;
+    sled.flush()?;
+    drop(sled);
+
+    let sled = SledEngine::open(path)?;
+    assert_eq!(sled.get("key0".to_owned())?, None);
+    assert_eq!(sled.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(sled.get("counter".to_owned())?, Some("5".to_owned()));
+    assert_eq!(sled.stats()?.keys, 100);
+    Ok(())
+}
+
+// Should fail `get_expect` with `KeyNotFound` only when the key is missing
+#[test]
+fn get_expect() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_expect("key1".to_owned())?, "value1");
+    assert!(matches!(
+        store.get_expect("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    store.remove("key1".to_owned())?;
+    assert!(matches!(
+        store.get_expect("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    Ok(())
+}
+
+// Should get and remove keys by `&str` as `get` and `remove` do by `String`, whatever
+// their length
+#[test]
+fn borrowed_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let long = "k".repeat(1000);
+    for key in ["", "key1", &long] {
+        store.set(key.to_owned(), format!("value of {}", key.len()))?;
+        assert_eq!(store.get_ref(key)?, store.get(key.to_owned())?);
+        assert_eq!(store.get_ref(key)?, Some(format!("value of {}", key.len())));
+        store.remove_ref(key)?;
+        assert_eq!(store.get_ref(key)?, None);
+        assert!(matches!(store.remove_ref(key), Err(KvsError::KeyNotFound)));
+    }
+    // A key's encoding on the stack doesn't carry over to a shorter one.
+    store.set("ab".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_ref("abc")?, None);
+    assert_eq!(store.get_ref("a")?, None);
+
+    Ok(())
+}
+
+#[cfg(feature = "lz4")]
+fn compression_round_trip(algorithm: Compression) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compressible = "{\"field\": \"value\"}, ".repeat(1000);
+    let incompressible: Vec<u8> = {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    };
+
+    // Start out with an uncompressed log.
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), compressible.clone())?;
+    drop(store);
+
+    let options = || KvStoreOptions::new().compression(Some(algorithm));
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("compressible".to_owned(), compressible.clone())?;
+    store.set_bytes(b"incompressible", &incompressible)?;
+    assert_eq!(
+        store.get("compressible".to_owned())?,
+        Some(compressible.clone())
+    );
+    assert_eq!(
+        store.get_bytes(b"incompressible")?,
+        Some(incompressible.clone())
+    );
+
+    let stats = store.stats()?;
+    assert_eq!(stats.value_bytes, 2 * compressible.len() as u64 + 4096);
+    assert!(stats.stored_value_bytes < stats.value_bytes);
+
+    // Reopen the mixed log without compression.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("plain".to_owned())?, Some(compressible.clone()));
+    assert_eq!(
+        store.get("compressible".to_owned())?,
+        Some(compressible.clone())
+    );
+    assert_eq!(
+        store.get_bytes(b"incompressible")?,
+        Some(incompressible.clone())
+    );
+    drop(store);
+
+    // Compaction re-encodes everything with the current setting.
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.compact()?;
+    let stats = store.stats()?;
+    assert!(stats.stored_value_bytes < 2 * compressible.len() as u64);
+    let len = compressible.len() as u64;
+    assert_eq!(store.value_len("plain".to_owned())?, Some(len));
+    assert!(store.stored_value_len("plain".to_owned())?.unwrap() < len);
+    assert_eq!(store.get("plain".to_owned())?, Some(compressible.clone()));
+    assert_eq!(store.get_bytes(b"incompressible")?, Some(incompressible));
+
+    Ok(())
+}
+
+// Should transparently compress values and read mixed logs
+#[cfg(feature = "lz4")]
+#[test]
+fn lz4_compression() -> Result<()> {
+    compression_round_trip(Compression::Lz4)
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_compression() -> Result<()> {
+    compression_round_trip(Compression::Zstd)
+}
+
+fn iterate<E: KvsEngine>(engine: &E) -> Result<()> {
+    const KEYS: usize = 20_000;
+    for i in 0..KEYS {
+        engine.set(format!("key{:05}", i), i.to_string())?;
+    }
+
+    let mut count = 0;
+    let mut last = String::new();
+    for pair in engine.iter()? {
+        let (key, value) = pair?;
+        assert!(key > last);
+        assert_eq!(format!("key{:05}", value.parse::<usize>().unwrap()), key);
+        last = key;
+        count += 1;
+    }
+    assert_eq!(count, KEYS);
+
+    Ok(())
+}
+
+// Should stream every pair in key order, reading values as they're reached
+#[test]
+fn iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store
+        .bucket("other")
+        .set("key00001".to_owned(), "hidden".to_owned())?;
+    iterate(&store)?;
+
+    let mut pairs = store.iter()?;
+    assert_eq!(
+        pairs.next().unwrap()?,
+        ("key00000".to_owned(), "0".to_owned())
+    );
+    store.remove("key00001".to_owned())?;
+    store.set("key00002".to_owned(), "two".to_owned())?;
+    store.set("key99999".to_owned(), "new".to_owned())?;
+    assert_eq!(
+        pairs.next().unwrap()?,
+        ("key00002".to_owned(), "two".to_owned())
+    );
+    assert_eq!(pairs.count(), 20_000 - 3);
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        iterate(&SledEngine::open(temp_dir.path())?)?;
+    }
+
+    Ok(())
+}
+
+/// Keys of arbitrary bytes, to scan ranges of.
+const RANGE_KEYS: &[&[u8]] = &[
+    b"",
+    b"\0",
+    b"\0\0",
+    b"\x01",
+    b"a",
+    b"a\0",
+    b"ab",
+    b"abc",
+    b"b",
+    b"\x7f",
+    b"\x80",
+    b"\xff",
+    b"\xff\xff",
+];
+
+/// The bounds a range of keys starts and ends at.
+type KeyRange = (Bound<&'static [u8]>, Bound<&'static [u8]>);
+
+/// Every pair of bounds the ranges scanned start and end at.
+fn range_bounds() -> Vec<KeyRange> {
+    let bounds: [Bound<&[u8]>; 11] = [
+        Bound::Unbounded,
+        Bound::Included(b""),
+        Bound::Included(b"\0"),
+        Bound::Excluded(b"\0"),
+        Bound::Included(b"a"),
+        Bound::Excluded(b"a"),
+        Bound::Included(b"aa"),
+        Bound::Excluded(b"abc"),
+        Bound::Included(b"\x80"),
+        Bound::Excluded(b"\xff"),
+        Bound::Included(b"\xff\xff\xff"),
+    ];
+    let mut pairs = vec![];
+    for start in bounds {
+        for end in bounds {
+            pairs.push((start, end));
+        }
+    }
+    pairs
+}
+
+/// The keys of every range scanned in `engine`, each read forwards, backwards, and from
+/// both ends in turn.
+fn scan_ranges<E: KvsEngine>(engine: &E) -> Result<Vec<[Vec<Vec<u8>>; 3]>> {
+    for key in RANGE_KEYS {
+        engine.set_bytes(key, &[key, &b"!"[..]].concat())?;
+    }
+
+    let mut scans = vec![];
+    for (start, end) in range_bounds() {
+        let mut forwards = vec![];
+        for pair in engine.range_bytes(start, end)? {
+            let (key, value) = pair?;
+            assert_eq!(value, [&key[..], b"!"].concat());
+            forwards.push(key);
+        }
+        let mut backwards = vec![];
+        for pair in engine.range_bytes(start, end)?.rev() {
+            backwards.push(pair?.0);
+        }
+        let mut pairs = engine.range_bytes(start, end)?;
+        let (mut front, mut back) = (vec![], vec![]);
+        while let Some(pair) = pairs.next() {
+            front.push(pair?.0);
+            match pairs.next_back() {
+                Some(pair) => back.push(pair?.0),
+                None => break,
+            }
+        }
+        front.extend(back.into_iter().rev());
+        scans.push([forwards, backwards, front]);
+    }
+    Ok(scans)
+}
+
+// Should scan ranges of keys in either direction, ordering keys by their raw bytes, alike
+// in every engine
+#[test]
+fn range_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // Keys in other buckets are left out.
+    store
+        .bucket("other")
+        .set("a".to_owned(), "hidden".to_owned())?;
+    let scans = scan_ranges(&store)?;
+
+    // The same ranges of a `BTreeSet`, which orders keys by their bytes.
+    let keys: BTreeSet<&[u8]> = RANGE_KEYS.iter().copied().collect();
+    for ((start, end), [forwards, backwards, both_ends]) in range_bounds().into_iter().zip(&scans) {
+        let expected: Vec<Vec<u8>> = keys
+            .iter()
+            .filter(|key| (start, end).contains(**key))
+            .map(|key| key.to_vec())
+            .collect();
+        assert_eq!(forwards, &expected, "{:?}..{:?}", start, end);
+        let reversed: Vec<Vec<u8>> = expected.iter().rev().cloned().collect();
+        assert_eq!(backwards, &reversed, "{:?}..{:?}", start, end);
+        assert_eq!(both_ends, &expected, "{:?}..{:?}", start, end);
+    }
+
+    let strings: Vec<(String, String)> = store
+        .range(Bound::Included("a"), Bound::Excluded("b"))?
+        .rev()
+        .collect::<Result<_>>()?;
+    let expected = [("abc", "abc!"), ("ab", "ab!"), ("a\0", "a\0!"), ("a", "a!")];
+    assert_eq!(
+        strings,
+        expected.map(|(key, value)| (key.to_owned(), value.to_owned()))
+    );
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sled = SledEngine::open(temp_dir.path())?;
+        assert_eq!(scan_ranges(&sled)?, scans);
+    }
+
+    Ok(())
+}
+
+fn value_lengths<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert_eq!(engine.value_len("key".to_owned())?, None);
+    for value in ["value", "", "a longer value", "héllo wörld"] {
+        engine.set("key".to_owned(), value.to_owned())?;
+        assert_eq!(
+            engine.value_len("key".to_owned())?,
+            Some(value.len() as u64)
+        );
+    }
+    engine.set("other".to_owned(), "x".repeat(1000))?;
+    engine.remove("key".to_owned())?;
+    assert_eq!(engine.value_len("key".to_owned())?, None);
+    assert_eq!(engine.value_len("other".to_owned())?, Some(1000));
+    Ok(())
+}
+
+// Should report the length of values without reading them, across overwrites
+#[test]
+fn value_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    value_lengths(&store)?;
+    assert_eq!(store.stored_value_len("other".to_owned())?, Some(1000));
+
+    // And across compaction and a replay of the log
+    store.compact()?;
+    assert_eq!(store.value_len("other".to_owned())?, Some(1000));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.value_len("other".to_owned())?, Some(1000));
+    assert_eq!(store.value_len("key".to_owned())?, None);
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        value_lengths(&SledEngine::open(temp_dir.path())?)?;
+    }
+
+    Ok(())
+}
+
+fn value_limit<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("key".to_owned(), "value".to_owned())?;
+    let usage = engine.disk_usage()?;
+    assert!(matches!(
+        engine.set("key".to_owned(), "x".repeat(101)),
+        Err(KvsError::ValueTooLarge)
+    ));
+    assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(engine.disk_usage()?, usage);
+
+    engine.set("key".to_owned(), "x".repeat(100))?;
+    assert_eq!(engine.get("key".to_owned())?, Some("x".repeat(100)));
+    Ok(())
+}
+
+// Should reject values over the configured limit without writing anything
+#[test]
+fn max_value_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_value_bytes(Some(100));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    value_limit(&store)?;
+    assert!(matches!(
+        store.update("key".to_owned(), |value| value.map(|v| v + "!")),
+        Err(KvsError::ValueTooLarge)
+    ));
+    assert!(matches!(
+        store
+            .bucket("bucket")
+            .set("key".to_owned(), "x".repeat(101)),
+        Err(KvsError::ValueTooLarge)
+    ));
+    assert_eq!(store.stats()?.live_keys, 1);
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        value_limit(&SledEngine::open(temp_dir.path())?.max_value_bytes(Some(100)))?;
+    }
+
+    Ok(())
+}
+
+// Should apply `update` atomically, removing the key when the closure returns `None`
+#[test]
+fn update() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let new = store.update("key1".to_owned(), |current| {
+        assert_eq!(current, None);
+        Some("value1".to_owned())
+    })?;
+    assert_eq!(new, Some("value1".to_owned()));
+
+    let new = store.update("key1".to_owned(), |current| current.map(|v| v + "!"))?;
+    assert_eq!(new, Some("value1!".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1!".to_owned()));
+
+    assert_eq!(store.update("key1".to_owned(), |_| None)?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    store.update("key2".to_owned(), |_| Some("value2".to_owned()))?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+fn concurrent_increments<E: KvsEngine>(store: E) -> Result<()> {
+    let handles: Vec<_> = (0..10)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    store.increment("counter".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("1000".to_owned()));
+
+    assert_eq!(store.increment("counter".to_owned(), -1500)?, -500);
+    store.set("word".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        store.increment("word".to_owned(), 1),
+        Err(KvsError::NotAnInteger)
+    ));
+    assert_eq!(store.get("word".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should not lose concurrent increments
+#[test]
+fn increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_increments(KvStore::open(temp_dir.path())?)?;
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        concurrent_increments(SledEngine::open(temp_dir.path())?)?;
+    }
+
+    Ok(())
+}
+
+// Should never report a present key as missing when the bloom filter is enabled
+#[test]
+fn bloom_filter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().bloom_filter(1000, 0.01);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in (0..1000).step_by(2) {
+        store.remove(format!("key{}", i))?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..1000 {
+            let expected = (i % 2 == 1).then(|| format!("value{}", i));
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+            assert_eq!(
+                store.contains_key(format!("key{}", i).as_bytes()),
+                i % 2 == 1
+            );
+            assert_eq!(store.get(format!("missing{}", i))?, None);
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    let stats = store.stats()?.bloom.expect("bloom filter enabled");
+    assert_eq!(stats.keys, 1000);
+    assert!(stats.bits >= 9000);
+    assert!(stats.estimated_fpr > 0.0 && stats.estimated_fpr < 0.02);
+
+    // Compaction rebuilds the filter from the live keys only.
+    store.compact()?;
+    check(&store)?;
+    assert_eq!(store.stats()?.bloom.unwrap().keys, 500);
+
+    // Open from disk again and check the rebuilt filter
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    check(&store)
+}
+
+// Should serve repeated reads from the value cache without ever returning stale values
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().value_cache(64);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for _ in 0..3 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    let stats = store.stats()?.cache.expect("cache enabled");
+    assert_eq!((stats.hits, stats.misses, stats.entries), (2, 1, 1));
+
+    // Overwrites and removes invalidate the cached value.
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.update("key1".to_owned(), |_| Some("value3".to_owned()))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Older values are evicted once the capacity is exceeded.
+    for i in 0..10 {
+        store.set(format!("key{}", i), "x".repeat(10))?;
+        store.get(format!("key{}", i))?;
+    }
+    let stats = store.stats()?.cache.unwrap();
+    assert!(stats.size <= 64);
+    assert_eq!(stats.entries, 6);
+    let misses = stats.misses;
+    store.get("key9".to_owned())?;
+    store.get("key0".to_owned())?;
+    assert_eq!(store.stats()?.cache.unwrap().misses, misses + 1);
+
+    // Compaction doesn't change what reads return.
+    store.compact()?;
+    assert_eq!(store.get("key9".to_owned())?, Some("x".repeat(10)));
+
+    Ok(())
+}
+
+// Should report disk usage growing with writes and dropping after compaction
+#[test]
+fn disk_usage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(Some(1024 * 1024));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    // Just the log file's header
+    let mut usage = store.disk_usage()?;
+    assert_eq!(usage, 8);
+    let mut compactions = 0;
+    for _ in 0..2000 {
+        store.set("key".to_owned(), "x".repeat(1000))?;
+        let new_usage = store.disk_usage()?;
+        if new_usage < usage {
+            compactions += 1;
+        }
+        assert!(!store.compaction_pending());
+        usage = new_usage;
+    }
+    assert!(compactions > 0);
+
+    // Without automatic compaction, usage only grows until an explicit compaction
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(None);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let mut usage = 0;
+    for _ in 0..2000 {
+        store.set("key".to_owned(), "x".repeat(1000))?;
+        let new_usage = store.disk_usage()?;
+        assert!(new_usage > usage);
+        usage = new_usage;
+    }
+    store.compact()?;
+    assert!(store.disk_usage()? < usage);
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sled = SledEngine::open(temp_dir.path())?;
+        sled.set("key".to_owned(), "x".repeat(1024 * 1024))?;
+        assert!(sled.disk_usage()? > 1024 * 1024);
+    }
+
+    Ok(())
+}
+
+// Should adopt a store without a manifest, and record compactions in it
+#[test]
+fn manifest_adopts_legacy_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manifest = temp_dir.path().join("kvstore-logs.MANIFEST");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(manifest.is_file());
+
+    fs::remove_file(&manifest)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(manifest.is_file());
+
+    store.compact()?;
+    let contents = fs::read_to_string(&manifest)?;
+    assert!(contents.contains("\"compactions\":1"));
+
+    Ok(())
+}
+
+// Should refuse to open a store whose files don't match its manifest, unless repairing
+#[test]
+fn manifest_detects_mismatched_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // An unexpected log file
+    let extra = temp_dir.path().join("kvstore-logs.seg-3");
+    fs::write(&extra, "")?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    let repair = KvStoreOptions::new().repair_manifest(true);
+    let store = KvStore::open_with_options(temp_dir.path(), repair.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!extra.exists());
+    drop(store);
+
+    // A missing log file
+    fs::remove_file(temp_dir.path().join("kvstore-logs"))?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    let store = KvStore::open_with_options(temp_dir.path(), repair.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // A corrupt manifest
+    fs::write(
+        temp_dir.path().join("kvstore-logs.MANIFEST"),
+        "{\"version\":",
+    )?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    let store = KvStore::open_with_options(temp_dir.path(), repair)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+// Should keep serving reads and writes while a large compaction runs
+#[test]
+fn compaction_does_not_block() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(None);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let value = "x".repeat(500);
+    for round in 0..2 {
+        for key_id in 0..10000 {
+            store.set(format!("key{}", key_id), format!("{}{}", value, round))?;
+        }
+    }
+
+    let compactor = {
+        let store = store.clone();
+        thread::spawn(move || {
+            let started = std::time::Instant::now();
+            store.compact().unwrap();
+            started.elapsed()
+        })
+    };
+
+    let mut reads = 0;
+    let mut slowest = Duration::ZERO;
+    while !compactor.is_finished() {
+        let key_id = reads % 10000;
+        store.set(
+            format!("new{}", reads),
+            "written during compaction".to_owned(),
+        )?;
+        let started = std::time::Instant::now();
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}1", value))
+        );
+        slowest = slowest.max(started.elapsed());
+        reads += 1;
+    }
+    let duration = compactor.join().unwrap();
+    assert!(reads > 10);
+    assert!(slowest < duration / 4);
+
+    // Writes that landed during the compaction were carried over
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..reads {
+        assert_eq!(
+            store.get(format!("new{}", i))?,
+            Some("written during compaction".to_owned())
+        );
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some(format!("{}1", value)));
+    assert_eq!(store.stats()?.redundant_size, 0);
+
+    Ok(())
+}
+
+// Should replay a log split into several files the same way on any number of threads
+#[test]
+fn segmented_log_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(Some(4096))
+        .compaction_threshold(None);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for round in 0..3 {
+        for key_id in 0..500 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, round),
+            )?;
+        }
+        for key_id in (0..500).step_by(7 + round) {
+            store.remove(format!("key{}", key_id))?;
+        }
+    }
+    let expected = store.stats()?;
+    assert!(expected.log_files > 10);
+    drop(store);
+
+    for threads in [1, 2, 8] {
+        let store =
+            KvStore::open_with_options(temp_dir.path(), options.clone().replay_threads(threads))?;
+        let stats = store.stats()?;
+        assert_eq!(stats.live_keys, expected.live_keys);
+        assert_eq!(stats.redundant_size, expected.redundant_size);
+        assert_eq!(stats.value_bytes, expected.value_bytes);
+        assert_eq!(stats.log_size, expected.log_size);
+        for key_id in 0..500 {
+            let value = (key_id % 9 != 0).then(|| format!("value{}-2", key_id));
+            assert_eq!(store.get(format!("key{}", key_id))?, value);
+        }
+    }
+
+    // Compaction splits its output the same way
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.compact()?;
+    let stats = store.stats()?;
+    assert!(stats.log_files > 1);
+    assert!(stats.log_size < expected.log_size);
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats()?.live_keys, expected.live_keys);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1-2".to_owned()));
+
+    Ok(())
+}
+
+// Should refuse to open a store whose sealed log files don't match their checksums
+#[test]
+fn segment_checksum_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(Some(1024));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    let sealed = temp_dir.path().join("kvstore-logs");
+    let contents = fs::read_to_string(&sealed)?.replacen(":\"value\"", ":\"VALUE\"", 1);
+    fs::write(&sealed, contents)?;
+    assert!(KvStore::open_with_options(temp_dir.path(), options.clone()).is_err());
+
+    let repair = options.repair_manifest(true);
+    let store = KvStore::open_with_options(temp_dir.path(), repair)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("VALUE".to_owned()));
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+// Should walk every record in a log, reporting corrupt ones and carrying on past them
+#[test]
+fn inspect_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("kvstore-logs");
+    let records: Vec<RecordInfo> = KvStore::inspect(&log)?.collect();
+    let summary: Vec<_> = records
+        .iter()
+        .map(|r| (r.offset, r.len, r.op.clone()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (
+                8,
+                74,
+                Some(Record::Set {
+                    bucket: None,
+                    key: b"key1".to_vec(),
+                    value: b"value1".to_vec(),
+                    compression: None
+                })
+            ),
+            (
+                82,
+                74,
+                Some(Record::Set {
+                    bucket: None,
+                    key: b"key2".to_vec(),
+                    value: b"value2".to_vec(),
+                    compression: None
+                })
+            ),
+            (
+                156,
+                29,
+                Some(Record::Rm {
+                    bucket: None,
+                    key: b"key1".to_vec()
+                })
+            ),
+        ]
+    );
+    assert!(records.iter().all(|r| r.error.is_none()));
+
+    // Corrupt the middle record
+    let contents = fs::read_to_string(&log)?.replacen("{\"key\":\"key2\"", "{\"kex\"!\"key2\"", 1);
+    fs::write(&log, contents)?;
+    let records: Vec<RecordInfo> = KvStore::inspect(&log)?.collect();
+    assert_eq!(records.len(), 3);
+    assert!(records[0].op.is_some());
+    assert_eq!((records[1].offset, records[1].len), (82, 74));
+    assert!(records[1].op.is_none());
+    assert!(records[1].error.is_some());
+    assert_eq!(
+        records[2].op,
+        Some(Record::Rm {
+            bucket: None,
+            key: b"key1".to_vec()
+        })
+    );
+
+    let records: Vec<RecordInfo> = KvStore::inspect(&log)?.from_offset(83).collect();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].offset, 156);
+
+    Ok(())
+}
+
+// Should acknowledge concurrent writes only once a shared sync covers them
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().durability(Durability::EveryWrite);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let handles: Vec<_> = (0..20)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for j in 0..50 {
+                    store.set(format!("key{}-{}", i, j), j.to_string()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let crashed = crash_copy(&temp_dir)?;
+    let recovered = KvStore::open(crashed.path())?;
+    for i in 0..20 {
+        for j in 0..50 {
+            assert_eq!(
+                recovered.get(format!("key{}-{}", i, j))?,
+                Some(j.to_string())
+            );
+        }
+    }
+    drop(store);
+
+    // A lone writer waits out the delay, but not much longer
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let delay = Duration::from_millis(20);
+    let options = KvStoreOptions::new()
+        .durability(Durability::EveryWrite)
+        .commit_delay(delay);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..5 {
+        let started = std::time::Instant::now();
+        store.set(format!("key{}", i), "value".to_owned())?;
+        let elapsed = started.elapsed();
+        assert!(elapsed >= delay);
+        assert!(
+            elapsed < delay + Duration::from_millis(500),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    Ok(())
+}
+
+// Should sync writes, and flush the memtable, in the background every interval, and
+// once more when the store is dropped
+#[test]
+fn periodic_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let interval = Duration::from_millis(20);
+    let options = KvStoreOptions::new()
+        .durability(Durability::Periodic(interval))
+        .memtable(1 << 20, Duration::from_secs(3600));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key3".to_owned())?;
+    thread::sleep(interval * 10);
+
+    let crashed = crash_copy(&temp_dir)?;
+    let recovered = KvStore::open(crashed.path())?;
+    for i in 0..10 {
+        let expected = (i != 3).then(|| format!("value{}", i));
+        assert_eq!(recovered.get(format!("key{}", i))?, expected);
+    }
+
+    // Clones share the thread, which is joined along with the last of them.
+    let clone = store.clone();
+    drop(store);
+    clone.set("key10".to_owned(), "value10".to_owned())?;
+    drop(clone);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key10".to_owned())?, Some("value10".to_owned()));
+
+    Ok(())
+}
+
+// Copy the files of the store in `dir` as they are while it's still open, as if it had
+// crashed
+fn crash_copy(dir: &TempDir) -> Result<TempDir> {
+    let crashed = TempDir::new().expect("unable to create temporary working directory");
+    for entry in fs::read_dir(dir.path())? {
+        let path = entry?.path();
+        if !path.to_string_lossy().ends_with(".LOCK") {
+            fs::copy(&path, crashed.path().join(path.file_name().unwrap()))?;
+        }
+    }
+    Ok(crashed)
+}
+
+// Should serve writes held in the memtable, and flush them to the log in order on sync,
+// once full, once the flush interval passes, and on drop
+#[test]
+fn memtable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().memtable(1 << 20, Duration::from_secs(3600));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.sync()?;
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(
+        store.remove("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // Queries that don't read values count what's in the memtable without flushing it
+    assert_eq!(store.stats()?.live_keys, 2);
+    assert_eq!(store.stats()?.value_bytes, 12);
+    assert_eq!(store.count_prefix("key")?, 2);
+    assert_eq!(store.estimate_size_prefix("key2")?, 0);
+    assert_eq!(store.value_len("key3".to_owned())?, Some(6));
+    assert_eq!(store.value_len("key2".to_owned())?, None);
+
+    // Nothing since the sync has reached the log yet
+    let copy = crash_copy(&temp_dir)?;
+    let crashed = KvStore::open(copy.path())?;
+    assert_eq!(crashed.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(crashed.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(crashed.get("key3".to_owned())?, None);
+
+    // Reads of the index flush first
+    assert_eq!(
+        store.scan("key")?,
+        vec![
+            ("key1".to_owned(), "value4".to_owned()),
+            ("key3".to_owned(), "value5".to_owned()),
+        ]
+    );
+    store.sync()?;
+    let records: Vec<_> = KvStore::inspect(temp_dir.path().join("kvstore-logs"))?
+        .skip(2)
+        .map(|record| match record.op {
+            Some(Record::Set { key, value, .. }) => (key, Some(value)),
+            Some(Record::Rm { key, .. }) => (key, None),
+            Some(op) => panic!("unexpected record: {:?}", op),
+            None => panic!("unreadable record: {:?}", record.error),
+        })
+        .collect();
+    assert_eq!(
+        records,
+        vec![
+            (b"key1".to_vec(), Some(b"value3".to_vec())),
+            (b"key1".to_vec(), Some(b"value4".to_vec())),
+            (b"key2".to_vec(), None),
+            (b"key3".to_vec(), Some(b"value5".to_vec())),
+        ]
+    );
+
+    // Dropping the last handle flushes
+    store.set("key4".to_owned(), "value6".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value6".to_owned()));
+    drop(store);
+
+    // Flushed in the background once full, or once the interval passes
+    for options in [
+        KvStoreOptions::new().memtable(64, Duration::from_secs(3600)),
+        KvStoreOptions::new().memtable(1 << 20, Duration::from_millis(20)),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..10 {
+            store.set(format!("key{}", i), "value".to_owned())?;
+        }
+        let mut flushed = false;
+        for _ in 0..100 {
+            let copy = crash_copy(&temp_dir)?;
+            let crashed = KvStore::open(copy.path())?;
+            if crashed.get("key9".to_owned())?.is_some() {
+                flushed = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(flushed);
+    }
+
+    Ok(())
+}
+
+// Should read the same values through memory maps as through file reads, across seals
+// and compactions
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .mmap_reads(true)
+        .max_segment_size(Some(4096))
+        .compaction_threshold(None);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let check = |store: &KvStore, round: usize| -> Result<()> {
+        for i in 0..500 {
+            let expected = format!("value{}-{}", i, round);
+            assert_eq!(store.get(format!("key{}", i))?, Some(expected));
+        }
+        Ok(())
+    };
+
+    for round in 0..3 {
+        for i in 0..500 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+        check(&store, round)?;
+        assert!(store.stats()?.log_files > 1);
+
+        // Compaction deletes every file mapped so far
+        store.compact()?;
+        check(&store, round)?;
+        store.copy("key0".to_owned(), "copy".to_owned(), true)?;
+        assert_eq!(
+            store.get("copy".to_owned())?,
+            Some(format!("value0-{}", round))
+        );
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store, 2)?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new())?;
+    check(&store, 2)?;
+
+    Ok(())
+}
+
+// Should compact through a scratch directory, on the same filesystem or another one,
+// and leave the store as it was if the scratch directory can't be used
+#[test]
+fn compact_to() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(Some(4096))
+        .compaction_threshold(None);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let check = |store: &KvStore, round: usize| -> Result<()> {
+        for i in 0..500 {
+            let expected = format!("value{}-{}", i, round);
+            assert_eq!(store.get(format!("key{}", i))?, Some(expected));
+        }
+        Ok(())
+    };
+
+    let mut scratches = vec![TempDir::new().expect("unable to create scratch directory")];
+    if std::path::Path::new("/dev/shm").is_dir() {
+        scratches.push(TempDir::new_in("/dev/shm").expect("unable to create scratch directory"));
+    }
+    for (round, scratch) in scratches.iter().enumerate() {
+        for i in 0..500 {
+            store.set(format!("key{}", i), "stale".to_owned())?;
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+        let before = store.stats()?;
+        store.compact_to(scratch.path())?;
+        let after = store.stats()?;
+        assert!(after.log_size < before.log_size);
+        assert_eq!(after.redundant_size, 0);
+        assert_eq!(fs::read_dir(scratch.path())?.count(), 0);
+        check(&store, round)?;
+        store.set("key0".to_owned(), format!("value0-{}", round))?;
+    }
+    let round = scratches.len() - 1;
+
+    // A scratch directory that doesn't exist fails the compaction, but nothing else
+    let missing = temp_dir.path().join("missing");
+    assert!(store.compact_to(&missing).is_err());
+    assert!(!missing.exists());
+    check(&store, round)?;
+    store.set("after".to_owned(), "failure".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store, round)?;
+    assert_eq!(store.get("after".to_owned())?, Some("failure".to_owned()));
+
+    Ok(())
+}
+
+// Should stamp each write with the time it was made, keep it through compaction and
+// reopening, and report none for records written before there were timestamps
+#[test]
+fn write_timestamps() -> Result<()> {
+    let now = Arc::new(AtomicU64::new(0));
+    let clock = {
+        let now = Arc::clone(&now);
+        move || UNIX_EPOCH + Duration::from_secs(now.load(Ordering::SeqCst))
+    };
+    let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+    // The same `set` of "old" to "v", in each codec, from before timestamps
+    let legacy = [
+        (
+            Codec::Json,
+            [
+                b"KVSLOG\0\x02",
+                br#"{"Set":{"key":"old","value":"v"}}"#.as_slice(),
+            ]
+            .concat(),
+        ),
+        (
+            Codec::Bincode,
+            b"KVSBIN\0\x02\x09\0\0\0\0\x03old\x01v\0\0".to_vec(),
+        ),
+    ];
+
+    for (codec, log) in legacy {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        fs::write(temp_dir.path().join("kvstore-logs"), log)?;
+        let options = KvStoreOptions::new()
+            .codec(codec)
+            .compaction_threshold(None)
+            .clock(clock.clone());
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+        now.store(1000, Ordering::SeqCst);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        now.store(2000, Ordering::SeqCst);
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        now.store(3000, Ordering::SeqCst);
+        store.set("key1".to_owned(), "value1b".to_owned())?;
+        store.copy("key2".to_owned(), "key3".to_owned(), false)?;
+
+        let check = |store: &KvStore| -> Result<()> {
+            let old = store.get_with_meta("old".to_owned())?.unwrap();
+            assert_eq!((old.value.as_str(), old.written_at), ("v", None));
+            let key1 = store.get_with_meta("key1".to_owned())?.unwrap();
+            assert_eq!(key1.value, "value1b");
+            assert_eq!(key1.written_at, at(3000));
+            assert_eq!(key1.size, 7);
+            assert_eq!(
+                store.get_with_meta("key2".to_owned())?.unwrap().written_at,
+                at(2000)
+            );
+            assert_eq!(
+                store.get_with_meta("key3".to_owned())?.unwrap().written_at,
+                at(3000)
+            );
+            assert_eq!(store.get_with_meta("missing".to_owned())?, None);
+            Ok(())
+        };
+        check(&store)?;
+
+        // Compaction keeps the time a value was written, not copied
+        now.store(9000, Ordering::SeqCst);
+        store.compact()?;
+        check(&store)?;
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        check(&store)?;
+    }
+
+    Ok(())
+}
+
+fn read_all(reader: Option<ValueReader>) -> Result<Option<Vec<u8>>> {
+    let mut reader = match reader {
+        Some(reader) => reader,
+        None => return Ok(None),
+    };
+    let mut value = vec![];
+    reader.read_to_end(&mut value)?;
+    assert_eq!(value.len() as u64, reader.len());
+    Ok(Some(value))
+}
+
+// Should stream values exactly as `get` reads them, in every codec and compressed or not,
+// and keep reading a value whose log file a compaction deletes meanwhile
+#[test]
+fn get_reader() -> Result<()> {
+    let values: Vec<(&str, Vec<u8>)> = vec![
+        ("plain", b"value".to_vec()),
+        ("empty", vec![]),
+        (
+            "escaped",
+            "quote\" back\\slash\n\t\u{1} \u{7f} é 😀 /".into(),
+        ),
+        ("binary", (0..=255).collect()),
+        ("key with \"quotes\" and \\", b"tricky key".to_vec()),
+        ("large", "x\"y\n".repeat(100_000).into_bytes()),
+    ];
+    let options = KvStoreOptions::new().compaction_threshold(None);
+    let mut configs = vec![options.clone(), options.clone().codec(Codec::Bincode)];
+    #[cfg(feature = "lz4")]
+    configs.push(options.clone().compression(Some(Compression::Lz4)));
+
+    for options in configs {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for (key, value) in &values {
+            store.set_bytes(key.as_bytes(), value)?;
+        }
+        for (key, value) in &values {
+            let read = read_all(store.get_reader(key.to_string())?)?;
+            assert_eq!(read.as_ref(), Some(value), "{}", key);
+        }
+        assert!(store.get_reader("missing".to_owned())?.is_none());
+
+        let mut reader = store.get_reader("large".to_owned())?.unwrap();
+        let mut start = vec![0; 1000];
+        reader.read_exact(&mut start)?;
+        store.set("large".to_owned(), "replaced".to_owned())?;
+        store.compact()?;
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest)?;
+        start.extend(rest);
+        assert_eq!(start, values[5].1);
+    }
+
+    // Records written by hand are read the same way, escapes and all
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let records = [
+        br#"{"Set":{"key":"emoji","value":"\ud83d\ude00 \/ \u00e9"}}"#.as_slice(),
+        br#"{"Set":{"key":{"base64":"a2V5"},"value":"v"}}"#,
+        br#"{"Set": {"key": "spaced", "value": "v"}}"#,
+    ];
+    fs::write(temp_dir.path().join("kvstore-logs"), records.concat())?;
+    let store = KvStore::open(temp_dir.path())?;
+    let read = read_all(store.get_reader("emoji".to_owned())?)?;
+    assert_eq!(read, Some("😀 / é".into()));
+    assert_eq!(
+        read_all(store.get_reader("key".to_owned())?)?,
+        Some(b"v".to_vec())
+    );
+    assert_eq!(
+        read_all(store.get_reader("spaced".to_owned())?)?,
+        Some(b"v".to_vec())
+    );
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sled = SledEngine::open(temp_dir.path())?;
+        sled.set("key".to_owned(), "value".to_owned())?;
+        let read = read_all(sled.get_reader("key".to_owned())?)?;
+        assert_eq!(read, Some(b"value".to_vec()));
+    }
+
+    Ok(())
+}
+
+// Should read slices of values clamped to their length, like Redis's GETRANGE, in every
+// codec, compressed, patched or not
+#[test]
+fn getrange() -> Result<()> {
+    let values: Vec<(&str, Vec<u8>)> = vec![
+        ("plain", b"0123456789".to_vec()),
+        ("escaped", "a\"b\\c\nd\u{1}e".into()),
+        ("binary", (0..=255).collect()),
+        ("empty", vec![]),
+    ];
+    let options = KvStoreOptions::new().compaction_threshold(None);
+    let mut configs = vec![options.clone(), options.clone().codec(Codec::Bincode)];
+    #[cfg(feature = "lz4")]
+    configs.push(options.clone().compression(Some(Compression::Lz4)));
+
+    for options in configs {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for (key, value) in &values {
+            store.set_bytes(key.as_bytes(), value)?;
+        }
+        for (key, value) in &values {
+            let len = value.len();
+            let ranges = [
+                // Inside the value, all of it, and empty.
+                (2, 5, 2..5),
+                (0, len, 0..len),
+                (3, 3, 3..3),
+                // Straddling the end.
+                (len.saturating_sub(2), len + 10, len.saturating_sub(2)..len),
+                // Past the end, or backwards.
+                (len + 1, len + 5, len..len),
+                (5, 2, 5..5),
+            ];
+            for (start, end, expected) in ranges {
+                let expected = expected.start.min(len)..expected.end.min(len);
+                let slice = store.getrange_bytes(key.to_string(), start..end)?;
+                assert_eq!(
+                    slice.as_deref(),
+                    Some(&value[expected]),
+                    "{} {}..{}",
+                    key,
+                    start,
+                    end
+                );
+            }
+        }
+        assert_eq!(store.getrange_bytes("missing".to_owned(), 0..5)?, None);
+        assert_eq!(
+            store.getrange("plain".to_owned(), 3..6)?,
+            Some("345".to_owned())
+        );
+        assert!(store.getrange("binary".to_owned(), 128..130).is_err());
+
+        store.setrange("plain".to_owned(), 4, b"ab")?;
+        assert_eq!(
+            store.getrange("plain".to_owned(), 3..7)?,
+            Some("3ab6".to_owned())
+        );
+    }
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sled = SledEngine::open(temp_dir.path())?;
+        sled.set("key".to_owned(), "value".to_owned())?;
+        assert_eq!(
+            sled.getrange("key".to_owned(), 1..100)?,
+            Some("alue".to_owned())
+        );
+    }
+    Ok(())
+}
+
+/// Every record in the log files of the store in `dir`.
+fn log_records(dir: &Path) -> Result<Vec<Record>> {
+    let mut records = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let number = name.strip_prefix("kvstore-logs").unwrap_or("-");
+        if number.is_empty() || number[1..].parse::<u64>().is_ok() {
+            records.extend(KvStore::inspect(&path)?.filter_map(|record| record.op));
+        }
+    }
+    Ok(records)
+}
+
+// Should patch values in place, padding them with zeros, and read them back the same
+// across reopening and compaction, including patches made while a compaction runs
+#[test]
+fn setrange() -> Result<()> {
+    for codec in [Codec::Json, Codec::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .codec(codec)
+            .compaction_threshold(None);
+        let mut expected: HashMap<&str, Vec<u8>> = HashMap::new();
+        let check = |store: &KvStore, expected: &HashMap<&str, Vec<u8>>| -> Result<()> {
+            let snapshot = store.snapshot()?;
+            for (key, value) in expected {
+                assert_eq!(
+                    store.get_bytes(key.as_bytes())?.as_ref(),
+                    Some(value),
+                    "{}",
+                    key
+                );
+                let read = read_all(store.get_reader(key.to_string())?)?;
+                assert_eq!(read.as_ref(), Some(value), "{}", key);
+                let len = store.value_len(key.to_string())?;
+                assert_eq!(len, Some(value.len() as u64), "{}", key);
+                assert_eq!(snapshot.get_bytes(key.as_bytes())?.as_ref(), Some(value));
+            }
+            let stats = store.stats()?;
+            assert_eq!(stats.live_keys, expected.len());
+            let value_bytes: usize = expected.values().map(Vec::len).sum();
+            assert_eq!(stats.value_bytes, value_bytes as u64);
+            Ok(())
+        };
+
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        // Past the end of a missing key, and of an existing one.
+        assert_eq!(store.setrange("fresh".to_owned(), 3, b"ab")?, 5);
+        expected.insert("fresh", b"\0\0\0ab".to_vec());
+        store.set("short".to_owned(), "abc".to_owned())?;
+        assert_eq!(store.setrange("short".to_owned(), 5, b"xy")?, 7);
+        expected.insert("short", b"abc\0\0xy".to_vec());
+        // Overlapping one another.
+        store.set("text".to_owned(), "hello world".to_owned())?;
+        assert_eq!(store.setrange("text".to_owned(), 6, b"WORLD")?, 11);
+        assert_eq!(store.setrange("text".to_owned(), 4, b"O W")?, 11);
+        assert_eq!(store.setrange("text".to_owned(), 9, b"LD!!")?, 13);
+        expected.insert("text", b"hellO WORLD!!".to_vec());
+        // Enough times over to be written whole along the way.
+        let mut many = vec![];
+        for i in 0..100 {
+            let patch = [b'a' + (i % 26) as u8; 2];
+            let len = store.setrange("many".to_owned(), i, &patch)?;
+            patch_bytes(&mut many, i, &patch);
+            assert_eq!(len, many.len());
+        }
+        expected.insert("many", many);
+        // Replaced, and removed then patched afresh.
+        store.setrange("replaced".to_owned(), 0, b"patch")?;
+        store.set("replaced".to_owned(), "set".to_owned())?;
+        expected.insert("replaced", b"set".to_vec());
+        store.set("removed".to_owned(), "value".to_owned())?;
+        store.setrange("removed".to_owned(), 1, b"A")?;
+        store.remove("removed".to_owned())?;
+        store.setrange("removed".to_owned(), 1, b"B")?;
+        expected.insert("removed", b"\0B".to_vec());
+        check(&store, &expected)?;
+        let records = log_records(temp_dir.path())?;
+        assert!(records.contains(&Record::SetRange {
+            bucket: None,
+            key: b"text".to_vec(),
+            offset: 4,
+            patch: b"O W".to_vec(),
+            len: 11,
+        }));
+
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        check(&store, &expected)?;
+
+        let controller = store.compaction_controller();
+        controller.pause();
+        let compaction = {
+            let store = store.clone();
+            thread::spawn(move || store.compact())
+        };
+        while controller.status().phase == CompactionPhase::Idle {
+            thread::sleep(Duration::from_millis(1));
+        }
+        store.setrange("text".to_owned(), 0, b"J")?;
+        expected.insert("text", b"JellO WORLD!!".to_vec());
+        store.setrange("during".to_owned(), 1, b"new")?;
+        expected.insert("during", b"\0new".to_vec());
+        controller.resume();
+        compaction.join().unwrap()?;
+        check(&store, &expected)?;
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        check(&store, &expected)?;
+        store.compact()?;
+        check(&store, &expected)?;
+        let records = log_records(temp_dir.path())?;
+        assert!(records
+            .iter()
+            .all(|record| matches!(record, Record::Set { .. })));
+        assert_eq!(store.stats()?.redundant_size, 0);
+    }
+    Ok(())
+}
+
+fn patch_bytes(value: &mut Vec<u8>, offset: usize, patch: &[u8]) {
+    if value.len() < offset + patch.len() {
+        value.resize(offset + patch.len(), 0);
+    }
+    value[offset..offset + patch.len()].copy_from_slice(patch);
+}
+
+// Should flush and sync writes on close, whether or not other handles are still open
+#[test]
+fn close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().memtable(1 << 20, Duration::from_secs(3600));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let other = store.clone();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+
+    let copy = crash_copy(&temp_dir)?;
+    let crashed = KvStore::open(copy.path())?;
+    assert_eq!(crashed.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    other.set("key2".to_owned(), "value2".to_owned())?;
+    other.close()?;
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should hold a compaction while paused, letting writes carry on, and report on it
+#[test]
+fn compaction_controller() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(None);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for round in 0..2 {
+        for i in 0..2000 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+    }
+    let controller = store.compaction_controller();
+    assert_eq!(controller.status().phase, CompactionPhase::Idle);
+    assert_eq!(controller.status().runs, 0);
+
+    controller.pause();
+    let compaction = {
+        let store = store.clone();
+        thread::spawn(move || store.compact())
+    };
+    while controller.status().phase == CompactionPhase::Idle {
+        thread::sleep(Duration::from_millis(1));
+    }
+    for i in 0..100 {
+        store.set(format!("new{}", i), i.to_string())?;
+        store.remove(format!("key{}", i))?;
+    }
+    assert_eq!(store.get("new99".to_owned())?, Some("99".to_owned()));
+    thread::sleep(Duration::from_millis(50));
+    let status = store.stats()?.compaction;
+    assert!(status.paused);
+    assert_eq!(status.phase, CompactionPhase::Running { progress: 0.0 });
+    assert!(!compaction.is_finished());
+
+    controller.resume();
+    compaction.join().unwrap()?;
+    let status = controller.status();
+    assert_eq!(status.phase, CompactionPhase::Idle);
+    assert!(!status.paused);
+    assert_eq!(status.runs, 1);
+    let report = status.last_run.unwrap();
+    assert!(report.bytes_after < report.bytes_before);
+    assert_eq!(
+        status.reclaimed_bytes,
+        report.bytes_before - report.bytes_after
+    );
+    assert_eq!(status.total_duration, report.duration);
+
+    for i in 0..2000 {
+        let expected = (i >= 100).then(|| format!("value{}-1", i));
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+    for i in 0..100 {
+        assert_eq!(store.get(format!("new{}", i))?, Some(i.to_string()));
+    }
+
+    Ok(())
+}
+
+// Should keep writes held in the memtable across compactions
+#[test]
+fn memtable_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .memtable(4096, Duration::from_millis(5))
+        .compaction_threshold(Some(8192));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let writers: Vec<_> = (0..4)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for j in 0..2000 {
+                    store.set(format!("key{}", i), j.to_string()).unwrap();
+                    assert_eq!(store.get(format!("key{}", i)).unwrap(), Some(j.to_string()));
+                }
+            })
+        })
+        .collect();
+    for _ in 0..5 {
+        store.compact()?;
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    store.compact()?;
+    for i in 0..4 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("1999".to_owned()));
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..4 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("1999".to_owned()));
+    }
+    assert!(store.stats()?.redundant_size < 8192);
+
+    Ok(())
+}
+
+// Should notify subscribers of writes made after they subscribe, and of dropped events
+#[test]
+fn subscribe_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("before".to_owned(), "value".to_owned())?;
+
+    let changes = store.subscribe();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.increment("counter".to_owned(), 1)?;
+    store.remove("key1".to_owned())?;
+    assert!(store.remove("missing".to_owned()).is_err());
+    assert_eq!(
+        changes.try_iter().collect::<Vec<_>>(),
+        vec![
+            ChangeEvent::Set {
+                bucket: None,
+                key: b"key1".to_vec()
+            },
+            ChangeEvent::Set {
+                bucket: None,
+                key: b"counter".to_vec()
+            },
+            ChangeEvent::Removed {
+                bucket: None,
+                key: b"key1".to_vec()
+            },
+        ]
+    );
+
+    // A subscriber that doesn't keep up misses events without holding up writers
+    for i in 0..1100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert_eq!(changes.try_iter().count(), 1024);
+    store.set("last".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        changes.try_iter().collect::<Vec<_>>(),
+        vec![
+            ChangeEvent::Lagged { missed: 76 },
+            ChangeEvent::Set {
+                bucket: None,
+                key: b"last".to_vec()
+            },
+        ]
+    );
+
+    // Dropped receivers are unsubscribed
+    drop(changes);
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    Ok(())
+}
+
+// Should delete only the store's own files, and only once no handle has it open
+#[test]
+fn destroy_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let foreign = temp_dir.path().join("notes.txt");
+    fs::write(&foreign, "not part of the store")?;
+
+    let options = KvStoreOptions::new()
+        .compaction_threshold(None)
+        .max_segment_size(Some(256));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.compact()?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AlreadyLocked)
+    ));
+    assert!(matches!(
+        KvStore::destroy(temp_dir.path()),
+        Err(KvsError::AlreadyLocked)
+    ));
+    let clone = store.clone();
+    assert!(matches!(
+        store.close_and_destroy(),
+        Err(KvsError::AlreadyLocked)
+    ));
+    clone.close_and_destroy()?;
+
+    let remaining: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(remaining, vec![foreign]);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    drop(store);
+    KvStore::destroy(temp_dir.path())?;
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 1);
+
+    Ok(())
+}
+
+// Should allocate space for a bulk import without changing what the log holds
+#[test]
+fn reserve_bulk_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().bloom_filter(10, 0.01);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("before".to_owned(), "value".to_owned())?;
+    let usage = store.disk_usage()?;
+    let bits = store.stats()?.bloom.unwrap().bits;
+
+    store.reserve(1000, 1024 * 1024)?;
+    assert_eq!(store.disk_usage()?, usage);
+    assert_eq!(store.get("before".to_owned())?, Some("value".to_owned()));
+    let bloom = store.stats()?.bloom.unwrap();
+    assert!(bloom.bits > bits * 50);
+    assert_eq!(bloom.keys, 1);
+
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("before".to_owned())?, Some("value".to_owned()));
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Should sync alongside writers and compactions without deadlocking
+#[test]
+fn sync_concurrently() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(Some(4096))
+        .max_segment_size(Some(8192));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.sync()?;
+
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..500 {
+                    store.set(format!("key{}", i % 20), format!("{}-{}", t, i))?;
+                }
+                Ok::<_, KvsError>(())
+            })
+        })
+        .collect();
+    let syncer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for _ in 0..200 {
+                store.sync()?;
+            }
+            Ok::<_, KvsError>(())
+        })
+    };
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    syncer.join().unwrap()?;
+    store.sync()?;
+    store.sync()?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(
+        store.get("key19".to_owned())?.map(|v| v.ends_with("-499")),
+        Some(true)
+    );
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sled = SledEngine::open(temp_dir.path())?;
+        sled.set("key".to_owned(), "value".to_owned())?;
+        sled.sync()?;
+    }
+
+    Ok(())
+}
+
+// Should remove a batch of keys, reporting which existed, like sequential removes would
+#[test]
+fn remove_many() -> Result<()> {
+    let options = KvStoreOptions::new().compaction_threshold(None);
+    let batched_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sequential_dir = TempDir::new().expect("unable to create temporary working directory");
+    let batched = KvStore::open_with_options(batched_dir.path(), options.clone())?;
+    let sequential = KvStore::open_with_options(sequential_dir.path(), options.clone())?;
+    for store in [&batched, &sequential] {
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+    }
+
+    let keys: Vec<String> = ["key1", "missing", "key2", "key1", "key9"]
+        .iter()
+        .map(|k| k.to_string())
+        .collect();
+    assert_eq!(
+        batched.remove_many(&keys)?,
+        vec![true, false, true, false, true]
+    );
+    for key in ["key1", "key2", "key9"] {
+        sequential.remove(key.to_owned())?;
+    }
+    assert_eq!(
+        batched.stats()?.redundant_size,
+        sequential.stats()?.redundant_size
+    );
+    assert_eq!(batched.disk_usage()?, sequential.disk_usage()?);
+
+    let missing = vec!["key1".to_owned(), "nothing".to_owned()];
+    let usage = batched.disk_usage()?;
+    assert_eq!(batched.remove_many(&missing)?, vec![false, false]);
+    assert!(batched.remove_many(&[])?.is_empty());
+    assert_eq!(batched.disk_usage()?, usage);
+
+    drop(batched);
+    let batched = KvStore::open_with_options(batched_dir.path(), options)?;
+    for i in 0..10 {
+        let expected = match i {
+            1 | 2 | 9 => None,
+            _ => Some(format!("value{}", i)),
+        };
+        assert_eq!(batched.get(format!("key{}", i))?, expected);
+    }
+    assert_eq!(
+        batched.stats()?.redundant_size,
+        sequential.stats()?.redundant_size
+    );
+
+    Ok(())
+}
+
+// Should set many pairs at once, faster than setting them one by one
+#[test]
+fn populate() -> Result<()> {
+    let entries: Vec<(String, String)> = (0..10000)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let started = std::time::Instant::now();
+    for (key, value) in entries.clone() {
+        store.set(key, value)?;
+    }
+    let naive = started.elapsed();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "overwritten".to_owned())?;
+    let started = std::time::Instant::now();
+    store.populate(entries.clone())?;
+    let populated = started.elapsed();
+    assert!(
+        populated < naive,
+        "populate took {:?}, a loop of sets {:?}",
+        populated,
+        naive
+    );
+
+    for (key, value) in &entries {
+        assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, value) in &entries {
+        assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+    }
+
+    Ok(())
+}
+
+// Check the batched sets and removes of `store`, which limits values to 100 bytes, made
+// with `populate` and `remove_many`
+fn batches<E: KvsEngine>(
+    store: &E,
+    populate: impl Fn(&E, Vec<(String, String)>) -> Result<()>,
+    remove_many: impl Fn(&E, &[String]) -> Result<Vec<bool>>,
+) -> Result<()> {
+    let entries: Vec<(String, String)> = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    store.set("key0".to_owned(), "overwritten".to_owned())?;
+    populate(store, entries.clone())?;
+    populate(store, vec![])?;
+    for (key, value) in &entries {
+        assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+    }
+
+    // A value too large fails the whole batch.
+    let too_large = vec![
+        ("key1".to_owned(), "changed".to_owned()),
+        ("key2".to_owned(), "x".repeat(101)),
+    ];
+    assert!(matches!(
+        populate(store, too_large),
+        Err(KvsError::ValueTooLarge)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Missing keys are reported, rather than failing the batch.
+    let keys: Vec<String> = ["key1", "missing", "key2", "key1", "key9"]
+        .iter()
+        .map(|k| k.to_string())
+        .collect();
+    assert_eq!(
+        remove_many(store, &keys)?,
+        vec![true, false, true, false, true]
+    );
+    let missing = vec!["key1".to_owned(), "nothing".to_owned()];
+    assert_eq!(remove_many(store, &missing)?, vec![false, false]);
+    assert!(remove_many(store, &[])?.is_empty());
+    for (i, (key, value)) in entries.iter().enumerate() {
+        let expected = (![1, 2, 9].contains(&i)).then_some(value);
+        assert_eq!(store.get(key.clone())?.as_ref(), expected);
+    }
+
+    Ok(())
+}
+
+// Should set and remove batches of keys alike in every engine
+#[test]
+fn batches_all_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_value_bytes(Some(100));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    batches(&store, KvStore::populate, KvStore::remove_many)?;
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sled = SledEngine::open(temp_dir.path())?.max_value_bytes(Some(100));
+        batches(&sled, SledEngine::populate, SledEngine::remove_many)?;
+    }
+
+    Ok(())
+}
+
+// Should migrate headerless logs from before format version 2, and refuse newer ones
+#[test]
+fn migrate_log_format() -> Result<()> {
+    assert_eq!(kvs::FORMAT_VERSION, 2);
+    let set =
+        |key: &str, value: &str| format!(r#"{{"Set":{{"key":"{}","value":"{}"}}}}"#, key, value);
+
+    // A store from before manifests, with a single log file
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvstore-logs");
+    fs::write(&log, set("key1", "value1") + &set("key2", "value2"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert!(fs::read(&log)?.starts_with(b"KVSLOG\0\x02{"));
+    let manifest = fs::read_to_string(temp_dir.path().join("kvstore-logs.MANIFEST"))?;
+    assert!(manifest.contains("\"version\":2"));
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 1..=3 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    drop(store);
+
+    // A version 1 store with a sealed log file
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sealed = set("key1", "value1");
+    fs::write(temp_dir.path().join("kvstore-logs"), &sealed)?;
+    fs::write(
+        temp_dir.path().join("kvstore-logs.seg-1"),
+        set("key1", "value2"),
+    )?;
+    fs::write(
+        temp_dir.path().join("kvstore-logs.MANIFEST"),
+        format!(
+            r#"{{"version":1,"active":1,"sealed":[{{"number":0,"len":{},"checksum":0}}],"compactions":0,"compacted_len":0}}"#,
+            sealed.len()
+        ),
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // A log file from a newer build
+    let mut contents = fs::read(&log)?;
+    contents[7] = 3;
+    fs::write(&log, contents)?;
+    match KvStore::open(log.parent().unwrap()) {
+        Err(KvsError::Corrupt(e)) => assert!(e.contains("format version 3")),
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("opened a log in a newer format"),
+    }
+
+    Ok(())
+}
+
+// Should read and write bincode logs, and convert between codecs on reopening and compaction
+#[test]
+fn bincode_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvstore-logs");
+    let json = || KvStoreOptions::new().compaction_threshold(None);
+    let bincode = || json().codec(Codec::Bincode);
+
+    let store = KvStore::open_with_options(temp_dir.path(), bincode())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    assert!(fs::read(&log)?.starts_with(b"KVSBIN\0\x02"));
+    let records: Vec<RecordInfo> = KvStore::inspect(&log)?.collect();
+    assert_eq!(records.len(), 3);
+    assert!(records.iter().all(|r| r.error.is_none()));
+    assert_eq!(
+        records[2].op,
+        Some(Record::Rm {
+            bucket: None,
+            key: b"key2".to_vec()
+        })
+    );
+
+    let store = KvStore::open_with_options(temp_dir.path(), bincode())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    // Reopened as JSON, the bincode file is sealed and read as it is
+    let store = KvStore::open_with_options(temp_dir.path(), json())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(fs::read(temp_dir.path().join("kvstore-logs.seg-1"))?.starts_with(b"KVSLOG"));
+    let snapshot = store.snapshot()?;
+    drop(store);
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(snapshot);
+
+    // And back to bincode, which compaction carries through to every file
+    let store = KvStore::open_with_options(temp_dir.path(), bincode())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.compact()?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        let is_log = match path.extension() {
+            Some(number) => number.to_string_lossy().parse::<u64>().is_ok(),
+            None => true,
+        };
+        if is_log {
+            assert!(
+                fs::read(&path)?.starts_with(b"KVSBIN"),
+                "{}",
+                path.display()
+            );
+        }
+    }
+    let store = KvStore::open_with_options(temp_dir.path(), bincode())?;
+    for i in [1, 3, 4] {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // A checkpoint of a store in one codec can be opened in the other
+    let dest = TempDir::new().expect("unable to create temporary working directory");
+    store.checkpoint(dest.path().join("copy"))?;
+    let copy = KvStore::open_with_options(dest.path().join("copy"), json())?;
+    assert_eq!(copy.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should open a log file with nothing in it, as left by a crash before any write
+#[test]
+fn open_empty_log_files() -> Result<()> {
+    let record = r#"{"Set":{"key":"key1","value":"value1"}}"#;
+    let cases: [(&[u8], Option<&str>); 6] = [
+        (b"", None),
+        (b"  \n\t\n", None),
+        (record.as_bytes(), Some("value1")),
+        (b"KVSLOG\0\x02", None),
+        (b"KVSL", None),
+        (b"KVSBIN\0", None),
+    ];
+    for (contents, value) in cases {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        fs::write(temp_dir.path().join("kvstore-logs"), contents)?;
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, value.map(str::to_owned));
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, value.map(str::to_owned));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should keep each bucket's keys apart from every other bucket's, across compaction and reopening
+#[test]
+fn buckets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let sessions = store.bucket("sessions");
+    let users = store.bucket("users");
+    // Names and keys that would run together if simply concatenated
+    let s = store.bucket("s");
+
+    store.set("alice".to_owned(), "default".to_owned())?;
+    sessions.set("alice".to_owned(), "session".to_owned())?;
+    users.set("alice".to_owned(), "user".to_owned())?;
+    users.set("bob".to_owned(), "user".to_owned())?;
+    s.set("essionsalice".to_owned(), "s".to_owned())?;
+    store.set(
+        "\u{1}\0\0\0\u{5}usersalice".to_owned(),
+        "default".to_owned(),
+    )?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        let (sessions, users) = (store.bucket("sessions"), store.bucket("users"));
+        assert_eq!(store.get("alice".to_owned())?, Some("default".to_owned()));
+        assert_eq!(
+            sessions.get("alice".to_owned())?,
+            Some("session".to_owned())
+        );
+        assert_eq!(users.get("alice".to_owned())?, Some("user".to_owned()));
+        assert_eq!(sessions.get("bob".to_owned())?, None);
+        assert_eq!(
+            store.bucket("s").get("essionsalice".to_owned())?,
+            Some("s".to_owned())
+        );
+        assert_eq!(store.bucket("s").get("alice".to_owned())?, None);
+        assert_eq!(
+            users.scan("")?,
+            vec![
+                ("alice".to_owned(), "user".to_owned()),
+                ("bob".to_owned(), "user".to_owned())
+            ]
+        );
+        assert_eq!(
+            store.scan("al")?,
+            vec![("alice".to_owned(), "default".to_owned())]
+        );
+        Ok(())
+    };
+    check(&store)?;
+    assert!(matches!(
+        sessions.remove("bob".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    store.compact()?;
+    check(&store)?;
+    drop((store, sessions, users, s));
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    // Clearing a bucket leaves the others alone
+    assert_eq!(store.bucket("users").clear()?, 2);
+    assert!(store.bucket("users").scan("")?.is_empty());
+    assert_eq!(store.bucket("users").clear()?, 0);
+    assert_eq!(
+        store.bucket("sessions").get("alice".to_owned())?,
+        Some("session".to_owned())
+    );
+    assert_eq!(store.scan("")?.len(), 2);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.bucket("users").scan("")?.is_empty());
+    assert_eq!(store.get("alice".to_owned())?, Some("default".to_owned()));
+
+    Ok(())
+}
+
+/// What the buckets of every engine can do.
+trait BucketOps {
+    fn set(&self, key: &str, value: &str) -> Result<()>;
+    fn get(&self, key: &str) -> Result<Option<String>>;
+    fn remove(&self, key: &str) -> Result<()>;
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>>;
+    fn clear(&self) -> Result<usize>;
+    fn len(&self) -> Result<usize>;
+}
+
+macro_rules! impl_bucket_ops {
+    ($bucket:ty) => {
+        impl BucketOps for $bucket {
+            fn set(&self, key: &str, value: &str) -> Result<()> {
+                <$bucket>::set(self, key.to_owned(), value.to_owned())
+            }
+            fn get(&self, key: &str) -> Result<Option<String>> {
+                <$bucket>::get(self, key.to_owned())
+            }
+            fn remove(&self, key: &str) -> Result<()> {
+                <$bucket>::remove(self, key.to_owned())
+            }
+            fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+                <$bucket>::scan(self, prefix)
+            }
+            fn clear(&self) -> Result<usize> {
+                <$bucket>::clear(self)
+            }
+            fn len(&self) -> Result<usize> {
+                <$bucket>::len(self)
+            }
+        }
+    };
+}
+
+impl_bucket_ops!(Bucket);
+#[cfg(feature = "sled")]
+impl_bucket_ops!(SledBucket);
+
+// Check that the buckets `bucket` opens in `store` are kept apart from each other and
+// from the store's own keys
+fn bucket_conformance<E: KvsEngine, B: BucketOps>(
+    store: &E,
+    bucket: impl Fn(&str) -> B,
+) -> Result<()> {
+    let (sessions, users) = (bucket("sessions"), bucket("users"));
+    store.set("alice".to_owned(), "default".to_owned())?;
+    sessions.set("alice", "session")?;
+    users.set("alice", "user")?;
+    users.set("bob", "user")?;
+    users.set("albert", "user")?;
+    // Names that an engine might use for its own keys
+    bucket("__sled__default").set("alice", "named")?;
+    bucket("").set("alice", "empty")?;
+
+    assert_eq!(store.get("alice".to_owned())?, Some("default".to_owned()));
+    assert_eq!(sessions.get("alice")?, Some("session".to_owned()));
+    assert_eq!(users.get("alice")?, Some("user".to_owned()));
+    assert_eq!(
+        bucket("__sled__default").get("alice")?,
+        Some("named".to_owned())
+    );
+    assert_eq!(bucket("").get("alice")?, Some("empty".to_owned()));
+    assert_eq!(sessions.get("bob")?, None);
+    assert_eq!(bucket("other").get("alice")?, None);
+    assert_eq!(
+        users.scan("al")?,
+        vec![
+            ("albert".to_owned(), "user".to_owned()),
+            ("alice".to_owned(), "user".to_owned())
+        ]
+    );
+    assert_eq!(users.len()?, 3);
+    assert_eq!(bucket("other").len()?, 0);
+    assert!(matches!(sessions.remove("bob"), Err(KvsError::KeyNotFound)));
+
+    users.remove("albert")?;
+    assert_eq!(users.len()?, 2);
+    // Clearing a bucket leaves the others alone
+    assert_eq!(users.clear()?, 2);
+    assert_eq!(users.clear()?, 0);
+    assert_eq!(users.len()?, 0);
+    assert!(users.scan("")?.is_empty());
+    assert_eq!(sessions.len()?, 1);
+    assert_eq!(store.get("alice".to_owned())?, Some("default".to_owned()));
+    Ok(())
+}
+
+// Should keep buckets apart alike in every engine, sled's as trees of their own
+#[test]
+fn buckets_all_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    bucket_conformance(&store, |name| store.bucket(name))?;
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sled = SledEngine::open(temp_dir.path())?;
+        bucket_conformance(&sled, |name| sled.bucket(name))?;
+        assert_eq!(sled.stats()?.keys, 1);
+        drop(sled);
+
+        let sled = SledEngine::open(temp_dir.path())?;
+        assert_eq!(sled.get("alice".to_owned())?, Some("default".to_owned()));
+        assert_eq!(
+            sled.bucket("sessions").get("alice".to_owned())?,
+            Some("session".to_owned())
+        );
+        assert_eq!(sled.bucket("users").len()?, 0);
+    }
+    Ok(())
+}
+
+// Should write a consistent, openable copy of the store while it keeps taking writes
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(Some(64 * 1024));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.populate((0..5000).map(|i| (format!("key{}", i), format!("value{}", i))))?;
+    for i in 0..2500 {
+        store.set(format!("key{}", i), format!("updated{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    store
+        .bucket("meta")
+        .set("version".to_owned(), "1".to_owned())?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            let mut written = 0;
+            while store.get("stop".to_owned()).unwrap().is_none() {
+                store
+                    .set(format!("during{}", written), "x".to_owned())
+                    .unwrap();
+                written += 1;
+            }
+            written
+        })
+    };
+    let dest = temp_dir.path().join("checkpoint");
+    let report = store.checkpoint(&dest)?;
+    store.set("stop".to_owned(), "".to_owned())?;
+    let written = writer.join().unwrap();
+    assert!(report.keys >= 5000);
+
+    // Another checkpoint can't overwrite this one
+    assert!(store.checkpoint(&dest).is_err());
+
+    let copy = KvStore::open(&dest)?;
+    assert_eq!(copy.get("key0".to_owned())?, None);
+    for i in 1..5000 {
+        let expected = if i < 2500 { "updated" } else { "value" };
+        assert_eq!(
+            copy.get(format!("key{}", i))?,
+            Some(format!("{}{}", expected, i))
+        );
+    }
+    assert_eq!(
+        copy.bucket("meta").get("version".to_owned())?,
+        Some("1".to_owned())
+    );
+    assert_eq!(copy.get("stop".to_owned())?, None);
+    // Writes made during the checkpoint either all made it in up to some point, or not
+    let during = (0..written)
+        .take_while(|i| copy.get(format!("during{}", i)).unwrap().is_some())
+        .count();
+    for i in during..written {
+        assert_eq!(copy.get(format!("during{}", i))?, None);
+    }
+    assert_eq!(copy.stats()?.live_keys, report.keys);
+    assert_eq!(copy.stats()?.redundant_size, 0);
+
+    Ok(())
+}
+
+// Should leave the original log intact if compaction is interrupted before the swap
+#[cfg(feature = "failpoints")]
+#[test]
+fn compaction_interrupted_before_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(None)
+        .max_segment_size(Some(4096))
+        .fail_compaction_before_swap(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i % 100), format!("value{}", i))?;
+    }
+    let files = |dir: &std::path::Path| -> Vec<_> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
+    };
+    let before = files(temp_dir.path());
+    let manifest = fs::read(temp_dir.path().join("kvstore-logs.MANIFEST"))?;
+
+    assert!(store.compact().is_err());
+    assert_eq!(files(temp_dir.path()), before);
+    assert_eq!(
+        fs::read(temp_dir.path().join("kvstore-logs.MANIFEST"))?,
+        manifest
+    );
+    // The store carries on as if nothing happened
+    for i in 900..1000 {
+        assert_eq!(
+            store.get(format!("key{}", i % 100))?,
+            Some(format!("value{}", i))
+        );
+    }
+    drop(store);
+
+    // And reopens from the original log, clearing away the half-finished generation
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(files(temp_dir.path()), before);
+    for i in 900..1000 {
+        assert_eq!(
+            store.get(format!("key{}", i % 100))?,
+            Some(format!("value{}", i))
+        );
+    }
+    store.compact()?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value900".to_owned()));
+
+    Ok(())
+}
+
+// Should report on the health of a store without changing it, and repair a torn tail
+#[test]
+fn verify_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(None)
+        .max_segment_size(Some(1024));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    // Healthy
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.openable);
+    assert_eq!(report.records_scanned, 101);
+    assert_eq!(report.live_keys, 99);
+    assert!(report.corrupt_records.is_empty());
+    assert!(report.orphaned_files.is_empty());
+
+    // A torn write at the end of the active log file
+    let manifest = fs::read_to_string(temp_dir.path().join("kvstore-logs.MANIFEST"))?;
+    let active = manifest
+        .split("\"active\":")
+        .nth(1)
+        .and_then(|rest| rest.split(',').next())
+        .unwrap();
+    let log = temp_dir.path().join(format!("kvstore-logs.seg-{}", active));
+    let contents = fs::read(&log)?;
+    fs::write(&log, &contents[..contents.len() - 5])?;
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.openable);
+    assert_eq!(report.corrupt_records.len(), 1);
+    assert_eq!(report.corrupt_records[0].file, log);
+    assert_eq!(fs::read(&log)?.len(), contents.len() - 5);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let report = KvStore::repair(temp_dir.path())?;
+    assert!(report.openable);
+    assert_eq!(report.records_scanned, 100);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    // The torn record was the removal of key0
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    drop(store);
+
+    // Corruption in the middle of a sealed file, and a file the manifest doesn't know
+    let sealed = temp_dir.path().join("kvstore-logs");
+    let contents =
+        fs::read_to_string(&sealed)?.replacen("{\"key\":\"key2\"", "{\"kex\"!\"key2\"", 1);
+    fs::write(&sealed, contents)?;
+    let orphan = temp_dir.path().join("kvstore-logs.seg-900");
+    fs::write(&orphan, "")?;
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.openable);
+    assert_eq!(report.corrupt_records.len(), 1);
+    assert_eq!(report.corrupt_records[0].file, sealed);
+    assert!(report.corrupt_records[0].offset > 8);
+    assert_eq!(report.checksum_mismatches, vec![sealed.clone()]);
+    assert_eq!(report.orphaned_files, vec![orphan.clone()]);
+    assert_eq!(report.live_keys, 99);
+
+    // Only the manifest can be repaired
+    let report = KvStore::repair(temp_dir.path())?;
+    assert!(!report.openable);
+    assert_eq!(report.corrupt_records.len(), 1);
+
+    Ok(())
+}
+
+// Should verify and repair a store laid out by custom options, leaving the default
+// layout in the same directory alone
+#[test]
+fn verify_store_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .data_dir("data")
+        .log_name("custom-log");
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "default".to_owned())?;
+    drop(store);
+
+    let report = KvStore::verify_with_options(temp_dir.path(), options.clone())?;
+    assert!(report.openable);
+    assert_eq!(report.live_keys, 10);
+
+    // A torn write at the end of the log
+    let log = temp_dir.path().join("data").join("custom-log");
+    let contents = fs::read(&log)?;
+    fs::write(&log, &contents[..contents.len() - 5])?;
+    let report = KvStore::verify_with_options(temp_dir.path(), options.clone())?;
+    assert!(!report.openable);
+    assert_eq!(report.corrupt_records[0].file, log);
+    assert!(KvStore::verify(temp_dir.path())?.openable);
+
+    let report = KvStore::repair_with_options(temp_dir.path(), options.clone())?;
+    assert!(report.openable);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key8".to_owned())?, Some("value8".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+
+    Ok(())
+}
+
+// A store with ten keys in a single log file
+fn recovery_fixture() -> Result<(TempDir, std::path::PathBuf)> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let log = temp_dir.path().join("kvstore-logs");
+    Ok((temp_dir, log))
+}
+
+fn open_in(dir: &TempDir, mode: RecoveryMode) -> Result<(KvStore, RecoveryReport)> {
+    KvStore::open_with_report(dir.path(), KvStoreOptions::new().recovery_mode(mode))
+}
+
+#[test]
+fn recovery_mode_torn_tail() -> Result<()> {
+    let (temp_dir, log) = recovery_fixture()?;
+    let contents = fs::read(&log)?;
+    fs::write(&log, &contents[..contents.len() - 5])?;
+
+    assert!(matches!(
+        open_in(&temp_dir, RecoveryMode::Strict),
+        Err(KvsError::Corrupt(_))
+    ));
+    assert_eq!(fs::read(&log)?.len(), contents.len() - 5);
+
+    let (store, report) = open_in(&temp_dir, RecoveryMode::TruncateTail)?;
+    assert!(report.skipped.is_empty());
+    assert_eq!(report.truncated.len(), 1);
+    assert_eq!(report.truncated[0].file, log);
+    assert_eq!(store.get("key8".to_owned())?, Some("value8".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, None);
+    store.set("key9".to_owned(), "again".to_owned())?;
+    drop(store);
+
+    // The tail is gone, so the store is healthy again
+    let (store, report) = open_in(&temp_dir, RecoveryMode::Strict)?;
+    assert!(report.is_clean());
+    assert_eq!(store.get("key9".to_owned())?, Some("again".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn recovery_mode_corrupt_record() -> Result<()> {
+    let (temp_dir, log) = recovery_fixture()?;
+    let contents = fs::read_to_string(&log)?.replacen("{\"key\":\"key2\"", "{\"kex\"!\"key2\"", 1);
+    fs::write(&log, &contents)?;
+
+    assert!(open_in(&temp_dir, RecoveryMode::Strict).is_err());
+    assert!(open_in(&temp_dir, RecoveryMode::TruncateTail).is_err());
+
+    let (store, report) = open_in(&temp_dir, RecoveryMode::SkipCorrupt)?;
+    assert!(report.truncated.is_empty());
+    assert_eq!(report.skipped.len(), 1);
+    assert_eq!(report.skipped[0].file, log);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.stats()?.live_keys, 9);
+    // Skipped records are left in place
+    assert_eq!(fs::read_to_string(&log)?, contents);
+
+    Ok(())
+}
+
+#[test]
+fn recovery_mode_manifest_mismatch() -> Result<()> {
+    let (temp_dir, _) = recovery_fixture()?;
+    let orphan = temp_dir.path().join("kvstore-logs.seg-900");
+    fs::write(&orphan, "")?;
+
+    assert!(open_in(&temp_dir, RecoveryMode::Strict).is_err());
+    assert!(open_in(&temp_dir, RecoveryMode::TruncateTail).is_err());
+    assert!(orphan.exists());
+
+    let (store, report) = open_in(&temp_dir, RecoveryMode::SkipCorrupt)?;
+    assert_eq!(report.manifest_repairs.len(), 1);
+    assert!(report.manifest_repairs[0].contains("kvstore-logs.seg-900"));
+    assert!(!orphan.exists());
+    assert_eq!(store.stats()?.live_keys, 10);
+
+    Ok(())
+}
+
+// Single-key and multi-key writes from many threads should leave the store matching a
+// model of what each thread did
+#[test]
+fn concurrent_mixed_writes() -> Result<()> {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::HashMap;
+
+    const THREADS: usize = 8;
+    const OPS: usize = 400;
+    const COUNTERS: usize = 4;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(Some(16 * 1024))
+        .max_segment_size(Some(32 * 1024));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let workers: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let mut rng = StdRng::seed_from_u64(t as u64);
+                let mut model = HashMap::new();
+                let mut increments = 0;
+                for i in 0..OPS {
+                    let key = |n: usize| format!("t{}-key{}", t, n);
+                    match rng.gen_range(0..5) {
+                        0 => {
+                            store.increment(format!("counter{}", i % COUNTERS), 1)?;
+                            increments += 1;
+                        }
+                        1 => {
+                            let k = key(rng.gen_range(0..20));
+                            store.set(k.clone(), i.to_string())?;
+                            model.insert(k, i.to_string());
+                        }
+                        2 => {
+                            let k = key(rng.gen_range(0..20));
+                            store.update(k.clone(), |v| {
+                                Some(format!("{}+", v.unwrap_or_default()))
+                            })?;
+                            let v = model.get(&k).cloned().unwrap_or_default();
+                            model.insert(k, format!("{}+", v));
+                        }
+                        3 => {
+                            let ks: Vec<_> = (0..3).map(|_| key(rng.gen_range(0..20))).collect();
+                            store.remove_many(&ks)?;
+                            for k in ks {
+                                model.remove(&k);
+                            }
+                        }
+                        _ => {
+                            let pairs: Vec<_> = (0..3)
+                                .map(|_| (key(rng.gen_range(0..20)), format!("p{}", i)))
+                                .collect();
+                            store.populate(pairs.clone())?;
+                            model.extend(pairs);
+                        }
+                    }
+                }
+                Ok::<_, KvsError>((model, increments))
+            })
+        })
+        .collect();
+
+    let mut expected = HashMap::new();
+    let mut increments = 0;
+    for worker in workers {
+        let (model, n) = worker.join().unwrap()?;
+        expected.extend(model);
+        increments += n;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        let mut total = 0;
+        for c in 0..COUNTERS {
+            total += store
+                .get(format!("counter{}", c))?
+                .map_or(0, |v| v.parse::<i64>().unwrap());
+        }
+        assert_eq!(total, increments);
+        let mut found: HashMap<_, _> = store.scan("t")?.into_iter().collect();
+        assert_eq!(found.len(), expected.len());
+        for (k, v) in &expected {
+            assert_eq!(found.remove(k).as_ref(), Some(v), "{}", k);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options)?)?;
+
+    Ok(())
+}
+
+// A snapshot should keep reading the store as it was, through writes and compactions
+#[test]
+fn snapshot_isolation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(None)
+        .max_segment_size(Some(1024));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    let snapshot = store.snapshot()?;
+
+    for round in 0..5 {
+        for i in 0..100 {
+            store.set(format!("key{:02}", i), format!("round{}", round))?;
+        }
+    }
+    store.remove_many(&(0..50).map(|i| format!("key{:02}", i)).collect::<Vec<_>>())?;
+    store.set("new".to_owned(), "value".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key60".to_owned())?, Some("round4".to_owned()));
+    assert_eq!(store.get("key10".to_owned())?, None);
+
+    assert_eq!(snapshot.len(), 100);
+    assert_eq!(
+        snapshot.get("key10".to_owned())?,
+        Some("value10".to_owned())
+    );
+    assert_eq!(
+        snapshot.get("key60".to_owned())?,
+        Some("value60".to_owned())
+    );
+    assert_eq!(snapshot.get("new".to_owned())?, None);
+    let scanned = snapshot.scan("key9")?;
+    assert_eq!(scanned.len(), 10);
+    assert_eq!(scanned[0], ("key90".to_owned(), "value90".to_owned()));
+
+    // A fresh snapshot sees the store as it is now
+    let snapshot = store.snapshot()?;
+    assert_eq!(snapshot.len(), 51);
+    assert_eq!(snapshot.get("key60".to_owned())?, Some("round4".to_owned()));
+
+    Ok(())
+}
+
+// Should count and size the keys under a prefix, matching what was written
+#[test]
+fn count_prefix() -> Result<()> {
+    use std::collections::BTreeMap;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    #[cfg(feature = "sled")]
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    #[cfg(feature = "sled")]
+    let sled = SledEngine::open(sled_dir.path())?;
+    let mut model = BTreeMap::new();
+    for i in 0..300 {
+        let key = format!("events:{}:{}", ["2024-05", "2024-06"][i % 2], i % 120);
+        let value = "x".repeat(i % 17);
+        store.set(key.clone(), value.clone())?;
+        #[cfg(feature = "sled")]
+        sled.set(key.clone(), value.clone())?;
+        model.insert(key, value);
+    }
+    for i in (0..120).step_by(7) {
+        let key = format!("events:2024-06:{}", i);
+        if model.remove(&key).is_some() {
+            store.remove(key.clone())?;
+            #[cfg(feature = "sled")]
+            sled.remove(key)?;
+        }
+    }
+    store.set("events".to_owned(), "not under the prefix".to_owned())?;
+    let snapshot = store.snapshot()?;
+    store.set("events:2024-06:new".to_owned(), "later".to_owned())?;
+
+    let prefix = "events:2024-06:";
+    let matching: Vec<_> = model
+        .iter()
+        .filter(|(k, _)| k.starts_with(prefix))
+        .collect();
+    let raw: u64 = matching
+        .iter()
+        .map(|(k, v)| (k.len() + v.len()) as u64)
+        .sum();
+    assert_eq!(snapshot.count_prefix(prefix)?, matching.len());
+    assert_eq!(store.count_prefix(prefix)?, matching.len() + 1);
+    #[cfg(feature = "sled")]
+    assert_eq!(sled.count_prefix(prefix)?, matching.len());
+    assert_eq!(store.count_prefix("nothing")?, 0);
+
+    let estimate = snapshot.estimate_size_prefix(prefix)?;
+    assert!(estimate >= raw && estimate <= raw + 72 * matching.len() as u64);
+    assert!(store.estimate_size_prefix(prefix)? > estimate);
+    #[cfg(feature = "sled")]
+    assert_eq!(sled.estimate_size_prefix(prefix)?, raw);
+    assert_eq!(store.estimate_size_prefix("nothing")?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn rename_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("old".to_owned(), "value".to_owned())?;
+    store.set("taken".to_owned(), "other".to_owned())?;
+
+    store.rename("old".to_owned(), "new".to_owned(), false)?;
+    assert_eq!(store.get("old".to_owned())?, None);
+    assert_eq!(store.get("new".to_owned())?, Some("value".to_owned()));
+
+    assert!(matches!(
+        store.rename("new".to_owned(), "taken".to_owned(), false),
+        Err(KvsError::KeyExists)
+    ));
+    assert_eq!(store.get("new".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("taken".to_owned())?, Some("other".to_owned()));
+    store.rename("new".to_owned(), "taken".to_owned(), true)?;
+    assert_eq!(store.get("new".to_owned())?, None);
+    assert_eq!(store.get("taken".to_owned())?, Some("value".to_owned()));
+
+    store.rename("taken".to_owned(), "taken".to_owned(), false)?;
+    assert_eq!(store.get("taken".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        store.rename("missing".to_owned(), "new".to_owned(), true),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.stats()?.live_keys, 1);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("taken".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.stats()?.live_keys, 1);
+
+    Ok(())
+}
+
+// Copying a key should leave an independent value at the destination
+#[test]
+fn copy_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("src".to_owned(), "value".to_owned())?;
+    store.set("taken".to_owned(), "other".to_owned())?;
+
+    store.copy("src".to_owned(), "dst".to_owned(), false)?;
+    assert_eq!(store.get("src".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("dst".to_owned())?, Some("value".to_owned()));
+    store.set("src".to_owned(), "changed".to_owned())?;
+    assert_eq!(store.get("dst".to_owned())?, Some("value".to_owned()));
+
+    assert!(matches!(
+        store.copy("src".to_owned(), "taken".to_owned(), false),
+        Err(KvsError::KeyExists)
+    ));
+    assert_eq!(store.get("taken".to_owned())?, Some("other".to_owned()));
+    store.copy("src".to_owned(), "taken".to_owned(), true)?;
+    assert_eq!(store.get("taken".to_owned())?, Some("changed".to_owned()));
+
+    assert!(matches!(
+        store.copy("missing".to_owned(), "dst".to_owned(), true),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.get("dst".to_owned())?, Some("value".to_owned()));
+    store.remove("src".to_owned())?;
+    assert_eq!(store.stats()?.live_keys, 2);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("src".to_owned())?, None);
+    assert_eq!(store.get("dst".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("taken".to_owned())?, Some("changed".to_owned()));
+
+    Ok(())
+}
+
+// A rename cut short should leave both keys as they were
+#[test]
+fn rename_key_torn() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("kvstore-logs");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "value".to_owned())?;
+    let before = fs::read(&log)?;
+    store.rename("from".to_owned(), "to".to_owned(), false)?;
+    drop(store);
+    let after = fs::read(&log)?;
+    let rm_end = KvStore::inspect(&log)?
+        .from_offset(before.len() as u64)
+        .next()
+        .map(|record| (record.offset + record.len) as usize)
+        .unwrap();
+
+    // Cut short within the set, and right after the removal
+    for len in [after.len() - 3, rm_end] {
+        fs::write(&log, &after[..len])?;
+        assert!(KvStore::open(temp_dir.path()).is_err());
+        let options = KvStoreOptions::new().recovery_mode(RecoveryMode::TruncateTail);
+        let (store, report) = KvStore::open_with_report(temp_dir.path(), options)?;
+        assert_eq!(report.truncated.len(), 1);
+        assert_eq!(report.truncated[0].offset, before.len() as u64);
+        assert_eq!(store.get("from".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("to".to_owned())?, None);
+        drop(store);
+        assert_eq!(fs::read(&log)?, before);
+    }
+
+    Ok(())
+}
+
+fn set_record(bucket: Option<&str>, key: &str, value: &[u8]) -> Record {
+    Record::Set {
+        bucket: bucket.map(str::to_owned),
+        key: key.as_bytes().to_vec(),
+        value: value.to_vec(),
+        compression: None,
+    }
+}
+
+// Should build a store from a sequence of ops, ending up as if they had been written to it
+#[test]
+fn from_ops() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ops = vec![
+        set_record(None, "key1", b"value1"),
+        set_record(None, "key2", b"value2"),
+        set_record(Some("bucket"), "key1", b"in a bucket"),
+        set_record(None, "key1", b"overwritten"),
+        Record::Rm {
+            bucket: None,
+            key: b"key2".to_vec(),
+        },
+        // Removing a key that doesn't exist does nothing.
+        Record::Rm {
+            bucket: None,
+            key: b"missing".to_vec(),
+        },
+        Record::SetRange {
+            bucket: None,
+            key: b"key1".to_vec(),
+            offset: 4,
+            patch: b"WRIT".to_vec(),
+            len: 11,
+        },
+        Record::SetRange {
+            bucket: Some("bucket".to_owned()),
+            key: b"key3".to_vec(),
+            offset: 2,
+            patch: b"new".to_vec(),
+            // Worked out afresh rather than trusted.
+            len: 100,
+        },
+    ];
+    let store = KvStore::from_ops(temp_dir.path(), ops.clone())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(
+            store.get("key1".to_owned())?,
+            Some("overWRITten".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("missing".to_owned())?, None);
+        let bucket = store.bucket("bucket");
+        assert_eq!(
+            bucket.get("key1".to_owned())?,
+            Some("in a bucket".to_owned())
+        );
+        assert_eq!(bucket.get("key3".to_owned())?, Some("\0\0new".to_owned()));
+        assert_eq!(store.stats()?.live_keys, 3);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    // Fails rather than adding to a store that's already there.
+    assert!(matches!(
+        KvStore::from_ops(temp_dir.path(), ops.clone()),
+        Err(KvsError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists
+    ));
+
+    // A failing op leaves nothing behind, so the path can be built into again.
+    let failed_dir = TempDir::new().expect("unable to create temporary working directory");
+    let failing = vec![
+        set_record(None, "key1", b"value1"),
+        Record::SetRange {
+            bucket: None,
+            key: b"key1".to_vec(),
+            offset: u64::MAX,
+            patch: b"x".to_vec(),
+            len: 0,
+        },
+    ];
+    assert!(matches!(
+        KvStore::from_ops(failed_dir.path(), failing),
+        Err(KvsError::TooLarge)
+    ));
+    assert_eq!(fs::read_dir(failed_dir.path())?.count(), 0);
+    check(&KvStore::from_ops(failed_dir.path(), ops)?)?;
+
+    // The records read back from a store's log rebuild it.
+    let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+    let records = KvStore::inspect(temp_dir.path().join("kvstore-logs"))?.filter_map(|r| r.op);
+    check(&KvStore::from_ops(copy_dir.path(), records)?)?;
+    Ok(())
+}
+
+fn history_values(store: &KvStore, key: &str, limit: usize) -> Result<Vec<String>> {
+    let versions = store.history(key.to_owned(), limit)?;
+    Ok(versions.into_iter().map(|version| version.value).collect())
+}
+
+// Should read back the earlier values of a key, newest first, until compaction
+#[test]
+fn history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(Some(256));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..5 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+        store.set("other".to_owned(), format!("other{}", i))?;
+    }
+    store.setrange("key".to_owned(), 0, b"VAL")?;
+    store.set("key".to_owned(), "last".to_owned())?;
+    store.setrange("key".to_owned(), 4, b"!")?;
+
+    let expected = [
+        "last!", "last", "VALue4", "value4", "value3", "value2", "value1", "value0",
+    ];
+    let versions = store.history("key".to_owned(), 100)?;
+    assert_eq!(
+        versions
+            .iter()
+            .map(|v| v.value.as_str())
+            .collect::<Vec<_>>(),
+        expected
+    );
+    // Each version is a record of its own, stamped when it was written.
+    let mut places: Vec<_> = versions.iter().map(|v| (v.segment, v.offset)).collect();
+    places.sort();
+    places.dedup();
+    assert_eq!(places.len(), expected.len());
+    assert!(versions.iter().all(|v| v.written_at.is_some()));
+    assert_eq!(history_values(&store, "key", 3)?, expected[..3]);
+    assert_eq!(history_values(&store, "key", 0)?, Vec::<String>::new());
+    assert_eq!(history_values(&store, "missing", 10)?, Vec::<String>::new());
+
+    // The history is rebuilt when the store is reopened.
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(history_values(&store, "key", 100)?, expected);
+    assert_eq!(history_values(&store, "other", 2)?, ["other4", "other3"]);
+
+    // Removing a key drops its history, and so does compaction, but for later writes.
+    store.remove("other".to_owned())?;
+    store.set("other".to_owned(), "again".to_owned())?;
+    assert_eq!(history_values(&store, "other", 10)?, ["again"]);
+    store.compact()?;
+    assert_eq!(history_values(&store, "key", 10)?, ["last!"]);
+    store.set("key".to_owned(), "after".to_owned())?;
+    assert_eq!(history_values(&store, "key", 10)?, ["after", "last!"]);
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(history_values(&store, "key", 10)?, ["after", "last!"]);
+    assert_eq!(history_values(&store, "other", 10)?, ["again"]);
+    Ok(())
+}
+
+fn sequence_numbers_since(store: &KvStore, seq: u64) -> Result<Vec<u64>> {
+    store
+        .ops_since(seq)?
+        .map(|op| op.map(|(seq, _)| seq))
+        .collect()
+}
+
+// Should number every write in order, without gaps, across batches, reopens and compactions
+#[test]
+fn sequence_numbers() -> Result<()> {
+    for codec in [Codec::Json, Codec::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .codec(codec)
+            .compaction_threshold(None)
+            .max_segment_size(Some(512));
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(store.last_sequence(), 0);
+        assert_eq!(sequence_numbers_since(&store, 0)?, Vec::<u64>::new());
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+        // Keys that don't exist aren't removed, so take no number.
+        store.remove_many(&["key2".to_owned(), "missing".to_owned()])?;
+        assert!(store.remove("missing".to_owned()).is_err());
+        store.populate([
+            ("a".to_owned(), "1".to_owned()),
+            ("b".to_owned(), "2".to_owned()),
+            ("c".to_owned(), "3".to_owned()),
+        ])?;
+        store.rename("a".to_owned(), "z".to_owned(), false)?;
+        store.setrange("b".to_owned(), 1, b"!")?;
+        assert_eq!(store.last_sequence(), 10);
+
+        let ops = store.ops_since(0)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            ops.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(),
+            (1..=10).collect::<Vec<_>>()
+        );
+        assert_eq!(ops[0].1, set_record(None, "key1", b"value1"));
+        assert_eq!(
+            ops[2].1,
+            Record::Rm {
+                bucket: None,
+                key: b"key1".to_vec()
+            }
+        );
+        assert_eq!(ops[8].1, set_record(None, "z", b"1"));
+        assert_eq!(
+            ops[9].1,
+            Record::SetRange {
+                bucket: None,
+                key: b"b".to_vec(),
+                offset: 1,
+                patch: b"!".to_vec(),
+                len: 2,
+            }
+        );
+        assert_eq!(sequence_numbers_since(&store, 7)?, [8, 9, 10]);
+        assert_eq!(sequence_numbers_since(&store, 10)?, Vec::<u64>::new());
+        assert_eq!(sequence_numbers_since(&store, 100)?, Vec::<u64>::new());
+
+        // The numbering carries on where it left off when reopened.
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(store.last_sequence(), 10);
+        assert_eq!(sequence_numbers_since(&store, 0)?.len(), 10);
+        for i in 0..20 {
+            store.set("key3".to_owned(), i.to_string())?;
+        }
+        assert_eq!(store.last_sequence(), 30);
+
+        // Compaction drops the ops written before it, but not those written since.
+        store.compact()?;
+        assert!(matches!(store.ops_since(0), Err(KvsError::Compacted(30))));
+        assert!(matches!(store.ops_since(29), Err(KvsError::Compacted(30))));
+        assert_eq!(sequence_numbers_since(&store, 30)?, Vec::<u64>::new());
+        store.set("key4".to_owned(), "value4".to_owned())?;
+        assert_eq!(sequence_numbers_since(&store, 30)?, [31]);
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(store.last_sequence(), 31);
+        assert!(matches!(store.ops_since(0), Err(KvsError::Compacted(30))));
+        assert_eq!(sequence_numbers_since(&store, 30)?, [31]);
+
+        // Even once no record holds the last number, it isn't handed out again.
+        let keys = ["b", "c", "z", "key3", "key4"].map(str::to_owned);
+        store.remove_many(&keys)?;
+        store.compact()?;
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.last_sequence(), 36);
+        store.set("key5".to_owned(), "value5".to_owned())?;
+        assert_eq!(sequence_numbers_since(&store, 36)?, [37]);
+
+        // A checkpoint carries on the numbering, without the ops before it.
+        let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+        store.checkpoint(checkpoint_dir.path())?;
+        let checkpoint = KvStore::open(checkpoint_dir.path())?;
+        assert_eq!(checkpoint.last_sequence(), 37);
+        assert!(matches!(
+            checkpoint.ops_since(36),
+            Err(KvsError::Compacted(37))
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! Reads of values too large to hold in memory comfortably.
+//!
+//! These run in a binary of their own, as they count every allocation the process makes.
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Codec, KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsServer, Result};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use tempfile::TempDir;
+
+/// The size of the values read.
+const VALUE_LEN: usize = 8 << 20;
+/// How far above what was allocated beforehand a read may take the process's memory.
+const MAX_OVERHEAD: usize = 1 << 20;
+
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let live = LIVE.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(live, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Run `f`, returning how far it took the memory allocated above what it was before.
+fn peak_during(f: impl FnOnce()) -> usize {
+    let before = LIVE.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    f();
+    PEAK.load(Ordering::SeqCst) - before
+}
+
+/// A writer checking what's written to it against `expected`, without keeping it.
+struct Compare<'a> {
+    expected: &'a [u8],
+}
+
+impl Write for Compare<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.expected.len());
+        assert_eq!(&buf[..len], &self.expected[..len], "read bytes that differ");
+        assert_eq!(len, buf.len(), "read more bytes than the value has");
+        self.expected = &self.expected[len..];
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn stream(store: &KvStore, key: &str, expected: &[u8]) {
+    let mut reader = store.get_reader(key.to_owned()).unwrap().unwrap();
+    assert_eq!(reader.len(), expected.len() as u64);
+    let mut compare = Compare { expected };
+    let peak = peak_during(|| {
+        io::copy(&mut reader, &mut compare).unwrap();
+    });
+    assert!(compare.expected.is_empty(), "{} ended early", key);
+    assert!(
+        peak < MAX_OVERHEAD,
+        "reading {} allocated {} bytes",
+        key,
+        peak
+    );
+}
+
+// Should stream values of tens of megabytes, from the store and over the network,
+// without reading them into memory whole
+#[test]
+fn stream_large_values() -> Result<()> {
+    // Text with something to unescape in JSON on every line.
+    let mut text = String::with_capacity(VALUE_LEN);
+    while text.len() < VALUE_LEN {
+        text.push_str(&format!("\"line\" {}\té\\🦀\n", text.len()));
+    }
+    // Bytes that aren't UTF-8, so are written as base64 in JSON.
+    let binary: Vec<u8> = (0..VALUE_LEN).map(|i| (i * 7 % 251) as u8 | 0x80).collect();
+
+    for codec in [Codec::Json, Codec::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().codec(codec);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set("text".to_owned(), text.clone())?;
+        store.set_bytes(b"binary", &binary)?;
+
+        stream(&store, "text", text.as_bytes());
+        stream(&store, "binary", &binary);
+
+        let pool = SharedQueueThreadPool::new(2)?;
+        let (server, shutdown) =
+            KvsServer::bind("127.0.0.1:0".parse().unwrap(), store.clone(), pool).unwrap();
+        let addr = server.local_addr().unwrap();
+        let server_thread = thread::spawn(move || server.run().unwrap());
+        let mut client = KvsClient::connect(addr).unwrap();
+        for (key, expected) in [("text", text.as_bytes()), ("binary", &binary[..])] {
+            let mut compare = Compare { expected };
+            let mut len = None;
+            let peak = peak_during(|| {
+                len = client.get_to(key.to_owned(), &mut compare).unwrap();
+            });
+            assert_eq!(len, Some(expected.len() as u64));
+            assert!(compare.expected.is_empty(), "{} ended early", key);
+            assert!(
+                peak < MAX_OVERHEAD,
+                "getting {} allocated {} bytes",
+                key,
+                peak
+            );
+        }
+        assert_eq!(
+            client.get_to("missing".to_owned(), io::sink()).unwrap(),
+            None
+        );
+        drop(client);
+        shutdown.shutdown().unwrap();
+        server_thread.join().unwrap();
+
+        // The reader reads the same as `get`, all at once.
+        let mut whole = vec![];
+        store
+            .get_reader("text".to_owned())?
+            .unwrap()
+            .read_to_end(&mut whole)?;
+        assert_eq!(whole, text.as_bytes());
+    }
+    Ok(())
+}
@@ -0,0 +1,886 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+#[cfg(feature = "sled")]
+use kvs::SledEngine;
+use kvs::{
+    KvStore, KvStoreOptions, KvsClient, KvsClientPool, KvsEngine, KvsServer, Result, WatchEvent,
+};
+use std::fs;
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use tempfile::TempDir;
+
+/// Run a server for `engine` on a free port, calling `f` with its address.
+fn with_server<E: KvsEngine>(engine: E, f: impl FnOnce(SocketAddr)) -> Result<()> {
+    let pool = SharedQueueThreadPool::new(8)?;
+    let (server, shutdown) = KvsServer::bind("127.0.0.1:0".parse().unwrap(), engine, pool).unwrap();
+    let addr = server.local_addr().unwrap();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    f(addr);
+
+    shutdown.shutdown().unwrap();
+    server_thread.join().unwrap();
+    Ok(())
+}
+
+fn concurrent_increments<E: KvsEngine>(engine: E) -> Result<()> {
+    with_server(engine, |addr| {
+        let handles: Vec<_> = (0..50)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mut client = KvsClient::connect(addr).unwrap();
+                    for _ in 0..100 {
+                        client.increment("counter".to_owned(), 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let mut client = KvsClient::connect(addr).unwrap();
+        assert_eq!(
+            client.get("counter".to_owned()).unwrap(),
+            Some("5000".to_owned())
+        );
+        assert_eq!(client.increment("counter".to_owned(), -5000).unwrap(), 0);
+
+        client.set("word".to_owned(), "value".to_owned()).unwrap();
+        let err = client.increment("word".to_owned(), 1).unwrap_err();
+        assert!(err.to_string().contains("not an integer"));
+    })
+}
+
+// Increments from many clients at once should never be lost.
+#[test]
+fn increment_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_increments(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+#[cfg(feature = "sled")]
+fn increment_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_increments(SledEngine::open(temp_dir.path())?)
+}
+
+fn copy_over_network<E: KvsEngine>(engine: E) -> Result<()> {
+    with_server(engine, |addr| {
+        let mut client = KvsClient::connect(addr).unwrap();
+        client.set("src".to_owned(), "value".to_owned()).unwrap();
+        client.set("taken".to_owned(), "other".to_owned()).unwrap();
+
+        client
+            .copy("src".to_owned(), "dst".to_owned(), false)
+            .unwrap();
+        client.set("src".to_owned(), "changed".to_owned()).unwrap();
+        assert_eq!(
+            client.get("dst".to_owned()).unwrap(),
+            Some("value".to_owned())
+        );
+
+        let err = client
+            .copy("src".to_owned(), "taken".to_owned(), false)
+            .unwrap_err();
+        assert!(err.to_string().contains("Key already exists"));
+        client
+            .copy("src".to_owned(), "taken".to_owned(), true)
+            .unwrap();
+        assert_eq!(
+            client.get("taken".to_owned()).unwrap(),
+            Some("changed".to_owned())
+        );
+
+        let err = client
+            .copy("missing".to_owned(), "dst".to_owned(), true)
+            .unwrap_err();
+        assert!(err.to_string().contains("Key not found"));
+
+        assert_eq!(client.value_len("taken".to_owned()).unwrap(), Some(7));
+        assert_eq!(client.value_len("missing".to_owned()).unwrap(), None);
+    })
+}
+
+#[test]
+fn copy_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    copy_over_network(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+#[cfg(feature = "sled")]
+fn copy_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    copy_over_network(SledEngine::open(temp_dir.path())?)
+}
+
+/// Keys and values that are easy to mangle when escaping, framing or buffering them.
+fn adversarial_corpus() -> Vec<String> {
+    let mut corpus: Vec<String> = [
+        "",
+        "\n",
+        "line one\nline two\r\n",
+        "\0",
+        "nul\0in the middle",
+        "\"quoted\"",
+        "\\",
+        "}{\"id\":0}",
+        "\t\u{7}\u{1b}[0m\u{7f}",
+        "🦀🔥👩‍👩‍👧",
+        "键值存储",
+        "ｋｅｙ\u{feff}\u{200b}",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    corpus.push((0u8..0x20).map(char::from).collect());
+    // Around the size of a single network read, and well past it.
+    for len in [4090, 4095, 4096, 4097, 4100, 100_000] {
+        corpus.push("x".repeat(len));
+        corpus.push("🦀\n\0\"".repeat(len / 8));
+    }
+    corpus
+}
+
+fn round_trip<E: KvsEngine>(engine: E) -> Result<()> {
+    let corpus = adversarial_corpus();
+
+    with_server(engine.clone(), |addr| {
+        let mut client = KvsClient::connect(addr).unwrap();
+        for (i, value) in corpus.iter().enumerate() {
+            let key = format!("{}{}", value, i);
+            client.set(&key, value).unwrap();
+            assert_eq!(client.get(&key).unwrap().as_ref(), Some(value));
+            let mut streamed = vec![];
+            let len = client.get_to(&key, &mut streamed).unwrap();
+            assert_eq!(len, Some(value.len() as u64));
+            assert_eq!(streamed, value.as_bytes());
+            let whole = client.getrange(&key, 0..value.len() + 10).unwrap();
+            assert_eq!(whole.as_ref(), Some(value));
+            let past_end = client.getrange(&key, value.len() + 1..value.len() + 5);
+            assert_eq!(past_end.unwrap(), Some(String::new()));
+            assert_eq!(engine.get(key).unwrap().as_ref(), Some(value));
+
+            let key = format!("direct{}{}", i, value);
+            engine.set(key.clone(), value.clone()).unwrap();
+            assert_eq!(client.get(&key).unwrap().as_ref(), Some(value));
+            client.remove(&key).unwrap();
+            assert_eq!(engine.get(key.clone()).unwrap(), None);
+            assert_eq!(client.getrange(key, 0..1).unwrap(), None);
+        }
+    })
+}
+
+// Keys and values should round-trip intact through the engines and the network.
+#[test]
+fn round_trip_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    round_trip(KvStore::open(temp_dir.path())?)?;
+
+    // And survive a replay of the log
+    let store = KvStore::open(temp_dir.path())?;
+    for (i, value) in adversarial_corpus().iter().enumerate() {
+        assert_eq!(store.get(format!("{}{}", value, i))?.as_ref(), Some(value));
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "sled")]
+fn round_trip_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    round_trip(SledEngine::open(temp_dir.path())?)
+}
+
+// Keys and values should be accepted as borrowed strings, without copying them at the call
+#[test]
+fn borrowed_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keys = ["key1", "key2", "key3"];
+    with_server(KvStore::open(temp_dir.path())?, |addr| {
+        let mut client = KvsClient::connect(addr).unwrap();
+        for key in keys {
+            client.set(key, key).unwrap();
+        }
+        for _ in 0..10 {
+            for key in keys {
+                assert_eq!(client.get(key).unwrap().as_deref(), Some(key));
+            }
+        }
+        client.copy("key1", "copied", false).unwrap();
+        assert_eq!(client.value_len("copied").unwrap(), Some(4));
+        client.remove("key1").unwrap();
+        assert_eq!(client.get("key1").unwrap(), None);
+    })
+}
+
+// Writes made in-process through the server's engine handle should be served over TCP,
+// and writes made over TCP read through the handle
+#[test]
+fn engine_handle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::new(2)?;
+    let (server, shutdown) = KvsServer::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        KvStore::open(temp_dir.path())?,
+        pool,
+    )
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+    let engine = server.engine_handle();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    engine.set("local".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("local").unwrap(), Some("value1".to_owned()));
+    client.set("remote", "value2").unwrap();
+    assert_eq!(engine.get("remote".to_owned())?, Some("value2".to_owned()));
+    engine.remove("local".to_owned())?;
+    assert_eq!(client.get("local").unwrap(), None);
+
+    drop(client);
+    shutdown.shutdown().unwrap();
+    server_thread.join().unwrap();
+    // The handle outlives the server.
+    assert_eq!(engine.get("remote".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should serve clients in the same process over in-memory channels, with the whole
+// protocol: round trips, streamed values and watches
+#[test]
+fn channel_transport() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::new(4)?;
+    let (server, shutdown, handle) =
+        KvsServer::bind_channel(KvStore::open(temp_dir.path())?, pool).unwrap();
+    assert!(server.local_addr().is_err());
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    let mut watch = KvsClient::connect_channel(&handle)
+        .unwrap()
+        .watch("key")
+        .unwrap();
+    let mut client = KvsClient::connect_channel(&handle).unwrap();
+    assert_eq!(client.server_version(), env!("CARGO_PKG_VERSION"));
+    assert!(client.nodelay().unwrap());
+    assert_eq!(client.get("key1").unwrap(), None);
+    client.set("key1", "value1").unwrap();
+    assert_eq!(client.get("key1").unwrap(), Some("value1".to_owned()));
+    assert_eq!(
+        watch.next().unwrap().unwrap(),
+        WatchEvent {
+            key: "key1".to_owned(),
+            value: Some("value1".to_owned()),
+        }
+    );
+
+    // A value sent in several chunks.
+    let large = "x".repeat(200 * 1024);
+    client.set("large", large.clone()).unwrap();
+    let mut received = vec![];
+    assert_eq!(
+        client.get_to("large", &mut received).unwrap(),
+        Some(large.len() as u64)
+    );
+    assert_eq!(received, large.as_bytes());
+    client.remove("key1").unwrap();
+    assert_eq!(client.get("key1").unwrap(), None);
+
+    drop(watch);
+    client.shutdown().unwrap();
+    shutdown.shutdown().unwrap();
+    server_thread.join().unwrap();
+    assert!(KvsClient::connect_channel(&handle).is_err());
+    Ok(())
+}
+
+fn empty_values<E: KvsEngine>(engine: E) -> Result<()> {
+    with_server(engine, |addr| {
+        let mut watch = KvsClient::connect(addr)
+            .unwrap()
+            .watch("empty".to_owned())
+            .unwrap();
+
+        let mut client = KvsClient::connect(addr).unwrap();
+        assert_eq!(client.get("empty".to_owned()).unwrap(), None);
+        client.set("empty".to_owned(), "value".to_owned()).unwrap();
+        assert_eq!(
+            client.get("empty".to_owned()).unwrap(),
+            Some("value".to_owned())
+        );
+        client.set("empty".to_owned(), "".to_owned()).unwrap();
+        assert_eq!(client.get("empty".to_owned()).unwrap(), Some("".to_owned()));
+        client
+            .copy("empty".to_owned(), "empty copy".to_owned(), false)
+            .unwrap();
+        assert_eq!(
+            client.get("empty copy".to_owned()).unwrap(),
+            Some("".to_owned())
+        );
+        assert_eq!(client.get("missing".to_owned()).unwrap(), None);
+        client.remove("empty".to_owned()).unwrap();
+        assert_eq!(client.get("empty".to_owned()).unwrap(), None);
+
+        let values: Vec<_> = watch
+            .by_ref()
+            .take(4)
+            .map(|event| event.unwrap().value)
+            .collect();
+        assert_eq!(
+            values,
+            [
+                Some("value".to_owned()),
+                Some("".to_owned()),
+                Some("".to_owned()),
+                None
+            ]
+        );
+    })
+}
+
+// A key set to an empty value should never look missing to a client.
+#[test]
+fn empty_values_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    empty_values(KvStore::open(temp_dir.path())?)?;
+
+    // Nor after a replay of the log
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("empty copy".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("empty".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "sled")]
+fn empty_values_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    empty_values(SledEngine::open(temp_dir.path())?)
+}
+
+// A watcher should see changes made by other clients to keys under its prefix.
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    with_server(KvStore::open(temp_dir.path())?, |addr| {
+        let mut watch = KvsClient::connect(addr)
+            .unwrap()
+            .watch("config/".to_owned())
+            .unwrap();
+
+        let mut client = KvsClient::connect(addr).unwrap();
+        client
+            .set("other".to_owned(), "ignored".to_owned())
+            .unwrap();
+        client
+            .set("config/timeout".to_owned(), "30".to_owned())
+            .unwrap();
+        client.increment("config/retries".to_owned(), 3).unwrap();
+        client.remove("config/timeout".to_owned()).unwrap();
+
+        let expected = [
+            ("config/timeout", Some("30")),
+            ("config/retries", Some("3")),
+            ("config/timeout", None),
+        ];
+        for (key, value) in expected {
+            let event = watch.next().unwrap().unwrap();
+            assert_eq!(
+                event,
+                WatchEvent {
+                    key: key.to_owned(),
+                    value: value.map(str::to_owned)
+                }
+            );
+        }
+    })
+}
+
+#[test]
+fn connect_by_hostname() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    with_server(KvStore::open(temp_dir.path())?, |addr| {
+        let mut client = KvsClient::connect_to(format!("localhost:{}", addr.port())).unwrap();
+        client.set("key".to_owned(), "value".to_owned()).unwrap();
+        assert_eq!(
+            client.get("key".to_owned()).unwrap(),
+            Some("value".to_owned())
+        );
+
+        assert!(KvsClient::connect_to("no-such-host.invalid:4000").is_err());
+    })
+}
+
+#[test]
+fn bind_many_addresses() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::new(4)?;
+    let addrs = ["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()];
+    let (server, shutdown) =
+        KvsServer::bind_many(&addrs, KvStore::open(temp_dir.path())?, pool).unwrap();
+    let bound = server.local_addrs().unwrap();
+    assert_eq!(bound.len(), 2);
+    assert!(bound[0].is_ipv4() && bound[1].is_ipv6());
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    let mut v4 = KvsClient::connect(bound[0]).unwrap();
+    v4.set("key".to_owned(), "value".to_owned()).unwrap();
+    let mut v6 = KvsClient::connect(bound[1]).unwrap();
+    assert_eq!(v6.get("key".to_owned()).unwrap(), Some("value".to_owned()));
+    drop((v4, v6));
+
+    shutdown.shutdown().unwrap();
+    server_thread.join().unwrap();
+    Ok(())
+}
+
+// With a single writer thread, concurrent writes and reads should behave as on the pool.
+#[test]
+fn single_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(8)?;
+    let (mut server, shutdown) =
+        KvsServer::bind("127.0.0.1:0".parse().unwrap(), store, pool).unwrap();
+    server.set_single_writer(true);
+    let addr = server.local_addr().unwrap();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    let handles: Vec<_> = (0..20)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                for j in 0..50 {
+                    let key = format!("key{}-{}", i, j);
+                    client.set(key.clone(), j.to_string()).unwrap();
+                    assert_eq!(client.get(key).unwrap(), Some(j.to_string()));
+                    client.increment("counter".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("counter".to_owned()).unwrap(),
+        Some("1000".to_owned())
+    );
+    client.remove("key0-0".to_owned()).unwrap();
+    assert_eq!(client.get("key0-0".to_owned()).unwrap(), None);
+    assert!(client.remove("key0-0".to_owned()).is_err());
+    client
+        .copy("key1-1".to_owned(), "copy".to_owned(), false)
+        .unwrap();
+    assert_eq!(client.get("copy".to_owned()).unwrap(), Some("1".to_owned()));
+    drop(client);
+
+    shutdown.shutdown().unwrap();
+    server_thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn tcp_nodelay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    with_server(KvStore::open(temp_dir.path())?, |addr| {
+        let mut client = KvsClient::connect(addr).unwrap();
+        assert!(client.nodelay().unwrap());
+        client.set_nodelay(false).unwrap();
+        assert!(!client.nodelay().unwrap());
+        client.set("key".to_owned(), "value".to_owned()).unwrap();
+        assert_eq!(
+            client.get("key".to_owned()).unwrap(),
+            Some("value".to_owned())
+        );
+    })
+}
+
+#[test]
+fn client_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    with_server(KvStore::open(temp_dir.path())?, |addr| {
+        let pool = KvsClientPool::connect(addr, 4).unwrap();
+        assert_eq!(pool.open_connections(), 4);
+        for i in 0..100 {
+            let mut client = pool.get().unwrap();
+            client.set(format!("key{}", i), i.to_string()).unwrap();
+        }
+        assert_eq!(pool.open_connections(), 4);
+
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let value = pool.get().unwrap().get(format!("key{}", i)).unwrap();
+                        assert_eq!(value, Some(i.to_string()));
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(pool.open_connections(), 4);
+    })
+}
+
+// Should make acknowledged writes durable on a flush, even if the engine holds them in
+// memory
+#[test]
+fn flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().memtable(1 << 20, Duration::from_secs(3600));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let crashed = TempDir::new().expect("unable to create temporary working directory");
+    with_server(store, |addr| {
+        let mut client = KvsClient::connect(addr).unwrap();
+        for i in 0..10 {
+            client.set(format!("key{}", i), i.to_string()).unwrap();
+        }
+        client.flush().unwrap();
+        client
+            .set("unflushed".to_owned(), "value".to_owned())
+            .unwrap();
+
+        // Copy the files as they are while the server is still running, as if it had
+        // been killed
+        for entry in fs::read_dir(temp_dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            if !path.to_string_lossy().ends_with(".LOCK") {
+                fs::copy(&path, crashed.path().join(path.file_name().unwrap())).unwrap();
+            }
+        }
+    })?;
+
+    let store = KvStore::open(crashed.path())?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(i.to_string()));
+    }
+    assert_eq!(store.get("unflushed".to_owned())?, None);
+    Ok(())
+}
+
+// Should report the state of compactions to admin clients, for engines that track it
+#[test]
+fn compaction_status() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.compact()?;
+    with_server(store, |addr| {
+        let mut client = KvsClient::connect(addr).unwrap();
+        let status = client.compaction_status().unwrap().unwrap();
+        assert_eq!(status.runs, 1);
+        assert!(status.last_run.is_some());
+    })?;
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        with_server(SledEngine::open(temp_dir.path())?, |addr| {
+            let mut client = KvsClient::connect(addr).unwrap();
+            assert!(client.compaction_status().unwrap().is_none());
+        })?;
+    }
+
+    Ok(())
+}
+
+// Each mutating command should reach the log as the engine's own ops, and nothing else
+#[test]
+fn wire_commands_match_log_ops() -> Result<()> {
+    use kvs::Record;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    with_server(KvStore::open(temp_dir.path())?, |addr| {
+        let mut client = KvsClient::connect(addr).unwrap();
+        client.set("key".to_owned(), "value".to_owned()).unwrap();
+        client.get("key".to_owned()).unwrap();
+        client.increment("counter".to_owned(), 2).unwrap();
+        client.remove("key".to_owned()).unwrap();
+        let watch = KvsClient::connect(addr)
+            .unwrap()
+            .watch("key".to_owned())
+            .unwrap();
+        drop(watch);
+    })?;
+
+    let records: Vec<_> = KvStore::inspect(temp_dir.path().join("kvstore-logs"))?
+        .map(|record| record.op.unwrap())
+        .collect();
+    let set = |key: &str, value: &str| Record::Set {
+        bucket: None,
+        key: key.as_bytes().to_vec(),
+        value: value.as_bytes().to_vec(),
+        compression: None,
+    };
+    assert_eq!(
+        records,
+        vec![
+            set("key", "value"),
+            set("counter", "2"),
+            Record::Rm {
+                bucket: None,
+                key: b"key".to_vec()
+            },
+        ]
+    );
+    Ok(())
+}
+
+// Should send a value's write time and size along with it when asked
+#[test]
+fn get_with_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let written_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let options = KvStoreOptions::new().clock(move || written_at);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    with_server(store, |addr| {
+        let mut client = KvsClient::connect(addr).unwrap();
+        client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        let entry = client.get_with_meta("key1".to_owned()).unwrap().unwrap();
+        assert_eq!(entry.value, "value1");
+        assert_eq!(entry.written_at, Some(written_at));
+        assert_eq!(entry.size, 6);
+        assert_eq!(client.get_with_meta("key2".to_owned()).unwrap(), None);
+        assert_eq!(
+            client.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    })?;
+
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        with_server(SledEngine::open(temp_dir.path())?, |addr| {
+            let mut client = KvsClient::connect(addr).unwrap();
+            client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+            let entry = client.get_with_meta("key1".to_owned()).unwrap().unwrap();
+            assert_eq!((entry.written_at, entry.size), (None, 6));
+        })?;
+    }
+
+    Ok(())
+}
+
+// Should learn the server's version when connecting
+#[test]
+fn server_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    with_server(KvStore::open(temp_dir.path())?, |addr| {
+        let client = KvsClient::connect(addr).unwrap();
+        assert_eq!(client.server_version(), env!("CARGO_PKG_VERSION"));
+        let pool = KvsClientPool::connect(addr, 2).unwrap();
+        assert_eq!(
+            pool.get().unwrap().server_version(),
+            env!("CARGO_PKG_VERSION")
+        );
+    })
+}
+
+// Should refuse requests beyond the limit on a connection firing faster than it, without
+// holding up a connection keeping within it
+#[test]
+fn rate_limit() -> Result<()> {
+    const MAX_RPS: u32 = 20;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::new(4)?;
+    let (mut server, shutdown) = KvsServer::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        KvStore::open(temp_dir.path())?,
+        pool,
+    )
+    .unwrap();
+    server.set_max_rps_per_connection(NonZeroU32::new(MAX_RPS));
+    let addr = server.local_addr().unwrap();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    let mut flooding = KvsClient::connect(addr).unwrap();
+    let mut compliant = KvsClient::connect(addr).unwrap();
+    compliant.set("key".to_owned(), "value".to_owned()).unwrap();
+
+    let mut limited = 0;
+    for _ in 0..3 * MAX_RPS {
+        match flooding.get("key".to_owned()) {
+            Ok(value) => assert_eq!(value, Some("value".to_owned())),
+            Err(e) => {
+                assert!(e.to_string().contains("rate limited"), "{}", e);
+                limited += 1;
+            }
+        }
+    }
+    assert!(limited >= MAX_RPS, "only {} requests were limited", limited);
+
+    for _ in 0..10 {
+        thread::sleep(Duration::from_secs(1) / MAX_RPS * 2);
+        assert_eq!(
+            compliant.get("key".to_owned()).unwrap(),
+            Some("value".to_owned())
+        );
+    }
+    // The flooding connection is served again once it's back within the limit.
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(
+        flooding.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    drop((flooding, compliant));
+
+    shutdown.shutdown().unwrap();
+    server_thread.join().unwrap();
+    Ok(())
+}
+
+// Should refuse writes while read-only, serving reads all the while, and take them again
+// once it's turned off
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::new(4)?;
+    let (server, shutdown) = KvsServer::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        KvStore::open(temp_dir.path())?,
+        pool,
+    )
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+    let read_only = server.read_only_handle();
+    assert!(!read_only.is_read_only());
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key".to_owned(), "1".to_owned()).unwrap();
+
+    read_only.set_read_only(true);
+    fn refused<T: std::fmt::Debug, E: std::fmt::Display>(result: std::result::Result<T, E>) {
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("read-only"), "{}", err);
+    }
+    refused(client.set("key".to_owned(), "2".to_owned()));
+    refused(client.set("other".to_owned(), "2".to_owned()));
+    refused(client.remove("key".to_owned()));
+    refused(client.increment("key".to_owned(), 1));
+    refused(client.copy("key".to_owned(), "other".to_owned(), true));
+    assert_eq!(client.get("key".to_owned()).unwrap(), Some("1".to_owned()));
+    assert_eq!(client.get("other".to_owned()).unwrap(), None);
+    assert_eq!(client.value_len("key".to_owned()).unwrap(), Some(1));
+    client.flush().unwrap();
+
+    read_only.set_read_only(false);
+    client.set("key".to_owned(), "2".to_owned()).unwrap();
+    assert_eq!(client.increment("key".to_owned(), 1).unwrap(), 3);
+    drop(client);
+
+    shutdown.shutdown().unwrap();
+    server_thread.join().unwrap();
+    Ok(())
+}
+
+/// Wait for `f` to hold, for up to five seconds, returning whether it did.
+fn eventually(mut f: impl FnMut() -> bool) -> bool {
+    for _ in 0..500 {
+        if f() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    f()
+}
+
+/// Run a server following the leader at `leader`, returning its address and a function
+/// stopping it.
+fn spawn_follower(store: KvStore, leader: SocketAddr) -> Result<(SocketAddr, impl FnOnce())> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    let (mut server, shutdown) =
+        KvsServer::bind("127.0.0.1:0".parse().unwrap(), store, pool).unwrap();
+    server.follow(leader);
+    let addr = server.local_addr().unwrap();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+    let stop = move || {
+        shutdown.shutdown().unwrap();
+        server_thread.join().unwrap();
+    };
+    Ok((addr, stop))
+}
+
+// Writes through a leader should show up on its followers within a bounded delay, and
+// a follower should copy everything the leader had before it connected, in place of
+// what it had itself
+#[test]
+fn replication() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::new(4)?;
+    let (mut leader, leader_shutdown) = KvsServer::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        KvStore::open(leader_dir.path())?,
+        pool,
+    )
+    .unwrap();
+    leader.set_leader(true);
+    let leader_addr = leader.local_addr().unwrap();
+    let leader_thread = thread::spawn(move || leader.run().unwrap());
+
+    let mut client = KvsClient::connect(leader_addr).unwrap();
+    client.set("before".to_owned(), "1".to_owned()).unwrap();
+    KvStore::open(follower_dir.path())?.set("stale".to_owned(), "x".to_owned())?;
+
+    let (addr, stop) = spawn_follower(KvStore::open(follower_dir.path())?, leader_addr)?;
+    let mut follower = KvsClient::connect(addr).unwrap();
+    let mut shows = |key: &str, value: Option<&str>| {
+        let expected = value.map(str::to_owned);
+        eventually(|| follower.get(key.to_owned()).unwrap() == expected)
+    };
+    assert!(shows("before", Some("1")));
+    assert!(shows("stale", None));
+
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert!(shows("key", Some("value")));
+    client.increment("counter".to_owned(), 5).unwrap();
+    assert!(shows("counter", Some("5")));
+    client
+        .copy("key".to_owned(), "copy".to_owned(), false)
+        .unwrap();
+    assert!(shows("copy", Some("value")));
+    client.remove("key".to_owned()).unwrap();
+    assert!(shows("key", None));
+
+    // Followers refuse writes of their own.
+    let err = follower
+        .set("key".to_owned(), "other".to_owned())
+        .unwrap_err();
+    assert!(err.to_string().contains("read-only"), "{}", err);
+    drop(follower);
+    stop();
+
+    // A follower started again catches up on what it missed.
+    client.remove("before".to_owned()).unwrap();
+    client.set("after".to_owned(), "2".to_owned()).unwrap();
+    let (addr, stop) = spawn_follower(KvStore::open(follower_dir.path())?, leader_addr)?;
+    let mut follower = KvsClient::connect(addr).unwrap();
+    assert!(eventually(
+        || follower.get("after".to_owned()).unwrap() == Some("2".to_owned())
+    ));
+    assert_eq!(follower.get("before".to_owned()).unwrap(), None);
+    assert_eq!(
+        follower.get("copy".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    drop(follower);
+    stop();
+
+    drop(client);
+    leader_shutdown.shutdown().unwrap();
+    leader_thread.join().unwrap();
+    Ok(())
+}